@@ -0,0 +1,78 @@
+//! Memory allocated to broadcast a message of 1 KB to a room of 1000 users.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    iter,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use budget_chat::{Chatroom, ChatroomConfig};
+use tokio::sync::mpsc::channel;
+
+const USERS: usize = 1000;
+const MESSAGES: usize = 100;
+
+/// The system allocator, counting the allocations
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        // SAFETY: forwarded as is
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: forwarded as is
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn main() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        rate_limit: None,
+        ..Default::default()
+    });
+    let mut users = (0..USERS)
+        .map(|i| {
+            // large enough for the joins of the next users and every message
+            let (sender, receiver) = channel(USERS + MESSAGES + 16);
+            let session = chatroom.join(format!("user{i}"), sender).ok().unwrap();
+            (session, receiver)
+        })
+        .collect::<Vec<_>>();
+    for (_, receiver) in &mut users {
+        while receiver.try_recv().is_ok() {}
+    }
+    let text = "x".repeat(1024);
+
+    let (allocations, allocated) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED.load(Ordering::Relaxed),
+    );
+    for _ in 0..MESSAGES {
+        users[0].0.send_message(text.clone()).unwrap();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+
+    let received: usize = users[1..]
+        .iter_mut()
+        .map(|(_, receiver)| iter::from_fn(|| receiver.try_recv().ok()).count())
+        .sum();
+    assert_eq!(received, MESSAGES * (USERS - 1));
+    println!(
+        "broadcast to {USERS} users: {} allocations and {} KiB per message",
+        allocations / MESSAGES,
+        allocated / MESSAGES / 1024
+    );
+}
@@ -0,0 +1,76 @@
+//! Broadcast throughput: a chatter sends messages to a room of 1000 users, while
+//! other users join and leave.
+//!
+//! Run with `cargo bench --bench broadcast`.
+
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use budget_chat::{Chatroom, ChatroomConfig};
+use tokio::sync::mpsc::channel;
+
+const USERS: usize = 1000;
+const MESSAGES: usize = 2000;
+/// Maximum number of joins during the broadcasts
+const JOINS: usize = 1000;
+
+fn main() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        rate_limit: None,
+        ..Default::default()
+    });
+    let mut users = (0..USERS)
+        .map(|i| {
+            // large enough for every message, the joins and the leaves
+            let (sender, receiver) = channel(MESSAGES + 2 * JOINS + 16);
+            let session = chatroom.join(format!("user{i}"), sender).ok().unwrap();
+            (session, receiver)
+        })
+        .collect::<Vec<_>>();
+
+    // joins and leaves contend for the chatroom during the broadcasts
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let chatroom = chatroom.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut joins = 0;
+            let mut total = Duration::ZERO;
+            let mut slowest = Duration::ZERO;
+            while joins < JOINS && !stop.load(Ordering::Relaxed) {
+                let (sender, _receiver) = channel(16);
+                let start = Instant::now();
+                drop(chatroom.join("visitor".to_string(), sender));
+                total += start.elapsed();
+                slowest = slowest.max(start.elapsed());
+                joins += 1;
+            }
+            (joins, total / joins.max(1) as u32, slowest)
+        })
+    };
+
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        users[0].0.send_message(format!("message {i}")).unwrap();
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let (joins, average, slowest) = churn.join().unwrap();
+
+    let received: usize = users
+        .iter_mut()
+        .map(|(_, receiver)| iter::from_fn(|| receiver.try_recv().ok()).count())
+        .sum();
+    println!(
+        "{MESSAGES} messages to {USERS} users in {elapsed:?}: {:.0} messages/s, {received} received",
+        MESSAGES as f64 / elapsed.as_secs_f64()
+    );
+    println!("{joins} concurrent joins in {average:?} on average, the slowest took {slowest:?}");
+}
@@ -0,0 +1,199 @@
+//! The costs of the chatroom itself, without sockets: joining users, broadcasting a
+//! message to rooms of 10 to 10000 users and sending from 8 threads at once. The
+//! receivers drain their queues on a runtime, as the connections do.
+//!
+//! Run with `cargo bench --bench chatroom`, criterion compares each run with the
+//! previous one, or with a baseline saved by `-- --save-baseline <name>`.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use budget_chat::{Chatroom, ChatroomConfig, Envelope, Message, Session};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+/// Messages sent before waiting for their delivery
+const MESSAGES: usize = 100;
+const SENDERS: usize = 8;
+/// Queue of a receiver, large enough not to evict a receiver lagging behind a batch
+const QUEUE: usize = 4 * SENDERS * MESSAGES;
+/// How long a batch waits for its messages, a receiver evicted as a slow consumer
+/// would never receive them
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn drainers() -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn joins(c: &mut Criterion) {
+    let drainers = drainers();
+    let mut group = c.benchmark_group("join");
+    group.sample_size(10);
+    for users in [100, 1000, 2000] {
+        group.throughput(Throughput::Elements(users as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(users), &users, |b, &users| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let chatroom = chatroom();
+                    // every user is sent the joins of the next ones
+                    let channels = (0..users).map(|_| channel(users + 16)).collect::<Vec<_>>();
+                    let start = Instant::now();
+                    let mut sessions = Vec::with_capacity(users);
+                    for (i, (sender, receiver)) in channels.into_iter().enumerate() {
+                        sessions.push(chatroom.join(format!("user{i}"), sender).ok().unwrap());
+                        drain(&drainers, receiver, Arc::default());
+                    }
+                    elapsed += start.elapsed();
+                    // faster than the leaves of every user
+                    chatroom.shutdown();
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn broadcasts(c: &mut Criterion) {
+    let drainers = drainers();
+    let mut group = c.benchmark_group("broadcast");
+    group.sample_size(10);
+    for users in [10, 100, 1000, 10000] {
+        let chatroom = chatroom();
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let room = fill(&chatroom, &drainers, users, &delivered);
+        let sender = join(&chatroom, &drainers, "sender", &Arc::default());
+        group.throughput(Throughput::Elements(users as u64));
+        group.bench_function(BenchmarkId::from_parameter(users), |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for batch in batches(iters) {
+                    let target = delivered.load(Ordering::Relaxed) + batch * users;
+                    for i in 0..batch {
+                        sender.send_message(format!("message {i}")).unwrap();
+                    }
+                    wait_for(&delivered, target);
+                }
+                start.elapsed()
+            });
+        });
+        chatroom.shutdown();
+        drop((room, sender));
+    }
+    group.finish();
+}
+
+fn concurrent_senders(c: &mut Criterion) {
+    let drainers = drainers();
+    let users = 100;
+    let chatroom = chatroom();
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let room = fill(&chatroom, &drainers, users, &delivered);
+    let senders = (0..SENDERS)
+        .map(|i| join(&chatroom, &drainers, &format!("sender{i}"), &Arc::default()))
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("concurrent senders");
+    group.sample_size(10);
+    // an iteration is a message of every sender
+    group.throughput(Throughput::Elements(SENDERS as u64));
+    group.bench_function(format!("{SENDERS} senders, {users} receivers"), |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            for batch in batches(iters) {
+                // the senders receive the messages of each other, not counted
+                let target = delivered.load(Ordering::Relaxed) + SENDERS * batch * users;
+                thread::scope(|scope| {
+                    for sender in &senders {
+                        scope.spawn(move || {
+                            for i in 0..batch {
+                                sender.send_message(format!("message {i}")).unwrap();
+                            }
+                        });
+                    }
+                });
+                wait_for(&delivered, target);
+            }
+            start.elapsed()
+        });
+    });
+    group.finish();
+    chatroom.shutdown();
+    drop((room, senders));
+}
+
+criterion_group!(benches, joins, broadcasts, concurrent_senders);
+criterion_main!(benches);
+
+fn chatroom() -> Chatroom {
+    Chatroom::new(ChatroomConfig {
+        rate_limit: None,
+        ..Default::default()
+    })
+}
+
+/// The sizes of the batches of `iters` messages, up to [`MESSAGES`] each
+fn batches(iters: u64) -> impl Iterator<Item = usize> {
+    let iters = iters as usize;
+    (0..iters.div_ceil(MESSAGES)).map(move |i| MESSAGES.min(iters - i * MESSAGES))
+}
+
+/// Join `users` users counting the chat messages they receive in `delivered`
+fn fill(
+    chatroom: &Chatroom,
+    drainers: &Runtime,
+    users: usize,
+    delivered: &Arc<AtomicUsize>,
+) -> Vec<Session> {
+    (0..users)
+        .map(|i| join(chatroom, drainers, &format!("user{i}"), delivered))
+        .collect()
+}
+
+fn join(
+    chatroom: &Chatroom,
+    drainers: &Runtime,
+    nickname: &str,
+    delivered: &Arc<AtomicUsize>,
+) -> Session {
+    let (sender, receiver): (Sender<Envelope>, _) = channel(QUEUE);
+    let session = chatroom.join(nickname.to_string(), sender).ok().unwrap();
+    drain(drainers, receiver, delivered.clone());
+    session
+}
+
+/// Receive the messages of `receiver` until its user leaves, counting the chat
+/// messages in `delivered`
+fn drain(drainers: &Runtime, mut receiver: Receiver<Envelope>, delivered: Arc<AtomicUsize>) {
+    drainers.spawn(async move {
+        while let Some(envelope) = receiver.recv().await {
+            if let Message::Message { .. } = envelope.message {
+                delivered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+fn wait_for(delivered: &AtomicUsize, target: usize) {
+    let start = Instant::now();
+    while delivered.load(Ordering::Relaxed) < target {
+        assert!(
+            start.elapsed() < DELIVERY_TIMEOUT,
+            "messages not delivered, was a receiver evicted?"
+        );
+        thread::yield_now();
+    }
+}
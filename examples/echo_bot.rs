@@ -0,0 +1,54 @@
+//! A chat server with a bot, "echobot", repeating the messages mentioning it.
+//!
+//! Run with `cargo run --example echo_bot [port]`, the port defaults to 9000.
+
+use std::{env, future, sync::mpsc, thread};
+
+use budget_chat::{
+    server::{serve, ServerConfig},
+    Chatroom, JoinError, Message,
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+pub const NICKNAME: &str = "echobot";
+
+/// Join `chatroom` as the bot, it replies until evicted
+pub fn start(chatroom: &Chatroom) -> Result<(), JoinError> {
+    let (replies, texts) = mpsc::channel();
+    let session = chatroom.join_with_handler(NICKNAME.to_string(), move |message| {
+        let text = match message {
+            Message::Message { text, .. } | Message::Mentioned { text, .. } => text,
+            _ => return,
+        };
+        if text.contains(NICKNAME) {
+            let _ = replies.send(text.to_string());
+        }
+    })?;
+    // the handler is dropped once the session left, ending the replies
+    thread::spawn(move || {
+        for text in texts {
+            let _ = session.send_message(text);
+        }
+    });
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let port: u16 = match env::args().nth(1) {
+        Some(port) => port.parse().expect("invalid port"),
+        None => 9000,
+    };
+    let runtime = Runtime::new().expect("cannot start the runtime");
+    let listener = runtime
+        .block_on(TcpListener::bind(("0.0.0.0", port)))
+        .expect("cannot listen");
+    let chatroom = Chatroom::default();
+    start(&chatroom).expect("cannot join the chatroom");
+    runtime.block_on(serve(
+        listener,
+        chatroom,
+        ServerConfig::default(),
+        future::pending(),
+    ))
+}
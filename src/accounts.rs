@@ -0,0 +1,139 @@
+//! Registered nicknames, protected by a password
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use argon2::{
+    password_hash::{PasswordHashString, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+};
+
+/// The cost of the Argon2id hashes made by [`hash_password`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordParams {
+    /// memory used by a hash, in KiB
+    pub memory_kib: u32,
+    /// passes over the memory
+    pub passes: u32,
+    /// lanes of the memory, hashed in parallel by the other implementations
+    pub lanes: u32,
+}
+
+impl Default for PasswordParams {
+    /// The minimum recommended by OWASP: 19 MiB, 2 passes and 1 lane
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            passes: 2,
+            lanes: 1,
+        }
+    }
+}
+
+/// Hash `password` with Argon2id and a random salt, in the PHC string format of the
+/// accounts files: `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`
+pub fn hash_password(password: &str, params: PasswordParams) -> io::Result<String> {
+    let invalid = |e: argon2::password_hash::Error| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let mut salt = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut salt)?;
+    let salt = SaltString::encode_b64(&salt).map_err(invalid)?;
+    // Params::new multiplies the lanes by 8 before checking their maximum
+    if params.lanes > Params::MAX_P_COST {
+        return Err(invalid(argon2::Error::ThreadsTooMany.into()));
+    }
+    let params = Params::new(params.memory_kib, params.passes, params.lanes, None)
+        .map_err(|e| invalid(e.into()))?;
+    let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(invalid)?;
+    Ok(hash.to_string())
+}
+
+/// Parse an Argon2 hash in the PHC string format, checking its parameters
+fn parse_hash(s: &str) -> Result<PasswordHashString, String> {
+    let hash = PasswordHash::new(s).map_err(|e| format!("not an Argon2 hash: {e}"))?;
+    Algorithm::try_from(hash.algorithm).map_err(|e| format!("not an Argon2 hash: {e}"))?;
+    if hash
+        .version
+        .is_some_and(|version| Version::try_from(version).is_err())
+    {
+        return Err(format!("unsupported Argon2 version: {s}"));
+    }
+    let lanes = hash.params.get_decimal("p");
+    if lanes.is_some_and(|lanes| lanes > Params::MAX_P_COST) {
+        return Err(format!(
+            "invalid Argon2 parameters: {}",
+            argon2::Error::ThreadsTooMany
+        ));
+    }
+    Params::try_from(&hash).map_err(|e| format!("invalid Argon2 parameters: {e}"))?;
+    if hash.hash.is_none() {
+        return Err(format!("not an Argon2 hash: {s}"));
+    }
+    Ok(hash.serialize())
+}
+
+/// The registered nicknames and the Argon2 hashes of their passwords.
+///
+/// The users joining with a registered nickname are asked its password by the
+/// server, see [`ChatroomConfig::accounts`](crate::ChatroomConfig::accounts).
+#[derive(Clone, Debug, Default)]
+pub struct Accounts {
+    hashes: HashMap<String, PasswordHashString>,
+}
+
+impl Accounts {
+    /// Load the `nickname:hash` lines of `file`, the hashes made by [`hash_password`]
+    /// or any Argon2 implementation. Blank lines are ignored.
+    pub fn load(file: &Path) -> io::Result<Self> {
+        let invalid = |number: usize, reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {reason}", number + 1),
+            )
+        };
+        let mut hashes = HashMap::new();
+        for (number, line) in fs::read_to_string(file)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((nickname, hash)) = line.split_once(':') else {
+                return Err(invalid(number, "expected nickname:hash".to_string()));
+            };
+            let hash = parse_hash(hash).map_err(|e| invalid(number, e))?;
+            if hashes.insert(nickname.to_string(), hash).is_some() {
+                return Err(invalid(number, format!("duplicate nickname {nickname}")));
+            }
+        }
+        Ok(Self { hashes })
+    }
+
+    /// Number of registered nicknames
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn is_registered(&self, nickname: &str) -> bool {
+        self.hashes.contains_key(nickname)
+    }
+
+    /// Whether `password` is the one of the registered `nickname`. Slow by design:
+    /// call it off the async tasks.
+    pub fn verify(&self, nickname: &str, password: &str) -> bool {
+        self.hashes.get(nickname).is_some_and(|hash| {
+            // the algorithm and parameters are those of the hash
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash.password_hash())
+                .is_ok()
+        })
+    }
+}
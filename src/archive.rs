@@ -0,0 +1,370 @@
+//! Durable archive of the chat messages in a SQLite database, written by a dedicated
+//! thread
+
+use std::{
+    fmt::Display,
+    io, iter,
+    path::Path,
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use parking_lot::Mutex;
+use rusqlite::{
+    functions::FunctionFlags, params, Connection, ErrorCode, OpenFlags, Row, TransactionBehavior,
+};
+use serde_json::json;
+use tracing::warn;
+
+/// The schema of the archive: `PRAGMA user_version` is the number of the migrations
+/// applied, those missing are applied in order when the archive is opened
+const MIGRATIONS: &[&str] = &["CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        -- milliseconds since the Unix epoch
+        ts INTEGER NOT NULL,
+        room TEXT NOT NULL,
+        nick TEXT NOT NULL,
+        text TEXT NOT NULL,
+        -- written with /me
+        emote INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX messages_room ON messages (room, id);
+    CREATE INDEX messages_ts ON messages (ts);"];
+
+/// How long the writer thread waits for the readers of the archive, e.g. an
+/// `archive-dump`, before giving up a write
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Entry {
+    at: SystemTime,
+    room: String,
+    nickname: String,
+    text: String,
+    emote: bool,
+}
+
+enum Request {
+    Archive(Entry),
+    Search {
+        room: String,
+        /// lowercase
+        term: String,
+        limit: usize,
+        found: mpsc::SyncSender<io::Result<Vec<ArchivedMessage>>>,
+    },
+}
+
+/// SQLite archive of the messages and emotes of the chatroom, searched by `/search`.
+///
+/// Messages are queued to a writer thread: archiving never blocks on the database.
+/// The searches are run by the same thread, after the messages queued before them are
+/// written.
+pub struct Archive {
+    sender: Mutex<Option<mpsc::Sender<Request>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Archive {
+    /// Open the archive at `path`, creating it if needed, and bring its schema up to
+    /// date. Fails if the database is corrupt, locked by another process or written by
+    /// a newer version: the messages would not be archived.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut connection = Connection::open(path).map_err(error)?;
+        // fail rather than wait for a lock at startup
+        connection.busy_timeout(Duration::ZERO).map_err(error)?;
+        let check: String = connection
+            .pragma_query_value(None, "quick_check", |row| row.get(0))
+            .map_err(error)?;
+        if check != "ok" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the archive is corrupt: {check}"),
+            ));
+        }
+        // archive-dump reads while the server writes
+        connection
+            .pragma_update(None, "journal_mode", "wal")
+            .map_err(error)?;
+        migrate(&mut connection)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(error)?;
+        connection
+            .create_scalar_function(
+                "contains_folded",
+                2,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                |context| {
+                    let text = context.get_raw(0).as_str().unwrap_or_default();
+                    let term = context.get_raw(1).as_str().unwrap_or_default();
+                    Ok(text.to_lowercase().contains(term))
+                },
+            )
+            .map_err(error)?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("archive".to_string())
+            .spawn(move || serve_requests(connection, receiver))?;
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub(crate) fn record(&self, room: &str, nickname: &str, text: &str, emote: bool) {
+        if let Some(sender) = &*self.sender.lock() {
+            let _ = sender.send(Request::Archive(Entry {
+                at: SystemTime::now(),
+                room: room.to_string(),
+                nickname: nickname.to_string(),
+                text: text.to_string(),
+                emote,
+            }));
+        }
+    }
+
+    /// The last `limit` messages and emotes of `room` containing `term`, ignoring the
+    /// case, oldest first. Waits for the writer thread: call it off the async tasks.
+    pub fn search(&self, room: &str, term: &str, limit: usize) -> io::Result<Vec<ArchivedMessage>> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "the archive is closed");
+        let (found, receiver) = mpsc::sync_channel(1);
+        let request = Request::Search {
+            room: room.to_string(),
+            term: term.to_lowercase(),
+            limit,
+            found,
+        };
+        match &*self.sender.lock() {
+            Some(sender) => sender.send(request).map_err(|_| closed())?,
+            None => return Err(closed()),
+        }
+        receiver.recv().map_err(|_| closed())?
+    }
+
+    /// Write the queued messages and close the database. Further messages are ignored.
+    pub fn close(&self) {
+        drop(self.sender.lock().take());
+        if let Some(writer) = self.writer.lock().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A search of the archive allowed by [`Session::search`](crate::Session::search)
+pub struct Search {
+    archive: Arc<Archive>,
+    room: String,
+    term: String,
+}
+
+impl Search {
+    pub(crate) fn new(archive: Arc<Archive>, room: String, term: String) -> Self {
+        Self {
+            archive,
+            room,
+            term,
+        }
+    }
+
+    /// The last `limit` messages and emotes found, see [`Archive::search`]
+    pub fn run(self, limit: usize) -> io::Result<Vec<ArchivedMessage>> {
+        self.archive.search(&self.room, &self.term, limit)
+    }
+}
+
+/// A message read back from an archive, see [`read_archive`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedMessage {
+    /// the messages are numbered in the order they were archived
+    pub id: i64,
+    pub time: DateTime<Utc>,
+    pub room: String,
+    pub nickname: String,
+    pub text: String,
+    /// written with `/me`
+    pub emote: bool,
+}
+
+impl ArchivedMessage {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            time: DateTime::from_timestamp_millis(row.get("ts")?).unwrap_or_default(),
+            room: row.get("room")?,
+            nickname: row.get("nick")?,
+            text: row.get("text")?,
+            emote: row.get("emote")?,
+        })
+    }
+
+    /// The message as a JSON object, as the events of the chat log, e.g.
+    /// `{"id":1,"time":"2024-05-01T12:00:00.000Z","event":"message",...}`
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "event": if self.emote { "emote" } else { "message" },
+            "room": self.room,
+            "nickname": self.nickname,
+            "text": self.text,
+        })
+    }
+}
+
+impl Display for ArchivedMessage {
+    /// The time followed by the line of the message, e.g. `2024-05-01 12:00:00 [alice] hi`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.time.format("%Y-%m-%d %H:%M:%S"))?;
+        if self.emote {
+            write!(f, "* {} {}", self.nickname, self.text)
+        } else {
+            write!(f, "[{}] {}", self.nickname, self.text)
+        }
+    }
+}
+
+/// Call `each` with the messages of the archive at `path` from `since`, oldest first.
+/// The archive is only read, it may be written by a server meanwhile.
+pub fn read_archive(
+    path: &Path,
+    since: Option<DateTime<Utc>>,
+    mut each: impl FnMut(ArchivedMessage),
+) -> io::Result<()> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let connection = Connection::open_with_flags(path, flags).map_err(error)?;
+    let version: usize = connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(error)?;
+    if version == 0 || version > MIGRATIONS.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not an archive of this version, at schema version {version}"),
+        ));
+    }
+    let mut statement = connection
+        .prepare("SELECT * FROM messages WHERE ts >= ?1 ORDER BY id")
+        .map_err(error)?;
+    let since = since.map_or(i64::MIN, |since| since.timestamp_millis());
+    let messages = statement
+        .query_map([since], ArchivedMessage::from_row)
+        .map_err(error)?;
+    for message in messages {
+        each(message.map_err(error)?);
+    }
+    Ok(())
+}
+
+/// Apply the [`MIGRATIONS`] missing from the archive
+fn migrate(connection: &mut Connection) -> io::Result<()> {
+    // locks the database for the check of its version too
+    let transaction = connection
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(error)?;
+    let version: usize = transaction
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(error)?;
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the archive is at schema version {version}, written by a newer server"),
+        ));
+    }
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration).map_err(error)?;
+    }
+    transaction
+        .pragma_update(None, "user_version", MIGRATIONS.len())
+        .and_then(|()| transaction.commit())
+        .map_err(error)
+}
+
+fn serve_requests(mut connection: Connection, receiver: mpsc::Receiver<Request>) {
+    let mut pending = Vec::new();
+    while let Ok(request) = receiver.recv() {
+        for request in iter::once(request).chain(receiver.try_iter()) {
+            match request {
+                Request::Archive(entry) => pending.push(entry),
+                Request::Search {
+                    room,
+                    term,
+                    limit,
+                    found,
+                } => {
+                    // the messages sent before the search are found
+                    insert(&mut connection, &mut pending);
+                    let _ = found.send(search(&connection, &room, &term, limit).map_err(error));
+                }
+            }
+        }
+        insert(&mut connection, &mut pending);
+    }
+}
+
+/// Write the `pending` messages in a single transaction
+fn insert(connection: &mut Connection, pending: &mut Vec<Entry>) {
+    if pending.is_empty() {
+        return;
+    }
+    let written = connection.transaction().and_then(|transaction| {
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO messages (ts, room, nick, text, emote) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for entry in pending.iter() {
+                let at = DateTime::<Utc>::from(entry.at).timestamp_millis();
+                statement.execute(params![
+                    at,
+                    entry.room,
+                    entry.nickname,
+                    entry.text,
+                    entry.emote
+                ])?;
+            }
+        }
+        transaction.commit()
+    });
+    if let Err(e) = written {
+        warn!(error = %e, messages = pending.len(), "cannot write the archive");
+    }
+    pending.clear();
+}
+
+fn search(
+    connection: &Connection,
+    room: &str,
+    term: &str,
+    limit: usize,
+) -> rusqlite::Result<Vec<ArchivedMessage>> {
+    let mut statement = connection.prepare_cached(
+        "SELECT * FROM messages WHERE room = ?1 AND contains_folded(text, ?2)
+        ORDER BY id DESC LIMIT ?3",
+    )?;
+    let found = statement.query_map(
+        params![room, term, i64::try_from(limit).unwrap_or(i64::MAX)],
+        ArchivedMessage::from_row,
+    )?;
+    let mut found = found.collect::<rusqlite::Result<Vec<_>>>()?;
+    found.reverse();
+    Ok(found)
+}
+
+/// The I/O error of `e`, telling of the locked and corrupt archives
+fn error(e: rusqlite::Error) -> io::Error {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!("the archive is locked by another process: {e}"),
+        ),
+        Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the archive is corrupt: {e}"),
+        ),
+        _ => io::Error::other(e),
+    }
+}
@@ -0,0 +1,107 @@
+//! Addresses banned from the server, optionally persisted to a file
+
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+use tracing::warn;
+
+/// The banned IP addresses, shared by the chatroom and the servers accepting connections
+#[derive(Default)]
+pub struct BanList {
+    ips: Mutex<HashSet<IpAddr>>,
+    /// one address per line
+    file: Option<PathBuf>,
+    /// held while writing the file, the accept loops only take `ips`
+    saving: Mutex<()>,
+}
+
+impl BanList {
+    /// Load the bans saved in `file`, a missing file holds no ban.
+    ///
+    /// Further bans are appended to the file.
+    pub fn load(file: PathBuf) -> io::Result<Self> {
+        let ips = match fs::read_to_string(&file) {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    line.parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                })
+                .collect::<io::Result<_>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            ips: Mutex::new(ips),
+            file: Some(file),
+            saving: Mutex::new(()),
+        })
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.ips.lock().contains(&ip)
+    }
+
+    /// The banned addresses, sorted
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<_> = self.ips.lock().iter().copied().collect();
+        ips.sort();
+        ips
+    }
+
+    /// Ban `ip`, returns `false` if it was already banned
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        if !self.ips.lock().insert(ip) {
+            return false;
+        }
+        if let Some(file) = &self.file {
+            let _saving = self.saving.lock();
+            let saved = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut file| writeln!(file, "{ip}"));
+            if let Err(e) = saved {
+                warn!(%ip, error = %e, "cannot save the ban");
+            }
+        }
+        true
+    }
+
+    /// Lift the ban of `ip`, returns `false` if it was not banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        if !self.ips.lock().remove(&ip) {
+            return false;
+        }
+        if let Some(file) = &self.file {
+            let _saving = self.saving.lock();
+            // after the bans appended meanwhile
+            let ips = self.ips();
+            if let Err(e) = rewrite(file, &ips) {
+                warn!(%ip, error = %e, "cannot save the lifted ban");
+            }
+        }
+        true
+    }
+}
+
+/// Replace `file` by the list of `ips`, never left half written: a temporary file is
+/// renamed over it
+fn rewrite(file: &Path, ips: &[IpAddr]) -> io::Result<()> {
+    let mut temporary = file.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let mut writer = fs::File::create(&temporary)?;
+    for ip in ips {
+        writeln!(writer, "{ip}")?;
+    }
+    writer.sync_all()?;
+    fs::rename(&temporary, file)
+}
@@ -0,0 +1,422 @@
+//! Append-only log of the chat events, written to disk by a dedicated thread
+
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat, Utc};
+use parking_lot::Mutex;
+use serde_json::json;
+use tracing::warn;
+
+/// When the chat log is synced to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSync {
+    /// after every write
+    Every,
+    /// at most this often
+    Interval(Duration),
+    /// left to the OS
+    Never,
+}
+
+impl FromStr for LogSync {
+    type Err = String;
+
+    /// Parse `every`, `interval:<secs>` or `never`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "every" => Ok(LogSync::Every),
+            "never" => Ok(LogSync::Never),
+            _ => s
+                .strip_prefix("interval:")
+                .and_then(|secs| secs.parse().ok())
+                .map(|secs| LogSync::Interval(Duration::from_secs(secs)))
+                .ok_or_else(|| format!("expected every, interval:<secs> or never, got {s}")),
+        }
+    }
+}
+
+/// When the chat log file is rotated: renamed to `<name>-<date>.<ext>`, e.g.
+/// `chat-2024-05-01.log`, and replaced by an empty file. The files rotated the same
+/// day are numbered: `chat-2024-05-01.1.log`...
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogRotation {
+    /// when the local date changes
+    pub daily: bool,
+    /// before an event would make the file larger than this many bytes
+    pub max_bytes: Option<u64>,
+    /// how many rotated files are kept, the oldest ones are deleted
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    /// Never rotated
+    fn default() -> Self {
+        Self {
+            daily: false,
+            max_bytes: None,
+            keep: 7,
+        }
+    }
+}
+
+/// An event of the chat log
+pub(crate) enum ChatEvent {
+    Join,
+    Leave,
+    Message,
+    Emote,
+}
+
+struct Entry {
+    at: SystemTime,
+    event: ChatEvent,
+    room: String,
+    nickname: String,
+    text: Option<String>,
+}
+
+/// JSON lines log of the joins, leaves and messages of the chatroom.
+///
+/// Events are queued to a writer thread: logging never blocks on the disk.
+pub struct ChatLog {
+    sender: Mutex<Option<mpsc::Sender<Entry>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ChatLog {
+    /// Open `path` for appending, creating it if needed. Fails if another chat log
+    /// has it open, in this process or another.
+    pub fn open(path: &Path, sync: LogSync) -> io::Result<Self> {
+        Self::open_rotating(path, sync, LogRotation::default())
+    }
+
+    /// Open `path` as [`ChatLog::open`], rotated per `rotation`
+    pub fn open_rotating(path: &Path, sync: LogSync, rotation: LogRotation) -> io::Result<Self> {
+        Self::open_with_clock(path, sync, rotation, SystemTime::now)
+    }
+
+    /// Open `path` as [`ChatLog::open_rotating`], rotated by the dates of `now` rather
+    /// than of the system clock, e.g. to test the rotations
+    pub fn open_with_clock(
+        path: &Path,
+        sync: LogSync,
+        rotation: LogRotation,
+        now: impl Fn() -> SystemTime + Send + 'static,
+    ) -> io::Result<Self> {
+        let file = open_locked(path)?;
+        let rotator = Rotator::new(path, &file, rotation, Box::new(now))?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("chat-log".to_string())
+            .spawn(move || write_entries(file, receiver, sync, rotator))?;
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub(crate) fn record(&self, event: ChatEvent, room: &str, nickname: &str, text: Option<&str>) {
+        if let Some(sender) = &*self.sender.lock() {
+            let _ = sender.send(Entry {
+                at: SystemTime::now(),
+                event,
+                room: room.to_string(),
+                nickname: nickname.to_string(),
+                text: text.map(str::to_string),
+            });
+        }
+    }
+
+    /// Write the queued events, sync and close the file. Further events are ignored.
+    pub fn close(&self) {
+        drop(self.sender.lock().take());
+        if let Some(writer) = self.writer.lock().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for ChatLog {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// An event read back from a chat log, see [`read_chat_log`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoggedEvent {
+    pub time: DateTime<Utc>,
+    /// `join`, `leave`, `message` or `emote`
+    pub event: String,
+    pub room: String,
+    pub nickname: String,
+    /// the text of the messages and emotes, the reason of the leaves
+    pub text: Option<String>,
+}
+
+impl LoggedEvent {
+    fn parse(line: &str) -> Option<Self> {
+        let json: serde_json::Value = serde_json::from_str(line).ok()?;
+        let field = |name: &str| json[name].as_str().map(str::to_string);
+        Some(Self {
+            time: DateTime::parse_from_rfc3339(json["time"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+            event: field("event")?,
+            room: field("room")?,
+            nickname: field("nickname")?,
+            text: field("text"),
+        })
+    }
+
+    /// The JSON line of the event in the chat log
+    pub fn to_json(&self) -> serde_json::Value {
+        to_json(&Entry {
+            at: self.time.into(),
+            event: match self.event.as_str() {
+                "join" => ChatEvent::Join,
+                "leave" => ChatEvent::Leave,
+                "emote" => ChatEvent::Emote,
+                _ => ChatEvent::Message,
+            },
+            room: self.room.clone(),
+            nickname: self.nickname.clone(),
+            text: self.text.clone(),
+        })
+    }
+}
+
+impl Display for LoggedEvent {
+    /// The time followed by the line of the event, e.g. `2024-05-01 12:00:00 [alice] hi`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.time.format("%Y-%m-%d %H:%M:%S"))?;
+        let text = self.text.as_deref().unwrap_or_default();
+        match self.event.as_str() {
+            "join" => write!(f, "* {} joined the room", self.nickname),
+            "leave" if text.is_empty() => write!(f, "* {} left the room", self.nickname),
+            "leave" => write!(f, "* {} left the room ({text})", self.nickname),
+            "emote" => write!(f, "* {} {text}", self.nickname),
+            _ => write!(f, "[{}] {text}", self.nickname),
+        }
+    }
+}
+
+/// The events of the chat log at `path`, oldest first. The lines that are not events,
+/// e.g. cut by a crash, are skipped.
+pub fn read_chat_log(path: &Path) -> io::Result<impl Iterator<Item = io::Result<LoggedEvent>>> {
+    let lines = BufReader::new(File::open(path)?).lines();
+    Ok(lines.filter_map(|line| match line {
+        Ok(line) => LoggedEvent::parse(&line).map(Ok),
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+fn write_entries(file: File, receiver: mpsc::Receiver<Entry>, sync: LogSync, mut rotator: Rotator) {
+    let mut log = BufWriter::new(file);
+    let mut synced_at = Instant::now();
+    let timeout = match sync {
+        LogSync::Interval(interval) => interval,
+        _ => Duration::from_secs(3600),
+    };
+    loop {
+        let entry = match receiver.recv_timeout(timeout) {
+            Ok(entry) => Some(entry),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let written = entry
+            .into_iter()
+            .chain(receiver.try_iter())
+            .try_for_each(|entry| {
+                let line = format!("{}\n", to_json(&entry));
+                // between two lines: an event is entirely in one file
+                if rotator.is_due(line.len()) {
+                    // the events go on to the current file
+                    if let Err(e) = rotator.rotate(&mut log) {
+                        warn!(error = %e, "cannot rotate the chat log");
+                    }
+                }
+                rotator.bytes += line.len() as u64;
+                log.write_all(line.as_bytes())
+            })
+            .and_then(|_| log.flush());
+        let written = written.and_then(|_| match sync {
+            LogSync::Every => log.get_ref().sync_data(),
+            LogSync::Interval(interval) if synced_at.elapsed() >= interval => {
+                synced_at = Instant::now();
+                log.get_ref().sync_data()
+            }
+            _ => Ok(()),
+        });
+        if let Err(e) = written {
+            warn!(error = %e, "cannot write the chat log");
+        }
+    }
+    if let Err(e) = log.flush().and_then(|_| log.get_ref().sync_all()) {
+        warn!(error = %e, "cannot write the chat log");
+    }
+}
+
+/// Open `path` for appending, locked for this chat log
+fn open_locked(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    // SAFETY: the descriptor is open, the lock is released when the file is closed
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "the chat log is used by another server",
+            ),
+            _ => e,
+        });
+    }
+    Ok(file)
+}
+
+/// The rotations of a chat log, run by its writer thread
+struct Rotator {
+    path: PathBuf,
+    rotation: LogRotation,
+    now: Box<dyn Fn() -> SystemTime + Send>,
+    /// the local date of the events of the current file
+    date: NaiveDate,
+    /// size of the current file
+    bytes: u64,
+}
+
+impl Rotator {
+    fn new(
+        path: &Path,
+        file: &File,
+        rotation: LogRotation,
+        now: Box<dyn Fn() -> SystemTime + Send>,
+    ) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        // the file may have been written another day
+        let written = match metadata.len() {
+            0 => now(),
+            _ => metadata.modified()?,
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            date: local_date(written),
+            bytes: metadata.len(),
+            now,
+        })
+    }
+
+    /// Whether the file is rotated before writing `len` bytes
+    fn is_due(&self, len: usize) -> bool {
+        let rotation = &self.rotation;
+        let full = rotation
+            .max_bytes
+            .is_some_and(|max_bytes| self.bytes > 0 && self.bytes + len as u64 > max_bytes);
+        let new_day = rotation.daily && local_date((self.now)()) != self.date;
+        full || new_day
+    }
+
+    /// Rename the current file, open an empty one in `log` and delete the oldest
+    /// rotated files
+    fn rotate(&mut self, log: &mut BufWriter<File>) -> io::Result<()> {
+        log.flush()?;
+        // tried once a day if the rotations fail
+        let date = std::mem::replace(&mut self.date, local_date((self.now)()));
+        let (stem, extension) = self.name_parts();
+        let directory = self.path.parent().unwrap_or(Path::new(""));
+        let mut rotated = directory.join(format!("{stem}-{date}{extension}"));
+        for number in 1.. {
+            if !rotated.exists() {
+                break;
+            }
+            rotated = directory.join(format!("{stem}-{date}.{number}{extension}"));
+        }
+        fs::rename(&self.path, &rotated)?;
+        *log = BufWriter::new(open_locked(&self.path)?);
+        self.bytes = 0;
+        if let Err(e) = self.prune(directory, &stem, &extension) {
+            warn!(error = %e, "cannot delete the old chat logs");
+        }
+        Ok(())
+    }
+
+    /// The file name of the log without its extension, and its extension with its dot
+    fn name_parts(&self) -> (String, String) {
+        let stem = self.path.file_stem().unwrap_or_default();
+        let extension = self
+            .path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()));
+        (stem.to_string_lossy().into(), extension.unwrap_or_default())
+    }
+
+    /// Delete the rotated files but the `keep` most recent ones
+    fn prune(&self, directory: &Path, stem: &str, extension: &str) -> io::Result<()> {
+        let prefix = format!("{stem}-");
+        let mut rotated = Vec::new();
+        let directory = match directory.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => directory,
+        };
+        for file in fs::read_dir(directory)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            let Some(suffix) = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(extension))
+            else {
+                continue;
+            };
+            // <date> or <date>.<number>
+            let (date, number) = match suffix.split_once('.') {
+                Some((date, number)) => (date, number.parse::<u32>().ok()),
+                None => (suffix, Some(0)),
+            };
+            let (Ok(date), Some(number)) = (date.parse::<NaiveDate>(), number) else {
+                continue;
+            };
+            rotated.push(((date, number), file.path()));
+        }
+        rotated.sort_unstable();
+        let expired = rotated.len().saturating_sub(self.rotation.keep);
+        for (_, path) in &rotated[..expired] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn local_date(time: SystemTime) -> NaiveDate {
+    DateTime::<Local>::from(time).date_naive()
+}
+
+fn to_json(entry: &Entry) -> serde_json::Value {
+    let at: DateTime<Utc> = entry.at.into();
+    let event = match entry.event {
+        ChatEvent::Join => "join",
+        ChatEvent::Leave => "leave",
+        ChatEvent::Message => "message",
+        ChatEvent::Emote => "emote",
+    };
+    let mut json = json!({
+        "time": at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "event": event,
+        "room": entry.room,
+        "nickname": entry.nickname,
+    });
+    if let Some(text) = &entry.text {
+        json["text"] = text.as_str().into();
+    }
+    json
+}
@@ -1,32 +1,817 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
-    sync::{mpsc::Sender, Arc},
+    net::{IpAddr, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use parking_lot::Mutex;
+use chrono::{DateTime, Local, Utc};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tracing::{debug, info, warn};
+
+use crate::{
+    accounts::Accounts,
+    archive::{Archive, Search},
+    bans::BanList,
+    chat_log::{ChatEvent, ChatLog},
+    fanout::{Fanout, Queue, Workers},
+    history::{History, HistoryConfig},
+    hooks::{HookResult, Hooks},
+    json,
+    metrics::{ChatroomStats, Metrics, MetricsSnapshot, SessionStats},
+    mutes::Mutes,
+    operators::OperatorList,
+    rate_limit::{RateLimit, RepeatGuard, TokenBucket},
+    sanitize::Sanitize,
+    sync::{Mutex, MutexGuard, RwLock},
+    templates::MessageTemplates,
+    unicode,
+    word_filter::{WordFilter, WordFilterMode},
+};
 
-#[derive(Default, Clone)]
+/// The users, their rooms and the messages they exchange.
+///
+/// The messages sent to rooms are ordered: they are sequenced under a single lock,
+/// and every user receives the messages of its room in that order, the joins,
+/// leaves and notices included. Two users of the room see the same transcript, but
+/// for the messages they sent themselves. The replies to a single user, such as
+/// [`Message::UserList`], are not part of that order.
+#[derive(Clone)]
 pub struct Chatroom {
     inner: Arc<ChatroomImpl>,
 }
 
+impl Default for Chatroom {
+    fn default() -> Self {
+        Self::new(ChatroomConfig::default())
+    }
+}
+
+/// Rules of the chatroom
+#[derive(Clone)]
+pub struct ChatroomConfig {
+    /// nicknames the users may join with
+    pub nicknames: NicknameRules,
+    /// how fast each user may send messages, unlimited if `None`
+    pub rate_limit: Option<RateLimit>,
+    /// users exceeding the rate limit this many times in a row are disconnected,
+    /// 0 to never disconnect them
+    pub max_rate_violations: u32,
+    /// how many times each user may send the same message per period, ignoring case
+    /// and surrounding spaces, the next copies are dropped. Unlimited if `None`.
+    pub repeat_limit: Option<RateLimit>,
+    /// users sending copies beyond the repeat limit this many times in a row are
+    /// kicked, 0 to never kick them
+    pub max_repeat_violations: u32,
+    /// users that sent nothing for this long are disconnected, never if `None`
+    pub idle_timeout: Option<Duration>,
+    /// maximum number of users in the chatroom, unlimited if `None`
+    pub max_users: Option<usize>,
+    /// maximum number of users joined from the same IP address with
+    /// [`Chatroom::join_from`], unlimited if `None`
+    pub max_sessions_per_ip: Option<usize>,
+    /// nickname of the operator, allowed to kick users. The first user to join
+    /// the chatroom is the operator if `None` and there are no [`operators`].
+    ///
+    /// [`operators`]: ChatroomConfig::operators
+    pub operator: Option<String>,
+    /// nicknames made operators when they join, changed by [`Chatroom::op`] and
+    /// [`Chatroom::deop`]. The server asks their password first if they are
+    /// registered in the [`accounts`](ChatroomConfig::accounts).
+    pub operators: Arc<OperatorList>,
+    /// addresses banned by the operators
+    pub bans: Arc<BanList>,
+    /// whether the chatroom starts read-only, see [`Chatroom::set_locked`]
+    pub locked: bool,
+    /// whether the joins are announced with the number of users of the room, see
+    /// [`Message::Joined`]
+    pub announce_counts: bool,
+    /// maximum number of users of the rooms but the lobby when they are created,
+    /// unlimited if `None`, see [`Session::set_room_limit`]
+    pub default_room_limit: Option<usize>,
+    /// messages replayed to the joining users
+    pub history: HistoryConfig,
+    /// where the joins, leaves and messages are recorded, closed on shutdown
+    pub chat_log: Option<Arc<ChatLog>>,
+    /// where the messages and emotes are archived, searched by [`Session::search`] and
+    /// closed on shutdown
+    pub archive: Option<Arc<Archive>>,
+    /// what to do when a user joins with the nickname of a connected user
+    pub on_duplicate: OnDuplicate,
+    /// number of threads queuing the broadcast messages for their recipients, 0 to
+    /// queue them on the thread sending them
+    pub fanout_workers: usize,
+    /// what to do with the messages for a user whose queue is full
+    pub slow_client_policy: SlowClientPolicy,
+    /// what to do with the messages containing control characters
+    pub sanitize: Sanitize,
+    /// users receive their own messages and emotes, they can switch it with
+    /// [`Session::set_echo`]
+    pub echo: bool,
+    /// the lines written to the clients by the servers
+    pub templates: Arc<MessageTemplates>,
+    /// the leave notices do not tell how long the users were online, as in the
+    /// original protocol
+    pub plain_leave: bool,
+    /// words not allowed in the messages, nor in the nicknames with
+    /// [`JoinError::DisallowedNickname`]
+    pub word_filter: Arc<WordFilter>,
+    /// what to do with the messages containing words of the [`ChatroomConfig::word_filter`]
+    pub word_filter_mode: WordFilterMode,
+    /// the registered nicknames: the server asks their password before joining, the
+    /// users cannot rename themselves to them
+    pub accounts: Arc<Accounts>,
+    /// how long the nickname of a user whose connection ended is kept for its address,
+    /// the others get [`JoinError::NicknameReserved`]. Not kept if `None`.
+    pub nick_hold: Option<Duration>,
+    /// the joining users are sent a token, with which a new connection takes over
+    /// their session for this long after a [`Session::detach`], see
+    /// [`Chatroom::resume`]. No tokens if `None`.
+    pub resume_window: Option<Duration>,
+}
+
+impl Default for ChatroomConfig {
+    fn default() -> Self {
+        Self {
+            nicknames: NicknameRules::default(),
+            rate_limit: None,
+            max_rate_violations: 5,
+            repeat_limit: None,
+            max_repeat_violations: 3,
+            idle_timeout: None,
+            max_users: None,
+            max_sessions_per_ip: None,
+            operator: None,
+            operators: Arc::default(),
+            bans: Arc::default(),
+            locked: false,
+            announce_counts: false,
+            default_room_limit: None,
+            history: HistoryConfig::default(),
+            chat_log: None,
+            archive: None,
+            on_duplicate: OnDuplicate::Reject,
+            fanout_workers: 0,
+            slow_client_policy: SlowClientPolicy::Disconnect,
+            sanitize: Sanitize::Strip,
+            echo: false,
+            templates: Arc::default(),
+            plain_leave: false,
+            word_filter: Arc::default(),
+            word_filter_mode: WordFilterMode::Mask,
+            accounts: Arc::default(),
+            nick_hold: None,
+            resume_window: None,
+        }
+    }
+}
+
+/// The settings that can be changed while the chatroom runs with
+/// [`Chatroom::reload`], initially those of the [`ChatroomConfig`]
+#[derive(Clone)]
+pub struct RuntimeConfig {
+    /// see [`ChatroomConfig::rate_limit`]
+    pub rate_limit: Option<RateLimit>,
+    /// see [`ChatroomConfig::max_rate_violations`]
+    pub max_rate_violations: u32,
+    /// see [`ChatroomConfig::max_users`]
+    pub max_users: Option<usize>,
+    /// see [`ChatroomConfig::bans`]
+    pub bans: Arc<BanList>,
+    /// see [`ChatroomConfig::operators`], the connected users it holds become
+    /// operators
+    pub operators: Arc<OperatorList>,
+    /// see [`ChatroomConfig::templates`]
+    pub templates: Arc<MessageTemplates>,
+    /// see [`ChatroomConfig::word_filter`]
+    pub word_filter: Arc<WordFilter>,
+    /// see [`ChatroomConfig::accounts`]
+    pub accounts: Arc<Accounts>,
+}
+
+impl From<&ChatroomConfig> for RuntimeConfig {
+    fn from(config: &ChatroomConfig) -> Self {
+        Self {
+            rate_limit: config.rate_limit,
+            max_rate_violations: config.max_rate_violations,
+            max_users: config.max_users,
+            bans: config.bans.clone(),
+            operators: config.operators.clone(),
+            templates: config.templates.clone(),
+            word_filter: config.word_filter.clone(),
+            accounts: config.accounts.clone(),
+        }
+    }
+}
+
+/// Handling of the joins with the nickname of a connected user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// refuse the join with [`JoinError::DuplicateNickname`]
+    Reject,
+    /// refuse the join with [`JoinError::NicknameTaken`], suggesting a free nickname
+    Suggest,
+    /// join the user with a free nickname instead
+    Auto,
+}
+
+impl FromStr for OnDuplicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OnDuplicate::Reject),
+            "suggest" => Ok(OnDuplicate::Suggest),
+            "auto" => Ok(OnDuplicate::Auto),
+            _ => Err(format!("expected reject, suggest or auto, got {s}")),
+        }
+    }
+}
+
+/// Handling of the messages for a user whose queue is full, a slow consumer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// evict the user from the chatroom
+    Disconnect,
+    /// keep the message, dropping the oldest of those waiting for room in the queue. As
+    /// many messages as the queue holds wait, behind those already in the queue.
+    DropOldest,
+    /// drop the message
+    DropNewest,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "drop-oldest" => Ok(SlowClientPolicy::DropOldest),
+            "drop-newest" => Ok(SlowClientPolicy::DropNewest),
+            _ => Err(format!(
+                "expected disconnect, drop-oldest or drop-newest, got {s}"
+            )),
+        }
+    }
+}
+
+/// Which nicknames are accepted, see [`NicknameRules::validate`]
+#[derive(Clone)]
+pub struct NicknameRules {
+    /// minimum length, in characters
+    pub min_len: usize,
+    /// maximum length, in characters
+    pub max_len: usize,
+    /// characters allowed besides the ASCII alphanumerics, e.g. `"_-"`
+    pub extra_chars: String,
+    /// allow the alphanumerics of every script, not only the ASCII ones. The
+    /// nicknames are then composed, and refused when they look like a connected
+    /// nickname: `ＡＢＣ` like `ABC` or a Cyrillic `аdmin` like `admin`
+    pub unicode: bool,
+}
+
+impl Default for NicknameRules {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: 16,
+            extra_chars: String::new(),
+            unicode: false,
+        }
+    }
+}
+
+impl NicknameRules {
+    /// Check `nickname` is an acceptable nickname
+    pub fn validate(&self, nickname: &str) -> Result<(), NicknameError> {
+        let len = nickname.chars().count();
+        if len < self.min_len {
+            return Err(NicknameError::TooShort { min: self.min_len });
+        }
+        if len > self.max_len {
+            return Err(NicknameError::TooLong { max: self.max_len });
+        }
+        match nickname
+            .chars()
+            .enumerate()
+            .find(|(_, c)| !self.is_nickname_char(*c))
+        {
+            Some((position, ch)) => Err(NicknameError::InvalidChar { position, ch }),
+            None => Ok(()),
+        }
+    }
+
+    fn is_nickname_char(&self, c: char) -> bool {
+        let alphanumeric = if self.unicode {
+            c.is_alphanumeric()
+        } else {
+            c.is_ascii_alphanumeric()
+        };
+        alphanumeric || self.extra_chars.contains(c)
+    }
+
+    /// `nickname` as it is stored and compared: composed with the [`Self::unicode`]
+    /// nicknames
+    fn normalize(&self, nickname: String) -> String {
+        if self.unicode && !nickname.is_ascii() {
+            unicode::compose(&nickname)
+        } else {
+            nickname
+        }
+    }
+}
+
+/// Check `nickname` follows the default [`NicknameRules`]
+pub fn validate_nickname(nickname: &str) -> Result<(), NicknameError> {
+    NicknameRules::default().validate(nickname)
+}
+
 impl Chatroom {
-    /// Join the chatroom
+    /// The room users are in when they join the chatroom
+    pub const LOBBY: &'static str = "lobby";
+
+    /// How many messages can be queued for an observer, see
+    /// [`Chatroom::subscribe_observer`]
+    pub const OBSERVER_QUEUE: usize = 256;
+
+    /// How many messages can be queued for a handler, see
+    /// [`Chatroom::join_with_handler`]
+    pub const HANDLER_QUEUE: usize = 256;
+
+    /// How often the messages kept for the slow consumers are moved to their queue,
+    /// with the notice of those dropped, see [`ChatroomConfig::slow_client_policy`]
+    pub const OVERFLOW_FLUSH_PERIOD: Duration = Duration::from_millis(100);
+
+    /// A chatroom following the rules of `config`
+    ///
+    /// With an [`ChatroomConfig::idle_timeout`], a background thread disconnects
+    /// the idle users until the chatroom is dropped. Another one expires the detached
+    /// sessions with a [`ChatroomConfig::resume_window`], and another one flushes the
+    /// queues of the slow consumers unless they are disconnected.
+    pub fn new(config: ChatroomConfig) -> Self {
+        let idle_timeout = config.idle_timeout;
+        let resume_window = config.resume_window;
+        let slow_client_policy = config.slow_client_policy;
+        let inner = Arc::new_cyclic(|chatroom_impl: &Weak<ChatroomImpl>| {
+            let workers = (config.fanout_workers > 0).then(|| {
+                let chatroom_impl = chatroom_impl.clone();
+                Workers::start(config.fanout_workers, move |slow_consumers| {
+                    if let Some(chatroom_impl) = chatroom_impl.upgrade() {
+                        let evicted = chatroom_impl.users.lock().evict(slow_consumers);
+                        disconnect(evicted);
+                    }
+                })
+            });
+            ChatroomImpl::new(config, workers)
+        });
+        if let Some(idle_timeout) = idle_timeout {
+            let chatroom_impl = Arc::downgrade(&inner);
+            let period = (idle_timeout / 4).max(Duration::from_millis(10));
+            thread::spawn(move || loop {
+                thread::sleep(period);
+                match chatroom_impl.upgrade() {
+                    Some(chatroom_impl) => chatroom_impl.disconnect_idle(idle_timeout),
+                    None => return,
+                }
+            });
+        }
+        if let Some(resume_window) = resume_window {
+            let chatroom_impl = Arc::downgrade(&inner);
+            let period = (resume_window / 4).max(Duration::from_millis(10));
+            thread::spawn(move || loop {
+                thread::sleep(period);
+                match chatroom_impl.upgrade() {
+                    Some(chatroom_impl) => chatroom_impl.expire_detached(),
+                    None => return,
+                }
+            });
+        }
+        if slow_client_policy != SlowClientPolicy::Disconnect {
+            let chatroom_impl = Arc::downgrade(&inner);
+            thread::spawn(move || loop {
+                thread::sleep(Self::OVERFLOW_FLUSH_PERIOD);
+                match chatroom_impl.upgrade() {
+                    Some(chatroom_impl) => chatroom_impl.flush_overflows(),
+                    None => return,
+                }
+            });
+        }
+        Self { inner }
+    }
+
+    /// Join the chatroom, in the [`Chatroom::LOBBY`] room
+    ///
+    /// Messages for the user are queued on `message_sender`: a user whose queue is full
+    /// is considered a slow consumer and is evicted from the chatroom, or its messages
+    /// are dropped, see [`ChatroomConfig::slow_client_policy`].
     pub fn join(
         &self,
         nickname: String,
-        message_sender: Sender<Message>,
+        message_sender: Sender<Envelope>,
+    ) -> Result<Session, JoinError> {
+        self.join_with_disconnect(nickname, message_sender, || {})
+    }
+
+    /// Join the chatroom, `on_disconnect` is called if the chatroom evicts the user.
+    pub fn join_with_disconnect(
+        &self,
+        nickname: String,
+        message_sender: Sender<Envelope>,
+        on_disconnect: impl FnOnce() + Send + 'static,
+    ) -> Result<Session, JoinError> {
+        Ok(Session {
+            id: self
+                .inner
+                .join(nickname, message_sender, Box::new(on_disconnect), None)?,
+            chatroom_impl: self.inner.clone(),
+        })
+    }
+
+    /// Join the chatroom, `handler` is called with each message for the user, e.g. by a
+    /// bot replying with [`Session::send_message`].
+    ///
+    /// The handler is called in order on a thread of the session, that ends once the
+    /// session left. A panicking handler is logged and called again for the next
+    /// messages. A handler more than [`Chatroom::HANDLER_QUEUE`] messages behind is a
+    /// slow consumer.
+    pub fn join_with_handler(
+        &self,
+        nickname: String,
+        handler: impl Fn(Message) + Send + 'static,
+    ) -> Result<Session, JoinError> {
+        let (sender, mut receiver) = channel::<Envelope>(Self::HANDLER_QUEUE);
+        let session = self.join(nickname, sender)?;
+        let id = session.id.0;
+        thread::Builder::new()
+            .name(format!("handler-{id}"))
+            .spawn(move || {
+                while let Some(envelope) = receiver.blocking_recv() {
+                    let handled =
+                        panic::catch_unwind(AssertUnwindSafe(|| handler(envelope.message)));
+                    if handled.is_err() {
+                        warn!(session = id, "message handler panicked");
+                    }
+                }
+            })
+            .expect("cannot start a message handler");
+        Ok(session)
+    }
+
+    /// Join the chatroom from `peer_addr`, like [`Chatroom::join_with_disconnect`].
+    ///
+    /// The address is registered as with [`Session::set_peer_addr`], within the
+    /// [`ChatroomConfig::max_sessions_per_ip`].
+    pub fn join_from(
+        &self,
+        nickname: String,
+        message_sender: Sender<Envelope>,
+        peer_addr: SocketAddr,
+        on_disconnect: impl FnOnce() + Send + 'static,
     ) -> Result<Session, JoinError> {
         Ok(Session {
-            id: self.inner.join(nickname, message_sender)?,
+            id: self.inner.join(
+                nickname,
+                message_sender,
+                Box::new(on_disconnect),
+                Some(peer_addr),
+            )?,
             chatroom_impl: self.inner.clone(),
         })
     }
+
+    /// Take over the detached session of the resume `token`, without telling the other
+    /// users. The messages queued for the session while detached are still on its
+    /// receiver, they are received first.
+    ///
+    /// `on_disconnect` replaces the handler of the former connection, the address is
+    /// updated if given.
+    pub fn resume(
+        &self,
+        token: &str,
+        peer_addr: Option<SocketAddr>,
+        on_disconnect: impl FnOnce() + Send + 'static,
+    ) -> Result<Resumed, ResumeError> {
+        let (id, sender, receiver) =
+            self.inner
+                .resume(token, peer_addr, Box::new(on_disconnect))?;
+        Ok(Resumed {
+            session: Session {
+                id,
+                chatroom_impl: self.inner.clone(),
+            },
+            sender,
+            receiver,
+        })
+    }
+
+    /// Nicknames of the connected users, sorted case-insensitively
+    pub fn connected_users(&self) -> Vec<String> {
+        let mut nicknames = self.inner.nicknames();
+        nicknames.sort_by(|a, b| by_nickname(a, b));
+        nicknames
+    }
+
+    /// Nicknames of the users in `room`, sorted case-insensitively
+    pub fn room_users(&self, room: &str) -> Vec<String> {
+        self.inner.room_users(room)
+    }
+
+    /// Nicknames of the users in `room` sorted case-insensitively, prefixed by `@` for
+    /// the operators and followed by `(away)` for those away, as listed by `/who`
+    pub fn who(&self, room: &str) -> Vec<String> {
+        let mut users = self.inner.who(room);
+        users.sort_by(|(a, ..), (b, ..)| by_nickname(a, b));
+        users
+            .into_iter()
+            .map(|(nickname, operator, away)| {
+                let prefix = if operator { "@" } else { "" };
+                if away {
+                    format!("{prefix}{nickname} (away)")
+                } else {
+                    format!("{prefix}{nickname}")
+                }
+            })
+            .collect()
+    }
+
+    /// The connected users, sorted case-insensitively by nickname
+    pub fn users(&self) -> Vec<UserInfo> {
+        let mut users = self.inner.users();
+        users.sort_by(|a, b| by_nickname(&a.nickname, &b.nickname));
+        users
+    }
+
+    /// The user named `nickname` as seen by `by`: its [`UserInfo::peer_addr`] is only
+    /// shown to the operators. `None` if no user has this nickname.
+    pub fn whois(&self, by: &Session, nickname: &str) -> Option<UserInfo> {
+        self.inner.whois(by.id, nickname)
+    }
+
+    /// The rooms that have at least one user, sorted by name
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        let mut rooms = self.inner.rooms();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// Tell every user the server is shutting down and disconnect them.
+    ///
+    /// Every session is ended as if it was evicted, and further joins are rejected.
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
+    }
+
+    /// Set the message of the day, sent line by line to the users joining the
+    /// chatroom before the list of users of the room. An empty MOTD is not sent.
+    pub fn set_motd(&self, lines: Vec<String>) {
+        self.inner.users.lock().motd = lines;
+    }
+
+    /// The current metrics of the chatroom, the counters read without locking
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.inner.metrics.snapshot(self.inner.started_at)
+    }
+
+    /// The users, uptime and messages of the chatroom, read without locking the users
+    pub fn stats(&self) -> ChatroomStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ChatroomStats {
+            users: load(&self.inner.metrics.connected_users),
+            uptime: self.inner.started_at.elapsed(),
+            messages: load(&self.inner.metrics.messages_broadcast),
+            locked: self.is_locked(),
+        }
+    }
+
+    /// Counters updated by the server
+    pub(crate) fn counters(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+
+    /// Disconnect the user named `target`, `by` must be an operator.
+    ///
+    /// The target is told why it is kicked and the users of its room are told who
+    /// kicked it.
+    pub fn kick(
+        &self,
+        by: &Session,
+        target: &str,
+        reason: Option<String>,
+    ) -> Result<(), KickError> {
+        self.inner.kick(by.id, target, reason)
+    }
+
+    /// Disconnect the user named `target` on behalf of the server administrator
+    pub fn kick_by_nick(&self, target: &str, reason: Option<String>) -> Result<(), KickError> {
+        self.inner.kick_as(SERVER, target, reason)
+    }
+
+    /// Watch the chatroom without joining it: the receiver gets the messages broadcast
+    /// to the rooms, e.g. the chat messages, the joins and the leaves, and the server
+    /// notices.
+    ///
+    /// Observers are not users, they are neither listed nor announced. An observer
+    /// more than [`Chatroom::OBSERVER_QUEUE`] messages behind is dropped: like on
+    /// shutdown, its receiver ends.
+    pub fn subscribe_observer(&self) -> Receiver<Message> {
+        let (sender, receiver) = channel(Self::OBSERVER_QUEUE);
+        self.inner.users.lock().add_observer(sender);
+        receiver
+    }
+
+    /// Watch the chatroom without joining it, like [`Chatroom::subscribe_observer`]
+    /// but with the queue of `sender`.
+    ///
+    /// The observer is unsubscribed when the handle is dropped, or when its queue is
+    /// full or closed.
+    pub fn observe(&self, sender: Sender<Message>) -> ObserverHandle {
+        ObserverHandle {
+            id: self.inner.users.lock().add_observer(sender),
+            chatroom_impl: self.inner.clone(),
+        }
+    }
+
+    /// Call `hook` with the chat messages and emotes sent by the users, before the
+    /// [`ChatroomConfig::rate_limit`] and the [`ChatroomConfig::repeat_limit`]: a
+    /// dropped message does not count. The message has been sanitized already.
+    ///
+    /// The hooks are called in the order they were added, each with the result of the
+    /// previous one. They are called on the thread of the sender, e.g. a task of the
+    /// server, so they should be quick and must not block. The chatroom is not locked
+    /// meanwhile: a hook may use it, other messages may be sent concurrently.
+    pub fn add_inbound_hook(&self, hook: impl Fn(&Message) -> HookResult + Send + Sync + 'static) {
+        self.inner.inbound_hooks.add(hook);
+    }
+
+    /// Call `hook` with the chat messages and emotes within the limits, right before
+    /// they are sent to the room, like [`Chatroom::add_inbound_hook`]. The users are
+    /// marked as mentioned by the resulting text.
+    pub fn add_outbound_hook(&self, hook: impl Fn(&Message) -> HookResult + Send + Sync + 'static) {
+        self.inner.outbound_hooks.add(hook);
+    }
+
+    /// Send `text` to every user, in every room, as a [`Message::ServerNotice`].
+    ///
+    /// The notice is kept in the history of every room.
+    pub fn broadcast_notice(&self, text: impl Into<String>) {
+        self.inner.broadcast_notice(text.into())
+    }
+
+    /// Make the chatroom read-only, or writable again: only the operators may send
+    /// messages and emotes while locked, to the rooms or privately, the others are
+    /// told so. `by` must be an operator, every user is sent a server notice of the
+    /// change. Returns `false` if the chatroom already was in this state.
+    pub fn set_locked(&self, by: &Session, locked: bool) -> Result<bool, KickError> {
+        let operator = self.inner.users.lock().operator(by.id)?;
+        if self.inner.locked.swap(locked, Ordering::Relaxed) == locked {
+            return Ok(false);
+        }
+        info!(by = operator, locked, "lockdown changed");
+        self.inner.broadcast_notice(match locked {
+            true => format!("the room is now read-only, by {operator}"),
+            false => format!("the room is no longer read-only, by {operator}"),
+        });
+        Ok(true)
+    }
+
+    /// Whether the chatroom is read-only, see [`Chatroom::set_locked`]
+    pub fn is_locked(&self) -> bool {
+        self.inner.locked.load(Ordering::Relaxed)
+    }
+
+    /// The number of messages kept in the history of each room, 0 if the history is
+    /// disabled, see [`Session::last_messages`]
+    pub fn history_limit(&self) -> usize {
+        self.inner.config.history.messages
+    }
+
+    /// The number of connected users
+    pub fn user_count(&self) -> usize {
+        self.inner.users.lock().connected.len()
+    }
+
+    /// Disconnect the user named `target` and ban its address, `by` must be an operator.
+    ///
+    /// Only users whose address was registered with [`Session::set_peer_addr`]
+    /// can be banned. Returns the banned address.
+    pub fn ban(&self, by: &Session, target: &str) -> Result<IpAddr, KickError> {
+        self.inner.ban(by.id, target)
+    }
+
+    /// Make `target` an operator, `by` must be an operator. The nickname is added to
+    /// the [`ChatroomConfig::operators`], the user is told if connected.
+    pub fn op(&self, by: &Session, target: &str) -> Result<(), KickError> {
+        self.inner.set_operator(by.id, target, true)
+    }
+
+    /// Take the operator status of `target` away, `by` must be an operator. The
+    /// nickname is removed from the [`ChatroomConfig::operators`], the user is told if
+    /// connected.
+    pub fn deop(&self, by: &Session, target: &str) -> Result<(), KickError> {
+        self.inner.set_operator(by.id, target, false)
+    }
+
+    /// Drop the messages of `target`, to the rooms and the private ones, for
+    /// `duration`, `by` must be an operator. The mute holds if the user leaves and
+    /// joins again meanwhile, the user still receives the messages of the others.
+    pub fn mute(&self, by: &Session, target: &str, duration: Duration) -> Result<(), KickError> {
+        self.inner.mute(by.id, target, duration)
+    }
+
+    /// End the mute of `target` early, `by` must be an operator. Returns `false` if
+    /// `target` was not muted.
+    pub fn unmute(&self, by: &Session, target: &str) -> Result<bool, KickError> {
+        let users = self.inner.users.lock();
+        users.operator(by.id)?;
+        let unmuted = self.inner.mutes.lock().unmute(target, Instant::now());
+        Ok(unmuted)
+    }
+
+    /// Lift the ban of `ip`, `by` must be an operator. Returns `false` if `ip` was not
+    /// banned.
+    pub fn unban(&self, by: &Session, ip: IpAddr) -> Result<bool, KickError> {
+        self.inner.users.lock().operator(by.id)?;
+        Ok(self.inner.runtime().bans.unban(ip))
+    }
+
+    /// The addresses banned from the chatroom
+    pub fn bans(&self) -> Arc<BanList> {
+        self.inner.runtime().bans.clone()
+    }
+
+    /// The settings currently in use, see [`Chatroom::reload`]
+    pub fn runtime_config(&self) -> Arc<RuntimeConfig> {
+        self.inner.runtime()
+    }
+
+    /// Use `config` from now on, the connected users keep their sessions. Their
+    /// message rate is measured afresh if the rate limit changed, those in the new
+    /// [`RuntimeConfig::operators`] become operators.
+    pub fn reload(&self, config: RuntimeConfig) {
+        let mut users = self.inner.users.lock();
+        let mut runtime = self.inner.runtime.write();
+        if config.rate_limit != runtime.rate_limit {
+            for user in users.connected.values_mut() {
+                user.rate_limit = config.rate_limit.map(TokenBucket::new);
+            }
+        }
+        for user in users.connected.values_mut() {
+            user.operator |= config.operators.is_operator(&user.nickname);
+        }
+        *runtime = Arc::new(config);
+    }
+
+    /// Send a private message to the user named `to`, `from` receives a confirmation.
+    /// The message is charged to the rate limit of `from` and dropped while it is
+    /// muted or the chatroom locked, as those to its room: `from` is then told why.
+    pub fn send_private(&self, from: &Session, to: &str, text: String) -> Result<(), SendError> {
+        self.inner.send_private(from, to, text)
+    }
+}
+
+/// Who kicks the users on behalf of the server administrator
+const SERVER: &str = "the server";
+
+/// How often a user may search the archive, see [`Session::search`]
+const SEARCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The text of a chat message or emote
+fn chat_text(message: &Message) -> Option<&str> {
+    match message {
+        Message::Message { text, .. } => Some(text),
+        Message::Emote { action, .. } => Some(action),
+        _ => None,
+    }
+}
+
+/// Identifies a session in the logs and [`UserInfo`], never reused
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub(crate) struct SessionId(pub(crate) u64);
+
+/// Identifies an observer, see [`Chatroom::observe`]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+struct ObserverId(u64);
+
+/// An observer of the chatroom, see [`Chatroom::observe`].
+///
+/// Dropping the handle unsubscribes the observer
+pub struct ObserverHandle {
+    id: ObserverId,
+    chatroom_impl: Arc<ChatroomImpl>,
 }
 
-#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-struct SessionId(usize);
+impl Drop for ObserverHandle {
+    fn drop(&mut self) {
+        self.chatroom_impl.users.lock().observers.remove(&self.id);
+    }
+}
 
 /// The current chat session.
 ///
@@ -37,135 +822,2599 @@ pub struct Session {
 }
 
 impl Session {
-    pub fn send_message(&self, text: String) {
-        self.chatroom_impl.send_message(self, text);
+    /// Send a message to the other users of the room, and to the user itself with
+    /// [`Session::set_echo`].
+    ///
+    /// Messages exceeding the [`ChatroomConfig::rate_limit`] are dropped and the
+    /// user is notified. Returns the number of users the message was queued for, or
+    /// handed to the [`ChatroomConfig::fanout_workers`] for.
+    pub fn send_message(&self, text: String) -> Result<usize, SendError> {
+        self.chatroom_impl.send_message(self, text)
     }
-}
 
-impl Drop for Session {
-    fn drop(&mut self) {
-        self.chatroom_impl.leave(self.id);
+    /// Leave the chatroom, telling the users of the room the parting `message` if
+    /// any. The message is sanitized like the chat messages, it is dropped if
+    /// rejected. The session is disconnected afterwards, as if evicted.
+    pub fn quit(&self, message: Option<String>) {
+        let reason = message.and_then(|text| self.chatroom_impl.sanitize(self, text).ok());
+        self.chatroom_impl.leave(self.id, reason);
     }
-}
 
-pub enum Message {
-    /// sent to all connected user when a new user just joined
-    Joined(String),
-    /// sent to all connected user when an users just left
-    Left(String),
-    /// sent to the joining user right before they joins
-    ConnectedUsers(Vec<String>),
-    Message {
-        from: String,
-        text: String,
-    },
-}
+    /// Send an emote (`/me`) to the other users of the room, like [`Session::send_message`]
+    pub fn send_emote(&self, action: String) -> Result<usize, SendError> {
+        self.chatroom_impl.send_emote(self, action)
+    }
 
-impl Display for Message {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Message::Joined(nick) => write!(f, "* {nick} joined the room"),
-            Message::Left(nick) => write!(f, "* {nick} left the room"),
-            Message::ConnectedUsers(users) => {
-                write!(f, "* Welcome, the room contains: {}", users.join(", "))
-            }
-            Message::Message { from, text } => write!(f, "[{from}] {text}"),
-        }
+    /// Send a block of lines to the other users of the room as a single
+    /// [`Message::Paste`], like [`Session::send_message`]. Each line is sanitized, the
+    /// block counts as one message for the rate limit.
+    pub fn send_paste(&self, lines: Vec<String>) -> Result<usize, SendError> {
+        self.chatroom_impl.send_paste(self, lines)
     }
-}
 
-pub enum JoinError {
-    DuplicateNickname,
-    InvalidNickname,
-}
+    /// A stable identifier of the session, unique within the chatroom
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
 
-impl Display for JoinError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            JoinError::DuplicateNickname => f.write_str("Nickname already used."),
-            JoinError::InvalidNickname => {
-                f.write_str("Nickname can only alphanumerical characters.")
-            }
-        }
+    /// The current nickname of the user, `None` if the session was evicted
+    pub fn nickname(&self) -> Option<String> {
+        let users = self.chatroom_impl.users.lock();
+        users
+            .connected
+            .get(&self.id)
+            .map(|user| user.nickname.clone())
     }
-}
 
-/// Chatroom private implementation
-#[derive(Default)]
-struct ChatroomImpl {
-    connected_users: Mutex<HashMap<SessionId, (String, Sender<Message>)>>,
-    session_count: Mutex<usize>,
-}
+    /// Whether the user is still in the chatroom: sessions are evicted when kicked,
+    /// idle or too slow
+    pub fn is_connected(&self) -> bool {
+        self.chatroom_impl
+            .users
+            .lock()
+            .connected
+            .contains_key(&self.id)
+    }
 
-impl ChatroomImpl {
-    fn join(
-        &self,
-        nickname: String,
-        message_sender: Sender<Message>,
-    ) -> Result<SessionId, JoinError> {
-        if nickname.len() == 0
-            || nickname
-                .chars()
-                .any(|c| (c < 'a' || c > 'z') && (c < 'A' || c > 'Z') && (c < '0' || c > '9'))
-        {
-            return Err(JoinError::InvalidNickname);
-        }
-        let mut connected_users = self.connected_users.lock();
+    /// The token with which [`Chatroom::resume`] takes over the session once detached,
+    /// also sent to the user as a notice. `None` without
+    /// [`ChatroomConfig::resume_window`] or once the session was evicted.
+    pub fn resume_token(&self) -> Option<String> {
+        let users = self.chatroom_impl.users.lock();
+        let user = users.connected.get(&self.id)?;
+        user.resume_token.as_deref().map(str::to_string)
+    }
 
-        for (n, _) in connected_users.values() {
-            if n == &nickname {
-                return Err(JoinError::DuplicateNickname);
-            }
+    /// Keep the session in the chatroom for the [`ChatroomConfig::resume_window`], its
+    /// connection having ended: nobody is told it left unless it is not resumed in time.
+    /// The messages for the user are queued meanwhile on `receiver`, the one of its
+    /// sender, up to its capacity: a detached session is a slow consumer too. Without
+    /// resume token, the user leaves as if the session was dropped.
+    pub fn detach(self, receiver: Receiver<Envelope>) {
+        self.chatroom_impl.detach(self.id, receiver);
+    }
+
+    /// The room the user is in, `None` if the session was evicted
+    pub fn room(&self) -> Option<String> {
+        self.chatroom_impl.room(self.id)
+    }
+
+    /// The last `count` messages of the history of the room of the user, oldest first,
+    /// as [`Message::History`]. Empty if the session was evicted.
+    pub fn last_messages(&self, count: usize) -> Vec<Envelope> {
+        self.chatroom_impl.last_messages(self.id, count)
+    }
+
+    /// Search the [`ChatroomConfig::archive`] for the messages of the room of the user
+    /// containing `term`, once every 10 seconds at most per nickname: a user cannot
+    /// search more often by reconnecting. The [`Search`] is then run off the async
+    /// tasks.
+    pub fn search(&self, term: &str) -> Result<Search, SearchError> {
+        self.chatroom_impl.search(self.id, term)
+    }
+
+    /// Move the user to `room`, creating it if needed.
+    ///
+    /// The users of the left room receive a Left message, those of the joined
+    /// room a Joined message.
+    pub fn join_room(&self, room: &str) -> Result<(), RoomError> {
+        self.chatroom_impl.join_room(self.id, room)
+    }
+
+    /// Allow the user named `nickname` to join the room of the user once, if it is
+    /// invite-only. Only the operators may invite, the invited user is told.
+    ///
+    /// The invites are forgotten when the room empties.
+    pub fn invite(&self, nickname: &str) -> Result<String, RoomError> {
+        self.chatroom_impl.invite(self.id, nickname)
+    }
+
+    /// Make `room` invite-only, or open to everyone again. The user must be an
+    /// operator in `room`. The users joining an invite-only room need an invite,
+    /// but for the operators. The room is open again once empty.
+    pub fn set_invite_only(&self, room: &str, invite_only: bool) -> Result<(), RoomError> {
+        self.chatroom_impl
+            .set_invite_only(self.id, room, invite_only)
+    }
+
+    /// Limit the number of users of `room`, or lift the limit with `None`. The user
+    /// must be an operator in `room`. The users already in the room stay, the joins
+    /// are rejected while it is full. The room is created with the
+    /// [`ChatroomConfig::default_room_limit`] again once empty.
+    pub fn set_room_limit(&self, room: &str, limit: Option<usize>) -> Result<(), RoomError> {
+        self.chatroom_impl.set_room_limit(self.id, room, limit)
+    }
+
+    /// The topic of the room of the user, `None` if it has none
+    pub fn topic(&self) -> Option<TopicEntry> {
+        self.chatroom_impl.topic(self.id)
+    }
+
+    /// Set the topic of the room of the user, an empty topic clears it. Only the
+    /// operators may set it, anyone if no operator is connected.
+    ///
+    /// The users of the room receive a [`Message::Topic`], the joining users receive
+    /// it after the user list.
+    pub fn set_topic(&self, text: String) -> Result<(), TopicError> {
+        self.chatroom_impl.set_topic(self, text)
+    }
+
+    /// Change the nickname of the user, with the same rules as [`Chatroom::join`].
+    ///
+    /// The users of the room, and the user itself, receive a Renamed message.
+    pub fn rename(&self, nickname: String) -> Result<(), JoinError> {
+        self.chatroom_impl.rename(self.id, nickname)
+    }
+
+    /// Register the address the user is connected from, so that it can be banned
+    pub fn set_peer_addr(&self, addr: SocketAddr) {
+        if let Some(user) = self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            user.peer_addr = Some(addr);
         }
+    }
 
-        // send nicknames to the joining user
-        let nicknames = connected_users
-            .values()
-            .map(|(n, _)| n)
-            .map(ToString::to_string)
-            .collect::<Vec<_>>();
-        let _ = message_sender.send(Message::ConnectedUsers(nicknames));
+    /// Register the number of the connection of the user in the logs of the server,
+    /// logged with the kicks and bans of the user and listed by [`Chatroom::users`]
+    pub fn set_connection(&self, connection: u64) {
+        if let Some(user) = self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            user.connection = Some(connection);
+        }
+    }
 
-        // send all connected users the Joined message
-        for (_, sender) in connected_users.values() {
-            let _ = sender.send(Message::Joined(nickname.clone()));
+    /// Whether the user receives its own messages and emotes, see
+    /// [`ChatroomConfig::echo`]
+    pub fn set_echo(&self, enabled: bool) {
+        if let Some(user) = self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            user.echo = enabled;
         }
+    }
 
-        let session_id = self.new_session_id();
+    /// How long the user has been connected and how many messages it sent, `None` if
+    /// it is disconnected
+    pub fn stats(&self) -> Option<SessionStats> {
+        let users = self.chatroom_impl.users.lock();
+        let user = users.connected.get(&self.id)?;
+        Some(SessionStats {
+            connected_for: user.joined_at.elapsed(),
+            messages: user.messages_sent,
+        })
+    }
 
-        // register the joined user in our connected user database
-        connected_users.insert(session_id, (nickname, message_sender));
+    /// Mark the user away with `reason`, possibly empty, or back with `None`. Those
+    /// sending it private messages are told it is away, the others are not told. The
+    /// reason is sanitized like the chat messages.
+    pub fn set_away(&self, reason: Option<String>) -> Result<(), SendError> {
+        let reason = match reason {
+            Some(reason) => Some(self.chatroom_impl.sanitize(self, reason)?),
+            None => None,
+        };
+        let mut users = self.chatroom_impl.users.lock();
+        let user = users
+            .connected
+            .get_mut(&self.id)
+            .ok_or(SendError::NotConnected)?;
+        user.away = reason;
+        Ok(())
+    }
 
-        Ok(session_id)
+    /// Record that the client processed the messages up to the number `seq`, see
+    /// [`Envelope::seq`]. Returns `false` if no message has this number yet.
+    pub fn ack(&self, seq: u64) -> bool {
+        self.queue().is_some_and(|queue| queue.ack(seq).is_ok())
     }
 
-    fn new_session_id(&self) -> SessionId {
-        let mut session_count = self.session_count.lock();
-        *session_count += 1;
-        SessionId(*session_count)
-    }
-
-    fn leave(&self, session: SessionId) {
-        let mut connected_users = self.connected_users.lock();
-        if let Some((nickname, _)) = connected_users.remove(&session) {
-            // send all connected users the Joined message
-            for (_, sender) in connected_users.values() {
-                let _ = sender.send(Message::Left(nickname.clone()));
-            }
-        }
-    }
-    fn send_message(&self, from: &Session, text: String) {
-        let connected_users = self.connected_users.lock();
-        if let Some((from_nickname, _)) = connected_users.get(&from.id) {
-            // send all connected users the Joined message
-            for (to, (_, sender)) in connected_users.iter() {
-                if to != &from.id {
-                    let _ = sender.send(Message::Message {
-                        from: from_nickname.clone(),
-                        text: text.clone(),
-                    });
-                }
-            }
+    /// The number of messages queued for the user since the last one it processed,
+    /// `None` if it never told with [`Session::ack`]
+    pub fn lag(&self) -> Option<u64> {
+        self.queue()?.lag()
+    }
+
+    /// The queue of the messages for the user, e.g. for the replies to its commands.
+    /// `None` if the session was evicted.
+    pub(crate) fn queue(&self) -> Option<Queue> {
+        let users = self.chatroom_impl.users.lock();
+        Some(users.connected.get(&self.id)?.queue.clone())
+    }
+
+    /// Whether the messages naming the user, whatever the case, and its private
+    /// messages ring its terminal bell: their lines start with a BEL, see
+    /// [`Envelope::bell`]
+    pub fn set_bell(&self, enabled: bool) {
+        if let Some(user) = self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            user.bell = enabled;
         }
     }
+
+    /// The away reason of the user, possibly empty, `None` if it is not away
+    pub fn away(&self) -> Option<String> {
+        let users = self.chatroom_impl.users.lock();
+        users.connected.get(&self.id)?.away.clone()
+    }
+
+    /// Stop delivering the messages, emotes and private messages of the user named
+    /// `nickname` to this user, whether or not it is connected. Ignores are by
+    /// nickname: they do not follow renames.
+    pub fn ignore(&self, nickname: &str) {
+        if let Some(user) = self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            user.ignored.insert(nickname.to_string());
+        }
+    }
+
+    /// Deliver the messages of `nickname` again, returns whether it was ignored
+    pub fn unignore(&self, nickname: &str) -> bool {
+        match self.chatroom_impl.users.lock().connected.get_mut(&self.id) {
+            Some(user) => user.ignored.remove(nickname),
+            None => false,
+        }
+    }
+
+    /// The nicknames ignored by the user, sorted
+    pub fn ignored(&self) -> Vec<String> {
+        let users = self.chatroom_impl.users.lock();
+        let mut ignored: Vec<_> = users
+            .connected
+            .get(&self.id)
+            .into_iter()
+            .flat_map(|user| user.ignored.iter().cloned())
+            .collect();
+        ignored.sort();
+        ignored
+    }
+
+    /// Record that the user is active, see [`ChatroomConfig::idle_timeout`]
+    pub fn record_activity(&self) {
+        self.chatroom_impl.record_activity(self.id);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.chatroom_impl.leave(self.id, None);
+    }
+}
+
+/// A detached session taken over with [`Chatroom::resume`]
+pub struct Resumed {
+    pub session: Session,
+    /// the sender of the messages for the user, e.g. to queue replies
+    pub sender: Sender<Envelope>,
+    /// the messages for the user, those queued while detached first
+    pub receiver: Receiver<Envelope>,
+}
+
+/// A message sent to the users, displayed as the line they receive.
+///
+/// The chat messages and emotes hold their nickname and text in an [`Arc<str>`], so
+/// that a broadcast shares them between its recipients.
+///
+/// # Serialization
+///
+/// With the `serde` feature, a message is an object tagged by its `type`, the same
+/// objects as those of the JSON line protocol:
+///
+/// | variant | object |
+/// |---|---|
+/// | [`Message::Joined`] | `{"type": "joined", "nick": ..., "count": <users>?}` |
+/// | [`Message::Left`] | `{"type": "left", "nick": ..., "online": <secs>?, "reason": ...?}` |
+/// | [`Message::ConnectedUsers`] | `{"type": "users", "users": [...]}` |
+/// | [`Message::Message`] | `{"type": "message", "from": ..., "text": ...}` |
+/// | [`Message::Mentioned`] | `{"type": "message", "from": ..., "text": ..., "mentioned": true}` |
+/// | [`Message::Emote`] | `{"type": "emote", "from": ..., "text": ...}` |
+/// | [`Message::Paste`] | `{"type": "paste", "from": ..., "lines": [...]}` |
+/// | [`Message::Private`] | `{"type": "private", "from": ..., "text": ...}` |
+/// | [`Message::PrivateSent`] | `{"type": "private_sent", "to": ..., "text": ...}` |
+/// | [`Message::UserList`] | `{"type": "who", "users": [...]}` |
+/// | [`Message::RoomList`] | `{"type": "rooms", "rooms": [{"name": ..., "users": <count>, "invite_only": true?, "limit": <count>?}, ...]}` |
+/// | [`Message::Notice`] | `{"type": "notice", "text": ...}` |
+/// | [`Message::ServerNotice`] | `{"type": "server_notice", "text": ...}` |
+/// | [`Message::Motd`] | `{"type": "motd", "text": ...}` |
+/// | [`Message::Kicked`] | `{"type": "kicked", "nick": ..., "by": ...}` |
+/// | [`Message::Banned`] | `{"type": "banned", "nick": ..., "by": ...}` |
+/// | [`Message::Renamed`] | `{"type": "renamed", "old": ..., "new": ...}` |
+/// | [`Message::Topic`] | `{"type": "topic", "text": ..., "by": ...}` |
+/// | [`Message::History`] | `{"type": "history", "message": {...}}` |
+///
+/// The fields marked `?` are omitted when unset, the `online` duration is truncated to
+/// whole seconds. Unknown fields are ignored when deserializing, e.g. the `time` of the
+/// JSON line protocol.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// sent to all connected user when a new user just joined
+    Joined {
+        nickname: String,
+        /// the number of users of the room with the joining one, with
+        /// [`ChatroomConfig::announce_counts`]
+        users: Option<usize>,
+    },
+    /// sent to all connected user when an users just left
+    Left {
+        nickname: String,
+        /// how long the user was online, unless [`ChatroomConfig::plain_leave`] is set
+        online: Option<Duration>,
+        /// the parting message given to [`Session::quit`]
+        reason: Option<String>,
+    },
+    /// sent to the joining user right before they joins
+    ConnectedUsers(Vec<String>),
+    /// message of a user to its room. The message is shared by its recipients: cloning
+    /// it does not copy the text.
+    Message { from: Arc<str>, text: Arc<str> },
+    /// the copy of a [`Message::Message`] sent to the users it mentions with
+    /// `@nickname`
+    Mentioned { from: Arc<str>, text: Arc<str> },
+    /// action of a user, e.g. `/me waves`, shared like [`Message::Message`]
+    Emote { from: Arc<str>, action: Arc<str> },
+    /// block of lines of a user to its room, sent with `/paste` and shared like
+    /// [`Message::Message`]. Rendered as a single line break separated string: the
+    /// line of its author then the lines indented, nothing can come between them.
+    Paste {
+        from: Arc<str>,
+        lines: Arc<[String]>,
+    },
+    /// private message, only sent to its recipient
+    Private { from: String, text: String },
+    /// sent to the author of a private message once it is delivered
+    PrivateSent { to: String, text: String },
+    /// answer to the `/who` command
+    UserList(Vec<String>),
+    /// answer to the `/rooms` command
+    RoomList(Vec<RoomInfo>),
+    /// information sent to a single user, e.g. the failure of a command
+    Notice(String),
+    /// announcement of the server to the users
+    ServerNotice(String),
+    /// a line of the message of the day, sent to the joining user
+    Motd(String),
+    /// sent to the room of a user kicked by an operator
+    Kicked { nickname: String, by: String },
+    /// sent to the room of a user banned by an operator
+    Banned { nickname: String, by: String },
+    /// sent to the room of a user that changed its nickname
+    Renamed { old: String, new: String },
+    /// the topic of the room, sent to the joining users and to the room when it is
+    /// changed. The text is empty when `set_by` cleared the topic.
+    Topic { text: String, set_by: String },
+    /// a message sent to the room before the user joined it
+    History(Box<Message>),
+}
+
+impl Message {
+    /// The line written to the client, without its line feed
+    pub fn render(&self, templates: &MessageTemplates) -> String {
+        templates.render(self)
+    }
+
+    /// The event as written to the clients of the JSON line protocol, e.g.
+    /// `{"type":"message","from":"bob","text":"hi"}`
+    pub fn to_json(&self) -> serde_json::Value {
+        json::to_json(self)
+    }
+}
+
+/// The line rendered with the built-in [`MessageTemplates`]
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(MessageTemplates::built_in()))
+    }
+}
+
+/// The order of the nickname lists: case-insensitive, then by bytes for the nicknames
+/// differing only by their case
+fn by_nickname(a: &str, b: &str) -> std::cmp::Ordering {
+    a.to_lowercase()
+        .cmp(&b.to_lowercase())
+        .then_with(|| a.cmp(b))
+}
+
+/// `duration` to the second: `59s`, `1m00s`, `1h01m01s`
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// A message queued for a user, with the time it was created
+#[derive(Clone)]
+pub struct Envelope {
+    pub at: SystemTime,
+    pub message: Message,
+    /// ring the terminal bell of the user, see [`Session::set_bell`]. Set on the
+    /// delivery only, the history and the chat log keep the message alone.
+    pub bell: bool,
+    /// the number of the message in the queue of the user, numbered from 1 as the
+    /// chatroom queues them: a gap tells of dropped messages, see
+    /// [`ChatroomConfig::slow_client_policy`]. `None` for the messages queued
+    /// directly, e.g. on the sender of [`Resumed`], and the notices of the drops.
+    pub seq: Option<u64>,
+}
+
+impl From<Message> for Envelope {
+    fn from(message: Message) -> Self {
+        Self {
+            at: SystemTime::now(),
+            message,
+            bell: false,
+            seq: None,
+        }
+    }
+}
+
+/// The clock used to render the timestamps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timestamps {
+    Local,
+    Utc,
+}
+
+impl Envelope {
+    /// The line written to the client, rendered with `templates` and prefixed with
+    /// `HH:MM:SS ` when `timestamps` is set, then with a BEL for a [`Envelope::bell`]
+    pub fn render(&self, timestamps: Option<Timestamps>, templates: &MessageTemplates) -> String {
+        let mut line = self.message.render(templates);
+        if let Some(timestamps) = timestamps {
+            let time = match timestamps {
+                Timestamps::Local => DateTime::<Local>::from(self.at).format("%H:%M:%S"),
+                Timestamps::Utc => DateTime::<Utc>::from(self.at).format("%H:%M:%S"),
+            };
+            line = format!("{time} {line}");
+        }
+        if self.bell {
+            line.insert(0, '\u{7}');
+        }
+        line
+    }
+}
+
+impl Display for Envelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(None, MessageTemplates::built_in()))
+    }
+}
+
+/// A room and its number of users
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub users: usize,
+    /// see [`Session::set_invite_only`]
+    pub invite_only: bool,
+    /// see [`Session::set_room_limit`]
+    pub limit: Option<usize>,
+}
+
+/// The topic of a room
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicEntry {
+    pub text: String,
+    /// nickname of the user who set the topic
+    pub set_by: String,
+    pub set_at: SystemTime,
+}
+
+/// A connected user
+#[derive(Clone)]
+pub struct UserInfo {
+    /// the number of its session, unique in the chatroom
+    pub id: u64,
+    pub nickname: String,
+    pub room: String,
+    /// where the user is connected from, if it joined with [`Chatroom::join_from`] or
+    /// registered it with [`Session::set_peer_addr`]
+    pub peer_addr: Option<SocketAddr>,
+    /// the number of its connection, if registered with [`Session::set_connection`]
+    pub connection: Option<u64>,
+    /// the reason given to [`Session::set_away`], empty if none, `None` if the user
+    /// is not away
+    pub away: Option<String>,
+    /// time since the user joined, as in [`Session::stats`]
+    pub connected_for: Duration,
+    /// time since the last chat message or emote of the user, or since it joined
+    pub idle_for: Duration,
+    /// chat messages and emotes the user sent to its rooms
+    pub messages: u64,
+    pub operator: bool,
+    /// see [`Session::lag`]
+    pub lag: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JoinError {
+    /// a connected user has this nickname
+    DuplicateNickname(String),
+    /// the nickname is used, the suggested one is free
+    NicknameTaken(String),
+    /// the nickname is refused by the [`ChatroomConfig::nicknames`] rules
+    InvalidNickname(NicknameError),
+    /// the chatroom is shut down
+    ShuttingDown,
+    /// the chatroom already has [`ChatroomConfig::max_users`] users
+    RoomFull,
+    /// the address already has [`ChatroomConfig::max_sessions_per_ip`] users
+    TooManySessions,
+    /// the nickname contains a word of the [`ChatroomConfig::word_filter`]
+    DisallowedNickname,
+    /// the password of the registered nickname was wrong, see
+    /// [`ChatroomConfig::accounts`]. Renames to a registered nickname fail with it too.
+    AuthenticationFailed,
+    /// the nickname is held for the address of the user who left, for this long, see
+    /// [`ChatroomConfig::nick_hold`]
+    NicknameReserved(Duration),
+}
+
+impl Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::DuplicateNickname(_) => f.write_str("Nickname already used."),
+            JoinError::NicknameTaken(suggestion) => write!(f, "Nickname taken, try: {suggestion}"),
+            JoinError::InvalidNickname(e) => write!(f, "{e}."),
+            JoinError::ShuttingDown => f.write_str("Server is shutting down."),
+            JoinError::RoomFull => f.write_str("The room is full, try again later."),
+            JoinError::TooManySessions => {
+                f.write_str("Too many users are connected from your address.")
+            }
+            JoinError::DisallowedNickname => f.write_str("This nickname is not allowed."),
+            JoinError::AuthenticationFailed => f.write_str("Authentication failed."),
+            JoinError::NicknameReserved(remaining) => write!(
+                f,
+                "Nickname reserved, try again in {}.",
+                format_duration(*remaining)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JoinError::InvalidNickname(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NicknameError {
+    TooShort {
+        min: usize,
+    },
+    TooLong {
+        max: usize,
+    },
+    /// `ch`, the character at `position` (counted in characters from 0), is not allowed
+    InvalidChar {
+        position: usize,
+        ch: char,
+    },
+}
+
+impl Display for NicknameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NicknameError::TooShort { min } => write!(f, "Nickname too short (min {min})"),
+            NicknameError::TooLong { max } => write!(f, "Nickname too long (max {max})"),
+            NicknameError::InvalidChar { position, ch } => {
+                write!(
+                    f,
+                    "Nickname contains an invalid character {ch:?} at position {position}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NicknameError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendError {
+    /// no connected user has this nickname
+    NoSuchUser(String),
+    /// the message exceeds the [`ChatroomConfig::rate_limit`]
+    RateLimited,
+    /// the session was evicted from the chatroom
+    NotConnected,
+    /// the message contains control characters, see [`ChatroomConfig::sanitize`]
+    ControlCharacters,
+    /// the message contains words of the [`ChatroomConfig::word_filter`], see
+    /// [`ChatroomConfig::word_filter_mode`]
+    FilteredWords,
+    /// the message was sent too many times, see [`ChatroomConfig::repeat_limit`]
+    Repeated,
+    /// an operator muted the sender, see [`Chatroom::mute`]
+    Muted,
+    /// only the operators may send messages, see [`Chatroom::set_locked`]
+    ReadOnly,
+    /// a hook dropped the message, see [`Chatroom::add_inbound_hook`]
+    Dropped,
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::NoSuchUser(nickname) => write!(f, "no such user: {nickname}"),
+            SendError::RateLimited => f.write_str("you are sending messages too fast"),
+            SendError::NotConnected => f.write_str("you are not connected"),
+            SendError::ControlCharacters => {
+                f.write_str("message dropped: control characters are not allowed")
+            }
+            SendError::FilteredWords => f.write_str("message dropped: it contains filtered words"),
+            SendError::Repeated => f.write_str("stop repeating yourself"),
+            SendError::Muted => f.write_str("you are muted"),
+            SendError::ReadOnly => f.write_str("the room is currently read-only"),
+            SendError::Dropped => f.write_str("message dropped"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KickError {
+    /// only operators may kick users
+    NotOperator,
+    /// no connected user has this nickname
+    NoSuchUser(String),
+    /// the address of the user is not known, it cannot be banned
+    UnknownAddress(String),
+}
+
+impl Display for KickError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KickError::NotOperator => f.write_str("permission denied: you are not an operator"),
+            KickError::NoSuchUser(nickname) => write!(f, "no such user: {nickname}"),
+            KickError::UnknownAddress(nickname) => {
+                write!(f, "the address of {nickname} is unknown")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KickError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TopicError {
+    /// only operators may set the topic, when one is connected
+    NotOperator,
+    /// the topic contains control characters, see [`ChatroomConfig::sanitize`]
+    ControlCharacters,
+    /// the session was evicted from the chatroom
+    NotConnected,
+}
+
+impl Display for TopicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopicError::NotOperator => f.write_str("permission denied: you are not an operator"),
+            TopicError::ControlCharacters => {
+                f.write_str("topic refused: control characters are not allowed")
+            }
+            TopicError::NotConnected => f.write_str("you are not connected"),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SearchError {
+    /// no [`ChatroomConfig::archive`]
+    NoArchive,
+    /// the user searched less than 10 seconds ago, it may search again after this
+    TooSoon(Duration),
+    /// the session was evicted from the chatroom
+    NotConnected,
+}
+
+impl Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::NoArchive => f.write_str("the messages are not archived on this server"),
+            SearchError::TooSoon(wait) => write!(
+                f,
+                "wait {} before searching again",
+                format_duration(*wait + Duration::from_millis(999))
+            ),
+            SearchError::NotConnected => f.write_str("you are not connected"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RoomError {
+    /// the room name is not alphanumerical
+    InvalidName(String),
+    /// the user is already in this room
+    AlreadyInRoom(String),
+    /// the room is invite-only and the user was not invited, see
+    /// [`Session::set_invite_only`]
+    InviteOnly(String),
+    /// only the operators may invite users and change the mode of a room
+    NotOperator,
+    /// the user is not in this room
+    NotInRoom(String),
+    /// no connected user has this nickname
+    NoSuchUser(String),
+    /// the room holds as many users as its limit, see [`Session::set_room_limit`]
+    Full { room: String, limit: usize },
+    /// the lobby is open to everyone
+    Lobby,
+}
+
+impl Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomError::InvalidName(_) => {
+                f.write_str("Room names can only contain alphanumerical characters.")
+            }
+            RoomError::AlreadyInRoom(room) => write!(f, "you are already in #{room}"),
+            RoomError::InviteOnly(room) => write!(f, "room #{room} is invite-only"),
+            RoomError::NotOperator => f.write_str("permission denied: you are not an operator"),
+            RoomError::NotInRoom(room) => write!(f, "you are not in #{room}"),
+            RoomError::NoSuchUser(nickname) => write!(f, "no such user: {nickname}"),
+            RoomError::Full { room, limit } => {
+                write!(f, "room #{room} is full, it holds up to {limit} users")
+            }
+            RoomError::Lobby => f.write_str("the mode of the lobby cannot be changed"),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResumeError {
+    /// no session has this token, or it expired
+    UnknownToken,
+    /// the session is not detached, its connection is still alive
+    StillConnected,
+    /// the chatroom is shut down
+    ShuttingDown,
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResumeError::UnknownToken => f.write_str("Unknown or expired resume token."),
+            ResumeError::StillConnected => f.write_str("The session is still connected."),
+            ResumeError::ShuttingDown => f.write_str("Server is shutting down."),
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {}
+
+type DisconnectHandler = Box<dyn FnOnce() + Send>;
+
+struct ConnectedUser {
+    nickname: String,
+    room: String,
+    queue: Queue,
+    on_disconnect: DisconnectHandler,
+    rate_limit: Option<TokenBucket>,
+    repeats: Option<RepeatGuard>,
+    last_activity: Instant,
+    joined_at: Instant,
+    /// chat messages and emotes sent to the rooms
+    messages_sent: u64,
+    /// when the last of them was sent, `joined_at` before
+    last_message: Instant,
+    operator: bool,
+    peer_addr: Option<SocketAddr>,
+    /// see [`Session::set_connection`]
+    connection: Option<u64>,
+    /// the user receives its own messages
+    echo: bool,
+    /// nicknames of the users whose messages are not delivered to the user
+    ignored: HashSet<String>,
+    /// the away reason, possibly empty, `None` while the user is not away
+    away: Option<String>,
+    /// the mentions ring the terminal bell of the user
+    bell: bool,
+    /// see [`Session::resume_token`]
+    resume_token: Option<Arc<str>>,
+    /// set while the session is detached, see [`Session::detach`]
+    detached: Option<Detached>,
+}
+
+impl ConnectedUser {
+    fn info(&self, id: SessionId) -> UserInfo {
+        UserInfo {
+            id: id.0,
+            nickname: self.nickname.clone(),
+            room: self.room.clone(),
+            peer_addr: self.peer_addr,
+            connection: self.connection,
+            away: self.away.clone(),
+            connected_for: self.joined_at.elapsed(),
+            idle_for: self.last_message.elapsed(),
+            messages: self.messages_sent,
+            operator: self.operator,
+            lag: self.queue.lag(),
+        }
+    }
+}
+
+/// A session without connection, waiting to be resumed
+struct Detached {
+    expires: Instant,
+    /// the messages queued for the user meanwhile
+    receiver: Receiver<Envelope>,
+}
+
+/// A random resume token, 128 bits in hexadecimal
+fn new_resume_token() -> Option<Arc<str>> {
+    let mut bytes = [0; 16];
+    if let Err(e) = getrandom::fill(&mut bytes) {
+        warn!(error = %e, "cannot make a resume token");
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+            .into(),
+    )
+}
+
+#[derive(Default)]
+struct Room {
+    members: HashSet<SessionId>,
+    /// see [`Session::set_invite_only`]
+    invite_only: bool,
+    /// nicknames allowed to join once, see [`Session::invite`]
+    invited: HashSet<String>,
+    /// see [`Session::set_room_limit`]
+    limit: Option<usize>,
+}
+
+/// Connected users and their rooms, protected by a single lock
+#[derive(Default)]
+struct Users {
+    connected: HashMap<SessionId, ConnectedUser>,
+    rooms: HashMap<String, Room>,
+    /// topics of the rooms, kept while the rooms are empty
+    topics: HashMap<String, TopicEntry>,
+    shut_down: bool,
+    /// whether a user joined the chatroom already
+    joined_once: bool,
+    motd: Vec<String>,
+    history: History,
+    chat_log: Option<Arc<ChatLog>>,
+    archive: Option<Arc<Archive>>,
+    /// see [`Chatroom::observe`], dropped once closed or full
+    observers: HashMap<ObserverId, Sender<Message>>,
+    next_observer: u64,
+    /// shared with [`ChatroomImpl`]
+    metrics: Arc<Metrics>,
+    /// held while queuing messages, so that every user receives them in the order of
+    /// the events. Taken under the `Users` lock, it can be kept once released, see
+    /// [`ChatroomImpl::fan_out`]. Shared with [`ChatroomImpl`].
+    fanout: Arc<Mutex<()>>,
+    /// queue the messages instead of the thread sending them, see
+    /// [`ChatroomConfig::fanout_workers`]
+    workers: Option<Workers>,
+    /// the sessions by [`unicode::skeleton`] of their nickname, to find the mentioned
+    /// users and the nicknames looking alike
+    folded_nicknames: HashMap<String, Vec<SessionId>>,
+    /// see [`ChatroomConfig::nick_hold`]
+    nick_hold: Option<Duration>,
+    /// the nicknames of the users who left, pruned on joins
+    held_nicknames: HashMap<String, HeldNickname>,
+    /// the sessions by [`Session::resume_token`]
+    resume_tokens: HashMap<Arc<str>, SessionId>,
+}
+
+/// A nickname kept for the address of the user who left, see
+/// [`ChatroomConfig::nick_hold`]
+struct HeldNickname {
+    expires: Instant,
+    ip: Option<IpAddr>,
+}
+
+impl Users {
+    fn find(&self, nickname: &str) -> Option<SessionId> {
+        self.connected
+            .iter()
+            .find(|(_, user)| user.nickname == nickname)
+            .map(|(id, _)| *id)
+    }
+
+    /// The user whose nickname is `nickname`, or looks like it with the
+    /// [`NicknameRules::unicode`] nicknames
+    fn find_taken(&self, nickname: &str, rules: &NicknameRules) -> Option<SessionId> {
+        if !rules.unicode {
+            return self.find(nickname);
+        }
+        self.folded_nicknames
+            .get(&unicode::skeleton(nickname))
+            .and_then(|ids| ids.first().copied())
+    }
+
+    /// Whether `id` ignores the user named `nickname`
+    fn ignores(&self, id: SessionId, nickname: &str) -> bool {
+        self.connected
+            .get(&id)
+            .is_some_and(|user| user.ignored.contains(nickname))
+    }
+
+    fn room_members(&self, room: &str, except: Option<SessionId>) -> Vec<SessionId> {
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flat_map(|room| room.members.iter().copied())
+            .filter(|id| Some(*id) != except)
+            .collect()
+    }
+
+    /// The nicknames of the users of `room` but `except`, sorted case-insensitively
+    fn room_nicknames(&self, room: &str, except: Option<SessionId>) -> Vec<String> {
+        let mut nicknames: Vec<_> = self
+            .room_members(room, except)
+            .into_iter()
+            .filter_map(|id| self.connected.get(&id))
+            .map(|user| user.nickname.clone())
+            .collect();
+        nicknames.sort_by(|a, b| by_nickname(a, b));
+        nicknames
+    }
+
+    /// Disconnect the users of `room` whose connection ended but did not leave yet,
+    /// before the list of the users is sent to a joining one: it would otherwise be
+    /// told of a user about to leave. Their room is told they left.
+    fn remove_closed(&mut self, room: &str) -> Vec<DisconnectHandler> {
+        let closed: Vec<_> = self
+            .room_members(room, None)
+            .into_iter()
+            .filter(|id| {
+                self.connected
+                    .get(id)
+                    .is_some_and(|user| user.queue.is_closed() && user.detached.is_none())
+            })
+            .collect();
+        let mut evicted = Vec::new();
+        for id in closed {
+            debug!(session = id.0, "closed session removed before a join");
+            evicted.extend(self.disconnect(id));
+        }
+        evicted
+    }
+
+    fn insert(&mut self, id: SessionId, user: ConnectedUser) {
+        self.index_nickname(id, &user.nickname);
+        self.rooms
+            .entry(user.room.clone())
+            .or_default()
+            .members
+            .insert(id);
+        self.connected.insert(id, user);
+        self.count_users();
+    }
+
+    /// Keep the nickname of `user`, whose connection ended, for its address
+    fn hold_nickname(&mut self, user: &ConnectedUser) {
+        if let Some(hold) = self.nick_hold {
+            let held = HeldNickname {
+                expires: Instant::now() + hold,
+                ip: user.peer_addr.map(|addr| addr.ip()),
+            };
+            self.held_nicknames.insert(user.nickname.clone(), held);
+        }
+    }
+
+    /// Refuse `nickname` if it is held for another address than `ip`, the expired
+    /// nicknames are released
+    fn check_held(&mut self, nickname: &str, ip: Option<IpAddr>) -> Result<(), JoinError> {
+        if self.held_nicknames.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.held_nicknames.retain(|_, held| held.expires > now);
+        match self.held_nicknames.get(nickname) {
+            Some(held) if held.ip != ip => {
+                // in whole seconds, rounded up
+                let remaining = held.expires - now;
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                Err(JoinError::NicknameReserved(Duration::from_secs(seconds)))
+            }
+            Some(_) => {
+                self.held_nicknames.remove(nickname);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn remove(&mut self, id: SessionId) -> Option<ConnectedUser> {
+        let user = self.connected.remove(&id)?;
+        if let Some(token) = &user.resume_token {
+            self.resume_tokens.remove(token);
+        }
+        self.unindex_nickname(id, &user.nickname);
+        self.count_users();
+        self.leave_room(id, &user.room);
+        Some(user)
+    }
+
+    fn index_nickname(&mut self, id: SessionId, nickname: &str) {
+        let folded = unicode::skeleton(nickname);
+        self.folded_nicknames.entry(folded).or_default().push(id);
+    }
+
+    fn unindex_nickname(&mut self, id: SessionId, nickname: &str) {
+        let folded = unicode::skeleton(nickname);
+        if let Some(ids) = self.folded_nicknames.get_mut(&folded) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                self.folded_nicknames.remove(&folded);
+            }
+        }
+    }
+
+    /// The users mentioned by `@nickname` in `text`, whatever the case, but `author`.
+    /// The nicknames end at the first character not allowed in nicknames by `rules`.
+    fn mentioned(
+        &self,
+        text: &str,
+        author: SessionId,
+        rules: &NicknameRules,
+    ) -> HashSet<SessionId> {
+        let is_nickname_char = |c: char| rules.is_nickname_char(c);
+        let mut mentioned = HashSet::new();
+        let mut previous = None;
+        for (i, c) in text.char_indices() {
+            if c == '@' && !previous.is_some_and(is_nickname_char) {
+                let token = &text[i + 1..];
+                let end = token.find(|c| !is_nickname_char(c)).unwrap_or(token.len());
+                if let Some(ids) = self.folded_nicknames.get(&unicode::skeleton(&token[..end])) {
+                    mentioned.extend(ids.iter().filter(|id| **id != author));
+                }
+            }
+            previous = Some(c);
+        }
+        mentioned
+    }
+
+    /// The users named by a word of `text`, whatever the case. The words end at the
+    /// characters not allowed in nicknames by `rules`.
+    fn named(&self, text: &str, rules: &NicknameRules) -> HashSet<SessionId> {
+        text.split(|c| !rules.is_nickname_char(c))
+            .filter(|word| !word.is_empty())
+            .filter_map(|word| self.folded_nicknames.get(&unicode::skeleton(word)))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    fn count_users(&self) {
+        self.metrics
+            .connected_users
+            .store(self.connected.len() as u64, Ordering::Relaxed);
+    }
+
+    /// The [`Message::Topic`] of `room`, `None` if it has no topic
+    fn topic_message(&self, room: &str) -> Option<Message> {
+        let topic = self.topics.get(room)?;
+        Some(Message::Topic {
+            text: topic.text.clone(),
+            set_by: topic.set_by.clone(),
+        })
+    }
+
+    /// Remove `id` from the members of `room`, empty rooms are dropped
+    fn leave_room(&mut self, id: SessionId, room: &str) {
+        if let Some(members) = self.rooms.get_mut(room).map(|room| &mut room.members) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Send `message` to every user of `room` but `except`.
+    ///
+    /// See [`Users::deliver`] for the handling of slow consumers.
+    fn broadcast(
+        &mut self,
+        room: &str,
+        except: Option<SessionId>,
+        message: Message,
+    ) -> Vec<DisconnectHandler> {
+        let fanout = self.prepare_broadcast(room, except, message);
+        self.send(fanout).1
+    }
+
+    /// Record `message` and snapshot the users of `room` but `except`, to send it to
+    /// them later
+    fn prepare_broadcast(
+        &mut self,
+        room: &str,
+        except: Option<SessionId>,
+        message: Message,
+    ) -> Fanout {
+        self.history.record(Some(room), &message);
+        self.log(room, &message);
+        self.notify_observers(&message);
+        let recipients = self.room_members(room, except);
+        debug!(room, recipients = recipients.len(), "broadcast");
+        self.prepare(recipients, message)
+    }
+
+    /// Subscribe the observer `sender`, unless the chatroom is shut down
+    fn add_observer(&mut self, sender: Sender<Message>) -> ObserverId {
+        let id = ObserverId(self.next_observer);
+        self.next_observer += 1;
+        if !self.shut_down {
+            self.observers.insert(id, sender);
+        }
+        id
+    }
+
+    /// Queue `message` for the observers, dropping those closed or full
+    fn notify_observers(&mut self, message: &Message) {
+        self.observers
+            .retain(|id, observer| match observer.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!(observer = id.0, "slow observer dropped");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+
+    /// Snapshot the queues of `recipients`, to send them `message` later
+    fn prepare(&self, recipients: Vec<SessionId>, message: Message) -> Fanout {
+        let recipients = recipients
+            .into_iter()
+            .filter_map(|id| Some((id, self.connected.get(&id)?.queue.clone())))
+            .collect();
+        Fanout {
+            recipients,
+            envelope: message.into(),
+        }
+    }
+
+    /// Record the joins, leaves and chat messages in the chat log, the chat messages in
+    /// the archive
+    fn log(&self, room: &str, message: &Message) {
+        if let Some(archive) = &self.archive {
+            match message {
+                Message::Message { from, text } => archive.record(room, from, text, false),
+                Message::Emote { from, action } => archive.record(room, from, action, true),
+                Message::Paste { from, lines } => {
+                    archive.record(room, from, &lines.join("\n"), false)
+                }
+                _ => {}
+            }
+        }
+        let Some(chat_log) = &self.chat_log else {
+            return;
+        };
+        match message {
+            Message::Joined { nickname, .. } => {
+                chat_log.record(ChatEvent::Join, room, nickname, None)
+            }
+            Message::Left {
+                nickname, reason, ..
+            } => chat_log.record(ChatEvent::Leave, room, nickname, reason.as_deref()),
+            Message::Kicked { nickname, by } => chat_log.record(
+                ChatEvent::Leave,
+                room,
+                nickname,
+                Some(&format!("kicked by {by}")),
+            ),
+            Message::Banned { nickname, by } => chat_log.record(
+                ChatEvent::Leave,
+                room,
+                nickname,
+                Some(&format!("banned by {by}")),
+            ),
+            Message::Message { from, text } => {
+                chat_log.record(ChatEvent::Message, room, from, Some(text))
+            }
+            Message::Emote { from, action } => {
+                chat_log.record(ChatEvent::Emote, room, from, Some(action))
+            }
+            Message::Paste { from, lines } => {
+                chat_log.record(ChatEvent::Message, room, from, Some(&lines.join("\n")))
+            }
+            _ => {}
+        }
+    }
+
+    /// Send `message` to `recipients`.
+    ///
+    /// Users whose message queue is full are removed from the chatroom, the users of
+    /// their room are told they left. Returns the disconnect handlers of the evicted
+    /// users, they must be called once the lock is released.
+    fn deliver(&mut self, recipients: Vec<SessionId>, message: Message) -> Vec<DisconnectHandler> {
+        let fanout = self.prepare(recipients, message);
+        self.send(fanout).1
+    }
+
+    /// Send `fanout` under the lock, like [`Users::deliver`]. Also returns the number
+    /// of users the message was queued for.
+    fn send(&mut self, fanout: Fanout) -> (usize, Vec<DisconnectHandler>) {
+        let (delivered, slow_consumers) = self.queue(fanout);
+        (delivered, self.evict(slow_consumers))
+    }
+
+    /// Queue `fanout`, or hand it to the workers: they evict the slow consumers
+    /// themselves. Returns the number of recipients and the slow consumers.
+    fn queue(&self, fanout: Fanout) -> (usize, Vec<SessionId>) {
+        match &self.workers {
+            Some(workers) => (workers.dispatch(fanout), Vec::new()),
+            None => {
+                let _order = self.fanout.lock();
+                fanout.send()
+            }
+        }
+    }
+
+    /// Remove the slow consumers from the chatroom, the users of their room are told
+    /// they left. Returns the disconnect handlers of the evicted users, they must be
+    /// called once the lock is released.
+    fn evict(&mut self, mut slow_consumers: Vec<SessionId>) -> Vec<DisconnectHandler> {
+        let mut evicted = Vec::new();
+        while let Some(id) = slow_consumers.pop() {
+            if let Some(user) = self.remove(id) {
+                warn!(
+                    connection = user.connection,
+                    nickname = user.nickname,
+                    room = user.room,
+                    "slow consumer evicted"
+                );
+                Metrics::add(&self.metrics.evictions, 1);
+                self.hold_nickname(&user);
+                evicted.push(user.on_disconnect);
+                let recipients = self.room_members(&user.room, None);
+                let fanout = self.prepare(
+                    recipients,
+                    Message::Left {
+                        nickname: user.nickname,
+                        online: None,
+                        reason: None,
+                    },
+                );
+                slow_consumers.extend(self.queue(fanout).1);
+            }
+        }
+        evicted
+    }
+
+    /// Remove `id` from the chatroom as if it was evicted, the users of its room are
+    /// told it left. Returns the disconnect handlers to call once the lock is released.
+    fn disconnect(&mut self, id: SessionId) -> Vec<DisconnectHandler> {
+        let Some(user) = self.remove(id) else {
+            return Vec::new();
+        };
+        let mut evicted = self.broadcast(
+            &user.room,
+            None,
+            Message::Left {
+                nickname: user.nickname,
+                online: None,
+                reason: None,
+            },
+        );
+        evicted.push(user.on_disconnect);
+        evicted
+    }
+
+    /// The nickname of `id` if it is an operator, not one either once it left the
+    /// chatroom
+    fn operator(&self, id: SessionId) -> Result<String, KickError> {
+        match self.connected.get(&id) {
+            Some(user) if user.operator => Ok(user.nickname.clone()),
+            _ => Err(KickError::NotOperator),
+        }
+    }
+
+    /// Remove `id` from the chatroom after sending it `notice`, its room receives
+    /// the `announcement` built from its nickname.
+    fn expel(
+        &mut self,
+        id: SessionId,
+        notice: String,
+        announcement: impl FnOnce(String) -> Message,
+    ) -> Vec<DisconnectHandler> {
+        let mut evicted = self.deliver(vec![id], Message::Notice(notice));
+        if let Some(user) = self.remove(id) {
+            evicted.push(user.on_disconnect);
+            evicted.extend(self.broadcast(&user.room, None, announcement(user.nickname)));
+        }
+        evicted
+    }
+}
+
+/// Chatroom private implementation
+struct ChatroomImpl {
+    config: ChatroomConfig,
+    /// see [`Chatroom::reload`], written with the users locked
+    runtime: RwLock<Arc<RuntimeConfig>>,
+    users: Mutex<Users>,
+    session_count: AtomicU64,
+    metrics: Arc<Metrics>,
+    started_at: Instant,
+    /// see [`Users::fanout`]
+    fanout: Arc<Mutex<()>>,
+    /// see [`Chatroom::add_inbound_hook`]
+    inbound_hooks: Hooks,
+    /// see [`Chatroom::add_outbound_hook`]
+    outbound_hooks: Hooks,
+    /// see [`Chatroom::mute`], locked after the users
+    mutes: Mutex<Mutes>,
+    /// when each case-folded nickname last searched, see [`Session::search`]. Locked
+    /// after the users.
+    searches: Mutex<HashMap<String, Instant>>,
+    /// see [`Chatroom::set_locked`]
+    locked: AtomicBool,
+}
+
+impl ChatroomImpl {
+    fn new(config: ChatroomConfig, workers: Option<Workers>) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let fanout = Arc::new(Mutex::new(()));
+        Self {
+            users: Mutex::new(Users {
+                history: History::new(config.history.clone()),
+                chat_log: config.chat_log.clone(),
+                archive: config.archive.clone(),
+                metrics: metrics.clone(),
+                fanout: fanout.clone(),
+                workers,
+                nick_hold: config.nick_hold,
+                ..Default::default()
+            }),
+            session_count: AtomicU64::new(0),
+            metrics,
+            started_at: Instant::now(),
+            fanout,
+            runtime: RwLock::new(Arc::new(RuntimeConfig::from(&config))),
+            locked: AtomicBool::new(config.locked),
+            config,
+            inbound_hooks: Hooks::default(),
+            outbound_hooks: Hooks::default(),
+            mutes: Mutex::default(),
+            searches: Mutex::default(),
+        }
+    }
+
+    fn runtime(&self) -> Arc<RuntimeConfig> {
+        self.runtime.read().clone()
+    }
+
+    /// Release the `users` lock, then send `fanout`: the other users can join, leave
+    /// or chat in the meantime. Returns the number of users the message was queued for.
+    fn fan_out(&self, users: MutexGuard<'_, Users>, fanout: Fanout) -> usize {
+        self.fan_out_all(users, [fanout])
+    }
+
+    /// [`ChatroomImpl::fan_out`] several messages of the same event, e.g. the copies
+    /// of a chat message marked for the mentioned users
+    fn fan_out_all<const N: usize>(
+        &self,
+        users: MutexGuard<'_, Users>,
+        fanouts: [Fanout; N],
+    ) -> usize {
+        if let Some(workers) = &users.workers {
+            // handed over under the lock, in the order of the events
+            return fanouts
+                .into_iter()
+                .map(|fanout| workers.dispatch(fanout))
+                .sum();
+        }
+        // taken before the release: the messages are sent in the order of the events
+        let order = self.fanout.lock();
+        drop(users);
+        let mut delivered = 0;
+        let mut slow_consumers = Vec::new();
+        for fanout in fanouts {
+            let (count, full) = fanout.send();
+            delivered += count;
+            slow_consumers.extend(full);
+        }
+        drop(order);
+        if !slow_consumers.is_empty() {
+            let evicted = self.users.lock().evict(slow_consumers);
+            disconnect(evicted);
+        }
+        delivered
+    }
+
+    fn join(
+        &self,
+        nickname: String,
+        message_sender: Sender<Envelope>,
+        on_disconnect: DisconnectHandler,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<SessionId, JoinError> {
+        self.try_join(nickname, message_sender, on_disconnect, peer_addr)
+            .inspect_err(|e| self.metrics.join_rejected(e))
+    }
+
+    fn try_join(
+        &self,
+        nickname: String,
+        message_sender: Sender<Envelope>,
+        on_disconnect: DisconnectHandler,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<SessionId, JoinError> {
+        let nickname = self.config.nicknames.normalize(nickname);
+        self.config
+            .nicknames
+            .validate(&nickname)
+            .map_err(JoinError::InvalidNickname)?;
+        if self.runtime().word_filter.matches(&nickname) {
+            return Err(JoinError::DisallowedNickname);
+        }
+        // drawn before locking the users, every other session waits on them
+        let resume_token = self.config.resume_window.and_then(|_| new_resume_token());
+        let mut users = self.users.lock();
+
+        if users.shut_down {
+            return Err(JoinError::ShuttingDown);
+        }
+        if self
+            .runtime()
+            .max_users
+            .is_some_and(|max_users| users.connected.len() >= max_users)
+        {
+            return Err(JoinError::RoomFull);
+        }
+        if let (Some(max_sessions), Some(addr)) = (self.config.max_sessions_per_ip, peer_addr) {
+            let sessions = users
+                .connected
+                .values()
+                .filter(|user| user.peer_addr.is_some_and(|peer| peer.ip() == addr.ip()))
+                .count();
+            if sessions >= max_sessions {
+                return Err(JoinError::TooManySessions);
+            }
+        }
+        users.check_held(&nickname, peer_addr.map(|addr| addr.ip()))?;
+        let requested = nickname;
+        let nickname = match users.find_taken(&requested, &self.config.nicknames) {
+            None => requested.clone(),
+            Some(_) => {
+                let free = match self.config.on_duplicate {
+                    OnDuplicate::Reject => None,
+                    _ => self.free_nickname(&users, &requested),
+                };
+                match (self.config.on_duplicate, free) {
+                    (OnDuplicate::Suggest, Some(free)) => {
+                        return Err(JoinError::NicknameTaken(free))
+                    }
+                    (OnDuplicate::Auto, Some(free)) => free,
+                    _ => return Err(JoinError::DuplicateNickname(requested)),
+                }
+            }
+        };
+        let queue = Queue::new(
+            message_sender,
+            self.config.slow_client_policy,
+            &self.metrics,
+        );
+        if nickname != requested {
+            let notice = format!("You have joined as {nickname}");
+            queue.push(Message::Notice(notice).into());
+        }
+        // send the MOTD, nicknames and history to the joining user, under the lock so
+        // that no broadcast is interleaved or missed
+        for line in &users.motd {
+            queue.push(Message::Motd(line.clone()).into());
+        }
+        let ghosts = users.remove_closed(Chatroom::LOBBY);
+        let nicknames = users.room_nicknames(Chatroom::LOBBY, None);
+        queue.push(Message::ConnectedUsers(nicknames).into());
+        if let Some(topic) = users.topic_message(Chatroom::LOBBY) {
+            queue.push(topic.into());
+        }
+        for message in users.history.replay(Chatroom::LOBBY) {
+            queue.push(message);
+        }
+        if let Some(token) = &resume_token {
+            let notice = Message::Notice(format!("resume-token: {token}"));
+            queue.push(notice.into());
+        }
+
+        // send all users of the room the Joined message
+        let joined = self.joined(&users, Chatroom::LOBBY, &nickname);
+        let joined = users.prepare_broadcast(Chatroom::LOBBY, None, joined);
+
+        let session_id = self.new_session_id();
+        let operators = &self.runtime().operators;
+        let operator = match &self.config.operator {
+            _ if operators.is_operator(&nickname) => true,
+            Some(operator) => *operator == nickname,
+            None => !users.joined_once && operators.is_empty(),
+        };
+        users.joined_once = true;
+        info!(
+            session = session_id.0,
+            nickname,
+            room = Chatroom::LOBBY,
+            "user joined"
+        );
+
+        // register the joined user in our connected user database
+        users.insert(
+            session_id,
+            ConnectedUser {
+                nickname,
+                room: Chatroom::LOBBY.to_string(),
+                queue: queue.clone(),
+                on_disconnect,
+                rate_limit: self.runtime().rate_limit.map(TokenBucket::new),
+                repeats: self.config.repeat_limit.map(RepeatGuard::new),
+                last_activity: Instant::now(),
+                joined_at: Instant::now(),
+                messages_sent: 0,
+                last_message: Instant::now(),
+                operator,
+                peer_addr,
+                connection: None,
+                echo: self.config.echo,
+                ignored: HashSet::new(),
+                away: None,
+                bell: false,
+                resume_token: resume_token.clone(),
+                detached: None,
+            },
+        );
+        if let Some(token) = resume_token {
+            users.resume_tokens.insert(token, session_id);
+        }
+
+        self.fan_out(users, joined);
+        disconnect(ghosts);
+        Ok(session_id)
+    }
+
+    /// `nickname` followed by the lowest numeric suffix, from 2, making it free.
+    /// The nickname is shortened to fit the suffix, `None` if it cannot.
+    fn free_nickname(&self, users: &Users, nickname: &str) -> Option<String> {
+        let rules = &self.config.nicknames;
+        for suffix in 2.. {
+            let suffix = suffix.to_string();
+            let kept = rules.max_len.checked_sub(suffix.len())?;
+            let candidate: String = nickname.chars().take(kept).chain(suffix.chars()).collect();
+            rules.validate(&candidate).ok()?;
+            if users.find_taken(&candidate, rules).is_none() {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn nicknames(&self) -> Vec<String> {
+        self.users
+            .lock()
+            .connected
+            .values()
+            .map(|user| user.nickname.clone())
+            .collect()
+    }
+
+    fn new_session_id(&self) -> SessionId {
+        // even at a million joins per second, the ids would last for 500 000 years
+        let count = self.session_count.fetch_add(1, Ordering::Relaxed);
+        debug_assert_ne!(count, u64::MAX, "session ids exhausted");
+        SessionId(count + 1)
+    }
+
+    fn leave(&self, session: SessionId, reason: Option<String>) {
+        let users = self.users.lock();
+        // detached sessions are kept, their Session was dropped
+        if users
+            .connected
+            .get(&session)
+            .is_some_and(|user| user.detached.is_some())
+        {
+            return;
+        }
+        self.remove_user(users, session, reason);
+    }
+
+    /// Remove `session`, the users of its room are told it left
+    fn remove_user(
+        &self,
+        mut users: MutexGuard<'_, Users>,
+        session: SessionId,
+        reason: Option<String>,
+    ) {
+        if let Some(user) = users.remove(session) {
+            info!(
+                session = session.0,
+                nickname = user.nickname,
+                room = user.room,
+                "user left"
+            );
+            users.hold_nickname(&user);
+            // send all users of the room the Left message
+            let online = (!self.config.plain_leave).then(|| user.joined_at.elapsed());
+            let left = Message::Left {
+                nickname: user.nickname,
+                online,
+                reason,
+            };
+            let left = users.prepare_broadcast(&user.room, None, left);
+            self.fan_out(users, left);
+        }
+    }
+
+    fn detach(&self, session: SessionId, receiver: Receiver<Envelope>) {
+        let mut users = self.users.lock();
+        let (Some(resume_window), Some(user)) =
+            (self.config.resume_window, users.connected.get_mut(&session))
+        else {
+            return self.remove_user(users, session, None);
+        };
+        if user.resume_token.is_none() {
+            return self.remove_user(users, session, None);
+        }
+        info!(
+            session = session.0,
+            nickname = user.nickname,
+            "session detached"
+        );
+        user.detached = Some(Detached {
+            expires: Instant::now() + resume_window,
+            receiver,
+        });
+    }
+
+    fn resume(
+        &self,
+        token: &str,
+        peer_addr: Option<SocketAddr>,
+        on_disconnect: DisconnectHandler,
+    ) -> Result<(SessionId, Sender<Envelope>, Receiver<Envelope>), ResumeError> {
+        let mut users = self.users.lock();
+        if users.shut_down {
+            return Err(ResumeError::ShuttingDown);
+        }
+        let id = *users
+            .resume_tokens
+            .get(token)
+            .ok_or(ResumeError::UnknownToken)?;
+        let user = users
+            .connected
+            .get_mut(&id)
+            .ok_or(ResumeError::UnknownToken)?;
+        let detached = user.detached.take().ok_or(ResumeError::StillConnected)?;
+        user.on_disconnect = on_disconnect;
+        if peer_addr.is_some() {
+            user.peer_addr = peer_addr;
+        }
+        user.last_activity = Instant::now();
+        info!(session = id.0, nickname = user.nickname, "session resumed");
+        Ok((id, user.queue.sender().clone(), detached.receiver))
+    }
+
+    /// Remove the detached sessions that were not resumed in time, the users of their
+    /// rooms are told they left
+    fn expire_detached(&self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .users
+            .lock()
+            .connected
+            .iter()
+            .filter(|(_, user)| user.detached.as_ref().is_some_and(|d| d.expires <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let mut users = self.users.lock();
+            // unless resumed meanwhile
+            let Some(user) = users.connected.get_mut(&id) else {
+                continue;
+            };
+            if user.detached.take().is_none() {
+                continue;
+            }
+            debug!(session = id.0, "detached session expired");
+            self.remove_user(users, id, None);
+        }
+    }
+
+    fn shutdown(&self) {
+        let mut users = self.users.lock();
+        users.shut_down = true;
+        let everyone: Vec<_> = users.connected.keys().copied().collect();
+        info!(users = everyone.len(), "disconnecting everyone");
+        let mut evicted = users.deliver(
+            everyone,
+            Message::ServerNotice("server is shutting down".to_string()),
+        );
+        let disconnected: Vec<_> = users.connected.drain().map(|(_, user)| user).collect();
+        users.folded_nicknames.clear();
+        users.resume_tokens.clear();
+        for user in disconnected {
+            users.log(
+                &user.room,
+                &Message::Left {
+                    nickname: user.nickname,
+                    online: None,
+                    reason: None,
+                },
+            );
+            evicted.push(user.on_disconnect);
+        }
+        users.count_users();
+        users.rooms.clear();
+        users.observers.clear();
+        drop(users);
+        disconnect(evicted);
+        if let Some(chat_log) = &self.config.chat_log {
+            chat_log.close();
+        }
+        if let Some(archive) = &self.config.archive {
+            archive.close();
+        }
+    }
+
+    fn rename(&self, session: SessionId, nickname: String) -> Result<(), JoinError> {
+        let nickname = self.config.nicknames.normalize(nickname);
+        self.config
+            .nicknames
+            .validate(&nickname)
+            .map_err(JoinError::InvalidNickname)?;
+        let runtime = self.runtime();
+        if runtime.word_filter.matches(&nickname) {
+            return Err(JoinError::DisallowedNickname);
+        }
+        if runtime.accounts.is_registered(&nickname) {
+            return Err(JoinError::AuthenticationFailed);
+        }
+        // checked and updated under the same lock as joins: nicknames stay unique
+        let mut users = self.users.lock();
+        // a user may change the case of its own nickname
+        let taken = users
+            .find_taken(&nickname, &self.config.nicknames)
+            .is_some_and(|id| id != session);
+        if taken || users.find(&nickname).is_some() {
+            return Err(JoinError::DuplicateNickname(nickname));
+        }
+        let Some(peer_addr) = users.connected.get(&session).map(|user| user.peer_addr) else {
+            return Ok(());
+        };
+        users.check_held(&nickname, peer_addr.map(|addr| addr.ip()))?;
+        let Some(user) = users.connected.get_mut(&session) else {
+            return Ok(());
+        };
+        let old = std::mem::replace(&mut user.nickname, nickname.clone());
+        let room = user.room.clone();
+        users.unindex_nickname(session, &old);
+        users.index_nickname(session, &nickname);
+        info!(session = session.0, old, new = nickname, "user renamed");
+        let evicted = users.broadcast(&room, None, Message::Renamed { old, new: nickname });
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+
+    fn record_activity(&self, session: SessionId) {
+        if let Some(user) = self.users.lock().connected.get_mut(&session) {
+            user.last_activity = Instant::now();
+        }
+    }
+
+    /// Move the messages kept for the slow consumers to their queue, as many as it has
+    /// room for
+    fn flush_overflows(&self) {
+        let users = self.users.lock();
+        let queues: Vec<Queue> = users
+            .connected
+            .values()
+            .filter(|user| user.queue.is_overflowing())
+            .map(|user| user.queue.clone())
+            .collect();
+        drop(users);
+        for queue in queues {
+            queue.flush();
+        }
+    }
+
+    /// Disconnect the users that were not active for `idle_timeout`
+    fn disconnect_idle(&self, idle_timeout: Duration) {
+        let mut users = self.users.lock();
+        let idle: Vec<_> = users
+            .connected
+            .iter()
+            .filter(|(_, user)| user.last_activity.elapsed() >= idle_timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut evicted = Vec::new();
+        for id in idle {
+            if let Some(user) = users.connected.get(&id) {
+                info!(
+                    session = id.0,
+                    connection = user.connection,
+                    nickname = user.nickname,
+                    "idle user disconnected"
+                );
+            }
+            evicted
+                .extend(users.deliver(vec![id], Message::Notice("disconnected: idle".to_string())));
+            evicted.extend(users.disconnect(id));
+        }
+        drop(users);
+        disconnect(evicted);
+    }
+
+    fn send_message(&self, from: &Session, text: String) -> Result<usize, SendError> {
+        let text = self.sanitize(from, text)?;
+        self.send_to_room(from, |from| Message::Message {
+            from: from.into(),
+            text: text.into(),
+        })
+    }
+
+    fn send_emote(&self, from: &Session, action: String) -> Result<usize, SendError> {
+        let action = self.sanitize(from, action)?;
+        self.send_to_room(from, |from| Message::Emote {
+            from: from.into(),
+            action: action.into(),
+        })
+    }
+
+    fn send_paste(&self, from: &Session, lines: Vec<String>) -> Result<usize, SendError> {
+        let lines = lines
+            .into_iter()
+            .map(|line| self.sanitize(from, line))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.send_to_room(from, |from| Message::Paste {
+            from: from.into(),
+            lines: lines.into(),
+        })
+    }
+
+    /// Run `hooks` on the `message` of `from`, without the users locked. `from` is
+    /// sent the notice of a dropped message.
+    fn run_hooks(
+        &self,
+        hooks: &Hooks,
+        from: &Session,
+        message: Message,
+    ) -> Result<Message, SendError> {
+        hooks.run(message).map_err(|notice| {
+            debug!(session = from.id.0, "message dropped by a hook");
+            if let Some(notice) = notice {
+                let evicted = self
+                    .users
+                    .lock()
+                    .deliver(vec![from.id], Message::Notice(notice));
+                disconnect(evicted);
+            }
+            SendError::Dropped
+        })
+    }
+
+    /// Apply [`ChatroomConfig::sanitize`] and the [`ChatroomConfig::word_filter`] to
+    /// `text`, `from` is notified of the rejected messages
+    fn sanitize(&self, from: &Session, text: String) -> Result<String, SendError> {
+        let filtered = match self.config.sanitize.apply(text) {
+            Some(text) => self
+                .runtime()
+                .word_filter
+                .apply(self.config.word_filter_mode, text)
+                .ok_or(SendError::FilteredWords),
+            None => Err(SendError::ControlCharacters),
+        };
+        if let Err(e) = &filtered {
+            let evicted = self
+                .users
+                .lock()
+                .deliver(vec![from.id], Message::Notice(e.to_string()));
+            disconnect(evicted);
+        }
+        filtered
+    }
+
+    /// Check that `from` may send, to its room or privately: that neither the
+    /// lockdown nor a mute silences it, and that it is within its rate limit. Otherwise
+    /// `from` is told why, the sessions to disconnect are returned with the error.
+    fn check_sender(
+        &self,
+        users: &mut Users,
+        from: SessionId,
+    ) -> Result<(), (SendError, Vec<DisconnectHandler>)> {
+        let Some(user) = users.connected.get_mut(&from) else {
+            return Err((SendError::NotConnected, Vec::new()));
+        };
+        if !user.operator && self.locked.load(Ordering::Relaxed) {
+            let notice = SendError::ReadOnly.to_string();
+            let evicted = users.deliver(vec![from], Message::Notice(notice));
+            return Err((SendError::ReadOnly, evicted));
+        }
+        let now = Instant::now();
+        if let Some(until) = self.mutes.lock().muted_until(&user.nickname, now) {
+            let until = DateTime::<Local>::from(SystemTime::now() + (until - now));
+            let notice = format!("you are muted until {}", until.format("%H:%M:%S"));
+            let evicted = users.deliver(vec![from], Message::Notice(notice));
+            return Err((SendError::Muted, evicted));
+        }
+        if let Some(bucket) = &mut user.rate_limit {
+            if !bucket.take() {
+                let flooding = bucket.violations == self.runtime().max_rate_violations;
+                let evicted = if flooding {
+                    warn!(
+                        session = from.0,
+                        connection = user.connection,
+                        nickname = user.nickname,
+                        "flooding user disconnected"
+                    );
+                    let notice = "you were disconnected for sending messages too fast";
+                    let mut evicted =
+                        users.deliver(vec![from], Message::Notice(notice.to_string()));
+                    evicted.extend(users.disconnect(from));
+                    evicted
+                } else {
+                    let notice = "you are sending messages too fast";
+                    users.deliver(vec![from], Message::Notice(notice.to_string()))
+                };
+                return Err((SendError::RateLimited, evicted));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the message built from the nickname of `from` to the other users of its
+    /// room, within the rate and repeat limits. The message goes through the inbound
+    /// hooks before the limits, the outbound hooks after. Returns the number of
+    /// recipients.
+    fn send_to_room(
+        &self,
+        from: &Session,
+        message: impl FnOnce(String) -> Message,
+    ) -> Result<usize, SendError> {
+        let mut users = self.users.lock();
+        let Some(user) = users.connected.get(&from.id) else {
+            return Err(SendError::NotConnected);
+        };
+        let mut message = message(user.nickname.clone());
+        if !self.inbound_hooks.is_empty() {
+            drop(users);
+            message = self.run_hooks(&self.inbound_hooks, from, message)?;
+            users = self.users.lock();
+        }
+        if let Err((e, evicted)) = self.check_sender(&mut users, from.id) {
+            drop(users);
+            disconnect(evicted);
+            return Err(e);
+        }
+        let Some(user) = users.connected.get_mut(&from.id) else {
+            return Err(SendError::NotConnected);
+        };
+        if let (Some(repeats), Some(text)) = (&mut user.repeats, chat_text(&message)) {
+            if !repeats.check(text) {
+                let spamming = repeats.violations == self.config.max_repeat_violations;
+                let evicted = if spamming {
+                    info!(
+                        session = from.id.0,
+                        connection = user.connection,
+                        nickname = user.nickname,
+                        by = SERVER,
+                        "repeating user kicked"
+                    );
+                    let notice = "you were kicked for repeating yourself".to_string();
+                    users.expel(from.id, notice, |nickname| Message::Kicked {
+                        nickname,
+                        by: SERVER.to_string(),
+                    })
+                } else {
+                    let notice = SendError::Repeated.to_string();
+                    users.deliver(vec![from.id], Message::Notice(notice))
+                };
+                drop(users);
+                disconnect(evicted);
+                return Err(SendError::Repeated);
+            }
+        }
+        if !self.outbound_hooks.is_empty() {
+            drop(users);
+            message = self.run_hooks(&self.outbound_hooks, from, message)?;
+            users = self.users.lock();
+        }
+        let Some(user) = users.connected.get_mut(&from.id) else {
+            return Err(SendError::NotConnected);
+        };
+        user.messages_sent += 1;
+        user.last_message = Instant::now();
+        let room = user.room.clone();
+        let nickname = user.nickname.clone();
+        let except = (!user.echo).then_some(from.id);
+        Metrics::add(&self.metrics.messages_broadcast, 1);
+        let rules = &self.config.nicknames;
+        let mentioned = match &message {
+            Message::Message { text, .. } => users.mentioned(text, from.id, rules),
+            _ => HashSet::new(),
+        };
+        // send all other users of the room the message, and the sender with echo
+        let mut fanout = users.prepare_broadcast(&room, except, message);
+        fanout
+            .recipients
+            .retain(|(id, _)| !users.ignores(*id, &nickname));
+        let (marked, text) = match &fanout.envelope.message {
+            Message::Message { from, text } => (
+                Message::Mentioned {
+                    from: from.clone(),
+                    text: text.clone(),
+                },
+                text.clone(),
+            ),
+            Message::Emote { action, .. } => (fanout.envelope.message.clone(), action.clone()),
+            _ => return Ok(self.fan_out(users, fanout)),
+        };
+        let named = users.named(&text, rules);
+        let rings = |id: &SessionId| {
+            (mentioned.contains(id) || named.contains(id))
+                && users.connected.get(id).is_some_and(|user| user.bell)
+        };
+        if mentioned.is_empty() && !fanout.recipients.iter().any(|(id, _)| rings(id)) {
+            return Ok(self.fan_out(users, fanout));
+        }
+        // the mentioned users get a marked copy instead, and the users who want a bell
+        // a ringing one
+        let copy = |message: &Message, bell| Fanout {
+            recipients: Vec::new(),
+            envelope: Envelope {
+                at: fanout.envelope.at,
+                message: message.clone(),
+                bell,
+                seq: None,
+            },
+        };
+        let mut ringing = copy(&fanout.envelope.message, true);
+        let (mut mentions, mut ringing_mentions) = (copy(&marked, false), copy(&marked, true));
+        fanout.recipients.retain(|(id, queue)| {
+            let copy = match (mentioned.contains(id), rings(id)) {
+                (false, false) => return true,
+                (false, true) => &mut ringing,
+                (true, false) => &mut mentions,
+                (true, true) => &mut ringing_mentions,
+            };
+            copy.recipients.push((*id, queue.clone()));
+            false
+        });
+        Ok(self.fan_out_all(users, [fanout, ringing, mentions, ringing_mentions]))
+    }
+
+    fn kick(&self, by: SessionId, target: &str, reason: Option<String>) -> Result<(), KickError> {
+        let operator = self.users.lock().operator(by)?;
+        self.kick_as(&operator, target, reason)
+    }
+
+    fn kick_as(
+        &self,
+        operator: &str,
+        target: &str,
+        reason: Option<String>,
+    ) -> Result<(), KickError> {
+        let mut users = self.users.lock();
+        let Some(target_id) = users.find(target) else {
+            return Err(KickError::NoSuchUser(target.to_string()));
+        };
+        info!(
+            session = target_id.0,
+            connection = users.connected[&target_id].connection,
+            nickname = target,
+            by = operator,
+            reason,
+            "user kicked"
+        );
+
+        let notice = match reason {
+            Some(reason) => format!("you were kicked: {reason}"),
+            None => format!("you were kicked by {operator}"),
+        };
+        let evicted = users.expel(target_id, notice, |nickname| Message::Kicked {
+            nickname,
+            by: operator.to_string(),
+        });
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+
+    fn broadcast_notice(&self, text: String) {
+        let mut users = self.users.lock();
+        let everyone: Vec<_> = users.connected.keys().copied().collect();
+        if everyone.is_empty() && users.observers.is_empty() {
+            return;
+        }
+        info!(text, "server notice");
+        let notice = Message::ServerNotice(text);
+        users.history.record(None, &notice);
+        users.notify_observers(&notice);
+        let evicted = users.deliver(everyone, notice);
+        drop(users);
+        disconnect(evicted);
+    }
+
+    fn set_operator(&self, by: SessionId, target: &str, operator: bool) -> Result<(), KickError> {
+        let mut users = self.users.lock();
+        let by = users.operator(by)?;
+        // the offline users are made operators too, with a nickname they could join with
+        let target = self.config.nicknames.normalize(target.to_string());
+        if self.config.nicknames.validate(&target).is_err() {
+            return Err(KickError::NoSuchUser(target));
+        }
+        let operators = &self.runtime().operators;
+        if operator {
+            operators.add(&target);
+        } else {
+            operators.remove(&target);
+        }
+        info!(nickname = target, by, operator, "operator status changed");
+        let target = users
+            .connected
+            .iter_mut()
+            .find(|(_, user)| user.nickname == target);
+        let Some((&target_id, user)) = target else {
+            return Ok(());
+        };
+        if user.operator == operator {
+            return Ok(());
+        }
+        user.operator = operator;
+        let notice = match operator {
+            true => format!("you are now an operator, by {by}"),
+            false => format!("you are no longer an operator, by {by}"),
+        };
+        let evicted = users.deliver(vec![target_id], Message::Notice(notice));
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+
+    fn mute(&self, by: SessionId, target: &str, duration: Duration) -> Result<(), KickError> {
+        let users = self.users.lock();
+        let operator = users.operator(by)?;
+        let Some(target_id) = users.find(target) else {
+            return Err(KickError::NoSuchUser(target.to_string()));
+        };
+        let nickname = &users.connected[&target_id].nickname;
+        info!(
+            session = target_id.0,
+            nickname,
+            by = operator,
+            seconds = duration.as_secs(),
+            "user muted"
+        );
+        self.mutes.lock().mute(nickname, Instant::now() + duration);
+        Ok(())
+    }
+
+    fn ban(&self, by: SessionId, target: &str) -> Result<IpAddr, KickError> {
+        let mut users = self.users.lock();
+        let operator = users.operator(by)?;
+        let Some(target_id) = users.find(target) else {
+            return Err(KickError::NoSuchUser(target.to_string()));
+        };
+        let Some(ip) = users.connected[&target_id].peer_addr.map(|addr| addr.ip()) else {
+            return Err(KickError::UnknownAddress(target.to_string()));
+        };
+        info!(
+            session = target_id.0,
+            connection = users.connected[&target_id].connection,
+            nickname = target,
+            by = operator,
+            %ip,
+            "user banned"
+        );
+
+        self.runtime().bans.ban(ip);
+        let notice = "you are banned".to_string();
+        let evicted = users.expel(target_id, notice, |nickname| Message::Banned {
+            nickname,
+            by: operator,
+        });
+        drop(users);
+        disconnect(evicted);
+        Ok(ip)
+    }
+
+    fn send_private(&self, from: &Session, to: &str, text: String) -> Result<(), SendError> {
+        let text = self
+            .config
+            .sanitize
+            .apply(text)
+            .ok_or(SendError::ControlCharacters)?;
+        let mut users = self.users.lock();
+        let Some(from_nickname) = users
+            .connected
+            .get(&from.id)
+            .map(|user| user.nickname.clone())
+        else {
+            return Ok(());
+        };
+        if let Err((e, evicted)) = self.check_sender(&mut users, from.id) {
+            drop(users);
+            disconnect(evicted);
+            return Err(e);
+        }
+        let Some(to_id) = users.find(to) else {
+            return Err(SendError::NoSuchUser(to.to_string()));
+        };
+
+        // the author is not told that the recipient ignores it
+        let mut evicted = Vec::new();
+        if !users.ignores(to_id, &from_nickname) {
+            let mut fanout = users.prepare(
+                vec![to_id],
+                Message::Private {
+                    from: from_nickname,
+                    text: text.clone(),
+                },
+            );
+            fanout.envelope.bell = users.connected[&to_id].bell;
+            evicted = users.send(fanout).1;
+        }
+        evicted.extend(users.deliver(
+            vec![from.id],
+            Message::PrivateSent {
+                to: to.to_string(),
+                text,
+            },
+        ));
+        // answered on behalf of the recipient
+        if let Some(away) = &users.connected[&to_id].away {
+            let notice = match away.as_str() {
+                "" => format!("{to} is away"),
+                reason => format!("{to} is away: {reason}"),
+            };
+            evicted.extend(users.deliver(vec![from.id], Message::Notice(notice)));
+        }
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+
+    fn room(&self, session: SessionId) -> Option<String> {
+        let users = self.users.lock();
+        users.connected.get(&session).map(|user| user.room.clone())
+    }
+
+    fn search(&self, session: SessionId, term: &str) -> Result<Search, SearchError> {
+        let archive = self.config.archive.clone().ok_or(SearchError::NoArchive)?;
+        let users = self.users.lock();
+        let user = users
+            .connected
+            .get(&session)
+            .ok_or(SearchError::NotConnected)?;
+        let now = Instant::now();
+        let mut searches = self.searches.lock();
+        searches.retain(|_, at| now.duration_since(*at) < SEARCH_INTERVAL);
+        let nickname = user.nickname.to_ascii_lowercase();
+        if let Some(at) = searches.get(&nickname) {
+            return Err(SearchError::TooSoon(
+                SEARCH_INTERVAL - now.duration_since(*at),
+            ));
+        }
+        searches.insert(nickname, now);
+        Ok(Search::new(archive, user.room.clone(), term.to_string()))
+    }
+
+    /// The messages are copied under the lock, rendered by the caller without it
+    fn last_messages(&self, session: SessionId, count: usize) -> Vec<Envelope> {
+        let users = self.users.lock();
+        match users.connected.get(&session) {
+            Some(user) => users.history.last(&user.room, count),
+            None => Vec::new(),
+        }
+    }
+
+    fn topic(&self, session: SessionId) -> Option<TopicEntry> {
+        let users = self.users.lock();
+        let room = &users.connected.get(&session)?.room;
+        users.topics.get(room).cloned()
+    }
+
+    fn set_topic(&self, from: &Session, text: String) -> Result<(), TopicError> {
+        let text = self
+            .config
+            .sanitize
+            .apply(text)
+            .ok_or(TopicError::ControlCharacters)?;
+        let mut users = self.users.lock();
+        let Some(user) = users.connected.get(&from.id) else {
+            return Err(TopicError::NotConnected);
+        };
+        if !user.operator && users.connected.values().any(|user| user.operator) {
+            return Err(TopicError::NotOperator);
+        }
+        let room = user.room.clone();
+        let set_by = user.nickname.clone();
+        info!(
+            session = from.id.0,
+            nickname = set_by,
+            room,
+            topic = text,
+            "topic set"
+        );
+        if text.is_empty() {
+            users.topics.remove(&room);
+        } else {
+            users.topics.insert(
+                room.clone(),
+                TopicEntry {
+                    text: text.clone(),
+                    set_by: set_by.clone(),
+                    set_at: SystemTime::now(),
+                },
+            );
+        }
+        let fanout = users.prepare_broadcast(&room, None, Message::Topic { text, set_by });
+        self.fan_out(users, fanout);
+        Ok(())
+    }
+
+    fn room_users(&self, room: &str) -> Vec<String> {
+        self.users.lock().room_nicknames(room, None)
+    }
+
+    /// The nicknames of the users in `room`, and whether they are away
+    fn who(&self, room: &str) -> Vec<(String, bool, bool)> {
+        let users = self.users.lock();
+        users
+            .room_members(room, None)
+            .into_iter()
+            .filter_map(|id| users.connected.get(&id))
+            .map(|user| (user.nickname.clone(), user.operator, user.away.is_some()))
+            .collect()
+    }
+
+    fn users(&self) -> Vec<UserInfo> {
+        self.users
+            .lock()
+            .connected
+            .iter()
+            .map(|(id, user)| user.info(*id))
+            .collect()
+    }
+
+    fn whois(&self, by: SessionId, nickname: &str) -> Option<UserInfo> {
+        let users = self.users.lock();
+        let operator = users.connected.get(&by).is_some_and(|user| user.operator);
+        let id = users.find(nickname)?;
+        let mut info = users.connected[&id].info(id);
+        if !operator {
+            info.peer_addr = None;
+        }
+        Some(info)
+    }
+
+    fn rooms(&self) -> Vec<RoomInfo> {
+        self.users
+            .lock()
+            .rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                users: room.members.len(),
+                invite_only: room.invite_only,
+                limit: room.limit,
+            })
+            .collect()
+    }
+
+    fn join_room(&self, session: SessionId, room: &str) -> Result<(), RoomError> {
+        if room.is_empty() || room.chars().any(|c| !c.is_ascii_alphanumeric()) {
+            return Err(RoomError::InvalidName(room.to_string()));
+        }
+        let mut users = self.users.lock();
+        let Some(user) = users.connected.get(&session) else {
+            return Ok(());
+        };
+        if user.room == room {
+            return Err(RoomError::AlreadyInRoom(room.to_string()));
+        }
+        let (nickname, operator) = (user.nickname.clone(), user.operator);
+        // checked under the users lock, the concurrent joins cannot both take the
+        // last place
+        if let Some(joined) = users.rooms.get_mut(room) {
+            if let Some(limit) = joined.limit.filter(|limit| joined.members.len() >= *limit) {
+                let room = room.to_string();
+                return Err(RoomError::Full { room, limit });
+            }
+            if joined.invite_only && !operator && !joined.invited.remove(&nickname) {
+                return Err(RoomError::InviteOnly(room.to_string()));
+            }
+        }
+        let user = users.connected.get_mut(&session).unwrap();
+        let previous_room = std::mem::replace(&mut user.room, room.to_string());
+        info!(
+            session = session.0,
+            nickname,
+            from = previous_room,
+            to = room,
+            "user changed room"
+        );
+
+        users.leave_room(session, &previous_room);
+        let mut evicted = users.broadcast(
+            &previous_room,
+            None,
+            Message::Left {
+                nickname: nickname.clone(),
+                online: None,
+                reason: None,
+            },
+        );
+
+        evicted.extend(users.remove_closed(room));
+        let nicknames = users.room_nicknames(room, None);
+        let joined = self.joined(&users, room, &nickname);
+        evicted.extend(users.broadcast(room, None, joined));
+        let default_limit = self.config.default_room_limit;
+        users
+            .rooms
+            .entry(room.to_string())
+            .or_insert_with(|| Room {
+                limit: default_limit,
+                ..Default::default()
+            })
+            .members
+            .insert(session);
+        evicted.extend(users.deliver(
+            vec![session],
+            Message::Notice(format!("you are now in #{room}")),
+        ));
+        evicted.extend(users.deliver(vec![session], Message::ConnectedUsers(nicknames)));
+        if let Some(topic) = users.topic_message(room) {
+            evicted.extend(users.deliver(vec![session], topic));
+        }
+
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+}
+
+impl ChatroomImpl {
+    /// The [`Message::Joined`] of `nickname` joining `room`, not counted yet in its
+    /// members
+    fn joined(&self, users: &Users, room: &str, nickname: &str) -> Message {
+        let count = || users.rooms.get(room).map_or(0, |room| room.members.len()) + 1;
+        Message::Joined {
+            nickname: nickname.to_string(),
+            users: self.config.announce_counts.then(count),
+        }
+    }
+
+    fn invite(&self, session: SessionId, nickname: &str) -> Result<String, RoomError> {
+        let mut users = self.users.lock();
+        let Some(user) = users.connected.get(&session) else {
+            return Err(RoomError::NotOperator);
+        };
+        if !user.operator {
+            return Err(RoomError::NotOperator);
+        }
+        let (by, room) = (user.nickname.clone(), user.room.clone());
+        let Some(invited) = users.find(nickname) else {
+            return Err(RoomError::NoSuchUser(nickname.to_string()));
+        };
+        if let Some(joined) = users.rooms.get_mut(&room) {
+            joined.invited.insert(nickname.to_string());
+        }
+        info!(nickname, by, room, "user invited");
+        let notice = format!("{by} invited you to #{room}");
+        let evicted = users.deliver(vec![invited], Message::Notice(notice));
+        drop(users);
+        disconnect(evicted);
+        Ok(room)
+    }
+
+    fn set_invite_only(
+        &self,
+        session: SessionId,
+        room: &str,
+        invite_only: bool,
+    ) -> Result<(), RoomError> {
+        self.change_mode(session, room, |joined, by| {
+            joined.invite_only = invite_only;
+            if !invite_only {
+                joined.invited.clear();
+            }
+            info!(room, by, invite_only, "room mode changed");
+            match invite_only {
+                true => format!("#{room} is now invite-only, by {by}"),
+                false => format!("#{room} is no longer invite-only, by {by}"),
+            }
+        })
+    }
+
+    fn set_room_limit(
+        &self,
+        session: SessionId,
+        room: &str,
+        limit: Option<usize>,
+    ) -> Result<(), RoomError> {
+        self.change_mode(session, room, |joined, by| {
+            joined.limit = limit;
+            info!(room, by, limit, "room mode changed");
+            match limit {
+                Some(limit) => format!("#{room} now holds up to {limit} users, by {by}"),
+                None => format!("#{room} is no longer limited, by {by}"),
+            }
+        })
+    }
+
+    /// Apply `change` to `room`, the room of the operator `session`, its users are
+    /// sent the notice it returns. `change` is given the nickname of the operator.
+    fn change_mode(
+        &self,
+        session: SessionId,
+        room: &str,
+        change: impl FnOnce(&mut Room, &str) -> String,
+    ) -> Result<(), RoomError> {
+        if room == Chatroom::LOBBY {
+            return Err(RoomError::Lobby);
+        }
+        let mut users = self.users.lock();
+        let Some(user) = users.connected.get(&session) else {
+            return Err(RoomError::NotOperator);
+        };
+        if user.room != room {
+            return Err(RoomError::NotInRoom(room.to_string()));
+        }
+        if !user.operator {
+            return Err(RoomError::NotOperator);
+        }
+        let by = user.nickname.clone();
+        let Some(joined) = users.rooms.get_mut(room) else {
+            return Err(RoomError::NotInRoom(room.to_string()));
+        };
+        let notice = change(joined, &by);
+        let evicted = users.broadcast(room, None, Message::Notice(notice));
+        drop(users);
+        disconnect(evicted);
+        Ok(())
+    }
+}
+
+fn disconnect(evicted: Vec<DisconnectHandler>) {
+    for on_disconnect in evicted {
+        on_disconnect();
+    }
 }
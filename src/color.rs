@@ -0,0 +1,109 @@
+//! The ANSI colors of the lines written to the clients that asked for them with
+//! `/color on`, see [`ServerConfig::color`](crate::server::ServerConfig::color).
+//!
+//! Only the server writes escape sequences: those of the users are stripped from
+//! their messages, see [`Sanitize`](crate::Sanitize).
+
+use crate::{Envelope, Message, MessageTemplates, Timestamps};
+
+/// The SGR codes of the nicknames: the red, green, yellow, blue, magenta and cyan
+/// foregrounds, normal then bright
+const NICKNAME_COLORS: [u8; 12] = [31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+const BOLD: u8 = 1;
+const DIM: u8 = 2;
+
+/// The SGR code of the foreground color of `nickname`, the same on every run and
+/// every server: the users recognize each other by their colors
+pub fn nickname_color(nickname: &str) -> u8 {
+    // FNV-1a, the hashers of the standard library are not guaranteed to stay the same
+    let hash = nickname
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    NICKNAME_COLORS[(hash % NICKNAME_COLORS.len() as u64) as usize]
+}
+
+/// The line of `envelope` as [`Envelope::render`] with its colors: the nicknames of
+/// the authors colored, the notices of the server dim and the mentions of the user
+/// bold
+pub(crate) fn render(
+    envelope: &Envelope,
+    timestamps: Option<Timestamps>,
+    templates: &MessageTemplates,
+) -> String {
+    let (message, style) = paint(&envelope.message);
+    let mut line = Envelope {
+        at: envelope.at,
+        message,
+        bell: false,
+        seq: envelope.seq,
+    }
+    .render(timestamps, templates);
+    if let Some(style) = style {
+        line = format!("\x1b[{style}m{line}\x1b[0m");
+    }
+    // first, as without colors
+    if envelope.bell {
+        line.insert(0, '\u{7}');
+    }
+    line
+}
+
+/// `message` with the nickname of its author colored, and the style of its whole line
+fn paint(message: &Message) -> (Message, Option<u8>) {
+    match message {
+        Message::Message { from, text } => (
+            Message::Message {
+                from: colored(from).into(),
+                text: text.clone(),
+            },
+            None,
+        ),
+        Message::Mentioned { from, text } => (
+            Message::Mentioned {
+                from: colored(from).into(),
+                text: text.clone(),
+            },
+            Some(BOLD),
+        ),
+        Message::Emote { from, action } => (
+            Message::Emote {
+                from: colored(from).into(),
+                action: action.clone(),
+            },
+            None,
+        ),
+        Message::Paste { from, lines } => (
+            Message::Paste {
+                from: colored(from).into(),
+                lines: lines.clone(),
+            },
+            None,
+        ),
+        Message::Private { from, text } => (
+            Message::Private {
+                from: colored(from),
+                text: text.clone(),
+            },
+            None,
+        ),
+        Message::PrivateSent { to, text } => (
+            Message::PrivateSent {
+                to: colored(to),
+                text: text.clone(),
+            },
+            None,
+        ),
+        Message::History(message) => {
+            let (message, style) = paint(message);
+            (Message::History(Box::new(message)), style)
+        }
+        message => (message.clone(), Some(DIM)),
+    }
+}
+
+/// `nickname` in its color, the style of the rest of the line is kept
+fn colored(nickname: &str) -> String {
+    format!("\x1b[{}m{nickname}\x1b[39m", nickname_color(nickname))
+}
@@ -0,0 +1,528 @@
+//! Parsing of the lines sent by the clients
+
+use std::{fmt::Display, net::IpAddr, time::Duration};
+
+use crate::mutes::parse_mute_duration;
+
+/// A line sent by a client
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command<'a> {
+    /// plain chat message, broadcast to the room. A message starting with a slash is
+    /// sent with another one in front, e.g. `//shrug` for `/shrug`
+    Message(&'a str),
+    /// `/me <action>`: emote, broadcast to the room
+    Emote(&'a str),
+    /// `/paste`: the next lines up to a line holding a single `.` are a single
+    /// message
+    Paste,
+    /// `/msg <nick> <text>`: private message
+    Private { to: &'a str, text: &'a str },
+    /// `/who`: list the users of the room
+    Who,
+    /// `/whois <nick>`: show the details of a user
+    Whois(&'a str),
+    /// `/join <room>`: move to another room
+    Join(&'a str),
+    /// `/leave`: go back to the lobby
+    Leave,
+    /// `/rooms`: list the rooms
+    Rooms,
+    /// `/invite <nick>`: allow a user to join the room once, for operators
+    Invite(&'a str),
+    /// `/mode #room +i|-i|limit <count>|-l`: change the mode of a room, for operators
+    Mode { room: &'a str, mode: RoomMode },
+    /// `/kick <nick> [reason]`: disconnect a user, for operators
+    Kick {
+        target: &'a str,
+        reason: Option<&'a str>,
+    },
+    /// `/ban <nick>`: disconnect a user and ban its address, for operators
+    Ban(&'a str),
+    /// `/unban <ip>`: lift a ban, for operators
+    Unban(IpAddr),
+    /// `/mute <nick> <duration>`: drop the messages of a user for a while, for
+    /// operators
+    Mute { target: &'a str, duration: Duration },
+    /// `/unmute <nick>`: end a mute early, for operators
+    Unmute(&'a str),
+    /// `/lockdown on|off`: make the rooms read-only but for the operators, or
+    /// writable again, for operators
+    Lockdown(bool),
+    /// `/op <nick>`: make a user an operator, for operators
+    Op(&'a str),
+    /// `/deop <nick>`: take the operator status of a user away, for operators
+    Deop(&'a str),
+    /// `/nick <newname>`: change nickname
+    Nick(&'a str),
+    /// `/timestamps on|off`: prefix the delivered lines with their time
+    Timestamps(bool),
+    /// `/color on|off`: color the delivered lines with ANSI escape sequences
+    Color(bool),
+    /// `/seq on|off`: prefix the delivered lines with their number
+    Seq(bool),
+    /// `/ack <seq>`: tell the number of the last line processed
+    Ack(u64),
+    /// `/echo on|off`: receive your own messages
+    Echo(bool),
+    /// `/bell on|off`: ring the terminal bell on the messages mentioning you
+    Bell(bool),
+    /// `/topic [text]`: show the topic of the room, or set it
+    Topic(Option<&'a str>),
+    /// `/ignore [nick]`: stop receiving the messages of a user, or list the ignored
+    /// users
+    Ignore(Option<&'a str>),
+    /// `/unignore <nick>`: receive the messages of an ignored user again
+    Unignore(&'a str),
+    /// `/last [count]`: show the last messages of the room, 10 by default
+    Last(Option<usize>),
+    /// `/search <term>`: show the last messages of the room containing the term, from
+    /// the archive
+    Search(&'a str),
+    /// `/stats`: show the activity of the chatroom and of the session
+    Stats,
+    /// `/quit [message]`: leave the chatroom and close the connection
+    Quit(Option<&'a str>),
+    /// `/away [reason]`: mark yourself away, or back without reason while away
+    Away(Option<&'a str>),
+    /// `/back`: clear the away status
+    Back,
+    /// `/protocol json|plain`: receive the events as JSON objects or as lines, only
+    /// as the first line after the nickname
+    Protocol { json: bool },
+    /// `/help`: list the commands
+    Help,
+    /// a command used with invalid arguments, holds its usage
+    Usage(&'static str),
+    /// a line starting with a slash but no command, holds its first word
+    Unknown(&'a str),
+}
+
+/// A change of the mode of a room, see [`Command::Mode`]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RoomMode {
+    /// `+i` or `-i`
+    InviteOnly(bool),
+    /// `limit <count>`, or `-l` to lift the limit
+    Limit(Option<usize>),
+}
+
+impl<'a> Command<'a> {
+    pub(crate) fn parse(line: &'a str) -> Self {
+        // a chat message starting with a slash
+        if line.starts_with("//") {
+            return Command::Message(&line[1..]);
+        }
+        if !line.starts_with('/') {
+            return Command::Message(line);
+        }
+        for spec in COMMANDS {
+            if let Some(args) = command_args(line, spec.name) {
+                return (spec.parse)(args).unwrap_or(Command::Usage(spec.usage));
+            }
+        }
+        let name = line.split(char::is_whitespace).next().unwrap_or(line);
+        Command::Unknown(name)
+    }
+}
+
+/// The line of the command, e.g. `/msg bob hi`, which [`Command::parse`] reads back
+/// as the same command. A [`Command::Usage`] is written as its usage.
+impl Display for Command<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let on_off = |on: &bool| if *on { "on" } else { "off" };
+        let optional = |name: &str, args: &Option<&str>| match args {
+            Some(args) => format!("{name} {args}"),
+            None => name.to_string(),
+        };
+        match self {
+            Command::Message(text) if text.starts_with('/') => write!(f, "/{text}"),
+            Command::Message(text) => f.write_str(text),
+            Command::Emote(action) => write!(f, "/me {action}"),
+            Command::Paste => f.write_str("/paste"),
+            Command::Private { to, text } => write!(f, "/msg {to} {text}"),
+            Command::Who => f.write_str("/who"),
+            Command::Whois(nickname) => write!(f, "/whois {nickname}"),
+            Command::Join(room) => write!(f, "/join #{room}"),
+            Command::Leave => f.write_str("/leave"),
+            Command::Rooms => f.write_str("/rooms"),
+            Command::Invite(nickname) => write!(f, "/invite {nickname}"),
+            Command::Mode { room, mode } => match mode {
+                RoomMode::InviteOnly(true) => write!(f, "/mode #{room} +i"),
+                RoomMode::InviteOnly(false) => write!(f, "/mode #{room} -i"),
+                RoomMode::Limit(Some(limit)) => write!(f, "/mode #{room} limit {limit}"),
+                RoomMode::Limit(None) => write!(f, "/mode #{room} -l"),
+            },
+            Command::Kick { target, reason } => {
+                f.write_str(&optional(&format!("/kick {target}"), reason))
+            }
+            Command::Ban(nickname) => write!(f, "/ban {nickname}"),
+            Command::Unban(ip) => write!(f, "/unban {ip}"),
+            Command::Mute { target, duration } => {
+                write!(f, "/mute {target} {}s", duration.as_secs())
+            }
+            Command::Unmute(nickname) => write!(f, "/unmute {nickname}"),
+            Command::Lockdown(on) => write!(f, "/lockdown {}", on_off(on)),
+            Command::Op(nickname) => write!(f, "/op {nickname}"),
+            Command::Deop(nickname) => write!(f, "/deop {nickname}"),
+            Command::Nick(nickname) => write!(f, "/nick {nickname}"),
+            Command::Timestamps(on) => write!(f, "/timestamps {}", on_off(on)),
+            Command::Color(on) => write!(f, "/color {}", on_off(on)),
+            Command::Seq(on) => write!(f, "/seq {}", on_off(on)),
+            Command::Ack(seq) => write!(f, "/ack {seq}"),
+            Command::Echo(on) => write!(f, "/echo {}", on_off(on)),
+            Command::Bell(on) => write!(f, "/bell {}", on_off(on)),
+            Command::Topic(text) => f.write_str(&optional("/topic", text)),
+            Command::Ignore(nickname) => f.write_str(&optional("/ignore", nickname)),
+            Command::Unignore(nickname) => write!(f, "/unignore {nickname}"),
+            Command::Last(Some(count)) => write!(f, "/last {count}"),
+            Command::Last(None) => f.write_str("/last"),
+            Command::Search(term) => write!(f, "/search {term}"),
+            Command::Stats => f.write_str("/stats"),
+            Command::Quit(message) => f.write_str(&optional("/quit", message)),
+            Command::Away(reason) => f.write_str(&optional("/away", reason)),
+            Command::Back => f.write_str("/back"),
+            Command::Protocol { json: true } => f.write_str("/protocol json"),
+            Command::Protocol { json: false } => f.write_str("/protocol plain"),
+            Command::Help => f.write_str("/help"),
+            Command::Usage(usage) => f.write_str(usage),
+            Command::Unknown(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A command of [`COMMANDS`]
+pub(crate) struct CommandSpec {
+    /// e.g. `/msg`
+    pub(crate) name: &'static str,
+    /// the name and the arguments, e.g. `/msg <nick> <text>`
+    pub(crate) usage: &'static str,
+    pub(crate) description: &'static str,
+    /// the command of the arguments, `None` if they are invalid
+    parse: for<'a> fn(&'a str) -> Option<Command<'a>>,
+}
+
+/// The optional argument `args`, `None` if empty
+fn optional(args: &str) -> Option<&str> {
+    Some(args.trim_end()).filter(|args| !args.is_empty())
+}
+
+/// `on` or `off`
+fn on_off(args: &str) -> Option<bool> {
+    match args.trim_end() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// The commands understood by [`Command::parse`], in the order of `/help`
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "/help",
+        usage: "/help",
+        description: "list the commands",
+        parse: |_| Some(Command::Help),
+    },
+    CommandSpec {
+        name: "/msg",
+        usage: "/msg <nick> <text>",
+        description: "send a private message",
+        parse: |args| match args.split_once(char::is_whitespace) {
+            Some((to, text)) if !text.trim().is_empty() => Some(Command::Private {
+                to,
+                text: text.trim(),
+            }),
+            _ => None,
+        },
+    },
+    CommandSpec {
+        name: "/me",
+        usage: "/me <action>",
+        description: "describe what you are doing",
+        parse: |args| optional(args).map(Command::Emote),
+    },
+    CommandSpec {
+        name: "/paste",
+        usage: "/paste",
+        description: "send the next lines as a single message, up to a line holding a single .",
+        parse: |_| Some(Command::Paste),
+    },
+    CommandSpec {
+        name: "/who",
+        usage: "/who",
+        description: "list the users of the room",
+        parse: |_| Some(Command::Who),
+    },
+    CommandSpec {
+        name: "/whois",
+        usage: "/whois <nick>",
+        description: "show since when a user is connected and idle",
+        parse: |args| optional(args).map(Command::Whois),
+    },
+    CommandSpec {
+        name: "/join",
+        usage: "/join <room>",
+        description: "move to another room",
+        parse: |args| {
+            let room = args.trim_end();
+            optional(room.strip_prefix('#').unwrap_or(room)).map(Command::Join)
+        },
+    },
+    CommandSpec {
+        name: "/leave",
+        usage: "/leave",
+        description: "go back to the lobby",
+        parse: |_| Some(Command::Leave),
+    },
+    CommandSpec {
+        name: "/rooms",
+        usage: "/rooms",
+        description: "list the rooms",
+        parse: |_| Some(Command::Rooms),
+    },
+    CommandSpec {
+        name: "/invite",
+        usage: "/invite <nick>",
+        description: "allow a user to join the room once, for operators",
+        parse: |args| optional(args).map(Command::Invite),
+    },
+    CommandSpec {
+        name: "/mode",
+        usage: "/mode #room +i|-i|limit <count>|-l",
+        description: "make a room invite-only or limit its users, for operators",
+        parse: |args| {
+            let args = args.split_whitespace().collect::<Vec<_>>();
+            let mode = match args.as_slice() {
+                [_, "+i"] => RoomMode::InviteOnly(true),
+                [_, "-i"] => RoomMode::InviteOnly(false),
+                [_, "limit", limit] => match limit.parse() {
+                    Ok(limit) if limit > 0 => RoomMode::Limit(Some(limit)),
+                    _ => return None,
+                },
+                [_, "-l"] => RoomMode::Limit(None),
+                _ => return None,
+            };
+            Some(Command::Mode {
+                room: args[0].strip_prefix('#').unwrap_or(args[0]),
+                mode,
+            })
+        },
+    },
+    CommandSpec {
+        name: "/kick",
+        usage: "/kick <nick> [reason]",
+        description: "disconnect a user, for operators",
+        parse: |args| {
+            let (target, reason) = match args.split_once(char::is_whitespace) {
+                Some((target, reason)) => (target, optional(reason.trim())),
+                None => (args.trim_end(), None),
+            };
+            optional(target).map(|target| Command::Kick { target, reason })
+        },
+    },
+    CommandSpec {
+        name: "/ban",
+        usage: "/ban <nick>",
+        description: "disconnect a user and ban its address, for operators",
+        parse: |args| optional(args).map(Command::Ban),
+    },
+    CommandSpec {
+        name: "/unban",
+        usage: "/unban <ip>",
+        description: "lift a ban, for operators",
+        parse: |args| args.trim_end().parse().ok().map(Command::Unban),
+    },
+    CommandSpec {
+        name: "/mute",
+        usage: "/mute <nick> <duration>",
+        description: "drop the messages of a user for a while, e.g. 10m, for operators",
+        parse: |args| {
+            let (target, duration) = args.split_once(char::is_whitespace)?;
+            let duration = parse_mute_duration(duration).ok()?;
+            Some(Command::Mute { target, duration })
+        },
+    },
+    CommandSpec {
+        name: "/unmute",
+        usage: "/unmute <nick>",
+        description: "end a mute early, for operators",
+        parse: |args| optional(args).map(Command::Unmute),
+    },
+    CommandSpec {
+        name: "/lockdown",
+        usage: "/lockdown on|off",
+        description: "make the rooms read-only but for the operators, for operators",
+        parse: |args| on_off(args).map(Command::Lockdown),
+    },
+    CommandSpec {
+        name: "/op",
+        usage: "/op <nick>",
+        description: "make a user an operator, for operators",
+        parse: |args| optional(args).map(Command::Op),
+    },
+    CommandSpec {
+        name: "/deop",
+        usage: "/deop <nick>",
+        description: "take the operator status of a user away, for operators",
+        parse: |args| optional(args).map(Command::Deop),
+    },
+    CommandSpec {
+        name: "/nick",
+        usage: "/nick <newname>",
+        description: "change your nickname",
+        parse: |args| optional(args).map(Command::Nick),
+    },
+    CommandSpec {
+        name: "/timestamps",
+        usage: "/timestamps on|off",
+        description: "prefix the lines with their time",
+        parse: |args| on_off(args).map(Command::Timestamps),
+    },
+    CommandSpec {
+        name: "/color",
+        usage: "/color on|off",
+        description: "color the lines",
+        parse: |args| on_off(args).map(Command::Color),
+    },
+    CommandSpec {
+        name: "/seq",
+        usage: "/seq on|off",
+        description: "prefix the lines with their number",
+        parse: |args| on_off(args).map(Command::Seq),
+    },
+    CommandSpec {
+        name: "/ack",
+        usage: "/ack <seq>",
+        description: "tell the number of the last line you processed",
+        parse: |args| args.trim_end().parse().ok().map(Command::Ack),
+    },
+    CommandSpec {
+        name: "/topic",
+        usage: "/topic [text]",
+        description: "show the topic of the room, or set it",
+        parse: |args| Some(Command::Topic(optional(args))),
+    },
+    CommandSpec {
+        name: "/ignore",
+        usage: "/ignore [nick]",
+        description: "stop receiving the messages of a user, or list the ignored users",
+        parse: |args| Some(Command::Ignore(optional(args))),
+    },
+    CommandSpec {
+        name: "/unignore",
+        usage: "/unignore <nick>",
+        description: "receive the messages of an ignored user again",
+        parse: |args| optional(args).map(Command::Unignore),
+    },
+    CommandSpec {
+        name: "/quit",
+        usage: "/quit [message]",
+        description: "leave the chatroom",
+        parse: |args| Some(Command::Quit(optional(args))),
+    },
+    CommandSpec {
+        name: "/away",
+        usage: "/away [reason]",
+        description: "mark yourself away, or back",
+        parse: |args| Some(Command::Away(optional(args))),
+    },
+    CommandSpec {
+        name: "/back",
+        usage: "/back",
+        description: "clear your away status",
+        parse: |_| Some(Command::Back),
+    },
+    CommandSpec {
+        name: "/last",
+        usage: "/last [count]",
+        description: "show the last messages of the room",
+        parse: |args| match optional(args) {
+            None => Some(Command::Last(None)),
+            Some(count) => match count.parse() {
+                Ok(count) if count > 0 => Some(Command::Last(Some(count))),
+                _ => None,
+            },
+        },
+    },
+    CommandSpec {
+        name: "/search",
+        usage: "/search <term>",
+        description: "show the last messages of the room containing a term",
+        parse: |args| optional(args).map(Command::Search),
+    },
+    CommandSpec {
+        name: "/stats",
+        usage: "/stats",
+        description: "show the activity of the chatroom and of your session",
+        parse: |_| Some(Command::Stats),
+    },
+    CommandSpec {
+        name: "/bell",
+        usage: "/bell on|off",
+        description: "ring your terminal bell on the messages naming you",
+        parse: |args| on_off(args).map(Command::Bell),
+    },
+    CommandSpec {
+        name: "/protocol",
+        usage: "/protocol json|plain",
+        description: "receive the events as JSON objects or as lines, as the first line",
+        parse: |args| match args.trim_end() {
+            "json" => Some(Command::Protocol { json: true }),
+            "plain" => Some(Command::Protocol { json: false }),
+            _ => None,
+        },
+    },
+    CommandSpec {
+        name: "/echo",
+        usage: "/echo on|off",
+        description: "receive your own messages",
+        parse: |args| on_off(args).map(Command::Echo),
+    },
+];
+
+/// The arguments of `line` if it is the command `name`
+fn command_args<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let args = line.strip_prefix(name)?;
+    (args.is_empty() || args.starts_with(char::is_whitespace)).then(|| args.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Command, COMMANDS};
+
+    /// A line, mostly a command of [`COMMANDS`] with arguments of the kinds they take
+    fn line() -> impl Strategy<Value = String> {
+        let names = COMMANDS.iter().map(|spec| spec.name).collect::<Vec<_>>();
+        let arg = prop_oneof![
+            "[a-z#/+-]{0,6}",
+            "on|off|json|plain|\\+i|-i|-l|limit",
+            "[0-9]{1,3}[smhd]?",
+            "127\\.0\\.0\\.1|::1",
+            "#{0,2}[a-z]{0,3}",
+            "\\PC{0,4}",
+        ];
+        let args = prop::collection::vec(arg, 0..4);
+        let spaces = "[ \t]{0,2}";
+        prop_oneof![
+            1 => any::<String>(),
+            4 => (prop::sample::select(names), args, spaces).prop_map(|(name, args, spaces)| {
+                format!("{name}{spaces} {}{spaces}", args.join(" "))
+            }),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn parsed_commands_are_written_back(line in line()) {
+            let command = Command::parse(line.trim());
+            if !matches!(command, Command::Usage(_)) {
+                let written = command.to_string();
+                prop_assert_eq!(Command::parse(&written), command, "written as {:?}", written);
+            }
+        }
+    }
+}
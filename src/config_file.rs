@@ -0,0 +1,145 @@
+//! The TOML files of the server, e.g. the `--config` file and the templates
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+use toml::Spanned;
+
+/// A value of a configuration file
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    /// an offset date-time, a local date-time, date or time, in the RFC 3339 syntax
+    Datetime(String),
+    Array(Vec<ConfigValue>),
+    /// the entries of a table, sorted by key
+    Table(Vec<(String, ConfigValue)>),
+}
+
+impl Display for ConfigValue {
+    /// Write the value in the TOML syntax
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::String(text) => {
+                f.write_str("\"")?;
+                for c in text.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\r' => f.write_str("\\r")?,
+                        c if c.is_control() => write!(f, "\\u{:04X}", c as u32)?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            ConfigValue::Integer(value) => write!(f, "{value}"),
+            ConfigValue::Float(value) => write!(f, "{}", toml::Value::Float(*value)),
+            ConfigValue::Boolean(value) => write!(f, "{value}"),
+            ConfigValue::Datetime(datetime) => f.write_str(datetime),
+            ConfigValue::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            ConfigValue::Table(entries) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, " {} = {value}", toml::Value::String(key.clone()))?;
+                }
+                f.write_str(" }")
+            }
+        }
+    }
+}
+
+/// A `key = value` entry of a configuration file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigEntry {
+    /// number of the line of the key, from 1
+    pub line: usize,
+    pub key: String,
+    pub value: ConfigValue,
+}
+
+/// A configuration file that cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// number of the line, from 1
+    pub line: usize,
+    /// the key of the line, if it could be parsed
+    pub key: Option<String>,
+    pub reason: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "line {}, {key}: {}", self.line, self.reason),
+            None => write!(f, "line {}: {}", self.line, self.reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parse the top-level entries of a TOML file, in their order. The options are not
+/// grouped: a table is an entry holding [`ConfigValue::Table`].
+pub fn parse_config(toml: &str) -> Result<Vec<ConfigEntry>, ConfigError> {
+    let line = |offset: usize| toml[..offset].matches('\n').count() + 1;
+    let document: BTreeMap<Spanned<String>, Spanned<toml::Value>> =
+        toml::from_str(toml).map_err(|e| ConfigError {
+            line: e.span().map_or(1, |span| line(span.start)),
+            key: None,
+            reason: e.message().to_string(),
+        })?;
+    let mut entries: Vec<_> = document
+        .into_iter()
+        .map(|(key, value)| (key.span().start, key.into_inner(), value.into_inner()))
+        .collect();
+    entries.sort_by_key(|(offset, ..)| *offset);
+    Ok(entries
+        .into_iter()
+        .map(|(offset, key, value)| ConfigEntry {
+            line: line(offset),
+            key,
+            value: value.into(),
+        })
+        .collect())
+}
+
+impl From<toml::Value> for ConfigValue {
+    fn from(value: toml::Value) -> Self {
+        match value {
+            toml::Value::String(text) => ConfigValue::String(text),
+            toml::Value::Integer(value) => ConfigValue::Integer(value),
+            toml::Value::Float(value) => ConfigValue::Float(value),
+            toml::Value::Boolean(value) => ConfigValue::Boolean(value),
+            toml::Value::Datetime(datetime) => ConfigValue::Datetime(datetime.to_string()),
+            toml::Value::Array(values) => {
+                ConfigValue::Array(values.into_iter().map(Into::into).collect())
+            }
+            toml::Value::Table(table) => ConfigValue::Table(
+                table
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Queuing of the messages for their recipients, on the sending thread or on a pool
+//! of worker threads
+
+use std::{
+    collections::VecDeque,
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::{
+    chatroom::{Envelope, Message, SessionId, SlowClientPolicy},
+    metrics::Metrics,
+    sync::Mutex,
+};
+
+/// The queue of the messages for a session: its channel, in front of which the
+/// chatroom keeps the messages waiting while the channel is full when the policy
+/// drops messages rather than disconnecting. The messages are numbered as they are
+/// queued, a gap tells of the dropped ones.
+#[derive(Clone)]
+pub(crate) struct Queue {
+    sender: Sender<Envelope>,
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    policy: SlowClientPolicy,
+    metrics: Arc<Metrics>,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// the number of the last message queued
+    seq: u64,
+    /// the number of the last message the client processed, see [`Queue::ack`]
+    acked: Option<u64>,
+    /// the messages waiting for room in the channel, as many as it holds at most
+    pending: VecDeque<Envelope>,
+    /// the messages dropped since the last notice
+    dropped: u64,
+}
+
+/// What became of a message pushed to a [`Queue`]
+pub(crate) enum Pushed {
+    Queued,
+    Dropped,
+    Full,
+    Closed,
+}
+
+impl Queue {
+    pub(crate) fn new(
+        sender: Sender<Envelope>,
+        policy: SlowClientPolicy,
+        metrics: &Arc<Metrics>,
+    ) -> Self {
+        let inner = QueueInner {
+            policy,
+            metrics: metrics.clone(),
+            state: Mutex::default(),
+        };
+        Self {
+            sender,
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// A queue for `sender` outside of the chatroom, e.g. for the replies to an
+    /// evicted session
+    pub(crate) fn standalone(sender: Sender<Envelope>) -> Self {
+        Self::new(sender, SlowClientPolicy::Disconnect, &Arc::default())
+    }
+
+    pub(crate) fn sender(&self) -> &Sender<Envelope> {
+        &self.sender
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Number and queue `envelope`, behind the messages waiting while the channel was
+    /// full. With a full channel, the policy tells whether the message waits, is
+    /// dropped, or the session is a slow consumer.
+    pub(crate) fn push(&self, mut envelope: Envelope) -> Pushed {
+        // numbered and sent under the lock: the numbers of the channel are in order
+        let mut state = self.inner.state.lock();
+        if self.sender.is_closed() {
+            return Pushed::Closed;
+        }
+        state.seq += 1;
+        envelope.seq = Some(state.seq);
+        if state.acked.is_some() {
+            Metrics::add(&self.inner.metrics.client_lag, 1);
+        }
+        let envelope = if self.flush_locked(&mut state) {
+            match self.sender.try_send(envelope) {
+                Ok(()) => return Pushed::Queued,
+                Err(TrySendError::Full(envelope)) => envelope,
+                Err(TrySendError::Closed(_)) => return Pushed::Closed,
+            }
+        } else {
+            envelope
+        };
+        let metrics = &self.inner.metrics;
+        match self.inner.policy {
+            SlowClientPolicy::Disconnect => Pushed::Full,
+            SlowClientPolicy::DropNewest => {
+                Metrics::add(&metrics.newest_drops, 1);
+                state.dropped += 1;
+                Pushed::Dropped
+            }
+            SlowClientPolicy::DropOldest => {
+                state.pending.push_back(envelope);
+                if state.pending.len() > self.sender.max_capacity() {
+                    Metrics::add(&metrics.oldest_drops, 1);
+                    state.pending.pop_front();
+                    state.dropped += 1;
+                }
+                Pushed::Queued
+            }
+        }
+    }
+
+    /// Record that the client processed the messages up to `seq`. Returns the number
+    /// of the last message queued if `seq` is beyond.
+    pub(crate) fn ack(&self, seq: u64) -> Result<(), u64> {
+        let mut state = self.inner.state.lock();
+        if seq > state.seq {
+            return Err(state.seq);
+        }
+        let metrics = &self.inner.metrics;
+        match state.acked.replace(seq) {
+            None => {
+                Metrics::add(&metrics.acking_clients, 1);
+                Metrics::add(&metrics.client_lag, state.seq - seq);
+            }
+            Some(acked) if acked < seq => Metrics::sub(&metrics.client_lag, seq - acked),
+            Some(acked) => Metrics::add(&metrics.client_lag, acked - seq),
+        }
+        Ok(())
+    }
+
+    /// The number of messages queued since the last one the client processed, `None`
+    /// if it never told with [`Queue::ack`]
+    pub(crate) fn lag(&self) -> Option<u64> {
+        let state = self.inner.state.lock();
+        state.acked.map(|acked| state.seq - acked)
+    }
+
+    /// Whether messages are waiting for room in the channel, or a notice of the
+    /// dropped ones
+    pub(crate) fn is_overflowing(&self) -> bool {
+        let state = self.inner.state.lock();
+        !state.pending.is_empty() || state.dropped > 0
+    }
+
+    /// Move the messages waiting while the channel was full to the channel, as many as
+    /// it has room for
+    pub(crate) fn flush(&self) {
+        self.flush_locked(&mut self.inner.state.lock());
+    }
+
+    /// Returns whether the channel has room left. The notice of the dropped messages
+    /// comes first, without number: they were the oldest waiting, or newer than those
+    /// of the channel.
+    fn flush_locked(&self, state: &mut QueueState) -> bool {
+        if state.dropped > 0 {
+            let notice = format!("some messages were dropped ({})", state.dropped);
+            match self.sender.try_send(Message::Notice(notice).into()) {
+                Ok(()) => state.dropped = 0,
+                Err(_) => return false,
+            }
+        }
+        while let Some(envelope) = state.pending.pop_front() {
+            if let Err(e) = self.sender.try_send(envelope) {
+                if let TrySendError::Full(envelope) = e {
+                    state.pending.push_front(envelope);
+                }
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Drop for QueueInner {
+    /// Take the lag of the client out of the metrics once its session is gone
+    fn drop(&mut self) {
+        let state = self.state.lock();
+        if let Some(acked) = state.acked {
+            Metrics::sub(&self.metrics.acking_clients, 1);
+            Metrics::sub(&self.metrics.client_lag, state.seq - acked);
+        }
+    }
+}
+
+/// A message and the queues of its recipients, snapshot under the lock of the users
+pub(crate) struct Fanout {
+    pub(crate) recipients: Vec<(SessionId, Queue)>,
+    pub(crate) envelope: Envelope,
+}
+
+impl Fanout {
+    /// Queue the message, returns how many users it was queued for and the ones whose
+    /// queue is full with [`SlowClientPolicy::Disconnect`].
+    ///
+    /// The users who left since the snapshot have closed their queue, or their full
+    /// queue is ignored when evicting the slow consumers.
+    pub(crate) fn send(self) -> (usize, Vec<SessionId>) {
+        let mut delivered = 0;
+        let mut full = Vec::new();
+        for (id, queue) in self.recipients {
+            match queue.push(self.envelope.clone()) {
+                Pushed::Queued => delivered += 1,
+                Pushed::Full => full.push(id),
+                Pushed::Dropped | Pushed::Closed => {}
+            }
+        }
+        (delivered, full)
+    }
+}
+
+/// Threads queuing the messages for their recipients.
+///
+/// Every session is served by the same worker: the messages handed to the workers in
+/// order are received in that order.
+pub(crate) struct Workers {
+    queues: Vec<mpsc::Sender<Fanout>>,
+}
+
+impl Workers {
+    /// Start `count` workers, `on_full` is called with the recipients whose queue was
+    /// found full. The workers stop once dropped.
+    pub(crate) fn start(
+        count: usize,
+        on_full: impl Fn(Vec<SessionId>) + Send + Sync + 'static,
+    ) -> Self {
+        let on_full = Arc::new(on_full);
+        let queues = (0..count.max(1))
+            .map(|i| {
+                let (queue, fanouts) = mpsc::channel::<Fanout>();
+                let on_full = on_full.clone();
+                thread::Builder::new()
+                    .name(format!("fanout-{i}"))
+                    .spawn(move || {
+                        for fanout in fanouts {
+                            let (_, full) = fanout.send();
+                            if !full.is_empty() {
+                                on_full(full);
+                            }
+                        }
+                    })
+                    .expect("cannot start a fan-out worker");
+                queue
+            })
+            .collect();
+        Self { queues }
+    }
+
+    /// Hand `fanout` to the workers of its recipients, returns how many recipients did
+    /// not close their queue.
+    pub(crate) fn dispatch(&self, fanout: Fanout) -> usize {
+        let mut shares: Vec<Vec<_>> = self.queues.iter().map(|_| Vec::new()).collect();
+        let mut recipients = 0;
+        for (id, queue) in fanout.recipients {
+            if !queue.is_closed() {
+                shares[id.0 as usize % self.queues.len()].push((id, queue));
+                recipients += 1;
+            }
+        }
+        for (queue, share) in self.queues.iter().zip(shares) {
+            if !share.is_empty() {
+                let _ = queue.send(Fanout {
+                    recipients: share,
+                    envelope: fanout.envelope.clone(),
+                });
+            }
+        }
+        recipients
+    }
+}
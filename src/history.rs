@@ -0,0 +1,119 @@
+//! The last messages of the rooms, replayed to the joining users
+
+use std::{collections::VecDeque, time::SystemTime};
+
+use crate::chatroom::{Envelope, Message};
+
+/// Limits of the history kept by the chatroom
+#[derive(Clone)]
+pub struct HistoryConfig {
+    /// maximum number of messages kept, 0 disables the history
+    pub messages: usize,
+    /// maximum total size of the messages kept, as written to the clients
+    pub bytes: usize,
+    /// whether the Joined and Left notices are kept too
+    pub notices: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            messages: 50,
+            bytes: 64 * 1024,
+            notices: false,
+        }
+    }
+}
+
+struct Entry {
+    /// `None` for the messages to every room
+    room: Option<String>,
+    at: SystemTime,
+    message: Message,
+    bytes: usize,
+}
+
+/// Ring buffer of the messages broadcast to the rooms, bounded in count and bytes
+#[derive(Default)]
+pub(crate) struct History {
+    config: HistoryConfig,
+    entries: VecDeque<Entry>,
+    bytes: usize,
+}
+
+impl History {
+    pub(crate) fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Keep `message` if it belongs in the history of `room`, of every room if `None`
+    pub(crate) fn record(&mut self, room: Option<&str>, message: &Message) {
+        let kept = match message {
+            Message::Message { .. }
+            | Message::Emote { .. }
+            | Message::Paste { .. }
+            | Message::ServerNotice(_) => true,
+            Message::Joined { .. } | Message::Left { .. } => self.config.notices,
+            _ => false,
+        };
+        let bytes = message.to_string().len();
+        if !kept || self.config.messages == 0 || bytes > self.config.bytes {
+            return;
+        }
+        while self.entries.len() >= self.config.messages || self.bytes + bytes > self.config.bytes {
+            let Some(oldest) = self.entries.pop_front() else {
+                break;
+            };
+            self.bytes -= oldest.bytes;
+        }
+        self.bytes += bytes;
+        self.entries.push_back(Entry {
+            room: room.map(str::to_string),
+            at: SystemTime::now(),
+            message: message.clone(),
+            bytes,
+        });
+    }
+
+    /// The messages kept for `room`, oldest first
+    pub(crate) fn replay<'a>(&'a self, room: &'a str) -> impl Iterator<Item = Envelope> + 'a {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.is_for(room))
+            .map(Entry::to_envelope)
+    }
+
+    /// The last `count` messages kept for `room`, oldest first
+    pub(crate) fn last(&self, room: &str, count: usize) -> Vec<Envelope> {
+        let mut last: Vec<_> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.is_for(room))
+            .take(count)
+            .map(Entry::to_envelope)
+            .collect();
+        last.reverse();
+        last
+    }
+}
+
+impl Entry {
+    fn is_for(&self, room: &str) -> bool {
+        self.room
+            .as_ref()
+            .is_none_or(|entry_room| entry_room == room)
+    }
+
+    fn to_envelope(&self) -> Envelope {
+        Envelope {
+            at: self.at,
+            message: Message::History(Box::new(self.message.clone())),
+            bell: false,
+            seq: None,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Hooks filtering or rewriting the chat messages, see
+//! [`Chatroom::add_inbound_hook`](crate::Chatroom::add_inbound_hook)
+
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::chatroom::Message;
+
+/// What a hook does with a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HookResult {
+    /// hand the message unchanged to the next hook
+    Pass,
+    /// drop the message, the sender is sent the notice if any.
+    /// [`Session::send_message`](crate::Session::send_message) returns
+    /// [`SendError::Dropped`](crate::SendError::Dropped).
+    Drop(Option<String>),
+    /// hand this message to the next hook instead
+    Replace(Message),
+}
+
+type Hook = Arc<dyn Fn(&Message) -> HookResult + Send + Sync>;
+
+/// Hooks, called in the order they were added
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: RwLock<Vec<Hook>>,
+}
+
+impl Hooks {
+    pub(crate) fn add(&self, hook: impl Fn(&Message) -> HookResult + Send + Sync + 'static) {
+        self.hooks.write().push(Arc::new(hook));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.read().is_empty()
+    }
+
+    /// Run the hooks on `message`, returns the message to send or the notice of the
+    /// dropped message
+    pub(crate) fn run(&self, mut message: Message) -> Result<Message, Option<String>> {
+        // not locked while called: a hook may add hooks
+        let hooks = self.hooks.read().clone();
+        for hook in hooks {
+            match hook(&message) {
+                HookResult::Pass => {}
+                HookResult::Drop(notice) => return Err(notice),
+                HookResult::Replace(replacement) => message = replacement,
+            }
+        }
+        Ok(message)
+    }
+}
@@ -0,0 +1,130 @@
+//! The JSON line protocol: the events written as JSON objects, one per line, and
+//! the JSON commands of the clients
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use crate::{
+    chatroom::{Envelope, Message, Timestamps},
+    command::Command,
+};
+
+/// The event as a JSON object, its `type` names the kind of event
+pub(crate) fn to_json(message: &Message) -> Value {
+    match message {
+        Message::Joined { nickname, users } => {
+            let mut json = json!({ "type": "joined", "nick": nickname });
+            if let Some(users) = users {
+                json["count"] = (*users).into();
+            }
+            json
+        }
+        Message::Left {
+            nickname,
+            online,
+            reason,
+        } => {
+            let mut json = json!({ "type": "left", "nick": nickname });
+            if let Some(online) = online {
+                json["online"] = online.as_secs().into();
+            }
+            if let Some(reason) = reason {
+                json["reason"] = reason.as_str().into();
+            }
+            json
+        }
+        Message::ConnectedUsers(users) => json!({ "type": "users", "users": users }),
+        Message::Message { from, text } => {
+            json!({ "type": "message", "from": &**from, "text": &**text })
+        }
+        Message::Mentioned { from, text, .. } => {
+            json!({ "type": "message", "from": &**from, "text": &**text, "mentioned": true })
+        }
+        Message::Emote { from, action } => {
+            json!({ "type": "emote", "from": &**from, "text": &**action })
+        }
+        Message::Paste { from, lines } => {
+            json!({ "type": "paste", "from": &**from, "lines": &**lines })
+        }
+        Message::Private { from, text } => json!({ "type": "private", "from": from, "text": text }),
+        Message::PrivateSent { to, text } => {
+            json!({ "type": "private_sent", "to": to, "text": text })
+        }
+        Message::UserList(users) => json!({ "type": "who", "users": users }),
+        Message::RoomList(rooms) => {
+            let rooms: Vec<_> = rooms
+                .iter()
+                .map(|room| {
+                    let mut object = json!({ "name": room.name, "users": room.users });
+                    if room.invite_only {
+                        object["invite_only"] = true.into();
+                    }
+                    if let Some(limit) = room.limit {
+                        object["limit"] = limit.into();
+                    }
+                    object
+                })
+                .collect();
+            json!({ "type": "rooms", "rooms": rooms })
+        }
+        Message::Notice(text) => json!({ "type": "notice", "text": text }),
+        Message::ServerNotice(text) => json!({ "type": "server_notice", "text": text }),
+        Message::Motd(text) => json!({ "type": "motd", "text": text }),
+        Message::Kicked { nickname, by } => json!({ "type": "kicked", "nick": nickname, "by": by }),
+        Message::Banned { nickname, by } => json!({ "type": "banned", "nick": nickname, "by": by }),
+        Message::Renamed { old, new } => json!({ "type": "renamed", "old": old, "new": new }),
+        Message::Topic { text, set_by } => json!({ "type": "topic", "text": text, "by": set_by }),
+        Message::History(message) => json!({ "type": "history", "message": to_json(message) }),
+    }
+}
+
+/// The line written to a JSON client, without its line feed. The event has a
+/// RFC 3339 `time` when `timestamps` is set, and the `seq` of the envelope.
+pub(crate) fn render(envelope: &Envelope, timestamps: Option<Timestamps>) -> String {
+    let mut json = to_json(&envelope.message);
+    let time = match timestamps {
+        None => None,
+        Some(Timestamps::Local) => {
+            Some(DateTime::<Local>::from(envelope.at).to_rfc3339_opts(SecondsFormat::Secs, false))
+        }
+        Some(Timestamps::Utc) => {
+            Some(DateTime::<Utc>::from(envelope.at).to_rfc3339_opts(SecondsFormat::Secs, true))
+        }
+    };
+    if let Some(time) = time {
+        json["time"] = time.into();
+    }
+    if let Some(seq) = envelope.seq {
+        json["seq"] = seq.into();
+    }
+    json.to_string()
+}
+
+/// A line written to a JSON client before it joined, e.g. the nickname prompt
+pub(crate) fn handshake_line(kind: &str, text: &str) -> String {
+    json!({ "type": kind, "text": text }).to_string()
+}
+
+/// The command of a JSON object sent by a client: `{"type":"message","text":"hi"}`,
+/// an `emote` with its `text`, or a `private` message with `to` and `text`
+pub(crate) fn parse_command(json: &Value) -> Result<Command<'_>, String> {
+    let field = |name: &str| {
+        json[name]
+            .as_str()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("invalid command: expected a {name} string"))
+    };
+    match json["type"].as_str() {
+        Some("message") => Ok(Command::Message(field("text")?)),
+        Some("emote") => Ok(Command::Emote(field("text")?)),
+        Some("private") => Ok(Command::Private {
+            to: field("to")?,
+            text: field("text")?,
+        }),
+        Some(kind) => Err(format!(
+            "invalid command: unknown type {kind}, expected message, emote or private"
+        )),
+        None => Err("invalid command: expected a type string".to_string()),
+    }
+}
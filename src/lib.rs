@@ -0,0 +1,70 @@
+//! A tiny chat room server, implementing the protohackers "budget chat" protocol.
+//!
+//! The [`Chatroom`] can be driven directly from your own code by joining it with
+//! any tokio `Sender<Envelope>`, or served over TCP with [`server::run_server`]
+//! from within a tokio runtime.
+
+// the servers need `tokio::net`, which tokio leaves out with `--cfg loom`
+#![cfg_attr(loom, allow(dead_code))]
+
+mod accounts;
+mod archive;
+mod bans;
+mod chat_log;
+mod chatroom;
+mod color;
+mod command;
+mod config_file;
+mod fanout;
+mod history;
+mod hooks;
+mod json;
+mod lines;
+#[cfg(not(loom))]
+pub mod loadtest;
+mod metrics;
+mod mutes;
+mod operators;
+mod proxy;
+mod rate_limit;
+mod sanitize;
+#[cfg(not(loom))]
+pub mod server;
+mod sync;
+#[cfg(not(loom))]
+pub mod systemd;
+mod templates;
+#[cfg(all(feature = "tls", not(loom)))]
+mod tls;
+mod unicode;
+mod webhook;
+#[cfg(not(loom))]
+mod websocket;
+#[cfg(feature = "serde")]
+mod wire;
+mod word_filter;
+
+pub use accounts::{hash_password, Accounts, PasswordParams};
+pub use archive::{read_archive, Archive, ArchivedMessage, Search};
+pub use bans::BanList;
+pub use chat_log::{read_chat_log, ChatLog, LogRotation, LogSync, LoggedEvent};
+pub use chatroom::{
+    validate_nickname, Chatroom, ChatroomConfig, Envelope, JoinError, KickError, Message,
+    NicknameError, NicknameRules, ObserverHandle, OnDuplicate, ResumeError, Resumed, RoomError,
+    RoomInfo, RuntimeConfig, SearchError, SendError, Session, SlowClientPolicy, Timestamps,
+    TopicEntry, TopicError, UserInfo,
+};
+pub use color::nickname_color;
+pub use config_file::{parse_config, ConfigEntry, ConfigError, ConfigValue};
+pub use history::HistoryConfig;
+pub use hooks::HookResult;
+pub use metrics::{ChatroomStats, JoinRejections, MetricsSnapshot, SessionStats, SlowClientDrops};
+pub use mutes::parse_mute_duration;
+pub use operators::OperatorList;
+pub use rate_limit::RateLimit;
+pub use sanitize::Sanitize;
+pub use templates::{MessageTemplates, TemplateError};
+#[cfg(all(feature = "tls", not(loom)))]
+pub use tls::TlsConfig;
+pub use webhook::{start_webhook, WebhookConfig, WebhookEvents};
+pub use word_filter::{WordFilter, WordFilterMode};
@@ -0,0 +1,216 @@
+//! Reading of the lines sent by the clients, with bounded memory usage
+//!
+//! The chat clients may be telnet clients: their line endings are `\r\n` and they
+//! negotiate options, Windows telnet right after connecting, see [`LineReader::telnet`].
+
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// A line read by [`LineReader::read_line`], without its line feed
+pub(crate) enum Line {
+    Complete(Vec<u8>),
+    /// the line is longer than the limit, holds its first bytes
+    TooLong(Vec<u8>),
+}
+
+pub(crate) struct LineReader<R> {
+    inner: BufReader<R>,
+    /// where the stream is in the telnet commands, `None` to read the bytes as they are
+    telnet: Option<Telnet>,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            inner: BufReader::new(reader),
+            telnet: None,
+        }
+    }
+
+    /// Read the lines of a telnet client: the telnet commands and the carriage returns
+    /// are dropped wherever they are in the stream, the lines hold the data bytes only
+    pub(crate) fn telnet(reader: R) -> Self {
+        Self {
+            inner: BufReader::new(reader),
+            telnet: Some(Telnet::Data),
+        }
+    }
+
+    /// The underlying reader, with the bytes buffered past the lines read
+    pub(crate) fn into_inner(self) -> BufReader<R> {
+        self.inner
+    }
+
+    /// Read the next line, `None` at the end of the stream.
+    ///
+    /// At most `max_len` bytes are buffered: the rest of a longer line is left
+    /// unread, see [`LineReader::skip_line`].
+    pub(crate) async fn read_line(&mut self, max_len: usize) -> io::Result<Option<Line>> {
+        if let Some(telnet) = self.telnet {
+            return self.read_telnet_line(telnet, max_len).await;
+        }
+        let mut line = Vec::new();
+        loop {
+            let buf = self.inner.fill_buf().await?;
+            if buf.is_empty() {
+                return Ok((!line.is_empty()).then_some(Line::Complete(line)));
+            }
+            let newline = buf.iter().position(|b| *b == b'\n');
+            let chunk = &buf[..newline.unwrap_or(buf.len())];
+            let room = max_len - line.len();
+            if chunk.len() > room {
+                line.extend_from_slice(&chunk[..room]);
+                self.inner.consume(room);
+                return Ok(Some(Line::TooLong(line)));
+            }
+            line.extend_from_slice(chunk);
+            let read = chunk.len();
+            match newline {
+                Some(_) => {
+                    self.inner.consume(read + 1);
+                    return Ok(Some(Line::Complete(line)));
+                }
+                None => self.inner.consume(read),
+            }
+        }
+    }
+
+    async fn read_telnet_line(
+        &mut self,
+        mut telnet: Telnet,
+        max_len: usize,
+    ) -> io::Result<Option<Line>> {
+        let mut line = Vec::new();
+        let line = loop {
+            let buf = self.inner.fill_buf().await?;
+            if buf.is_empty() {
+                break (!line.is_empty()).then_some(Line::Complete(line));
+            }
+            let mut read = 0;
+            let mut end = None;
+            for &byte in buf {
+                let before = telnet;
+                match telnet.decode(byte) {
+                    Some(b'\n') => {
+                        read += 1;
+                        end = Some(Line::Complete(std::mem::take(&mut line)));
+                        break;
+                    }
+                    // left unread, along with the telnet state before it
+                    Some(_) if line.len() == max_len => {
+                        telnet = before;
+                        end = Some(Line::TooLong(std::mem::take(&mut line)));
+                        break;
+                    }
+                    Some(data) => line.push(data),
+                    None => {}
+                }
+                read += 1;
+            }
+            self.inner.consume(read);
+            if end.is_some() {
+                break end;
+            }
+        };
+        self.telnet = Some(telnet);
+        Ok(line)
+    }
+
+    /// Discard everything up to the end of the current line
+    pub(crate) async fn skip_line(&mut self) -> io::Result<()> {
+        if let Some(mut telnet) = self.telnet {
+            loop {
+                let buf = self.inner.fill_buf().await?;
+                if buf.is_empty() {
+                    break;
+                }
+                let newline = buf
+                    .iter()
+                    .position(|byte| telnet.decode(*byte) == Some(b'\n'));
+                let read = newline.map_or(buf.len(), |newline| newline + 1);
+                self.inner.consume(read);
+                if newline.is_some() {
+                    break;
+                }
+            }
+            self.telnet = Some(telnet);
+            return Ok(());
+        }
+        loop {
+            let buf = self.inner.fill_buf().await?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(newline) => {
+                    self.inner.consume(newline + 1);
+                    return Ok(());
+                }
+                None => {
+                    let read = buf.len();
+                    self.inner.consume(read);
+                }
+            }
+        }
+    }
+}
+
+/// Drop a UTF-8 character cut at the end of a truncated line
+pub(crate) fn trim_partial_char(line: &mut Vec<u8>) {
+    if let Err(e) = std::str::from_utf8(line) {
+        if e.error_len().is_none() {
+            line.truncate(e.valid_up_to());
+        }
+    }
+}
+
+/// Interpret as command, the start of the telnet commands
+const IAC: u8 = 0xff;
+/// Subnegotiation begin, up to [`SE`]
+const SB: u8 = 0xfa;
+/// Subnegotiation end
+const SE: u8 = 0xf0;
+/// WILL, WONT, DO and DONT are followed by the option they negotiate
+const WILL: u8 = 0xfb;
+const DONT: u8 = 0xfe;
+
+/// Where a telnet stream is, between its data and its commands (RFC 854)
+#[derive(Clone, Copy)]
+enum Telnet {
+    Data,
+    /// after a carriage return, dropped along with a NUL following it
+    Return,
+    /// after an [`IAC`]
+    Command,
+    /// after WILL, WONT, DO or DONT, before their option
+    Option,
+    /// in a subnegotiation, after [`SB`]
+    Subnegotiation,
+    /// after an [`IAC`] in a subnegotiation
+    SubnegotiationCommand,
+}
+
+impl Telnet {
+    /// The data byte `byte` is, `None` if it is part of a command or a carriage return
+    fn decode(&mut self, byte: u8) -> Option<u8> {
+        let (next, data) = match (*self, byte) {
+            (Telnet::Return, b'\0') => (Telnet::Data, None),
+            (Telnet::Data | Telnet::Return, IAC) => (Telnet::Command, None),
+            (Telnet::Data | Telnet::Return, b'\r') => (Telnet::Return, None),
+            (Telnet::Data | Telnet::Return, _) => (Telnet::Data, Some(byte)),
+            // an escaped 255
+            (Telnet::Command, IAC) => (Telnet::Data, Some(IAC)),
+            (Telnet::Command, WILL..=DONT) => (Telnet::Option, None),
+            (Telnet::Command, SB) => (Telnet::Subnegotiation, None),
+            // the commands without arguments, e.g. NOP or Are You There
+            (Telnet::Command | Telnet::Option, _) => (Telnet::Data, None),
+            (Telnet::Subnegotiation, IAC) => (Telnet::SubnegotiationCommand, None),
+            (Telnet::Subnegotiation, _) => (Telnet::Subnegotiation, None),
+            (Telnet::SubnegotiationCommand, SE) => (Telnet::Data, None),
+            (Telnet::SubnegotiationCommand, _) => (Telnet::Subnegotiation, None),
+        };
+        *self = next;
+        data
+    }
+}
@@ -0,0 +1,261 @@
+//! A load test of a running server, joining it with many clients chatting at a given
+//! rate, see [`run_load_test`]
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task::JoinSet,
+    time::{self, timeout},
+};
+
+use crate::rate_limit::parse_period;
+
+/// How long the clients keep reading once they stopped sending, for the messages in
+/// flight
+const DRAIN: Duration = Duration::from_secs(2);
+/// How long a client may take to connect and join
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The words starting the messages of the clients, followed by their send time
+const PAYLOAD: &str = "loadtest ";
+
+/// What a load test does
+#[derive(Clone, Debug)]
+pub struct LoadTestConfig {
+    /// the address of the server
+    pub target: SocketAddr,
+    /// how many clients join, as `load0`, `load1`...
+    pub clients: usize,
+    /// how many messages each client sends per second, the clients are spread evenly
+    /// over the period
+    pub msgs_per_sec: f64,
+    /// how long the clients send messages
+    pub duration: Duration,
+}
+
+/// The outcome of a load test, its [`Display`] is a summary
+#[derive(Clone, Debug, Default)]
+pub struct LoadTestReport {
+    pub clients: usize,
+    /// clients that could not connect or join
+    pub connect_failures: usize,
+    /// clients whose connection ended before the end of the test
+    pub disconnects: usize,
+    /// messages sent by the clients
+    pub sent: u64,
+    /// messages of the clients received by the others
+    pub received: u64,
+    latencies: Histogram,
+}
+
+impl LoadTestReport {
+    /// The time from its sending to its receipt under which the `quantile` (e.g. 0.99)
+    /// of the messages were received, with a precision of about 6%. `None` if no
+    /// message was received.
+    pub fn latency(&self, quantile: f64) -> Option<Duration> {
+        self.latencies.quantile(quantile).map(Duration::from_micros)
+    }
+}
+
+impl Display for LoadTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "clients: {}", self.clients)?;
+        writeln!(f, "connect failures: {}", self.connect_failures)?;
+        writeln!(f, "disconnects: {}", self.disconnects)?;
+        writeln!(f, "messages sent: {}", self.sent)?;
+        writeln!(f, "messages received: {}", self.received)?;
+        for (name, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+            match self.latency(quantile) {
+                Some(latency) => writeln!(f, "{name} latency: {latency:?}")?,
+                None => writeln!(f, "{name} latency: -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the duration of a load test, a number of `ms`, `s` or `m`, e.g. `30s`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    parse_period(s.trim()).ok_or_else(|| format!("invalid duration {s:?}, expected e.g. 30s"))
+}
+
+/// Join the server of `config` with its clients, which send their messages at the
+/// requested rate and read every line they receive.
+///
+/// The messages hold the time they were sent, their latency is measured by the
+/// clients receiving them. The test is deterministic but for the timing of the
+/// server: the nicknames and the sending schedule only depend on `config`.
+pub async fn run_load_test(config: LoadTestConfig) -> LoadTestReport {
+    let started = Instant::now();
+    let deadline = started + config.duration;
+    let period = Duration::from_secs_f64(1.0 / config.msgs_per_sec.max(0.001));
+    let mut clients = JoinSet::new();
+    for index in 0..config.clients {
+        // the first messages are spread over the period
+        let offset = period.mul_f64(index as f64 / config.clients as f64);
+        let target = config.target;
+        clients.spawn(client(index, target, started, offset, period, deadline));
+    }
+    let mut report = LoadTestReport {
+        clients: config.clients,
+        ..Default::default()
+    };
+    while let Some(outcome) = clients.join_next().await {
+        match outcome {
+            Ok(Some(stats)) => {
+                report.disconnects += usize::from(stats.disconnected);
+                report.sent += stats.sent;
+                report.received += stats.received;
+                report.latencies.merge(&stats.latencies);
+            }
+            Ok(None) | Err(_) => report.connect_failures += 1,
+        }
+    }
+    report
+}
+
+/// What a client did
+struct ClientStats {
+    sent: u64,
+    received: u64,
+    latencies: Histogram,
+    disconnected: bool,
+}
+
+/// Join as `load<index>`, then send a message every `period` from `offset` until
+/// `deadline`. `None` if the client could not join.
+async fn client(
+    index: usize,
+    target: SocketAddr,
+    started: Instant,
+    offset: Duration,
+    period: Duration,
+    deadline: Instant,
+) -> Option<ClientStats> {
+    let join = async {
+        let stream = TcpStream::connect(target).await.ok()?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        lines.next_line().await.ok()??;
+        write
+            .write_all(format!("load{index}\n").as_bytes())
+            .await
+            .ok()?;
+        // the welcome notice, the server prompts again if the nickname is refused
+        let welcome = lines.next_line().await.ok()??;
+        welcome.starts_with('*').then_some((lines, write))
+    };
+    let (mut lines, mut write) = timeout(JOIN_TIMEOUT, join).await.ok()??;
+
+    let mut stats = ClientStats {
+        sent: 0,
+        received: 0,
+        latencies: Histogram::default(),
+        disconnected: false,
+    };
+    let mut ticks = time::interval_at((started + offset).into(), period);
+    let drained = time::sleep_until((deadline + DRAIN).into());
+    tokio::pin!(drained);
+    loop {
+        tokio::select! {
+            _ = ticks.tick(), if Instant::now() < deadline => {
+                let sent_at = started.elapsed().as_micros();
+                let message = format!("{PAYLOAD}{sent_at}\n");
+                if write.write_all(message.as_bytes()).await.is_err() {
+                    stats.disconnected = true;
+                    break;
+                }
+                stats.sent += 1;
+            }
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    stats.disconnected = true;
+                    break;
+                };
+                let sent_at = line
+                    .rsplit_once(PAYLOAD)
+                    .and_then(|(_, sent_at)| sent_at.parse::<u64>().ok());
+                if let Some(sent_at) = sent_at {
+                    stats.received += 1;
+                    let latency = started.elapsed().as_micros() as u64;
+                    stats.latencies.record(latency.saturating_sub(sent_at));
+                }
+            }
+            _ = &mut drained => break,
+        }
+    }
+    Some(stats)
+}
+
+/// Counts of values in buckets of about 6%: 16 buckets per power of two, so that the
+/// latencies of millions of messages fit in a few KiB
+#[derive(Clone, Debug)]
+struct Histogram {
+    counts: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; Self::bucket(u64::MAX) + 1],
+        }
+    }
+}
+
+impl Histogram {
+    const SUB_BUCKETS: u64 = 16;
+
+    /// The bucket of `value`: the values under 16 have their own buckets, then each
+    /// power of two is split in 16
+    fn bucket(value: u64) -> usize {
+        if value < Self::SUB_BUCKETS {
+            return value as usize;
+        }
+        let exponent = 63 - u64::from(value.leading_zeros());
+        let mantissa = (value >> (exponent - 4)) - Self::SUB_BUCKETS;
+        (Self::SUB_BUCKETS + (exponent - 4) * Self::SUB_BUCKETS + mantissa) as usize
+    }
+
+    /// The lowest value of `bucket`
+    fn lowest(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < Self::SUB_BUCKETS {
+            return bucket;
+        }
+        let exponent = (bucket - Self::SUB_BUCKETS) / Self::SUB_BUCKETS + 4;
+        let mantissa = (bucket - Self::SUB_BUCKETS) % Self::SUB_BUCKETS;
+        (Self::SUB_BUCKETS + mantissa) << (exponent - 4)
+    }
+
+    fn record(&mut self, value: u64) {
+        self.counts[Self::bucket(value)] += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+
+    /// The lowest value of the bucket holding the `quantile`
+    fn quantile(&self, quantile: f64) -> Option<u64> {
+        let total: u64 = self.counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((quantile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::lowest(bucket));
+            }
+        }
+        None
+    }
+}
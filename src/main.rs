@@ -1,81 +1,1089 @@
 use std::{
-    io::{BufRead, BufReader, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::mpsc::channel,
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs,
+    future::Future,
+    io::{self, BufRead},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
     thread,
+    time::Duration,
 };
 
-use clap::Parser;
+use budget_chat::{
+    hash_password,
+    loadtest::{parse_duration, run_load_test, LoadTestConfig},
+    parse_config, read_archive,
+    server::{
+        bind, bind_unix, serve, serve_events, serve_metrics, serve_unix, serve_websocket,
+        InvalidUtf8, ServerConfig,
+    },
+    start_webhook, systemd, Accounts, Archive, BanList, ChatLog, Chatroom, ChatroomConfig,
+    ConfigEntry, ConfigError, ConfigValue, HistoryConfig, LogRotation, LogSync, MessageTemplates,
+    NicknameRules, OnDuplicate, OperatorList, PasswordParams, RateLimit, RuntimeConfig, Sanitize,
+    SlowClientPolicy, WebhookConfig, WebhookEvents, WordFilter, WordFilterMode,
+};
+#[cfg(feature = "tls")]
+use budget_chat::{server::serve_tls, TlsConfig};
+use chrono::{DateTime, Utc};
+use clap::{
+    error::ErrorKind, parser::ValueSource, Arg, ArgAction, ArgMatches, Command, CommandFactory,
+    FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use tokio::{
+    net::TcpListener,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    sync::watch,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
 
-use crate::chatroom::Chatroom;
+/// The options of the server, from the command line and the `--config` file
+#[derive(Parser)]
+struct Config {
+    #[command(subcommand)]
+    tool: Option<Tool>,
+    /// TOML file of the options, e.g. max_users = 100, those given on the command line
+    /// take precedence. Re-read on SIGHUP for the templates, MOTD, rate limit, maximum
+    /// users and ban file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// print the effective options as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+    /// bind the service to this tcp port on all IPv4 interfaces, default 5555
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// bind the service to this address, e.g. 127.0.0.1:5555 or [::]:5555
+    #[arg(long, conflicts_with = "port")]
+    bind: Option<SocketAddr>,
+    /// how many nicknames a client may try before being disconnected
+    #[arg(long, default_value = "3")]
+    nickname_attempts: usize,
+    /// how many messages can be queued for a client before it is evicted as a slow consumer
+    #[arg(long, default_value = "256")]
+    client_queue: usize,
+    /// how many seconds connections are given to close when shutting down
+    #[arg(long, default_value = "5")]
+    shutdown_grace: u64,
+    /// longer chat lines are truncated
+    #[arg(long, default_value = "1024")]
+    max_line_bytes: usize,
+    /// clients sending a longer nickname are disconnected
+    #[arg(long, default_value = "32")]
+    max_nickname_bytes: usize,
+    /// what to do with lines that are not valid UTF-8: lossy, drop or disconnect
+    #[arg(long, default_value = "lossy")]
+    invalid_utf8: InvalidUtf8,
+    /// maximum length of the nicknames, in characters
+    #[arg(long, default_value = "16")]
+    nick_max_len: usize,
+    /// characters allowed in nicknames besides the ASCII alphanumerics, e.g. "_-"
+    #[arg(long, default_value = "")]
+    nick_allow_extra: String,
+    /// allow the letters and digits of every script in nicknames, refusing the
+    /// nicknames that look like a connected one
+    #[arg(long)]
+    unicode_nicks: bool,
+    /// how many seconds the nickname of a disconnected user is kept for its address,
+    /// 0 to release it at once
+    #[arg(long, default_value = "0")]
+    nick_hold: u64,
+    /// send the joining users a token with which they take over their session within
+    /// this many seconds after a disconnect, e.g. 60. 0 to send no tokens
+    #[arg(long, default_value = "0")]
+    resume_window: u64,
+    /// TOML file of the lines written to the clients, e.g. joined = "* {nick} is here"
+    #[arg(long)]
+    templates: Option<PathBuf>,
+    /// file holding the message of the day sent to the joining users, re-read on SIGHUP
+    #[arg(long)]
+    motd_file: Option<PathBuf>,
+    /// most verbose level of the logged events: off, error, warn, info, debug or trace
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+    #[arg(long, value_enum, default_value = "plain")]
+    log_format: LogFormat,
+    /// serve Prometheus metrics over HTTP on this port, at /metrics
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// serve the messages of the rooms as Server-Sent Events over HTTP on this port, at
+    /// /events
+    #[arg(long)]
+    sse_port: Option<u16>,
+    /// POST the events of the rooms as JSON to this http:// or https:// URL, e.g. of a
+    /// Slack or Discord relay
+    #[arg(long)]
+    webhook_url: Option<String>,
+    /// the events POSTed to the webhook, among join, leave and message
+    #[arg(long, default_value = "join,leave,message")]
+    webhook_events: WebhookEvents,
+    /// header sent with the webhook requests, e.g. "Authorization: Bearer <token>"
+    #[arg(long)]
+    webhook_auth_header: Option<String>,
+    /// password asked to the clients before their nickname, those failing it twice are
+    /// disconnected
+    #[arg(long, env = "BUDGET_CHAT_PASSWORD", hide_env_values = true)]
+    server_password: Option<String>,
+    /// also serve the chat over WebSocket on this port, a text frame per line
+    #[arg(long)]
+    ws_port: Option<u16>,
+    /// also serve the chat on this port with the JSON line protocol, an object per event
+    #[arg(long)]
+    json_port: Option<u16>,
+    /// serve the chat on this Unix socket, instead of TCP unless --port or --bind is given
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// read the client address from the PROXY protocol header sent by the load balancer
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// serve the chat port over TLS with this PEM certificate chain, leaf first
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// the PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// serve the TCP socket passed by systemd as file descriptor 3, detected from
+    /// LISTEN_FDS and LISTEN_PID without this flag
+    #[arg(long)]
+    systemd_socket: bool,
+    /// maximum rate of messages per user, e.g. 10/5s for 10 messages per 5 seconds
+    #[arg(long)]
+    rate_limit: Option<RateLimit>,
+    /// maximum rate of connections from a single address, e.g. 5/10s
+    #[arg(long, default_value = "5/10s")]
+    conn_rate: RateLimit,
+    /// do not limit the rate of connections from the loopback addresses
+    #[arg(long)]
+    conn_rate_exempt_local: bool,
+    /// disconnect the users exceeding the rate limit this many times in a row, 0 for never
+    #[arg(long, default_value = "5")]
+    rate_limit_violations: u32,
+    /// how many times per period a user may send the same message, the next copies are
+    /// dropped
+    #[arg(long, default_value = "3/10s")]
+    repeat_limit: RateLimit,
+    /// let the users repeat their messages
+    #[arg(long)]
+    no_repeat_limit: bool,
+    /// kick the users sending dropped copies this many times in a row, 0 for never
+    #[arg(long, default_value = "3")]
+    repeat_limit_violations: u32,
+    /// disconnect the users that sent nothing for this many seconds
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// disconnect the clients that did not enter a nickname after this many seconds, 0
+    /// for never
+    #[arg(long, default_value = "30")]
+    handshake_timeout: u64,
+    /// write a ping line to the clients every this many seconds
+    #[arg(long)]
+    ping_interval: Option<u64>,
+    /// maximum number of joined users
+    #[arg(long)]
+    max_users: Option<usize>,
+    /// maximum number of joined users connected from the same IP address
+    #[arg(long)]
+    max_sessions_per_ip: Option<usize>,
+    /// maximum number of connections that did not join yet
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// nickname of the operator, by default the first user to join
+    #[arg(long)]
+    operator: Option<String>,
+    /// file holding the banned addresses, one per line
+    #[arg(long)]
+    ban_file: Option<PathBuf>,
+    /// start read-only, only the operators may send messages until /lockdown off
+    #[arg(long)]
+    start_locked: bool,
+    /// announce the joins with the number of users of the room
+    #[arg(long)]
+    announce_counts: bool,
+    /// maximum number of users of the rooms but the lobby, changed per room with
+    /// /mode #room limit <count>
+    #[arg(long)]
+    default_room_limit: Option<usize>,
+    /// file holding the nicknames made operators when they join, one per line,
+    /// rewritten by /op and /deop
+    #[arg(long)]
+    ops_file: Option<PathBuf>,
+    /// how many messages are replayed to the joining users, 0 disables the history
+    #[arg(long, default_value = "50")]
+    history: usize,
+    /// maximum total size of the replayed messages, in bytes
+    #[arg(long, default_value = "65536")]
+    history_bytes: usize,
+    /// replay the join and leave notices too
+    #[arg(long)]
+    history_notices: bool,
+    /// append the joins, leaves and messages to this file, as JSON lines
+    #[arg(long)]
+    log_chat: Option<PathBuf>,
+    /// when the chat log is synced to the disk: every, interval:<secs> or never
+    #[arg(long, default_value = "interval:1")]
+    chat_log_sync: LogSync,
+    /// rename the chat log to <name>-<date>.<ext> when the local date changes, and
+    /// start a new one
+    #[arg(long)]
+    chat_log_daily: bool,
+    /// rename the chat log, as --chat-log-daily, before it grows past this many bytes
+    #[arg(long)]
+    chat_log_max_bytes: Option<u64>,
+    /// number of renamed chat logs kept, the oldest ones are deleted
+    #[arg(long, default_value_t = 7)]
+    chat_log_keep: usize,
+    /// prefix the delivered lines with their time, clients can switch it with /timestamps
+    #[arg(long)]
+    timestamps: bool,
+    /// write the timestamps in UTC rather than in the local time
+    #[arg(long)]
+    timestamp_utc: bool,
+    /// color the delivered lines by default, on or off. Clients can switch it with /color
+    #[arg(long, default_value = "off", value_parser = parse_on_off, action = ArgAction::Set)]
+    color_default: bool,
+    /// what to do with a nickname already used: reject, suggest a free one or auto to use it
+    #[arg(long, default_value = "reject")]
+    on_duplicate: OnDuplicate,
+    /// what to do with the messages containing control characters: strip them or reject
+    /// the message
+    #[arg(long, default_value = "strip")]
+    sanitize: Sanitize,
+    /// file holding the words not allowed in the messages and nicknames, one per line,
+    /// re-read on SIGHUP
+    #[arg(long)]
+    word_filter: Option<PathBuf>,
+    /// what to do with the messages containing filtered words: mask them with asterisks
+    /// or reject the message
+    #[arg(long, default_value = "mask")]
+    word_filter_mode: WordFilterMode,
+    /// file of the registered nicknames, nickname:hash lines printed by hash-password.
+    /// Their password is asked when joining. Re-read on SIGHUP.
+    #[arg(long)]
+    accounts_file: Option<PathBuf>,
+    /// send the users their own messages, they can switch it with /echo
+    #[arg(long)]
+    echo: bool,
+    /// do not tell how long the leaving users were online, as in the original protocol
+    #[arg(long)]
+    plain_leave: bool,
+    /// number of threads queuing the messages for their recipients, 0 to queue them on
+    /// the connection of their sender. Default: the number of CPUs
+    #[arg(long)]
+    fanout_workers: Option<usize>,
+    /// what to do when the queue of a client is full: disconnect it, drop-oldest to drop
+    /// the oldest messages waiting or drop-newest to drop the incoming ones
+    #[arg(long, default_value = "disconnect")]
+    slow_client_policy: SlowClientPolicy,
+    /// write all the messages queued for a client at once, rather than each as soon as
+    /// it is queued
+    #[arg(long)]
+    write_batch: bool,
+    /// archive the messages in this SQLite database, created if needed. Searched by
+    /// /search, printed by archive-dump
+    #[arg(long, value_name = "PATH.db")]
+    archive: Option<PathBuf>,
+    /// broadcast the empty and blank lines as messages, as the protocol did
+    #[arg(long)]
+    allow_empty: bool,
+    /// maximum number of lines of a /paste
+    #[arg(long, default_value_t = 20)]
+    paste_max_lines: usize,
+    /// keep the Nagle algorithm on the TCP connections, delaying the small writes
+    #[arg(long)]
+    no_nodelay: bool,
+    /// send TCP keepalive probes after this many seconds without traffic
+    #[arg(long)]
+    tcp_keepalive: Option<u64>,
+}
 
-mod chatroom;
+/// Commands run instead of the server
+#[derive(Subcommand)]
+enum Tool {
+    /// print the line of NICKNAME in the --accounts-file, with the hash of the password
+    /// read from stdin
+    HashPassword { nickname: String },
+    /// print the messages of the --archive database as JSON lines, e.g. to export them
+    ArchiveDump {
+        /// only the messages from this time on, e.g. 2024-05-01T00:00:00Z
+        #[arg(long, value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+    },
+    /// join a running server with many clients chatting at a given rate, then print the
+    /// messages sent and received and their delivery latency. Exits with 1 if a client
+    /// was disconnected. The server may need --conn-rate-exempt-local.
+    Loadtest {
+        /// address of the server
+        #[arg(long, default_value = "127.0.0.1:5555")]
+        target: SocketAddr,
+        /// number of clients, joining as load0, load1...
+        #[arg(long, default_value_t = 100)]
+        clients: usize,
+        /// messages sent per second by each client
+        #[arg(long, default_value_t = 1.0)]
+        msgs_per_sec: f64,
+        /// how long the clients send messages, e.g. 30s or 2m
+        #[arg(long, default_value = "30s", value_parser = parse_duration)]
+        duration: Duration,
+    },
+}
 
-#[derive(Parser)]
-struct Args {
-    /// bind the service to this tcp port, default 5555
-    #[arg(short, long, default_value = "5555")]
-    port: u16,
-}
-
-fn main() {
-    let args = Args::parse();
-    let s = format!("0.0.0.0:{}", args.port)
-        .parse::<SocketAddr>()
-        .unwrap();
-    println!("Listening to {s}");
-    let chatroom = Chatroom::default();
-    let listener = TcpListener::bind(s).unwrap();
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(incoming) => {
-                let chatroom = chatroom.clone();
-                thread::spawn(|| chat(incoming, chatroom));
-            }
+#[derive(Clone, ValueEnum)]
+enum LogFormat {
+    /// human readable lines
+    Plain,
+    /// one JSON object per line
+    Json,
+}
 
-            Err(e) => eprintln!("error {e}"),
+#[tokio::main]
+async fn main() {
+    let (args, effective) = Config::load();
+    match &args.tool {
+        Some(Tool::HashPassword { nickname }) => process::exit(print_account(&args, nickname)),
+        Some(Tool::ArchiveDump { since }) => process::exit(dump_archive(&args, *since)),
+        Some(Tool::Loadtest {
+            target,
+            clients,
+            msgs_per_sec,
+            duration,
+        }) => {
+            let report = run_load_test(LoadTestConfig {
+                target: *target,
+                clients: *clients,
+                msgs_per_sec: *msgs_per_sec,
+                duration: *duration,
+            })
+            .await;
+            print!("{report}");
+            process::exit(i32::from(report.disconnects > 0));
         }
+        None => {}
+    }
+    let logs = tracing_subscriber::fmt().with_max_level(args.log_level);
+    match args.log_format {
+        LogFormat::Plain => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+    let s = args
+        .bind
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], args.port.unwrap_or(5555))));
+    let serve_tcp = args.unix_socket.is_none() || args.port.is_some() || args.bind.is_some();
+    let chatroom = Chatroom::new(args.chatroom_config());
+    if let Some(motd_file) = &args.motd_file {
+        load_motd(motd_file, &chatroom);
+    }
+    {
+        let chatroom = chatroom.clone();
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(async move {
+            let mut effective = effective;
+            while hangup.recv().await.is_some() {
+                if let Some(reloaded) = reload(&chatroom, &effective) {
+                    effective = reloaded;
+                }
+            }
+        });
+    }
+    if let Some(port) = args.metrics_port {
+        let listener = listen(SocketAddr::new(s.ip(), port), "metrics");
+        tokio::spawn(serve_metrics(listener, chatroom.clone()));
     }
+    if let Some(port) = args.sse_port {
+        let listener = listen(SocketAddr::new(s.ip(), port), "events");
+        tokio::spawn(serve_events(listener, chatroom.clone()));
+    }
+    if let Some(config) = args.webhook_config() {
+        if let Err(e) = start_webhook(&chatroom, config) {
+            error!(error = %e, "cannot start the webhook");
+            process::exit(1)
+        }
+    }
+    {
+        let chatroom = chatroom.clone();
+        thread::spawn(move || run_console(&chatroom));
+    }
+    let server_config = args.server_config();
+    let tcp = match systemd::activated_listener(args.systemd_socket) {
+        Ok(Some(listener)) => Some(listener),
+        Ok(None) => serve_tcp.then(|| listen(s, "chat")),
+        Err(e) => {
+            error!(error = %e, "cannot use the socket passed by systemd");
+            process::exit(1)
+        }
+    };
+    #[cfg(feature = "tls")]
+    let tls = args
+        .tls_cert
+        .as_ref()
+        .zip(args.tls_key.as_ref())
+        .map(|(cert, key)| {
+            TlsConfig::load(cert, key).unwrap_or_else(|e| {
+                error!(error = %e, "cannot load the TLS certificate");
+                process::exit(1)
+            })
+        });
+    if let Some(listener) = &tcp {
+        info!(addr = %listener.local_addr().unwrap(), "listening");
+    }
+    let websocket = args.ws_port.map(|port| {
+        let listener = listen(SocketAddr::new(s.ip(), port), "WebSocket");
+        info!(addr = %listener.local_addr().unwrap(), "listening for WebSocket clients");
+        listener
+    });
+    let json = args.json_port.map(|port| {
+        let listener = listen(SocketAddr::new(s.ip(), port), "JSON");
+        info!(addr = %listener.local_addr().unwrap(), "listening for JSON clients");
+        listener
+    });
+    let unix = args.unix_socket.map(|path| {
+        let listener = bind_unix(&path).unwrap_or_else(|e| {
+            error!(path = %path.display(), error = %e, "cannot bind the Unix socket");
+            process::exit(1)
+        });
+        info!(path = %path.display(), "listening");
+        listener
+    });
+
+    // every listener stops serving on the same signal
+    let (stop, stopping) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        systemd::notify("STOPPING=1");
+        let _ = stop.send(());
+    });
+    let stopped = || {
+        let mut stopping = stopping.clone();
+        async move {
+            let _ = stopping.changed().await;
+        }
+    };
+    let tcp = tcp.map(|listener| {
+        let (chatroom, config, stopped) = (chatroom.clone(), server_config.clone(), stopped());
+        async move {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                return serve_tls(listener, chatroom, config, tls, stopped).await;
+            }
+            serve(listener, chatroom, config, stopped).await
+        }
+    });
+    let websocket = websocket.map(|listener| {
+        serve_websocket(listener, chatroom.clone(), server_config.clone(), stopped())
+    });
+    let json = json.map(|listener| {
+        let config = ServerConfig {
+            json: true,
+            ..server_config.clone()
+        };
+        serve(listener, chatroom.clone(), config, stopped())
+    });
+    let unix = unix
+        .map(|listener| serve_unix(listener, chatroom.clone(), server_config.clone(), stopped()));
+    systemd::notify("READY=1");
+    tokio::join!(
+        serve_some(tcp),
+        serve_some(websocket),
+        serve_some(json),
+        serve_some(unix)
+    );
+}
+
+/// Listen on `addr` for the `service` clients, exits on failure
+fn listen(addr: SocketAddr, service: &str) -> TcpListener {
+    bind(addr).unwrap_or_else(|e| {
+        if e.kind() == io::ErrorKind::AddrInUse {
+            error!(%addr, service, "address already in use, is another server running?");
+        } else {
+            error!(%addr, service, error = %e, "cannot listen");
+        }
+        process::exit(1)
+    })
 }
 
-fn chat(mut stream: TcpStream, chatroom: Chatroom) {
-    let peer_addr = stream.peer_addr().unwrap();
+/// The options that cannot be set from the `--config` file
+const COMMAND_LINE_ONLY: &[&str] = &["help", "config", "print_config"];
 
-    println!("{peer_addr} - connected!");
+/// The options holding credentials, not printed by `--print-config`
+const SECRET: &[&str] = &["webhook_auth_header", "server_password"];
 
-    let mut read_stream = BufReader::new(stream.try_clone().unwrap());
+/// The options applied by [`reload`], the others need a restart
+const RELOADABLE: &[&str] = &[
+    "templates",
+    "motd_file",
+    "rate_limit",
+    "rate_limit_violations",
+    "max_users",
+    "ban_file",
+    "ops_file",
+    "word_filter",
+    "accounts_file",
+];
 
-    stream
-        .write_all(b"Welcome to our chat room, please enter your nickname:\n")
-        .unwrap();
+impl Config {
+    /// Parse the command line, then the `--config` file for the options not given on
+    /// the command line. Exits on errors, and after printing the options with
+    /// `--print-config`. Returns the options and their [`effective_toml`].
+    fn load() -> (Self, String) {
+        let command = Config::command();
+        let matches = with_config_file(&command, command.clone().get_matches())
+            .unwrap_or_else(|e| command.clone().error(ErrorKind::InvalidValue, e).exit());
+        let config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let toml = effective_toml(&command, &matches);
+        if config.print_config {
+            print!("{toml}");
+            process::exit(0);
+        }
+        (config, toml)
+    }
+
+    /// Load the options again, with the current content of the `--config` file
+    fn reload() -> Result<(Self, String), String> {
+        let command = Config::command();
+        let matches = command
+            .clone()
+            .try_get_matches()
+            .map_err(|e| first_line(&e))?;
+        let matches = with_config_file(&command, matches)?;
+        let config = Config::from_arg_matches(&matches).map_err(|e| first_line(&e))?;
+        Ok((config, effective_toml(&command, &matches)))
+    }
+
+    /// The rules of the nicknames
+    fn nickname_rules(&self) -> NicknameRules {
+        NicknameRules {
+            max_len: self.nick_max_len,
+            extra_chars: self.nick_allow_extra.clone(),
+            unicode: self.unicode_nicks,
+            ..Default::default()
+        }
+    }
+
+    /// The settings of the chatroom, exits if one of their files cannot be loaded
+    fn chatroom_config(&self) -> ChatroomConfig {
+        ChatroomConfig {
+            nicknames: self.nickname_rules(),
+            rate_limit: self.rate_limit,
+            max_rate_violations: self.rate_limit_violations,
+            repeat_limit: (!self.no_repeat_limit).then_some(self.repeat_limit),
+            max_repeat_violations: self.repeat_limit_violations,
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            max_users: self.max_users,
+            max_sessions_per_ip: self.max_sessions_per_ip,
+            operator: self.operator.clone(),
+            bans: match &self.ban_file {
+                Some(ban_file) => Arc::new(BanList::load(ban_file.clone()).unwrap_or_else(|e| {
+                    error!(error = %e, "cannot load the ban file");
+                    process::exit(1)
+                })),
+                None => Arc::default(),
+            },
+            locked: self.start_locked,
+            announce_counts: self.announce_counts,
+            default_room_limit: self.default_room_limit,
+            operators: match &self.ops_file {
+                Some(ops_file) => {
+                    Arc::new(OperatorList::load(ops_file.clone()).unwrap_or_else(|e| {
+                        error!(error = %e, "cannot load the ops file");
+                        process::exit(1)
+                    }))
+                }
+                None => Arc::default(),
+            },
+            history: HistoryConfig {
+                messages: self.history,
+                bytes: self.history_bytes,
+                notices: self.history_notices,
+            },
+            chat_log: self.log_chat.as_ref().map(|path| {
+                let rotation = LogRotation {
+                    daily: self.chat_log_daily,
+                    max_bytes: self.chat_log_max_bytes,
+                    keep: self.chat_log_keep,
+                };
+                let chat_log = ChatLog::open_rotating(path, self.chat_log_sync, rotation);
+                let chat_log = chat_log.unwrap_or_else(|e| {
+                    error!(path = %path.display(), error = %e, "cannot open the chat log");
+                    process::exit(1)
+                });
+                Arc::new(chat_log)
+            }),
+            archive: self.archive.as_ref().map(|path| {
+                Arc::new(Archive::open(path).unwrap_or_else(|e| {
+                    error!(path = %path.display(), error = %e, "cannot open the archive");
+                    process::exit(1)
+                }))
+            }),
+            on_duplicate: self.on_duplicate,
+            sanitize: self.sanitize,
+            echo: self.echo,
+            plain_leave: self.plain_leave,
+            templates: match &self.templates {
+                Some(path) => Arc::new(load_templates(path)),
+                None => Arc::default(),
+            },
+            word_filter: match &self.word_filter {
+                Some(path) => {
+                    Arc::new(read_word_filter(path).unwrap_or_else(|()| process::exit(1)))
+                }
+                None => Arc::default(),
+            },
+            word_filter_mode: self.word_filter_mode,
+            accounts: match &self.accounts_file {
+                Some(path) => Arc::new(read_accounts(path).unwrap_or_else(|()| process::exit(1))),
+                None => Arc::default(),
+            },
+            nick_hold: (self.nick_hold > 0).then(|| Duration::from_secs(self.nick_hold)),
+            resume_window: (self.resume_window > 0)
+                .then(|| Duration::from_secs(self.resume_window)),
+            fanout_workers: self
+                .fanout_workers
+                .unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get())),
+            slow_client_policy: self.slow_client_policy,
+        }
+    }
+
+    /// The settings of the webhook, `None` without its URL
+    fn webhook_config(&self) -> Option<WebhookConfig> {
+        Some(WebhookConfig {
+            url: self.webhook_url.clone()?,
+            events: self.webhook_events,
+            auth_header: self.webhook_auth_header.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// The settings of the chat listeners
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            nickname_attempts: self.nickname_attempts,
+            client_queue: self.client_queue,
+            shutdown_grace: Duration::from_secs(self.shutdown_grace),
+            max_line_bytes: self.max_line_bytes,
+            max_nickname_bytes: self.max_nickname_bytes,
+            invalid_utf8: self.invalid_utf8,
+            ping_interval: self.ping_interval.map(Duration::from_secs),
+            max_connections: self.max_connections,
+            timestamps: self.timestamps,
+            timestamp_utc: self.timestamp_utc,
+            color: self.color_default,
+            json: false,
+            proxy_protocol: self.proxy_protocol,
+            write_batch: self.write_batch,
+            allow_empty: self.allow_empty,
+            paste_max_lines: self.paste_max_lines,
+            paste_timeout: Duration::from_secs(30),
+            nodelay: !self.no_nodelay,
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+            handshake_timeout: (self.handshake_timeout > 0)
+                .then(|| Duration::from_secs(self.handshake_timeout)),
+            conn_rate: Some(self.conn_rate),
+            conn_rate_exempt_local: self.conn_rate_exempt_local,
+            password: self.server_password.clone(),
+        }
+    }
+}
+
+/// The command line `matches` with the options of the `--config` file they name
+fn with_config_file(command: &Command, matches: ArgMatches) -> Result<ArgMatches, String> {
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(matches);
+    };
+    let invalid =
+        |reason: String| format!("invalid configuration file {}: {reason}", path.display());
+    let toml = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let file_args = parse_config(&toml)
+        .and_then(|entries| file_args(command, &matches, &entries))
+        .map_err(|e| invalid(e.to_string()))?;
+    let args: Vec<OsString> = env::args_os().collect();
+    command
+        .clone()
+        .try_get_matches_from(args[..1].iter().chain(&file_args).chain(&args[1..]))
+        .map_err(|e| first_line(&e))
+}
+
+/// The message of a clap error, without the usage that follows
+fn first_line(e: &clap::Error) -> String {
+    let message = e.to_string();
+    let line = message.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+/// The command line arguments setting the options of the `entries`, but those given on
+/// the command line or conflicting with them
+fn file_args(
+    command: &Command,
+    matches: &ArgMatches,
+    entries: &[ConfigEntry],
+) -> Result<Vec<OsString>, ConfigError> {
+    let on_command_line =
+        |arg: &Arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let mut seen = HashSet::new();
+    let mut args = Vec::new();
+    for entry in entries {
+        let error = |reason: String| ConfigError {
+            line: entry.line,
+            key: Some(entry.key.clone()),
+            reason,
+        };
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == entry.key.as_str())
+            .filter(|arg| !COMMAND_LINE_ONLY.contains(&arg.get_id().as_str()))
+            .ok_or_else(|| error("unknown key".to_string()))?;
+        if !seen.insert(&entry.key) {
+            return Err(error("duplicate key".to_string()));
+        }
+        let conflicts = |other: &Arg| {
+            let conflicting = |a: &Arg, b: &Arg| {
+                command
+                    .get_arg_conflicts_with(a)
+                    .iter()
+                    .any(|c| c.get_id() == b.get_id())
+            };
+            conflicting(arg, other) || conflicting(other, arg)
+        };
+        if on_command_line(arg)
+            || command
+                .get_arguments()
+                .any(|other| on_command_line(other) && conflicts(other))
+        {
+            continue;
+        }
+        let long = arg.get_long().expect("the options have a long name");
+        if !arg.get_action().takes_values() {
+            match entry.value {
+                ConfigValue::Boolean(true) => args.push(format!("--{long}").into()),
+                ConfigValue::Boolean(false) => {}
+                _ => return Err(error("expected a boolean".to_string())),
+            }
+            continue;
+        }
+        let value = match &entry.value {
+            ConfigValue::String(text) | ConfigValue::Datetime(text) => text.clone(),
+            ConfigValue::Integer(value) => value.to_string(),
+            ConfigValue::Float(value) => value.to_string(),
+            ConfigValue::Boolean(_) | ConfigValue::Array(_) | ConfigValue::Table(_) => {
+                return Err(error("expected a string or a number".to_string()))
+            }
+        };
+        let arg = format!("--{long}={value}");
+        if let Err(e) = command.clone().try_get_matches_from(["budget-chat", &arg]) {
+            let reason = match std::error::Error::source(&e) {
+                Some(source) => format!("invalid value {value:?}: {source}"),
+                None => format!("invalid value {value:?}"),
+            };
+            return Err(error(reason));
+        }
+        args.push(arg.into());
+    }
+    Ok(args)
+}
 
-    let mut nickname = String::new();
-    read_stream.read_line(&mut nickname).unwrap();
+/// The options in the syntax of the `--config` file, commented out when not set
+fn effective_toml(command: &Command, matches: &ArgMatches) -> String {
+    let mut toml = String::new();
+    for arg in command.get_arguments() {
+        let key = arg.get_id().as_str();
+        if COMMAND_LINE_ONLY.contains(&key) {
+            continue;
+        }
+        let value = if !arg.get_action().takes_values() {
+            Some(ConfigValue::Boolean(matches.get_flag(key)))
+        } else {
+            matches
+                .get_raw(key)
+                .and_then(|mut values| values.next())
+                .map(|value| {
+                    let value = value.to_string_lossy();
+                    match value.parse::<i64>() {
+                        Ok(number) if number.to_string() == value => ConfigValue::Integer(number),
+                        _ => ConfigValue::String(value.into_owned()),
+                    }
+                })
+        };
+        match value {
+            Some(_) if SECRET.contains(&key) => toml.push_str(&format!("# {key} is secret\n")),
+            Some(value) => toml.push_str(&format!("{key} = {value}\n")),
+            None => toml.push_str(&format!("# {key} is not set\n")),
+        }
+    }
+    toml
+}
 
-    let (sender, receiver) = channel();
+/// Complete `serving`, at once if `None`
+async fn serve_some(serving: Option<impl Future<Output = ()>>) {
+    if let Some(serving) = serving {
+        serving.await;
+    }
+}
 
-    let nickname = nickname.trim().to_string();
-    match chatroom.join(nickname.clone(), sender) {
-        Ok(session) => {
-            thread::spawn(move || {
-                for message in receiver.iter() {
-                    let _ = write!(stream, "{message}\n");
+/// Run the admin commands read from stdin, until its end
+fn run_console(chatroom: &Chatroom) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        match (command, args.trim()) {
+            ("", _) => {}
+            ("users", _) => {
+                for user in chatroom.users() {
+                    let peer = user
+                        .peer_addr
+                        .map_or("-".to_string(), |addr| addr.to_string());
+                    let connection = user
+                        .connection
+                        .map_or("-".to_string(), |connection| format!("conn#{connection}"));
+                    println!(
+                        "{} {connection} {} #{} {peer} {}s {} messages",
+                        user.id,
+                        user.nickname,
+                        user.room,
+                        user.connected_for.as_secs(),
+                        user.messages
+                    );
                 }
-            });
-            for line in read_stream.lines() {
-                if let Ok(line) = line {
-                    let line = line.trim().to_string();
-                    session.send_message(line);
-                } else {
-                    break;
+            }
+            ("say", text) if !text.is_empty() => chatroom.broadcast_notice(text),
+            ("kick", args) if !args.is_empty() => {
+                let (target, reason) = match args.split_once(' ') {
+                    Some((target, reason)) => (target, Some(reason.trim().to_string())),
+                    None => (args, None),
+                };
+                match chatroom.kick_by_nick(target, reason) {
+                    Ok(()) => println!("{target} kicked"),
+                    Err(e) => println!("{e}"),
                 }
             }
-            // Note: session will be dropped thus, the user will leave the chatroom
+            ("stats", _) => {
+                let metrics = chatroom.metrics();
+                println!(
+                    "users: {}, messages: {}",
+                    metrics.connected_users, metrics.messages_broadcast
+                );
+            }
+            _ => println!("commands: users, say <text>, kick <nick> [reason], stats"),
         }
+    }
+    info!("stdin closed, the admin console is disabled");
+}
+
+/// Read the templates from `path`, exits if they are invalid
+fn load_templates(path: &Path) -> MessageTemplates {
+    read_templates(path).unwrap_or_else(|()| process::exit(1))
+}
+
+/// Read the templates from `path`, logging why they cannot be used
+fn read_templates(path: &Path) -> Result<MessageTemplates, ()> {
+    let templates = fs::read_to_string(path).map_err(|e| {
+        error!(path = %path.display(), error = %e, "cannot read the templates");
+    })?;
+    MessageTemplates::parse(&templates).map_err(|e| {
+        error!(path = %path.display(), error = %e, "invalid templates");
+    })
+}
+
+/// Apply the options of the `--config` file that can change at runtime, those of
+/// the `previous` [`effective_toml`] that cannot change are logged. Returns the
+/// effective TOML of the applied options, `None` if they are kept for an error.
+fn reload(chatroom: &Chatroom, previous: &str) -> Option<String> {
+    let (config, toml) = match Config::reload() {
+        Ok(reloaded) => reloaded,
         Err(e) => {
-            write!(stream, "{e}\n").unwrap();
+            error!(error = %e, "cannot reload the configuration");
+            return None;
+        }
+    };
+    let mut changed = Vec::new();
+    for (old, new) in previous
+        .lines()
+        .zip(toml.lines())
+        .filter(|(old, new)| old != new)
+    {
+        let key = new
+            .trim_start_matches("# ")
+            .split(' ')
+            .next()
+            .unwrap_or_default();
+        if RELOADABLE.contains(&key) {
+            changed.push(key);
+        } else {
+            warn!(option = key, old, new, "ignored on reload");
         }
     }
+    let templates = match &config.templates {
+        Some(path) => Arc::new(read_templates(path).ok()?),
+        None => Arc::default(),
+    };
+    let bans = match config.ban_file {
+        Some(ban_file) => match BanList::load(ban_file) {
+            Ok(bans) => Arc::new(bans),
+            Err(e) => {
+                error!(error = %e, "cannot load the ban file");
+                return None;
+            }
+        },
+        // keep the bans made since the start
+        None if !changed.contains(&"ban_file") => chatroom.bans(),
+        None => Arc::default(),
+    };
+    let operators = match config.ops_file {
+        Some(ops_file) => match OperatorList::load(ops_file) {
+            Ok(operators) => Arc::new(operators),
+            Err(e) => {
+                error!(error = %e, "cannot load the ops file");
+                return None;
+            }
+        },
+        // keep the operators made since the start
+        None if !changed.contains(&"ops_file") => chatroom.runtime_config().operators.clone(),
+        None => Arc::default(),
+    };
+    let word_filter = match &config.word_filter {
+        Some(path) => Arc::new(read_word_filter(path).ok()?),
+        None => Arc::default(),
+    };
+    let accounts = match &config.accounts_file {
+        Some(path) => Arc::new(read_accounts(path).ok()?),
+        None => Arc::default(),
+    };
+    match &config.motd_file {
+        Some(motd_file) => load_motd(motd_file, chatroom),
+        None => chatroom.set_motd(Vec::new()),
+    }
+    chatroom.reload(RuntimeConfig {
+        rate_limit: config.rate_limit,
+        max_rate_violations: config.rate_limit_violations,
+        max_users: config.max_users,
+        bans,
+        operators,
+        templates,
+        word_filter,
+        accounts,
+    });
+    info!(changed = changed.join(", "), "configuration reloaded");
+    Some(toml)
+}
+
+/// Read the word filter from `path`, logging why it cannot be used
+fn read_word_filter(path: &Path) -> Result<WordFilter, ()> {
+    WordFilter::load(path).map_err(|e| {
+        error!(path = %path.display(), error = %e, "cannot load the word filter");
+    })
+}
 
-    println!("{peer_addr} - connection ended");
+/// Read the registered nicknames from `path`, logging why they cannot be used
+fn read_accounts(path: &Path) -> Result<Accounts, ()> {
+    Accounts::load(path).map_err(|e| {
+        error!(path = %path.display(), error = %e, "cannot load the accounts");
+    })
+}
+
+/// Print the accounts file line of `nickname`, with the password read from stdin.
+/// Returns the exit code.
+fn print_account(args: &Config, nickname: &str) -> i32 {
+    if let Err(e) = args.nickname_rules().validate(nickname) {
+        eprintln!("invalid nickname: {e}");
+        return 1;
+    }
+    let password = match read_password() {
+        Ok(password) if !password.is_empty() => password,
+        Ok(_) => {
+            eprintln!("empty password");
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("cannot read the password: {e}");
+            return 1;
+        }
+    };
+    match hash_password(&password, PasswordParams::default()) {
+        Ok(hash) => {
+            println!("{nickname}:{hash}");
+            0
+        }
+        Err(e) => {
+            eprintln!("cannot hash the password: {e}");
+            1
+        }
+    }
+}
+
+fn parse_on_off(s: &str) -> Result<bool, String> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got {s}")),
+    }
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("expected an RFC 3339 time, e.g. 2024-05-01T00:00:00Z: {e}"))
+}
+
+/// Print the messages of the archive from `since`
+fn dump_archive(args: &Config, since: Option<DateTime<Utc>>) -> i32 {
+    let Some(path) = &args.archive else {
+        eprintln!("no archive, see --archive");
+        return 1;
+    };
+    match read_archive(path, since, |message| println!("{}", message.to_json())) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("cannot read {}: {e}", path.display());
+            1
+        }
+    }
+}
+
+/// Read a line of stdin, not echoed if stdin is a terminal
+fn read_password() -> io::Result<String> {
+    // SAFETY: termios is plain data, filled by tcgetattr before being used
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    let terminal = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+    if terminal {
+        eprint!("password: ");
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+    }
+    let mut password = String::new();
+    let read = io::stdin().lock().read_line(&mut password);
+    if terminal {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        eprintln!();
+    }
+    read?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Read the MOTD from `path`, without MOTD clients only get the built-in banner
+fn load_motd(path: &Path, chatroom: &Chatroom) {
+    match fs::read_to_string(path) {
+        Ok(motd) => chatroom.set_motd(motd.lines().map(str::to_string).collect()),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "cannot read the MOTD file");
+            chatroom.set_motd(Vec::new());
+        }
+    }
+}
+
+/// Completes on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
@@ -0,0 +1,277 @@
+//! Counters describing the activity of the chatroom, exported in the Prometheus text format
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::chatroom::JoinError;
+
+/// Live counters, updated without locking
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) connected_users: AtomicU64,
+    pub(crate) connections: AtomicU64,
+    pub(crate) duplicate_nickname_rejections: AtomicU64,
+    pub(crate) invalid_nickname_rejections: AtomicU64,
+    pub(crate) shutting_down_rejections: AtomicU64,
+    pub(crate) room_full_rejections: AtomicU64,
+    pub(crate) too_many_sessions_rejections: AtomicU64,
+    pub(crate) disallowed_nickname_rejections: AtomicU64,
+    pub(crate) authentication_failed_rejections: AtomicU64,
+    pub(crate) nickname_reserved_rejections: AtomicU64,
+    pub(crate) messages_broadcast: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) oldest_drops: AtomicU64,
+    pub(crate) newest_drops: AtomicU64,
+    pub(crate) webhook_drops: AtomicU64,
+    /// the lags summed over the acknowledging clients, kept by their queues
+    pub(crate) client_lag: AtomicU64,
+    pub(crate) acking_clients: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub(counter: &AtomicU64, value: u64) {
+        counter.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn join_rejected(&self, error: &JoinError) {
+        let counter = match error {
+            JoinError::DuplicateNickname(_) | JoinError::NicknameTaken(_) => {
+                &self.duplicate_nickname_rejections
+            }
+            JoinError::InvalidNickname(_) => &self.invalid_nickname_rejections,
+            JoinError::ShuttingDown => &self.shutting_down_rejections,
+            JoinError::RoomFull => &self.room_full_rejections,
+            JoinError::TooManySessions => &self.too_many_sessions_rejections,
+            JoinError::DisallowedNickname => &self.disallowed_nickname_rejections,
+            JoinError::AuthenticationFailed => &self.authentication_failed_rejections,
+            JoinError::NicknameReserved(_) => &self.nickname_reserved_rejections,
+        };
+        Self::add(counter, 1);
+    }
+
+    /// The counters, `started_at` being the creation of the chatroom
+    pub(crate) fn snapshot(&self, started_at: Instant) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            connected_users: load(&self.connected_users),
+            connections: load(&self.connections),
+            join_rejections: JoinRejections {
+                duplicate_nickname: load(&self.duplicate_nickname_rejections),
+                invalid_nickname: load(&self.invalid_nickname_rejections),
+                shutting_down: load(&self.shutting_down_rejections),
+                room_full: load(&self.room_full_rejections),
+                too_many_sessions: load(&self.too_many_sessions_rejections),
+                disallowed_nickname: load(&self.disallowed_nickname_rejections),
+                authentication_failed: load(&self.authentication_failed_rejections),
+                nickname_reserved: load(&self.nickname_reserved_rejections),
+            },
+            messages_broadcast: load(&self.messages_broadcast),
+            bytes_written: load(&self.bytes_written),
+            evictions: load(&self.evictions),
+            slow_client_drops: SlowClientDrops {
+                oldest: load(&self.oldest_drops),
+                newest: load(&self.newest_drops),
+            },
+            webhook_drops: load(&self.webhook_drops),
+            client_lag: load(&self.client_lag),
+            acking_clients: load(&self.acking_clients),
+            uptime: started_at.elapsed(),
+        }
+    }
+}
+
+/// The metrics of a chatroom at a point in time
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// users currently in the chatroom
+    pub connected_users: u64,
+    /// connections accepted by the server
+    pub connections: u64,
+    pub join_rejections: JoinRejections,
+    /// chat messages sent to a room
+    pub messages_broadcast: u64,
+    /// bytes written by the server to the clients
+    pub bytes_written: u64,
+    /// users evicted as slow consumers
+    pub evictions: u64,
+    pub slow_client_drops: SlowClientDrops,
+    /// the [`Session::lag`](crate::Session::lag) summed over the users acknowledging
+    /// their messages
+    pub client_lag: u64,
+    /// users acknowledging their messages with `/ack`
+    pub acking_clients: u64,
+    /// events not POSTed to the webhook, see [`start_webhook`](crate::start_webhook)
+    pub webhook_drops: u64,
+    /// time since the chatroom was created
+    pub uptime: Duration,
+}
+
+/// The activity of a chatroom since its creation, see
+/// [`Chatroom::stats`](crate::Chatroom::stats)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChatroomStats {
+    /// users currently in the chatroom
+    pub users: u64,
+    /// time since the chatroom was created
+    pub uptime: Duration,
+    /// chat messages sent to a room
+    pub messages: u64,
+    /// whether only the operators may send messages, see
+    /// [`Chatroom::set_locked`](crate::Chatroom::set_locked)
+    pub locked: bool,
+}
+
+/// The activity of a user since it joined, see
+/// [`Session::stats`](crate::Session::stats)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionStats {
+    /// time since the user joined
+    pub connected_for: Duration,
+    /// chat messages and emotes the user sent to its rooms
+    pub messages: u64,
+}
+
+/// Rejected joins, by [`JoinError`] variant
+#[derive(Clone, Debug, Default)]
+pub struct JoinRejections {
+    pub duplicate_nickname: u64,
+    pub invalid_nickname: u64,
+    pub shutting_down: u64,
+    pub room_full: u64,
+    pub too_many_sessions: u64,
+    pub disallowed_nickname: u64,
+    pub authentication_failed: u64,
+    pub nickname_reserved: u64,
+}
+
+/// Messages dropped for the slow consumers, by
+/// [`SlowClientPolicy`](crate::SlowClientPolicy)
+#[derive(Clone, Debug, Default)]
+pub struct SlowClientDrops {
+    /// the oldest messages waiting, with `drop-oldest`
+    pub oldest: u64,
+    /// the incoming messages, with `drop-newest`
+    pub newest: u64,
+}
+
+impl MetricsSnapshot {
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(text, "# HELP budget_chat_{name} {help}");
+            let _ = writeln!(text, "# TYPE budget_chat_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "budget_chat_{name}{labels} {value}");
+            }
+        };
+        metric(
+            "connected_users",
+            "gauge",
+            "Users currently in the chatroom.",
+            &[("", self.connected_users)],
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Connections accepted.",
+            &[("", self.connections)],
+        );
+        metric(
+            "join_rejections_total",
+            "counter",
+            "Rejected joins, by reason.",
+            &[
+                (
+                    "{reason=\"duplicate_nickname\"}",
+                    self.join_rejections.duplicate_nickname,
+                ),
+                (
+                    "{reason=\"invalid_nickname\"}",
+                    self.join_rejections.invalid_nickname,
+                ),
+                (
+                    "{reason=\"shutting_down\"}",
+                    self.join_rejections.shutting_down,
+                ),
+                ("{reason=\"room_full\"}", self.join_rejections.room_full),
+                (
+                    "{reason=\"too_many_sessions\"}",
+                    self.join_rejections.too_many_sessions,
+                ),
+                (
+                    "{reason=\"disallowed_nickname\"}",
+                    self.join_rejections.disallowed_nickname,
+                ),
+                (
+                    "{reason=\"authentication_failed\"}",
+                    self.join_rejections.authentication_failed,
+                ),
+                (
+                    "{reason=\"nickname_reserved\"}",
+                    self.join_rejections.nickname_reserved,
+                ),
+            ],
+        );
+        metric(
+            "messages_broadcast_total",
+            "counter",
+            "Chat messages sent to a room.",
+            &[("", self.messages_broadcast)],
+        );
+        metric(
+            "bytes_written_total",
+            "counter",
+            "Bytes written to the clients.",
+            &[("", self.bytes_written)],
+        );
+        metric(
+            "evictions_total",
+            "counter",
+            "Users evicted as slow consumers.",
+            &[("", self.evictions)],
+        );
+        metric(
+            "slow_client_drops_total",
+            "counter",
+            "Messages dropped for slow consumers, by policy.",
+            &[
+                ("{policy=\"drop-oldest\"}", self.slow_client_drops.oldest),
+                ("{policy=\"drop-newest\"}", self.slow_client_drops.newest),
+            ],
+        );
+        metric(
+            "client_lag",
+            "gauge",
+            "Messages queued since the last one acknowledged, summed over the clients.",
+            &[("", self.client_lag)],
+        );
+        metric(
+            "acking_clients",
+            "gauge",
+            "Clients acknowledging their messages.",
+            &[("", self.acking_clients)],
+        );
+        metric(
+            "webhook_drops_total",
+            "counter",
+            "Events not POSTed to the webhook.",
+            &[("", self.webhook_drops)],
+        );
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the chatroom was created.",
+            &[("", self.uptime.as_secs())],
+        );
+        text
+    }
+}
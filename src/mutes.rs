@@ -0,0 +1,81 @@
+//! Users whose messages are dropped for a while, see [`Chatroom::mute`](crate::Chatroom::mute)
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The expiry of the mutes by case-folded nickname: a muted user stays muted when
+/// joining again
+#[derive(Default)]
+pub(crate) struct Mutes {
+    until: HashMap<String, Instant>,
+}
+
+impl Mutes {
+    pub(crate) fn mute(&mut self, nickname: &str, until: Instant) {
+        self.until.insert(nickname.to_ascii_lowercase(), until);
+    }
+
+    /// Returns `false` if `nickname` was not muted
+    pub(crate) fn unmute(&mut self, nickname: &str, now: Instant) -> bool {
+        self.prune(now);
+        self.until.remove(&nickname.to_ascii_lowercase()).is_some()
+    }
+
+    /// When the mute of `nickname` ends, `None` if it is not muted. The expired mutes
+    /// are forgotten.
+    pub(crate) fn muted_until(&mut self, nickname: &str, now: Instant) -> Option<Instant> {
+        self.prune(now);
+        self.until.get(&nickname.to_ascii_lowercase()).copied()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.until.retain(|_, until| *until > now);
+    }
+}
+
+/// The longest mute
+const MAX_MUTE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parse the duration of a mute, numbers of `s`, `m`, `h` or `d` with the largest
+/// unit first, e.g. `90s`, `10m` or `2h30m`, up to a year
+pub fn parse_mute_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {s:?}, expected e.g. 10m or 2h30m");
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut total = Duration::ZERO;
+    let mut previous_unit = u64::MAX;
+    while !rest.is_empty() {
+        let unit_start = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let count: u64 = rest[..unit_start].parse().map_err(|_| invalid())?;
+        let mut chars = rest[unit_start..].chars();
+        let unit = match chars.next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        if unit >= previous_unit {
+            return Err(invalid());
+        }
+        previous_unit = unit;
+        let seconds = count.checked_mul(unit).ok_or_else(invalid)?;
+        total = total
+            .checked_add(Duration::from_secs(seconds))
+            .ok_or_else(invalid)?;
+        rest = chars.as_str();
+    }
+    if total.is_zero() {
+        return Err(invalid());
+    }
+    if total > MAX_MUTE {
+        return Err(format!("invalid duration {s:?}, a mute lasts up to 365d"));
+    }
+    Ok(total)
+}
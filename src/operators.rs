@@ -0,0 +1,94 @@
+//! Nicknames made operators when they join, optionally persisted to a file
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use parking_lot::Mutex;
+use tracing::warn;
+
+/// The nicknames of the operators, see [`ChatroomConfig::operators`](crate::ChatroomConfig::operators)
+#[derive(Default)]
+pub struct OperatorList {
+    nicknames: Mutex<BTreeSet<String>>,
+    /// one nickname per line
+    file: Option<PathBuf>,
+}
+
+impl OperatorList {
+    /// Load the nicknames of `file`, a missing file holds none.
+    ///
+    /// The file is rewritten by [`OperatorList::add`] and [`OperatorList::remove`].
+    pub fn load(file: PathBuf) -> io::Result<Self> {
+        let nicknames = match fs::read_to_string(&file) {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            nicknames: Mutex::new(nicknames),
+            file: Some(file),
+        })
+    }
+
+    pub fn is_operator(&self, nickname: &str) -> bool {
+        self.nicknames.lock().contains(nickname)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nicknames.lock().is_empty()
+    }
+
+    /// The nicknames, sorted
+    pub fn nicknames(&self) -> Vec<String> {
+        self.nicknames.lock().iter().cloned().collect()
+    }
+
+    /// Add `nickname`, returns `false` if it was already there
+    pub fn add(&self, nickname: &str) -> bool {
+        let mut nicknames = self.nicknames.lock();
+        if !nicknames.insert(nickname.to_string()) {
+            return false;
+        }
+        self.save(&nicknames);
+        true
+    }
+
+    /// Remove `nickname`, returns `false` if it was not there
+    pub fn remove(&self, nickname: &str) -> bool {
+        let mut nicknames = self.nicknames.lock();
+        if !nicknames.remove(nickname) {
+            return false;
+        }
+        self.save(&nicknames);
+        true
+    }
+
+    /// Replace the file, never left half written: a temporary file is renamed over it
+    fn save(&self, nicknames: &BTreeSet<String>) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut temporary = file.clone().into_os_string();
+        temporary.push(".tmp");
+        let saved = fs::File::create(&temporary)
+            .and_then(|mut temporary| {
+                for nickname in nicknames {
+                    writeln!(temporary, "{nickname}")?;
+                }
+                temporary.sync_all()
+            })
+            .and_then(|_| fs::rename(&temporary, file));
+        if let Err(e) = saved {
+            warn!(file = %file.display(), error = %e, "cannot save the operators");
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Parsing of the PROXY protocol header, sent by load balancers before the client
+//! stream to tell the address of the client.
+//!
+//! Both the human readable v1 and the binary v2 headers are supported, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Start of the v1 headers
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+/// Start of the v2 headers
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a v1 header, line feed included
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY header at the start of `stream`, and nothing past it.
+///
+/// Returns the address of the client, `None` if the proxy does not tell it (health
+/// checks, unknown protocols).
+pub(crate) async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+    if start == V1_SIGNATURE {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("v1 header not ASCII"))?;
+        parse_v1(line)
+    } else if start == V2_SIGNATURE[..6] {
+        let mut header = [0; 10];
+        stream.read_exact(&mut header).await?;
+        if header[..6] != V2_SIGNATURE[6..] {
+            return Err(invalid("bad v2 signature"));
+        }
+        let mut addresses = vec![0; usize::from(u16::from_be_bytes([header[8], header[9]]))];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(header[6], header[7], &addresses)
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+/// Parse a v1 header line, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 5555\r\n`
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = line.trim_end_matches("\r\n").split(' ').skip(1);
+    let ipv6 = match fields.next() {
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unsupported v1 protocol")),
+    };
+    let (Some(source), Some(_destination), Some(port), Some(_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("bad v1 header"));
+    };
+    let source: IpAddr = source.parse().map_err(|_| invalid("bad v1 address"))?;
+    let port = port.parse().map_err(|_| invalid("bad v1 port"))?;
+    if source.is_ipv6() != ipv6 {
+        return Err(invalid("v1 address of the wrong family"));
+    }
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Parse the addresses block of a v2 header
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match version_command {
+        // LOCAL: connections of the proxy itself
+        0x20 => return Ok(None),
+        // PROXY
+        0x21 => {}
+        _ => return Err(invalid("unsupported v2 version or command")),
+    }
+    let (ip, port): (IpAddr, _) = match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            (Ipv4Addr::from(ip).into(), &addresses[8..10])
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            (Ipv6Addr::from(ip).into(), &addresses[32..34])
+        }
+        0x11 | 0x21 => return Err(invalid("v2 addresses too short")),
+        // UDP, Unix sockets or unspecified
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(
+        ip,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
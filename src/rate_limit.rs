@@ -0,0 +1,199 @@
+//! Limitation of the rate at which users may send messages or repeat them, and clients
+//! connect
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// At most `messages` messages `per` period, e.g. `10/5s`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse `<messages>/<period>`, the period is a number of `ms`, `s` or `m`
+    /// (e.g. `10/5s`, `1/500ms`), the number defaults to 1 (`10/s`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit {s:?}, expected e.g. 10/5s");
+        let (messages, per) = s.split_once('/').ok_or_else(invalid)?;
+        let messages = messages.trim().parse().map_err(|_| invalid())?;
+        let per = parse_period(per.trim()).ok_or_else(invalid)?;
+        if messages == 0 || per.is_zero() {
+            return Err(invalid());
+        }
+        Ok(RateLimit { messages, per })
+    }
+}
+
+/// Parse a number of `ms`, `s` or `m`, e.g. `500ms`, the number defaults to 1 (`s`)
+pub(crate) fn parse_period(s: &str) -> Option<Duration> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = s.split_at(unit_start);
+    let count = match count {
+        "" => 1,
+        count => count.parse().ok()?,
+    };
+    match unit {
+        "ms" => Some(Duration::from_millis(count)),
+        "s" => Some(Duration::from_secs(count)),
+        "m" => Some(Duration::from_secs(count.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Token bucket holding up to [`RateLimit::messages`] tokens, refilled continuously
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    /// messages refused since the last accepted one
+    pub(crate) violations: u32,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.messages as f64,
+            refilled_at: Instant::now(),
+            violations: 0,
+        }
+    }
+
+    /// Take a token, returns `false` if the bucket is empty
+    pub(crate) fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let rate = self.limit.messages as f64 / self.limit.per.as_secs_f64();
+        self.tokens = (self.tokens + (now - self.refilled_at).as_secs_f64() * rate)
+            .min(self.limit.messages as f64);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.violations = 0;
+            true
+        } else {
+            self.violations += 1;
+            false
+        }
+    }
+}
+
+/// Messages remembered by a [`RepeatGuard`], older messages are not compared
+const REPEAT_MEMORY: usize = 16;
+
+/// Recent messages of a user, refusing the copies of a message beyond
+/// [`RateLimit::messages`] identical ones within [`RateLimit::per`]
+pub(crate) struct RepeatGuard {
+    limit: RateLimit,
+    /// hashes of the last messages, trimmed and lowercased, and when they were sent
+    recent: VecDeque<(u64, Instant)>,
+    /// copies refused since the last accepted message
+    pub(crate) violations: u32,
+}
+
+impl RepeatGuard {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            recent: VecDeque::with_capacity(REPEAT_MEMORY),
+            violations: 0,
+        }
+    }
+
+    /// Record `text`, returns `false` if it repeats a recent message too often
+    pub(crate) fn check(&mut self, text: &str) -> bool {
+        let now = Instant::now();
+        let mut hasher = DefaultHasher::new();
+        text.trim().to_lowercase().hash(&mut hasher);
+        let hash = hasher.finish();
+        while self
+            .recent
+            .front()
+            .is_some_and(|(_, sent_at)| now - *sent_at >= self.limit.per)
+        {
+            self.recent.pop_front();
+        }
+        let copies = self.recent.iter().filter(|(h, _)| *h == hash).count();
+        if copies >= self.limit.messages as usize {
+            self.violations += 1;
+            return false;
+        }
+        if self.recent.len() == REPEAT_MEMORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((hash, now));
+        self.violations = 0;
+        true
+    }
+}
+
+/// Recent connections per source address, refusing the addresses connecting faster
+/// than a [`RateLimit`]
+pub(crate) struct ConnectionThrottle {
+    limit: RateLimit,
+    /// the loopback addresses are never refused
+    exempt_local: bool,
+    recent: Mutex<Recent>,
+}
+
+struct Recent {
+    /// times of the last connections of each address, at most `limit.messages`
+    connections: HashMap<IpAddr, VecDeque<Instant>>,
+    /// when the addresses without recent connections were last dropped
+    swept_at: Instant,
+}
+
+impl ConnectionThrottle {
+    pub(crate) fn new(limit: RateLimit, exempt_local: bool) -> Self {
+        Self {
+            limit,
+            exempt_local,
+            recent: Mutex::new(Recent {
+                connections: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Record a connection from `ip`, returns `false` if it exceeds the limit.
+    ///
+    /// The refused connections are recorded too: an address stays refused until it
+    /// slows down.
+    pub(crate) fn admit(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.exempt_local && ip.is_loopback() {
+            return true;
+        }
+        let now = Instant::now();
+        let window = self.limit.per;
+        let mut recent = self.recent.lock();
+        // bound the map to the addresses seen within the last two windows
+        if now - recent.swept_at >= window {
+            recent
+                .connections
+                .retain(|_, times| times.back().is_some_and(|last| now - *last < window));
+            recent.swept_at = now;
+        }
+        let times = recent.connections.entry(ip).or_default();
+        while times.front().is_some_and(|first| now - *first >= window) {
+            times.pop_front();
+        }
+        let admitted = times.len() < self.limit.messages as usize;
+        if !admitted {
+            times.pop_front();
+        }
+        times.push_back(now);
+        admitted
+    }
+}
@@ -0,0 +1,61 @@
+//! Removal of the control characters from the messages, so that users cannot mess
+//! with the terminal of the others or spoof lines with carriage returns
+
+use std::str::FromStr;
+
+/// Handling of the messages containing control characters, tabs are allowed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sanitize {
+    /// remove the control characters and the ANSI escape sequences
+    Strip,
+    /// refuse the message with [`SendError::ControlCharacters`](crate::SendError::ControlCharacters)
+    Reject,
+}
+
+impl FromStr for Sanitize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Sanitize::Strip),
+            "reject" => Ok(Sanitize::Reject),
+            _ => Err(format!("expected strip or reject, got {s}")),
+        }
+    }
+}
+
+impl Sanitize {
+    /// The text without control characters, `None` if it has some and they are
+    /// rejected
+    pub(crate) fn apply(self, text: String) -> Option<String> {
+        if !text.chars().any(is_forbidden) {
+            return Some(text);
+        }
+        match self {
+            Sanitize::Strip => Some(strip(&text)),
+            Sanitize::Reject => None,
+        }
+    }
+}
+
+/// The C0 and C1 control characters and DEL, but tabs
+fn is_forbidden(c: char) -> bool {
+    c.is_control() && c != '\t'
+}
+
+/// Remove the control characters, the `ESC [ ...` sequences (CSI) are removed up to
+/// their final byte
+fn strip(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.next_if_eq(&'[').is_some() {
+            // parameters and intermediate bytes, then the final byte
+            while chars.next_if(|c| ('\x20'..='\x3f').contains(c)).is_some() {}
+            chars.next_if(|c| ('\x40'..='\x7e').contains(c));
+        } else if !is_forbidden(c) {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
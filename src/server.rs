@@ -0,0 +1,1617 @@
+use std::{
+    fmt::Display,
+    fs,
+    future::Future,
+    io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use blake2::{Blake2b512, Digest};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io::{self as aio, AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{
+        mpsc::{self, channel, Receiver},
+        oneshot, watch, OwnedSemaphorePermit, Semaphore,
+    },
+    task,
+    time::{interval_at, sleep, timeout, timeout_at, Instant, Interval},
+};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+use crate::{
+    chatroom::{
+        format_duration, Chatroom, Envelope, JoinError, Message, ResumeError, SendError, Session,
+        Timestamps,
+    },
+    color,
+    command::{Command, RoomMode, COMMANDS},
+    fanout::Queue,
+    json,
+    lines::{trim_partial_char, Line, LineReader},
+    metrics::Metrics,
+    proxy,
+    rate_limit::{ConnectionThrottle, RateLimit},
+    websocket,
+};
+
+/// Tuning of the connection handling
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// how many nicknames a client may submit before being disconnected
+    pub nickname_attempts: usize,
+    /// how many messages can be queued for a client before it is evicted as a slow consumer
+    pub client_queue: usize,
+    /// how long connections are given to close once the server shuts down
+    pub shutdown_grace: Duration,
+    /// longer chat lines are truncated
+    pub max_line_bytes: usize,
+    /// clients sending a longer nickname are disconnected
+    pub max_nickname_bytes: usize,
+    /// what to do with the lines that are not valid UTF-8
+    pub invalid_utf8: InvalidUtf8,
+    /// write a `* ping` line to the joined clients this often, so that dead
+    /// connections are detected
+    pub ping_interval: Option<Duration>,
+    /// maximum number of connections that did not join yet, unlimited if `None`
+    pub max_connections: Option<usize>,
+    /// prefix the delivered lines with their time, clients can switch it with
+    /// `/timestamps on|off`
+    pub timestamps: bool,
+    /// the timestamps are in UTC rather than in the server local time
+    pub timestamp_utc: bool,
+    /// color the delivered lines with ANSI escape sequences, clients can switch it
+    /// with `/color on|off`. The JSON clients are never sent colors.
+    pub color: bool,
+    /// write the events as JSON objects, one per line, rather than the lines of the
+    /// templates. The clients of the other servers switch with `/protocol json` as
+    /// their first line after the nickname.
+    pub json: bool,
+    /// the connections come from a proxy and start with a PROXY protocol (v1 or v2)
+    /// header giving the address of the client, connections without it are closed
+    pub proxy_protocol: bool,
+    /// write every message queued for a client at once, rather than each as soon as
+    /// it is queued
+    pub write_batch: bool,
+    /// broadcast the empty and blank lines as messages rather than skipping them
+    pub allow_empty: bool,
+    /// maximum number of lines of a `/paste`, the whole block is limited to
+    /// `max_line_bytes` too
+    pub paste_max_lines: usize,
+    /// how long a `/paste` may take to be terminated by its `.` line before it is
+    /// aborted
+    pub paste_timeout: Duration,
+    /// disable the Nagle algorithm on the TCP connections, so that the small writes
+    /// are sent right away
+    pub nodelay: bool,
+    /// probe the idle TCP connections after this long with keepalive packets, never
+    /// if `None`
+    pub tcp_keepalive: Option<Duration>,
+    /// disconnect the clients that did not join after this long, never if `None`
+    pub handshake_timeout: Option<Duration>,
+    /// maximum rate of connections from a single address, the faster connections are
+    /// closed right away. Unlimited if `None`.
+    pub conn_rate: Option<RateLimit>,
+    /// the connections from the loopback addresses are not limited by `conn_rate`
+    pub conn_rate_exempt_local: bool,
+    /// asked before the nickname, the clients that fail it twice are disconnected.
+    /// Nobody is asked if `None`.
+    pub password: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            nickname_attempts: 3,
+            client_queue: 256,
+            shutdown_grace: Duration::from_secs(5),
+            max_line_bytes: 1024,
+            max_nickname_bytes: 32,
+            invalid_utf8: InvalidUtf8::Lossy,
+            ping_interval: None,
+            max_connections: None,
+            timestamps: false,
+            timestamp_utc: false,
+            color: false,
+            json: false,
+            proxy_protocol: false,
+            write_batch: false,
+            allow_empty: false,
+            paste_max_lines: 20,
+            paste_timeout: Duration::from_secs(30),
+            nodelay: true,
+            tcp_keepalive: None,
+            handshake_timeout: Some(Duration::from_secs(30)),
+            conn_rate: None,
+            conn_rate_exempt_local: false,
+            password: None,
+        }
+    }
+}
+
+/// Handling of the lines that are not valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// replace the invalid bytes with U+FFFD
+    Lossy,
+    /// ignore the line and notify the client
+    Drop,
+    /// end the connection
+    Disconnect,
+}
+
+impl InvalidUtf8 {
+    /// Decode `line`, `None` if it must be dropped
+    fn decode(self, line: Vec<u8>) -> io::Result<Option<String>> {
+        match String::from_utf8(line) {
+            Ok(line) => Ok(Some(line)),
+            Err(e) => match self {
+                InvalidUtf8::Lossy => Ok(Some(String::from_utf8_lossy(e.as_bytes()).into_owned())),
+                InvalidUtf8::Drop => Ok(None),
+                InvalidUtf8::Disconnect => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            },
+        }
+    }
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lossy" => Ok(InvalidUtf8::Lossy),
+            "drop" => Ok(InvalidUtf8::Drop),
+            "disconnect" => Ok(InvalidUtf8::Disconnect),
+            _ => Err(format!("expected lossy, drop or disconnect, got {s}")),
+        }
+    }
+}
+
+/// How long a closing connection may take to write its queued messages
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a proxy may take to send the PROXY header of a connection
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many passwords a client may try for a registered nickname, see
+/// [`ChatroomConfig::accounts`](crate::ChatroomConfig::accounts)
+const PASSWORD_ATTEMPTS: usize = 3;
+/// How many times a client may try the [`ServerConfig::password`]
+const SERVER_PASSWORD_ATTEMPTS: usize = 2;
+/// What the clients send instead of a nickname, followed by their resume token, to take
+/// over their detached session, see [`Chatroom::resume`]
+const RESUME: &[u8] = b"/resume ";
+/// How many messages `/last` shows without a count
+const LAST_MESSAGES: usize = 10;
+/// How many messages `/search` shows at most
+const SEARCH_RESULTS: usize = 10;
+
+/// The number of the last accepted connection, on any listener
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Bind the chat service to `addr` and serve `chatroom` to every incoming connection
+/// until `shutdown` completes.
+pub async fn run_server(
+    addr: SocketAddr,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let listener = bind(addr)?;
+    info!(addr = %listener.local_addr()?, "listening");
+    serve(listener, chatroom, config, shutdown).await;
+    Ok(())
+}
+
+/// Bind a listener to `addr`.
+///
+/// Binding to the IPv6 unspecified address (`[::]`) also accepts IPv4 clients
+/// where the OS allows it. Must be called from within a tokio runtime.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        // not fatal: some systems only support IPv6-only sockets
+        let _ = socket.set_only_v6(false);
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Serve `chatroom` to every connection accepted by `listener` until `shutdown` completes.
+///
+/// Each connection is handled by its own task. On shutdown, every user is told the
+/// server is shutting down and disconnected: this function returns once all the
+/// connections are closed, or after the [`ServerConfig::shutdown_grace`] period.
+pub async fn serve(
+    listener: TcpListener,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    serve_transport(listener, chatroom, config, shutdown, Transport::Plain).await
+}
+
+/// Serve `chatroom` to the WebSocket clients accepted by `listener`, see [`serve`].
+///
+/// Every text frame is a line sent by the client, and every line written to the
+/// client is a text frame. The users share the chatroom with the TCP clients.
+pub async fn serve_websocket(
+    listener: TcpListener,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    serve_transport(listener, chatroom, config, shutdown, Transport::WebSocket).await
+}
+
+/// Serve `chatroom` to the TLS clients accepted by `listener`, see [`serve`].
+///
+/// The clients failing the TLS handshake are logged and disconnected.
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    listener: TcpListener,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    tls: TlsConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    serve_transport(listener, chatroom, config, shutdown, Transport::Tls(tls)).await
+}
+
+/// Bind a Unix socket listener to `path`, readable and writable by the owner and the group.
+///
+/// A stale socket file left at `path` is removed. Must be called from within a tokio
+/// runtime.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// Serve `chatroom` to the connections accepted by the Unix socket `listener`, see
+/// [`serve`]. The socket file is removed once served.
+pub async fn serve_unix(
+    listener: UnixListener,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()));
+    let listener = UnixSocket {
+        listener,
+        path: path.as_ref().map_or("unnamed socket".into(), |path| {
+            path.display().to_string().into()
+        }),
+        connections: AtomicU64::new(0),
+    };
+    serve_transport(listener, chatroom, config, shutdown, Transport::Plain).await;
+    if let Some(path) = path {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!(path = %path.display(), error = %e, "cannot remove the socket file");
+        }
+    }
+}
+
+/// Serve `chatroom` to a single client connected by `stream`, e.g. one end of a
+/// [`tokio::io::duplex`] pipe, until the client leaves.
+///
+/// The client is served as the clients accepted by [`serve`], without an address:
+/// bans and the limits per address do not apply.
+pub async fn serve_stream(
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+    chatroom: Chatroom,
+    config: ServerConfig,
+) {
+    Metrics::add(&chatroom.counters().connections, 1);
+    // never shut down: the stream is closed by the client
+    let (_shutting_down, shutdown_signal) = watch::channel(false);
+    chat(
+        stream,
+        Peer::Stream,
+        chatroom,
+        config,
+        shutdown_signal,
+        None,
+    )
+    .await
+}
+
+/// How the clients exchange their lines
+#[derive(Clone)]
+enum Transport {
+    /// plain lines on the stream
+    Plain,
+    /// a WebSocket message per line
+    WebSocket,
+    /// plain lines in a TLS stream
+    #[cfg(feature = "tls")]
+    Tls(TlsConfig),
+}
+
+async fn serve_transport<L: Listener>(
+    listener: L,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+    transport: Transport,
+) {
+    // every connection task holds a clone of `running`: `stopped` completes once they all ended
+    let (running, mut stopped) = mpsc::channel::<()>(1);
+    let (shutting_down, shutdown_signal) = watch::channel(false);
+    // a connection holds a permit until it joins the chatroom
+    let handshakes = config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+    let throttle = config.conn_rate.map(|limit| {
+        Arc::new(ConnectionThrottle::new(
+            limit,
+            config.conn_rate_exempt_local,
+        ))
+    });
+    tokio::pin!(shutdown);
+    let mut accept_errors = AcceptErrors::default();
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = listener.accept() => incoming,
+            _ = &mut shutdown => break,
+        };
+        match incoming {
+            Ok((incoming, peer)) => {
+                Metrics::add(&chatroom.counters().connections, 1);
+                L::configure(&incoming, &config);
+                // behind a proxy, the client address is known once the PROXY header is read
+                if !config.proxy_protocol && is_banned(&chatroom, &peer) {
+                    info!(%peer, "banned address turned away");
+                    turn_away(incoming, b"you are banned\n");
+                    continue;
+                }
+                if !config.proxy_protocol && is_throttled(&throttle, &peer) {
+                    info!(%peer, "connection rate exceeded");
+                    turn_away(incoming, THROTTLED);
+                    continue;
+                }
+                let handshake = match &handshakes {
+                    Some(handshakes) => match handshakes.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            turn_away(incoming, b"server full\n");
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let chatroom = chatroom.clone();
+                let config = config.clone();
+                let shutdown_signal = shutdown_signal.clone();
+                let running = running.clone();
+                let throttle = throttle.clone();
+                let transport = transport.clone();
+                tokio::spawn(async move {
+                    let mut incoming = incoming;
+                    let mut peer = peer;
+                    if config.proxy_protocol {
+                        match timeout(PROXY_TIMEOUT, proxy::read_header(&mut incoming)).await {
+                            Ok(Ok(source)) => peer = source.map_or(peer, Peer::Tcp),
+                            Ok(Err(e)) => {
+                                info!(%peer, error = %e, "invalid PROXY header");
+                                return;
+                            }
+                            Err(_) => {
+                                info!(%peer, "no PROXY header");
+                                return;
+                            }
+                        }
+                        if is_banned(&chatroom, &peer) {
+                            info!(%peer, "banned address turned away");
+                            let _ = incoming.write_all(b"you are banned\n").await;
+                            return;
+                        }
+                        if is_throttled(&throttle, &peer) {
+                            info!(%peer, "connection rate exceeded");
+                            let _ = incoming.write_all(THROTTLED).await;
+                            return;
+                        }
+                    }
+                    match transport {
+                        Transport::Plain => {
+                            chat(incoming, peer, chatroom, config, shutdown_signal, handshake).await
+                        }
+                        Transport::WebSocket => match websocket::accept(incoming).await {
+                            Ok(incoming) => {
+                                chat(incoming, peer, chatroom, config, shutdown_signal, handshake)
+                                    .await
+                            }
+                            Err(e) => {
+                                info!(%peer, error = %e, "WebSocket handshake failed")
+                            }
+                        },
+                        #[cfg(feature = "tls")]
+                        Transport::Tls(tls) => match tls.accept(incoming).await {
+                            Ok(incoming) => {
+                                chat(incoming, peer, chatroom, config, shutdown_signal, handshake)
+                                    .await
+                            }
+                            Err(e) => info!(%peer, error = %e, "TLS handshake failed"),
+                        },
+                    }
+                    drop(running);
+                });
+            }
+
+            Err(e) => {
+                tokio::select! {
+                    _ = accept_errors.back_off(&e) => {}
+                    _ = &mut shutdown => break,
+                }
+                continue;
+            }
+        }
+        accept_errors.reset();
+    }
+
+    info!("shutting down");
+    let _ = shutting_down.send(true);
+    chatroom.shutdown();
+    drop(running);
+
+    // turn away the clients connecting while the other connections are closing
+    let grace = sleep(config.shutdown_grace);
+    tokio::pin!(grace);
+    loop {
+        tokio::select! {
+            _ = stopped.recv() => break,
+            _ = &mut grace => break,
+            Ok((incoming, _)) = listener.accept() => turn_away(incoming, b"server shutting down\n"),
+        }
+    }
+}
+
+/// First delay before accepting again after a failure
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+/// The delay doubles on each consecutive failure, up to this
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// At most one warning about the accept failures is logged during this period
+const ACCEPT_WARNING_PERIOD: Duration = Duration::from_secs(10);
+
+/// Consecutive failures to accept connections, e.g. on file descriptors exhaustion
+#[derive(Default)]
+struct AcceptErrors {
+    /// the delay before the next attempt, `None` after a success
+    delay: Option<Duration>,
+    last_warning: Option<Instant>,
+    /// failures since the last warning
+    unreported: u64,
+}
+
+impl AcceptErrors {
+    /// Log the failure `e` if no warning was logged recently, then wait before the
+    /// next attempt: the failures to accept usually persist for some time.
+    async fn back_off(&mut self, e: &io::Error) {
+        // the transient errors, some concern a single connection
+        let transient = matches!(
+            e.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+        ) || matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::Interrupted
+        );
+        self.unreported += 1;
+        if self
+            .last_warning
+            .is_none_or(|last| last.elapsed() >= ACCEPT_WARNING_PERIOD)
+        {
+            if transient {
+                warn!(error = %e, failures = self.unreported, "accept failed");
+            } else {
+                error!(error = %e, failures = self.unreported, "accept failed");
+            }
+            self.last_warning = Some(Instant::now());
+            self.unreported = 0;
+        }
+        let delay = match self.delay {
+            Some(delay) if transient => (delay * 2).min(ACCEPT_BACKOFF_MAX),
+            None if transient => ACCEPT_BACKOFF_MIN,
+            // errors of the listener itself, that retrying quickly does not fix
+            _ => ACCEPT_BACKOFF_MAX,
+        };
+        self.delay = Some(delay);
+        sleep(delay).await;
+    }
+
+    /// Record a successful accept
+    fn reset(&mut self) {
+        self.delay = None;
+    }
+}
+
+fn is_banned(chatroom: &Chatroom, peer: &Peer) -> bool {
+    peer.addr()
+        .is_some_and(|addr| chatroom.bans().is_banned(addr.ip()))
+}
+
+/// Written to the clients exceeding [`ServerConfig::conn_rate`]
+const THROTTLED: &[u8] = b"too many connections, slow down\n";
+
+/// Record the connection of `peer`, returns whether it exceeds the connection rate
+fn is_throttled(throttle: &Option<Arc<ConnectionThrottle>>, peer: &Peer) -> bool {
+    match (throttle, peer.addr()) {
+        (Some(throttle), Some(addr)) => !throttle.admit(addr.ip()),
+        _ => false,
+    }
+}
+
+/// Answer the HTTP requests accepted by `listener` with the metrics of `chatroom`,
+/// in the Prometheus text format on `/metrics`.
+pub async fn serve_metrics(listener: TcpListener, chatroom: Chatroom) {
+    let mut accept_errors = AcceptErrors::default();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let chatroom = chatroom.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer_scrape(stream, &chatroom).await {
+                        debug!(error = %e, "metrics request failed");
+                    }
+                });
+            }
+            Err(e) => {
+                accept_errors.back_off(&e).await;
+                continue;
+            }
+        }
+        accept_errors.reset();
+    }
+}
+
+/// Maximum size of the request line of a metrics scrape
+const MAX_REQUEST_LINE: usize = 1024;
+
+/// How long an HTTP client may take to send its request line and headers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read the request line of an HTTP request and skip its headers, `None` if the
+/// client sent none within [`REQUEST_TIMEOUT`]
+async fn read_request<R: AsyncRead + Unpin>(
+    lines: &mut LineReader<R>,
+) -> io::Result<Option<String>> {
+    let read = async {
+        let request = match lines.read_line(MAX_REQUEST_LINE).await? {
+            Some(Line::Complete(request)) => String::from_utf8_lossy(&request).into_owned(),
+            _ => return Ok(None),
+        };
+        while let Some(Line::Complete(header)) = lines.read_line(MAX_REQUEST_LINE).await? {
+            if header.trim_ascii().is_empty() {
+                break;
+            }
+        }
+        Ok(Some(request))
+    };
+    timeout(REQUEST_TIMEOUT, read).await.unwrap_or(Ok(None))
+}
+
+async fn answer_scrape(stream: TcpStream, chatroom: &Chatroom) -> io::Result<()> {
+    let (read_stream, mut write_stream) = stream.into_split();
+    let mut lines = LineReader::new(read_stream);
+    let Some(request) = read_request(&mut lines).await? else {
+        return Ok(());
+    };
+
+    let mut request = request.split_whitespace();
+    let response = match (request.next(), request.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = chatroom.metrics().to_prometheus();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    write_stream.write_all(response.as_bytes()).await?;
+    write_stream.shutdown().await
+}
+
+/// Serve the messages broadcast in `chatroom` to the HTTP clients of `GET /events`,
+/// as Server-Sent Events: a `data:` line per message, holding its
+/// [`Message::to_json`] object.
+///
+/// The clients watch without joining, see [`Chatroom::subscribe_observer`]. Their
+/// streams end when the chatroom is shut down.
+pub async fn serve_events(listener: TcpListener, chatroom: Chatroom) {
+    let mut accept_errors = AcceptErrors::default();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let chatroom = chatroom.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = stream_events(stream, &chatroom).await {
+                        debug!(error = %e, "event stream failed");
+                    }
+                });
+            }
+            Err(e) => {
+                accept_errors.back_off(&e).await;
+                continue;
+            }
+        }
+        accept_errors.reset();
+    }
+}
+
+async fn stream_events(stream: TcpStream, chatroom: &Chatroom) -> io::Result<()> {
+    let (read_stream, mut write_stream) = stream.into_split();
+    let mut lines = LineReader::new(read_stream);
+    let Some(request) = read_request(&mut lines).await? else {
+        return Ok(());
+    };
+    let mut request = request.split_whitespace();
+    if (request.next(), request.next()) != (Some("GET"), Some("/events")) {
+        let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        write_stream.write_all(response.as_bytes()).await?;
+        return write_stream.shutdown().await;
+    }
+
+    let mut events = chatroom.subscribe_observer();
+    write_stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n")
+        .await?;
+    loop {
+        tokio::select! {
+            message = events.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let event = format!("data: {}\n\n", message.to_json());
+                write_stream.write_all(event.as_bytes()).await?;
+            }
+            // dropping the receiver unsubscribes once the client is gone
+            line = lines.read_line(MAX_REQUEST_LINE) => {
+                if !matches!(line, Ok(Some(_))) {
+                    break;
+                }
+            }
+        }
+    }
+    write_stream.shutdown().await
+}
+
+/// Write `line` to a client without serving it
+fn turn_away(mut stream: impl Connection + Unpin, line: &'static [u8]) {
+    tokio::spawn(async move {
+        let _ = stream.write_all(line).await;
+    });
+}
+
+/// A client connection: any byte stream, e.g. a [`TcpStream`] or an encrypted stream
+/// wrapping it
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> Connection for S {}
+
+/// Where a client is connected from
+#[derive(Clone)]
+enum Peer {
+    Tcp(SocketAddr),
+    /// the path of the Unix socket and the number of the connection on it
+    Unix {
+        path: Arc<str>,
+        connection: u64,
+    },
+    /// a stream given to [`serve_stream`]
+    Stream,
+}
+
+impl Peer {
+    /// The address of the client, `None` on local sockets
+    fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(*addr),
+            Peer::Unix { .. } | Peer::Stream => None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Unix { path, connection } => write!(f, "{path}#{connection}"),
+            Peer::Stream => f.write_str("stream"),
+        }
+    }
+}
+
+/// A listener of client connections
+trait Listener {
+    type Stream: Connection + Unpin;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, Peer)>>;
+
+    /// Apply the socket options of `config` to an accepted `stream`
+    fn configure(_stream: &Self::Stream, _config: &ServerConfig) {}
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, Peer)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream, Peer::Tcp(addr)))
+    }
+
+    /// Failures are logged: the connection is served without the option
+    fn configure(stream: &TcpStream, config: &ServerConfig) {
+        if let Err(e) = stream.set_nodelay(config.nodelay) {
+            warn!(error = %e, "cannot set TCP_NODELAY");
+        }
+        if let Some(idle) = config.tcp_keepalive {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+            if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+                warn!(error = %e, "cannot enable TCP keepalive");
+            }
+        }
+    }
+}
+
+/// A Unix socket listener, numbering its connections
+struct UnixSocket {
+    listener: UnixListener,
+    path: Arc<str>,
+    connections: AtomicU64,
+}
+
+impl Listener for UnixSocket {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(UnixStream, Peer)> {
+        let (stream, _) = self.listener.accept().await?;
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = self.path.clone();
+        Ok((stream, Peer::Unix { path, connection }))
+    }
+}
+
+async fn chat(
+    stream: impl Connection,
+    peer: Peer,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    mut shutdown: watch::Receiver<bool>,
+    handshake: Option<OwnedSemaphorePermit>,
+) {
+    // logged with every event of the connection, joined or not
+    let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    // the session and nickname are recorded once the client joined
+    let span = info_span!(
+        "connection",
+        conn = connection,
+        %peer,
+        session = field::Empty,
+        nickname = field::Empty
+    );
+
+    async {
+        info!("connected");
+        let talk = talk(
+            stream,
+            peer,
+            connection,
+            &chatroom,
+            &config,
+            &mut shutdown,
+            handshake,
+        );
+        if let Err(e) = talk.await {
+            warn!(error = %e, "I/O error");
+        }
+        info!("connection ended");
+    }
+    .instrument(span)
+    .await;
+}
+
+async fn talk<S: Connection>(
+    stream: S,
+    peer: Peer,
+    connection: u64,
+    chatroom: &Chatroom,
+    config: &ServerConfig,
+    shutdown: &mut watch::Receiver<bool>,
+    handshake: Option<OwnedSemaphorePermit>,
+) -> io::Result<()> {
+    let (read_stream, mut write_stream) = aio::split(stream);
+    let mut lines = LineReader::telnet(read_stream);
+
+    let handshake_steps = async {
+        if let Some(password) = &config.password {
+            if !check_password(&mut write_stream, &mut lines, config, password).await? {
+                return Ok(None);
+            }
+        }
+        let prompt = chatroom.runtime_config().templates.prompt();
+        write_stream
+            .write_all(handshake_line(config, "prompt", &prompt).as_bytes())
+            .await?;
+        join_chatroom(&mut write_stream, &mut lines, &peer, chatroom, config).await
+    };
+
+    // joined sessions are closed by the chatroom on shutdown, only the handshake needs watching
+    let joined = tokio::select! {
+        joined = handshake_steps => joined?,
+        _ = async { shutdown.wait_for(|shutting_down| *shutting_down).await.is_ok() } => {
+            let line = handshake_line(config, "error", "server shutting down");
+            write_stream.write_all(line.as_bytes()).await?;
+            None
+        }
+        _ = expire(config.handshake_timeout) => {
+            info!("join timed out");
+            let line = handshake_line(config, "error", "timed out waiting for nickname");
+            write_stream.write_all(line.as_bytes()).await?;
+            None
+        }
+    };
+    drop(handshake);
+
+    if let Some(joined) = joined {
+        joined.session.set_connection(connection);
+        let output = Arc::new(Output {
+            timestamps: AtomicBool::new(config.timestamps),
+            color: AtomicBool::new(config.color),
+            seq: AtomicBool::new(false),
+            json: AtomicBool::new(config.json),
+        });
+        let (detach, detached) = oneshot::channel();
+        let mut writer = tokio::spawn(write_messages(
+            write_stream,
+            joined.receiver,
+            chatroom.clone(),
+            config.clone(),
+            output.clone(),
+            detached,
+        ));
+
+        let result = tokio::select! {
+            result = read_messages(&mut lines, chatroom, config, &joined.session, &joined.replies, &output) => result,
+            _ = joined.evicted => Ok(()),
+        };
+
+        // the connection ended but the user did not quit, the session can be resumed
+        if joined.session.resume_token().is_some() {
+            let _ = detach.send(());
+            match timeout(FLUSH_TIMEOUT, &mut writer).await {
+                Ok(Ok(receiver)) => joined.session.detach(receiver),
+                _ => writer.abort(),
+            }
+            return result;
+        }
+
+        // Leaving the chatroom drops its message sender: without the replies sender
+        // the writer ends once the queued messages are written. It is aborted if
+        // stuck on a dead peer.
+        drop(joined.session);
+        drop(joined.replies);
+        if timeout(FLUSH_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+        return result;
+    }
+    Ok(())
+}
+
+/// How the lines are written to a joined client, switched by its commands
+struct Output {
+    /// prefix the lines with their time
+    timestamps: AtomicBool,
+    /// color the lines, see [`ServerConfig::color`]
+    color: AtomicBool,
+    /// prefix the lines with their [`Envelope::seq`], always in the JSON objects
+    seq: AtomicBool,
+    /// write the events as JSON objects, see [`ServerConfig::json`]
+    json: AtomicBool,
+}
+
+/// Handle every line written by the client. The JSON clients may also write JSON
+/// objects, see [`json::parse_command`].
+async fn read_messages<S: Connection>(
+    lines: &mut LineReader<ReadHalf<S>>,
+    chatroom: &Chatroom,
+    config: &ServerConfig,
+    session: &Session,
+    replies: &Queue,
+    output: &Output,
+) -> io::Result<()> {
+    let mut first_line = true;
+    while let Some(line) = lines.read_line(config.max_line_bytes).await? {
+        session.record_activity();
+        let reply = |text: String| {
+            replies.push(Message::Notice(text).into());
+        };
+        let line = match line {
+            Line::Complete(line) => line,
+            Line::TooLong(mut line) => {
+                lines.skip_line().await?;
+                reply(format!(
+                    "your message was truncated to {} bytes",
+                    config.max_line_bytes
+                ));
+                trim_partial_char(&mut line);
+                line
+            }
+        };
+        let Some(line) = config.invalid_utf8.decode(line)? else {
+            reply("message dropped: invalid encoding".to_string());
+            continue;
+        };
+        // the blank lines keep the user active, without noise for the others
+        if line.trim().is_empty() && !config.allow_empty {
+            continue;
+        }
+        let first_line = std::mem::replace(&mut first_line, false);
+        let object;
+        let command = if output.json.load(Ordering::Relaxed) && line.starts_with('{') {
+            let parsed = match serde_json::from_str(&line) {
+                Ok(parsed) => {
+                    object = parsed;
+                    json::parse_command(&object)
+                }
+                Err(e) => Err(format!("invalid JSON: {e}")),
+            };
+            match parsed {
+                Ok(command) => command,
+                Err(e) => {
+                    reply(e);
+                    continue;
+                }
+            }
+        } else {
+            Command::parse(line.trim())
+        };
+        match command {
+            // the chatroom tells the user about the rate limit itself
+            Command::Message(text) => match session.send_message(text.to_string()) {
+                Ok(recipients) => debug!(bytes = text.len(), recipients, "message sent"),
+                Err(e) => debug!(error = %e, "message dropped"),
+            },
+            Command::Emote(action) => match session.send_emote(action.to_string()) {
+                Ok(recipients) => debug!(bytes = action.len(), recipients, "emote sent"),
+                Err(e) => debug!(error = %e, "emote dropped"),
+            },
+            Command::Paste => match read_paste(lines, config, session).await? {
+                Ok(block) => match session.send_paste(block) {
+                    Ok(recipients) => debug!(recipients, "paste sent"),
+                    Err(e) => debug!(error = %e, "paste dropped"),
+                },
+                Err(notice) => reply(notice),
+            },
+            Command::Private { to, text } => {
+                match chatroom.send_private(session, to, text.to_string()) {
+                    Ok(()) => {}
+                    // the chatroom tells the user about its limits itself
+                    Err(
+                        e @ (SendError::RateLimited
+                        | SendError::Muted
+                        | SendError::ReadOnly
+                        | SendError::NotConnected),
+                    ) => debug!(error = %e, "private message dropped"),
+                    Err(e) => reply(e.to_string()),
+                }
+            }
+            Command::Who => {
+                let room = session.room().unwrap_or_default();
+                replies.push(Message::UserList(chatroom.who(&room)).into());
+            }
+            Command::Whois(target) => {
+                let Some(user) = chatroom.whois(session, target) else {
+                    reply("no such user".to_string());
+                    continue;
+                };
+                let nickname = &user.nickname;
+                reply(format!(
+                    "{nickname}: connected for {}, idle for {}",
+                    format_duration(user.connected_for),
+                    format_duration(user.idle_for)
+                ));
+                match user.away.as_deref() {
+                    Some("") => reply(format!("{nickname} is away")),
+                    Some(reason) => reply(format!("{nickname} is away: {reason}")),
+                    None => {}
+                }
+                if user.operator {
+                    reply(format!("{nickname} is an operator"));
+                }
+                if let Some(addr) = user.peer_addr {
+                    reply(format!("{nickname} is connected from {}", addr.ip()));
+                }
+                if let Some(lag) = user.lag {
+                    let lines = if lag == 1 { "line" } else { "lines" };
+                    reply(format!("{nickname} is {lag} {lines} behind its last /ack"));
+                }
+            }
+            Command::Join(room) => {
+                if let Err(e) = session.join_room(room) {
+                    reply(e.to_string());
+                }
+            }
+            Command::Leave => {
+                if let Err(e) = session.join_room(Chatroom::LOBBY) {
+                    reply(e.to_string());
+                }
+            }
+            Command::Rooms => {
+                replies.push(Message::RoomList(chatroom.rooms()).into());
+            }
+            Command::Invite(target) => match session.invite(target) {
+                Ok(room) => reply(format!("{target} is invited to #{room}")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Mode { room, mode } => {
+                let changed = match mode {
+                    RoomMode::InviteOnly(invite_only) => session.set_invite_only(room, invite_only),
+                    RoomMode::Limit(limit) => session.set_room_limit(room, limit),
+                };
+                if let Err(e) = changed {
+                    reply(e.to_string());
+                }
+            }
+            Command::Kick { target, reason } => {
+                if let Err(e) = chatroom.kick(session, target, reason.map(str::to_string)) {
+                    reply(e.to_string());
+                }
+            }
+            Command::Ban(target) => match chatroom.ban(session, target) {
+                Ok(ip) => reply(format!("{ip} is banned")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Unban(ip) => match chatroom.unban(session, ip) {
+                Ok(true) => reply(format!("{ip} is no longer banned")),
+                Ok(false) => reply(format!("{ip} is not banned")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Mute { target, duration } => match chatroom.mute(session, target, duration) {
+                Ok(()) => reply(format!(
+                    "{target} is muted for {}",
+                    format_duration(duration)
+                )),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Unmute(target) => match chatroom.unmute(session, target) {
+                Ok(true) => reply(format!("{target} is no longer muted")),
+                Ok(false) => reply(format!("{target} is not muted")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Lockdown(locked) => match chatroom.set_locked(session, locked) {
+                Ok(true) => {}
+                Ok(false) if locked => reply("the room is already read-only".to_string()),
+                Ok(false) => reply("the room is not read-only".to_string()),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Op(target) => match chatroom.op(session, target) {
+                Ok(()) => reply(format!("{target} is now an operator")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Deop(target) => match chatroom.deop(session, target) {
+                Ok(()) => reply(format!("{target} is no longer an operator")),
+                Err(e) => reply(e.to_string()),
+            },
+            Command::Nick(nickname) => {
+                if let Err(e) = session.rename(nickname.to_string()) {
+                    reply(e.to_string());
+                }
+            }
+            Command::Timestamps(enabled) => output.timestamps.store(enabled, Ordering::Relaxed),
+            Command::Color(enabled) => output.color.store(enabled, Ordering::Relaxed),
+            Command::Seq(enabled) => output.seq.store(enabled, Ordering::Relaxed),
+            Command::Ack(seq) => {
+                if !session.ack(seq) {
+                    reply(format!("no line is numbered {seq} yet"));
+                }
+            }
+            Command::Protocol { json } if first_line => output.json.store(json, Ordering::Relaxed),
+            Command::Protocol { .. } => {
+                reply("the protocol can only be chosen by the first line".to_string())
+            }
+            Command::Echo(enabled) => session.set_echo(enabled),
+            Command::Bell(enabled) => session.set_bell(enabled),
+            Command::Topic(None) => match session.topic() {
+                Some(topic) => reply(format!("topic: {} (set by {})", topic.text, topic.set_by)),
+                None => reply("no topic is set".to_string()),
+            },
+            Command::Ignore(None) => match session.ignored().as_slice() {
+                [] => reply("you are not ignoring anyone".to_string()),
+                ignored => reply(format!("ignored users: {}", ignored.join(", "))),
+            },
+            Command::Ignore(Some(nickname)) => {
+                session.ignore(nickname);
+                reply(format!("you are now ignoring {nickname}"));
+            }
+            Command::Unignore(nickname) => {
+                if session.unignore(nickname) {
+                    reply(format!("you are no longer ignoring {nickname}"));
+                } else {
+                    reply(format!("you are not ignoring {nickname}"));
+                }
+            }
+            // the writer ends once the goodbye and the queued messages are written
+            Command::Quit(message) => {
+                reply("goodbye".to_string());
+                session.quit(message.map(str::to_string));
+                return Ok(());
+            }
+            Command::Away(None) | Command::Back if session.away().is_some() => {
+                if session.set_away(None).is_ok() {
+                    reply("you are back".to_string());
+                }
+            }
+            Command::Back => reply("you are not away".to_string()),
+            Command::Away(reason) => {
+                let reason = reason.unwrap_or_default();
+                if session.set_away(Some(reason.to_string())).is_ok() {
+                    match session.away().unwrap_or_default().as_str() {
+                        "" => reply("you are now away".to_string()),
+                        reason => reply(format!("you are now away: {reason}")),
+                    }
+                }
+            }
+            Command::Stats => {
+                let stats = chatroom.stats();
+                let mut text = format!(
+                    "users: {}, uptime: {}, messages: {}",
+                    stats.users,
+                    format_duration(stats.uptime),
+                    stats.messages
+                );
+                if stats.locked {
+                    text.push_str(", read-only");
+                }
+                if let Some(session) = session.stats() {
+                    text.push_str(&format!(
+                        ", your session: {}, your messages: {}",
+                        format_duration(session.connected_for),
+                        session.messages
+                    ));
+                }
+                reply(text);
+            }
+            Command::Last(count) => {
+                let limit = chatroom.history_limit();
+                if limit == 0 {
+                    reply("history is disabled on this server".to_string());
+                    continue;
+                }
+                if count.is_some_and(|count| count > limit) {
+                    reply(format!("only the last {limit} messages are kept"));
+                }
+                let count = count.unwrap_or(LAST_MESSAGES).min(limit);
+                for message in session.last_messages(count) {
+                    replies.push(message);
+                }
+            }
+            Command::Search(term) => {
+                let search = match session.search(term) {
+                    Ok(search) => search,
+                    Err(e) => {
+                        reply(e.to_string());
+                        continue;
+                    }
+                };
+                match task::spawn_blocking(move || search.run(SEARCH_RESULTS)).await {
+                    Ok(Ok(found)) if found.is_empty() => reply("no message found".to_string()),
+                    Ok(Ok(found)) => {
+                        for message in found {
+                            reply(format!("[archive] {message}"));
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        warn!(error = %e, "cannot search the archive");
+                        reply("the search failed".to_string());
+                    }
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+            Command::Topic(Some(text)) => {
+                if let Err(e) = session.set_topic(text.to_string()) {
+                    reply(e.to_string());
+                }
+            }
+            Command::Help => {
+                reply("commands:".to_string());
+                for command in COMMANDS {
+                    reply(format!("{}: {}", command.usage, command.description));
+                }
+            }
+            Command::Usage(usage) => reply(format!("usage: {usage}")),
+            Command::Unknown(name) => reply(format!("unknown command: {name} — try /help")),
+        }
+    }
+    Ok(())
+}
+
+/// Read the lines of a `/paste`, up to the line holding a single `.`. Returns the
+/// notice for the client of a paste not terminated within
+/// [`ServerConfig::paste_timeout`], or beyond the limits, a line too long included:
+/// its lines are then read up to the `.` and dropped.
+async fn read_paste<R: AsyncRead + Unpin>(
+    lines: &mut LineReader<R>,
+    config: &ServerConfig,
+    session: &Session,
+) -> io::Result<Result<Vec<String>, String>> {
+    let deadline = Instant::now() + config.paste_timeout;
+    let mut block = Vec::new();
+    let mut bytes = 0;
+    let mut dropped = None;
+    loop {
+        let read = timeout_at(deadline, lines.read_line(config.max_line_bytes)).await;
+        let Ok(line) = read else {
+            let timeout = format_duration(config.paste_timeout);
+            return Ok(Err(format!("paste aborted: no . line within {timeout}")));
+        };
+        // the lines of a long paste keep the user active
+        let line = match line? {
+            Some(Line::Complete(line)) => {
+                session.record_activity();
+                line
+            }
+            Some(Line::TooLong(_)) => {
+                session.record_activity();
+                lines.skip_line().await?;
+                let max = config.max_line_bytes;
+                dropped.get_or_insert(format!("paste dropped: more than {max} bytes"));
+                continue;
+            }
+            None => return Ok(Err("paste aborted".to_string())),
+        };
+        let Some(line) = config.invalid_utf8.decode(line)? else {
+            dropped.get_or_insert("paste dropped: invalid encoding".to_string());
+            continue;
+        };
+        let line = line.trim_end();
+        if line == "." {
+            break;
+        }
+        // the lines of the block are separated by line feeds
+        bytes += line.len() + usize::from(!block.is_empty());
+        if block.len() == config.paste_max_lines {
+            let max = config.paste_max_lines;
+            dropped.get_or_insert(format!("paste dropped: more than {max} lines"));
+        } else if bytes > config.max_line_bytes {
+            let max = config.max_line_bytes;
+            dropped.get_or_insert(format!("paste dropped: more than {max} bytes"));
+        }
+        block.push(line.to_string());
+    }
+    Ok(match dropped {
+        Some(notice) => Err(notice),
+        None if block.is_empty() => Err("paste dropped: no lines".to_string()),
+        None => Ok(block),
+    })
+}
+
+/// Forward every message received on `receiver` to the client, until the chatroom
+/// drops the session. The messages are rendered per the current `output`.
+async fn write_messages<S: Connection>(
+    stream: WriteHalf<S>,
+    mut receiver: Receiver<Envelope>,
+    chatroom: Chatroom,
+    config: ServerConfig,
+    output: Arc<Output>,
+    mut detach: oneshot::Receiver<()>,
+) -> Receiver<Envelope> {
+    let clock = if config.timestamp_utc {
+        Timestamps::Utc
+    } else {
+        Timestamps::Local
+    };
+    let mut ping = config
+        .ping_interval
+        .map(|period| interval_at(Instant::now() + period, period));
+    let mut stream = BufWriter::new(stream);
+    let render = |message: Envelope| {
+        let prefix = output.timestamps.load(Ordering::Relaxed).then_some(clock);
+        let json = output.json.load(Ordering::Relaxed);
+        let mut line = if json {
+            json::render(&message, prefix)
+        } else if output.color.load(Ordering::Relaxed) {
+            color::render(&message, prefix, &chatroom.runtime_config().templates)
+        } else {
+            message.render(prefix, &chatroom.runtime_config().templates)
+        };
+        match message.seq {
+            Some(seq) if !json && output.seq.load(Ordering::Relaxed) => {
+                // after the BEL, which comes first
+                line.insert_str(usize::from(message.bell), &format!("<{seq}> "));
+            }
+            _ => {}
+        }
+        line.push('\n');
+        Metrics::add(&chatroom.counters().bytes_written, line.len() as u64);
+        line
+    };
+    loop {
+        let message = tokio::select! {
+            message = receiver.recv() => message,
+            _ = tick(&mut ping) => Some(Message::Notice("ping".to_string()).into()),
+            // the next messages are kept for the connection resuming the session
+            _ = &mut detach => return receiver,
+        };
+        let Some(message) = message else {
+            break;
+        };
+        if stream.write_all(render(message).as_bytes()).await.is_err() {
+            return receiver;
+        }
+        if config.write_batch {
+            // the other queued messages are written with a single flush
+            while let Ok(message) = receiver.try_recv() {
+                if stream.write_all(render(message).as_bytes()).await.is_err() {
+                    return receiver;
+                }
+            }
+        }
+        if stream.flush().await.is_err() {
+            return receiver;
+        }
+    }
+    // flushes the buffer first
+    let _ = stream.shutdown().await;
+    receiver
+}
+
+/// A line written to the client before it joined, as a JSON object of `kind` for
+/// the [`ServerConfig::json`] clients
+fn handshake_line(config: &ServerConfig, kind: &str, text: &str) -> String {
+    match config.json {
+        true => format!("{}\n", json::handshake_line(kind, text)),
+        false => format!("{text}\n"),
+    }
+}
+
+/// Completes on the next tick of `interval`, never without interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Completes after `duration`, never without duration
+async fn expire(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A client that joined the chatroom
+struct Joined {
+    session: Session,
+    /// messages to write to the client
+    receiver: Receiver<Envelope>,
+    /// replies to the client commands
+    replies: Queue,
+    /// completes when the chatroom evicts the session
+    evicted: oneshot::Receiver<()>,
+}
+
+/// Ask the [`ServerConfig::password`], up to [`SERVER_PASSWORD_ATTEMPTS`] times.
+/// Returns whether the client gave it.
+///
+/// The attempts are logged, with the peer of the connection span, never the submitted
+/// passwords.
+async fn check_password<S: Connection>(
+    stream: &mut WriteHalf<S>,
+    lines: &mut LineReader<ReadHalf<S>>,
+    config: &ServerConfig,
+    password: &str,
+) -> io::Result<bool> {
+    for attempt in 1..=SERVER_PASSWORD_ATTEMPTS {
+        let line = handshake_line(config, "password", "password:");
+        stream.write_all(line.as_bytes()).await?;
+        let submitted = match lines.read_line(config.max_line_bytes).await? {
+            Some(Line::Complete(submitted)) => submitted,
+            Some(Line::TooLong(_)) => Vec::new(),
+            None => {
+                info!("disconnected at the password prompt");
+                return Ok(false);
+            }
+        };
+        if constant_time_eq(&submitted, password.as_bytes()) {
+            info!(attempt, "server password accepted");
+            return Ok(true);
+        }
+        info!(attempt, "server password rejected");
+        let line = handshake_line(config, "error", "Wrong password.");
+        stream.write_all(line.as_bytes()).await?;
+    }
+    Ok(false)
+}
+
+/// Whether `a` and `b` are equal, in a time that depends on neither. Their digests are
+/// compared, so that the time does not depend on their lengths either.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    Blake2b512::digest(a)
+        .iter()
+        .zip(&Blake2b512::digest(b))
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// Ask the password of the registered `nickname`, up to [`PASSWORD_ATTEMPTS`] times.
+/// Returns whether the client gave it.
+///
+/// The password lines are only handed to the accounts, never logged.
+async fn authenticate<S: Connection>(
+    stream: &mut WriteHalf<S>,
+    lines: &mut LineReader<ReadHalf<S>>,
+    chatroom: &Chatroom,
+    config: &ServerConfig,
+    nickname: &str,
+) -> io::Result<bool> {
+    for attempt in 1..=PASSWORD_ATTEMPTS {
+        let line = handshake_line(config, "password", "password:");
+        stream.write_all(line.as_bytes()).await?;
+        let password = match lines.read_line(config.max_line_bytes).await? {
+            Some(Line::Complete(password)) => String::from_utf8_lossy(&password).into_owned(),
+            Some(Line::TooLong(_)) | None => return Ok(false),
+        };
+        let accounts = chatroom.runtime_config().accounts.clone();
+        let nickname = nickname.to_string();
+        // hashing takes tens of milliseconds, off the tasks of the other clients
+        let verified = task::spawn_blocking(move || accounts.verify(&nickname, &password))
+            .await
+            .unwrap_or(false);
+        if verified {
+            return Ok(true);
+        }
+        info!(attempt, "wrong password");
+        if attempt < PASSWORD_ATTEMPTS {
+            let line = handshake_line(config, "error", "Wrong password.");
+            stream.write_all(line.as_bytes()).await?;
+        }
+    }
+    Ok(false)
+}
+
+/// Read nicknames from the client until one is accepted by the chatroom.
+///
+/// Returns `None` when the client gave up (closed the connection) or exhausted
+/// its nickname attempts.
+async fn join_chatroom<S: Connection>(
+    stream: &mut WriteHalf<S>,
+    lines: &mut LineReader<ReadHalf<S>>,
+    peer: &Peer,
+    chatroom: &Chatroom,
+    config: &ServerConfig,
+) -> io::Result<Option<Joined>> {
+    // the resume lines may be longer than the nicknames
+    let too_long =
+        |line: &[u8]| line.len() > config.max_nickname_bytes && !line.starts_with(RESUME);
+    let max_bytes = config.max_nickname_bytes.max(RESUME.len() + 32);
+    for attempt in 1..=config.nickname_attempts {
+        let nickname = match lines.read_line(max_bytes).await? {
+            // undecodable nicknames are refused by the chatroom like any invalid nickname
+            Some(Line::Complete(nickname))
+                if !too_long(&nickname) && config.invalid_utf8 != InvalidUtf8::Disconnect =>
+            {
+                String::from_utf8_lossy(&nickname).into_owned()
+            }
+            Some(Line::Complete(nickname)) if !too_long(&nickname) => InvalidUtf8::Disconnect
+                .decode(nickname)?
+                .unwrap_or_default(),
+            Some(_) => {
+                info!("join rejected: nickname too long");
+                let line = handshake_line(config, "error", "Nickname too long.");
+                stream.write_all(line.as_bytes()).await?;
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        let (sender, receiver) = channel(config.client_queue);
+        let (evict, evicted) = oneshot::channel();
+        let on_disconnect = move || {
+            let _ = evict.send(());
+        };
+        let replies = sender.clone();
+        let nickname = nickname.trim();
+        if let Some(token) = nickname.strip_prefix("/resume ") {
+            match chatroom.resume(token.trim(), peer.addr(), on_disconnect) {
+                Ok(resumed) => {
+                    let nickname = resumed.session.nickname().unwrap_or_default();
+                    Span::current()
+                        .record("session", resumed.session.id())
+                        .record("nickname", &nickname);
+                    let line =
+                        handshake_line(config, "resumed", &format!("Resumed as {nickname}."));
+                    stream.write_all(line.as_bytes()).await?;
+                    let queue = resumed.session.queue();
+                    return Ok(Some(Joined {
+                        replies: queue.unwrap_or_else(|| Queue::standalone(resumed.sender)),
+                        session: resumed.session,
+                        receiver: resumed.receiver,
+                        evicted,
+                    }));
+                }
+                Err(e) => {
+                    info!(reason = %e, "resume rejected");
+                    let line = handshake_line(config, "error", &e.to_string());
+                    stream.write_all(line.as_bytes()).await?;
+                    if e == ResumeError::ShuttingDown {
+                        return Ok(None);
+                    }
+                    if attempt < config.nickname_attempts {
+                        let prompt = chatroom.runtime_config().templates.prompt_again();
+                        let line = handshake_line(config, "prompt", &prompt);
+                        stream.write_all(line.as_bytes()).await?;
+                    }
+                    continue;
+                }
+            }
+        }
+        if chatroom.runtime_config().accounts.is_registered(nickname)
+            && !authenticate(stream, lines, chatroom, config, nickname).await?
+        {
+            let e = JoinError::AuthenticationFailed;
+            chatroom.counters().join_rejected(&e);
+            info!(nickname, reason = %e, "join rejected");
+            let line = handshake_line(config, "error", &e.to_string());
+            stream.write_all(line.as_bytes()).await?;
+            return Ok(None);
+        }
+        let requested = nickname.to_string();
+        let joined = match peer.addr() {
+            Some(addr) => chatroom.join_from(requested, sender, addr, on_disconnect),
+            None => chatroom.join_with_disconnect(requested, sender, on_disconnect),
+        };
+        match joined {
+            Ok(session) => {
+                Span::current()
+                    .record("session", session.id())
+                    .record("nickname", nickname);
+                return Ok(Some(Joined {
+                    replies: session
+                        .queue()
+                        .unwrap_or_else(|| Queue::standalone(replies)),
+                    session,
+                    receiver,
+                    evicted,
+                }));
+            }
+            Err(e) => {
+                info!(nickname, reason = %e, "join rejected");
+                let line = handshake_line(config, "error", &e.to_string());
+                stream.write_all(line.as_bytes()).await?;
+                if matches!(e, JoinError::ShuttingDown | JoinError::TooManySessions) {
+                    return Ok(None);
+                }
+                if attempt < config.nickname_attempts {
+                    let prompt = chatroom.runtime_config().templates.prompt_again();
+                    let line = handshake_line(config, "prompt", &prompt);
+                    stream.write_all(line.as_bytes()).await?;
+                }
+            }
+        }
+    }
+    Ok(None)
+}
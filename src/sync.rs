@@ -0,0 +1,46 @@
+//! The locks of the chatroom: those of `parking_lot`, or of `loom` when the crate is
+//! built with `--cfg loom` for the models of `tests/loom.rs` to explore the
+//! interleavings of the sessions.
+
+#[cfg(not(loom))]
+pub(crate) use parking_lot::{Mutex, MutexGuard, RwLock};
+
+#[cfg(loom)]
+pub(crate) use self::modelled::{Mutex, MutexGuard, RwLock};
+
+/// The `loom` locks behind the interface of the `parking_lot` ones: they are never
+/// poisoned, a panic while holding one fails the model anyway.
+#[cfg(loom)]
+mod modelled {
+    pub(crate) use loom::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Mutex<T>(loom::sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Self(loom::sync::Mutex::new(value))
+        }
+
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct RwLock<T>(loom::sync::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Self(loom::sync::RwLock::new(value))
+        }
+
+        pub(crate) fn read(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap()
+        }
+
+        pub(crate) fn write(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap()
+        }
+    }
+}
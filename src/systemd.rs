@@ -0,0 +1,60 @@
+//! Integration with systemd: socket activation and readiness notifications
+
+use std::{
+    env, io,
+    net::TcpListener as StdTcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+};
+
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// The first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening socket passed by systemd, `None` without socket activation.
+///
+/// Socket activation is detected from the `LISTEN_FDS` and `LISTEN_PID` environment
+/// variables, `forced` assumes it. Must be called from within a tokio runtime, once:
+/// the socket is owned by the returned listener.
+pub fn activated_listener(forced: bool) -> io::Result<Option<TcpListener>> {
+    let activated = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string())
+        && env::var("LISTEN_FDS").is_ok_and(|fds| fds.parse::<u32>().is_ok_and(|fds| fds >= 1));
+    if !activated && !forced {
+        return Ok(None);
+    }
+    // SAFETY: systemd passes the listening socket as the first file descriptor after
+    // the standard streams, nothing else owns it
+    let listener = unsafe { StdTcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Some)
+}
+
+/// Tell systemd about the state of the service, e.g. `READY=1`.
+///
+/// Does nothing when not started by systemd, without `NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        match path.as_bytes().strip_prefix(b"@") {
+            // a socket in the abstract namespace
+            Some(name) => {
+                socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)
+            }
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+    if let Err(e) = sent {
+        warn!(state, error = %e, "cannot notify systemd");
+    }
+}
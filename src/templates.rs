@@ -0,0 +1,349 @@
+//! The lines written to the clients, customizable from a TOML file
+
+use std::{fmt::Display, sync::LazyLock};
+
+use crate::{
+    chatroom::{format_duration, Message},
+    config_file::{parse_config, ConfigValue},
+};
+
+/// A value substituted in a template, written `{name}`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Nick,
+    From,
+    To,
+    Text,
+    Users,
+    Rooms,
+    By,
+    Old,
+    New,
+    Online,
+    Count,
+}
+
+impl Placeholder {
+    fn name(self) -> &'static str {
+        match self {
+            Placeholder::Nick => "nick",
+            Placeholder::From => "from",
+            Placeholder::To => "to",
+            Placeholder::Text => "text",
+            Placeholder::Users => "users",
+            Placeholder::Rooms => "rooms",
+            Placeholder::By => "by",
+            Placeholder::Old => "old",
+            Placeholder::New => "new",
+            Placeholder::Online => "online",
+            Placeholder::Count => "count",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Value(Placeholder),
+}
+
+/// A line with `{placeholder}`s, `{{` and `}}` are literal braces
+#[derive(Clone, Debug)]
+struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse `text`, refusing the placeholders not in `allowed`
+    fn parse(text: &str, allowed: &[Placeholder]) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '{' | '}' if rest.starts_with(c) => {
+                    rest = &rest[1..];
+                    literal.push(c);
+                }
+                '{' => {
+                    let Some((name, after)) = rest.split_once('}') else {
+                        return Err("unclosed {, write {{ for a brace".to_string());
+                    };
+                    let Some(placeholder) = allowed.iter().find(|p| p.name() == name) else {
+                        let expected: Vec<_> = allowed
+                            .iter()
+                            .map(|p| format!("{{{}}}", p.name()))
+                            .collect();
+                        return Err(match expected.as_slice() {
+                            [] => format!("unknown placeholder {{{name}}}, expected none"),
+                            _ => format!(
+                                "unknown placeholder {{{name}}}, expected {}",
+                                expected.join(", ")
+                            ),
+                        });
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Value(*placeholder));
+                    rest = after;
+                }
+                '}' => return Err("unmatched }, write }} for a brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    fn render(&self, values: &[(Placeholder, &str)]) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => line.push_str(text),
+                Part::Value(placeholder) => {
+                    if let Some((_, value)) = values.iter().find(|(p, _)| p == placeholder) {
+                        line.push_str(value);
+                    }
+                }
+            }
+        }
+        line
+    }
+}
+
+/// Declare the templates: their field, built-in text and placeholders
+macro_rules! templates {
+    ($($(#[doc = $doc:literal])* $field:ident: $default:literal, [$($placeholder:ident),*];)*) => {
+        /// The lines written to the clients, one template per kind of line.
+        ///
+        /// The [`Default`] templates are the built-in lines, [`MessageTemplates::parse`]
+        /// replaces some of them.
+        #[derive(Clone, Debug)]
+        pub struct MessageTemplates {
+            $($(#[doc = $doc])* $field: Template,)*
+        }
+
+        impl Default for MessageTemplates {
+            fn default() -> Self {
+                Self {
+                    $($field: Template::parse($default, &[$(Placeholder::$placeholder),*])
+                        .expect("invalid built-in template"),)*
+                }
+            }
+        }
+
+        impl MessageTemplates {
+            /// Replace the template named `name` with `text`
+            fn set(&mut self, name: &str, text: &str) -> Result<(), String> {
+                match name {
+                    $(stringify!($field) => {
+                        self.$field = Template::parse(text, &[$(Placeholder::$placeholder),*])?
+                    })*
+                    _ => return Err(format!("unknown template {name}")),
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+templates! {
+    /// asks the connecting clients for their nickname
+    prompt: "Welcome to our chat room, please enter your nickname:", [];
+    /// asks again after a rejected nickname
+    prompt_again: "Please enter your nickname:", [];
+    joined: "* {nick} joined the room", [Nick];
+    /// a join with the number of users of the room, with `--announce-counts`
+    joined_count: "* {nick} joined the room ({count} users)", [Nick, Count];
+    left: "* {nick} left the room", [Nick];
+    /// a leave with the time the user was online, e.g. `42m13s`
+    left_online: "* {nick} left the room (online {online})", [Nick, Online];
+    /// a leave with the parting message of `/quit`
+    left_reason: "* {nick} left the room: {text}", [Nick, Text];
+    /// the user list sent to the joining user
+    connected_users: "* Welcome, the room contains {count} users: {users}", [Count, Users];
+    /// the user list holding a single user
+    connected_user: "* Welcome, the room contains 1 user: {users}", [Users];
+    /// the user list of an empty room
+    empty_room: "* Welcome, the room is empty", [];
+    message: "[{from}] {text}", [From, Text];
+    /// a message mentioning the user with `@nick`
+    mentioned: "(!) [{from}] {text}", [From, Text];
+    emote: "* {from} {text}", [From, Text];
+    /// the first line of a `/paste`, followed by its lines
+    paste: "[{from}] (paste, {count} lines)", [From, Count];
+    /// a line of a `/paste`
+    paste_line: "    {text}", [Text];
+    private: "[{from} -> you] {text}", [From, Text];
+    private_sent: "[you -> {to}] {text}", [To, Text];
+    user_list: "* Users in the room: {users}", [Users];
+    room_list: "* Rooms: {rooms}", [Rooms];
+    notice: "* {text}", [Text];
+    server_notice: "* [server] {text}", [Text];
+    motd: "* {text}", [Text];
+    kicked: "* {nick} was kicked by {by}", [Nick, By];
+    banned: "* {nick} was banned by {by}", [Nick, By];
+    renamed: "* {old} is now known as {new}", [Old, New];
+    topic: "* topic: {text}", [Text, By];
+    topic_cleared: "* {by} cleared the topic", [By];
+    /// a replayed message, `{text}` is its line without its leading `* `
+    history: "* [history] {text}", [Text];
+}
+
+/// Rendering of the [`Display`] of the messages
+static BUILT_IN: LazyLock<MessageTemplates> = LazyLock::new(MessageTemplates::default);
+
+impl MessageTemplates {
+    /// Parse a TOML file of templates, e.g. `joined = "* {nick} is here"`: the
+    /// templates not in the file keep their built-in text.
+    ///
+    /// The file holds a string per template, the templates may use `{{` and `}}` for
+    /// braces and the placeholders of their built-in text.
+    pub fn parse(toml: &str) -> Result<Self, TemplateError> {
+        let mut templates = Self::default();
+        let entries = parse_config(toml).map_err(|e| TemplateError {
+            line: e.line,
+            field: None,
+            reason: e.reason,
+        })?;
+        for entry in entries {
+            let error = |field: Option<&str>, reason: &str| TemplateError {
+                line: entry.line,
+                field: field.map(str::to_string),
+                reason: reason.to_string(),
+            };
+            let name = entry.key.as_str();
+            let ConfigValue::String(text) = &entry.value else {
+                return Err(error(None, "expected a string"));
+            };
+            templates
+                .set(name, text)
+                .map_err(|reason| error(Some(name), &reason))?;
+        }
+        Ok(templates)
+    }
+
+    /// The templates rendering the [`Display`] of the messages
+    pub(crate) fn built_in() -> &'static Self {
+        &BUILT_IN
+    }
+
+    /// The line asking the connecting clients for their nickname
+    pub(crate) fn prompt(&self) -> String {
+        self.prompt.render(&[])
+    }
+
+    /// The line asking for a nickname again, after a rejected one
+    pub(crate) fn prompt_again(&self) -> String {
+        self.prompt_again.render(&[])
+    }
+
+    pub(crate) fn render(&self, message: &Message) -> String {
+        use Placeholder::*;
+        match message {
+            Message::Joined {
+                nickname,
+                users: Some(users),
+            } => self
+                .joined_count
+                .render(&[(Nick, nickname), (Count, &users.to_string())]),
+            Message::Joined { nickname, .. } => self.joined.render(&[(Nick, nickname)]),
+            Message::Left {
+                nickname,
+                reason: Some(reason),
+                ..
+            } => self.left_reason.render(&[(Nick, nickname), (Text, reason)]),
+            Message::Left {
+                nickname,
+                online: Some(online),
+                ..
+            } => self
+                .left_online
+                .render(&[(Nick, nickname), (Online, &format_duration(*online))]),
+            Message::Left { nickname, .. } => self.left.render(&[(Nick, nickname)]),
+            Message::ConnectedUsers(users) => match users.as_slice() {
+                [] => self.empty_room.render(&[]),
+                [user] => self.connected_user.render(&[(Users, user)]),
+                users => self.connected_users.render(&[
+                    (Count, &users.len().to_string()),
+                    (Users, &users.join(", ")),
+                ]),
+            },
+            Message::Message { from, text } => self.message.render(&[(From, from), (Text, text)]),
+            Message::Mentioned { from, text } => {
+                self.mentioned.render(&[(From, from), (Text, text)])
+            }
+            Message::Emote { from, action } => self.emote.render(&[(From, from), (Text, action)]),
+            Message::Paste { from, lines } => {
+                let count = lines.len().to_string();
+                let mut rendered = self.paste.render(&[(From, from), (Count, &count)]);
+                for line in lines.iter() {
+                    rendered.push('\n');
+                    rendered.push_str(&self.paste_line.render(&[(Text, line)]));
+                }
+                rendered
+            }
+            Message::Private { from, text } => self.private.render(&[(From, from), (Text, text)]),
+            Message::PrivateSent { to, text } => {
+                self.private_sent.render(&[(To, to), (Text, text)])
+            }
+            Message::UserList(users) => self.user_list.render(&[(Users, &users.join(", "))]),
+            Message::RoomList(rooms) => {
+                let rooms: Vec<_> = rooms
+                    .iter()
+                    .map(|room| {
+                        let users = match room.limit {
+                            Some(limit) => format!("{}/{limit}", room.users),
+                            None => room.users.to_string(),
+                        };
+                        match room.invite_only {
+                            true => format!("#{} ({users}, invite-only)", room.name),
+                            false => format!("#{} ({users})", room.name),
+                        }
+                    })
+                    .collect();
+                self.room_list.render(&[(Rooms, &rooms.join(", "))])
+            }
+            Message::Notice(text) => self.notice.render(&[(Text, text)]),
+            Message::ServerNotice(text) => self.server_notice.render(&[(Text, text)]),
+            Message::Motd(line) => self.motd.render(&[(Text, line)]),
+            Message::Kicked { nickname, by } => self.kicked.render(&[(Nick, nickname), (By, by)]),
+            Message::Banned { nickname, by } => self.banned.render(&[(Nick, nickname), (By, by)]),
+            Message::Renamed { old, new } => self.renamed.render(&[(Old, old), (New, new)]),
+            Message::Topic { text, set_by } if text.is_empty() => {
+                self.topic_cleared.render(&[(By, set_by)])
+            }
+            Message::Topic { text, set_by } => self.topic.render(&[(Text, text), (By, set_by)]),
+            Message::History(message) => {
+                let line = self.render(message);
+                let line = line.strip_prefix("* ").unwrap_or(&line);
+                self.history.render(&[(Text, line)])
+            }
+        }
+    }
+}
+
+/// A template file that cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    /// number of the line, from 1
+    pub line: usize,
+    /// the template defined by the line, if it could be parsed
+    pub field: Option<String>,
+    pub reason: String,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "line {}, {field}: {}", self.line, self.reason),
+            None => write!(f, "line {}: {}", self.line, self.reason),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
@@ -0,0 +1,63 @@
+//! TLS transport: the connections are encrypted with rustls before the chat reads
+//! their lines.
+
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use tokio::time::timeout;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::server::Connection;
+
+/// How long a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate and key served to the TLS clients, see
+/// [`serve_tls`](crate::server::serve_tls)
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsConfig {
+    /// Load the PEM files of the certificate chain `cert`, leaf first, and of its
+    /// private `key`.
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .and_then(|config| config.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Complete the TLS handshake of the client on `stream`
+    pub(crate) async fn accept<S: Connection + Unpin>(
+        &self,
+        stream: S,
+    ) -> io::Result<TlsStream<S>> {
+        timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+    }
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {e}", path.display()),
+    )
+}
@@ -0,0 +1,89 @@
+//! The Unicode nicknames, see [`NicknameRules::unicode`](crate::NicknameRules::unicode):
+//! their composition and the skeletons telling the nicknames that look alike.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// The canonical composition of `s`, its NFC
+pub(crate) fn compose(s: &str) -> String {
+    s.nfc().collect()
+}
+
+/// What `nickname` looks like: the nicknames with the same skeleton cannot be told
+/// apart, whatever their case, their fullwidth forms or their Cyrillic and Greek
+/// letters drawn as Latin ones. The skeleton of an ASCII nickname is its lowercase.
+pub(crate) fn skeleton(nickname: &str) -> String {
+    nickname
+        .chars()
+        .map(|c| match c {
+            // the fullwidth forms of the ASCII characters
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            _ => LOOKALIKES
+                .binary_search_by_key(&c, |(lookalike, _)| *lookalike)
+                .map_or(c, |i| LOOKALIKES[i].1),
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The Cyrillic and Greek letters drawn as Latin ones, sorted
+const LOOKALIKES: [(char, char); 59] = [
+    ('\u{391}', 'A'), // Α
+    ('\u{392}', 'B'), // Β
+    ('\u{395}', 'E'), // Ε
+    ('\u{396}', 'Z'), // Ζ
+    ('\u{397}', 'H'), // Η
+    ('\u{399}', 'I'), // Ι
+    ('\u{39a}', 'K'), // Κ
+    ('\u{39c}', 'M'), // Μ
+    ('\u{39d}', 'N'), // Ν
+    ('\u{39f}', 'O'), // Ο
+    ('\u{3a1}', 'P'), // Ρ
+    ('\u{3a4}', 'T'), // Τ
+    ('\u{3a5}', 'Y'), // Υ
+    ('\u{3a7}', 'X'), // Χ
+    ('\u{3b1}', 'a'), // α
+    ('\u{3b9}', 'i'), // ι
+    ('\u{3ba}', 'k'), // κ
+    ('\u{3bd}', 'v'), // ν
+    ('\u{3bf}', 'o'), // ο
+    ('\u{3c1}', 'p'), // ρ
+    ('\u{3c5}', 'u'), // υ
+    ('\u{405}', 'S'), // Ѕ
+    ('\u{406}', 'I'), // І
+    ('\u{408}', 'J'), // Ј
+    ('\u{410}', 'A'), // А
+    ('\u{412}', 'B'), // В
+    ('\u{415}', 'E'), // Е
+    ('\u{41a}', 'K'), // К
+    ('\u{41c}', 'M'), // М
+    ('\u{41d}', 'H'), // Н
+    ('\u{41e}', 'O'), // О
+    ('\u{420}', 'P'), // Р
+    ('\u{421}', 'C'), // С
+    ('\u{422}', 'T'), // Т
+    ('\u{425}', 'X'), // Х
+    ('\u{430}', 'a'), // а
+    ('\u{435}', 'e'), // е
+    ('\u{43e}', 'o'), // о
+    ('\u{440}', 'p'), // р
+    ('\u{441}', 'c'), // с
+    ('\u{443}', 'y'), // у
+    ('\u{445}', 'x'), // х
+    ('\u{455}', 's'), // ѕ
+    ('\u{456}', 'i'), // і
+    ('\u{458}', 'j'), // ј
+    ('\u{474}', 'V'), // Ѵ
+    ('\u{475}', 'v'), // ѵ
+    ('\u{4ae}', 'Y'), // Ү
+    ('\u{4af}', 'y'), // ү
+    ('\u{4ba}', 'h'), // Һ
+    ('\u{4bb}', 'h'), // һ
+    ('\u{4c0}', 'I'), // Ӏ
+    ('\u{4cf}', 'l'), // ӏ
+    ('\u{500}', 'd'), // Ԁ
+    ('\u{501}', 'd'), // ԁ
+    ('\u{51a}', 'Q'), // Ԛ
+    ('\u{51b}', 'q'), // ԛ
+    ('\u{51c}', 'W'), // Ԝ
+    ('\u{51d}', 'w'), // ԝ
+];
@@ -0,0 +1,242 @@
+//! Events of the chatroom POSTed as JSON to an HTTP endpoint, e.g. a relay to another
+//! chat service
+
+use std::{
+    io,
+    str::FromStr,
+    sync::mpsc::{self, TrySendError},
+    thread,
+    time::Duration,
+};
+
+use tokio::sync::mpsc::Receiver;
+use tracing::{debug, warn};
+use ureq::{http::Uri, Agent};
+
+use crate::{
+    chatroom::{Chatroom, Message},
+    metrics::Metrics,
+};
+
+/// Timeout of the connection to the endpoint, of the request and of its response
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The kinds of events POSTed to the webhook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WebhookEvents {
+    pub join: bool,
+    pub leave: bool,
+    /// the chat messages and emotes, with their text
+    pub message: bool,
+}
+
+impl Default for WebhookEvents {
+    fn default() -> Self {
+        Self {
+            join: true,
+            leave: true,
+            message: true,
+        }
+    }
+}
+
+impl WebhookEvents {
+    fn matches(&self, message: &Message) -> bool {
+        match message {
+            Message::Joined { .. } => self.join,
+            Message::Left { .. } => self.leave,
+            Message::Message { .. } | Message::Emote { .. } | Message::Paste { .. } => self.message,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for WebhookEvents {
+    type Err = String;
+
+    /// Parse a comma separated list of `join`, `leave` and `message`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = Self {
+            join: false,
+            leave: false,
+            message: false,
+        };
+        for event in s.split(',').map(str::trim) {
+            match event {
+                "join" => events.join = true,
+                "leave" => events.leave = true,
+                "message" => events.message = true,
+                _ => return Err(format!("expected join, leave or message, got {event}")),
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Where and how the events are POSTed
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` URL, e.g. of a relay to Slack or Discord
+    pub url: String,
+    pub events: WebhookEvents,
+    /// sent with every request, e.g. `Authorization: Bearer <token>`
+    pub auth_header: Option<String>,
+    /// how many events can wait for their request, the next ones are dropped
+    pub queue: usize,
+    /// how many times a failed request is retried before its event is dropped
+    pub retries: u32,
+    /// delay before the first retry, doubled for each of the next ones
+    pub backoff: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            events: WebhookEvents::default(),
+            auth_header: None,
+            queue: 1024,
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The client POSTing the events to the URL of a webhook
+struct Client {
+    agent: Agent,
+    url: Uri,
+    /// the name and value of [`WebhookConfig::auth_header`]
+    auth_header: Option<(String, String)>,
+}
+
+impl Client {
+    fn new(config: &WebhookConfig) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let url: Uri = config
+            .url
+            .parse()
+            .map_err(|e| invalid(format!("invalid URL {}: {e}", config.url)))?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) {
+            return Err(invalid(format!(
+                "expected an http:// or https:// URL, got {}",
+                config.url
+            )));
+        }
+        if url.host().is_none_or(str::is_empty) {
+            return Err(invalid(format!("no host in {}", config.url)));
+        }
+        let auth_header = match &config.auth_header {
+            Some(header) => match header.split_once(':') {
+                Some((name, value))
+                    if !name.trim().is_empty() && !header.contains(['\r', '\n']) =>
+                {
+                    Some((name.trim().to_string(), value.trim().to_string()))
+                }
+                _ => return Err(invalid("expected a \"Name: value\" header".to_string())),
+            },
+            None => None,
+        };
+        let agent = Agent::config_builder()
+            .timeout_connect(Some(TIMEOUT))
+            .timeout_send_request(Some(TIMEOUT))
+            .timeout_recv_response(Some(TIMEOUT))
+            .timeout_recv_body(Some(TIMEOUT))
+            .build()
+            .into();
+        Ok(Self {
+            agent,
+            url,
+            auth_header,
+        })
+    }
+
+    /// POST `body`, successful if answered with a 2xx status
+    fn post(&self, body: &str) -> Result<(), ureq::Error> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", "application/json");
+        if let Some((name, value)) = &self.auth_header {
+            request = request.header(name, value);
+        }
+        let response = request.send(body)?;
+        if !response.status().is_success() {
+            return Err(ureq::Error::StatusCode(response.status().as_u16()));
+        }
+        Ok(())
+    }
+}
+
+/// POST the events of `chatroom` to the webhook of `config`, until the chatroom shuts
+/// down.
+///
+/// The events are queued to a sender thread: the requests never block the chat. A
+/// failed request is retried with a backoff, then its event is dropped and counted in
+/// the [`MetricsSnapshot::webhook_drops`](crate::MetricsSnapshot::webhook_drops), as
+/// are the events overflowing the queue. Errors if the URL or the header is invalid.
+pub fn start_webhook(chatroom: &Chatroom, config: WebhookConfig) -> io::Result<()> {
+    let client = Client::new(&config)?;
+    let (queue, bodies) = mpsc::sync_channel::<String>(config.queue);
+    let events = chatroom.subscribe_observer();
+    let drops = chatroom.clone();
+    let filter = config.events;
+    thread::Builder::new()
+        .name("webhook-queue".to_string())
+        .spawn(move || enqueue(events, filter, queue, &drops))?;
+    let drops = chatroom.clone();
+    thread::Builder::new()
+        .name("webhook".to_string())
+        .spawn(move || {
+            for body in bodies {
+                if let Err(e) = post_with_retries(&client, &config, &body) {
+                    warn!(error = %e, "webhook event dropped");
+                    Metrics::add(&drops.counters().webhook_drops, 1);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Queue the JSON of the `events` matching `filter`, until the chatroom shuts down
+fn enqueue(
+    mut events: Receiver<Message>,
+    filter: WebhookEvents,
+    queue: mpsc::SyncSender<String>,
+    chatroom: &Chatroom,
+) {
+    while let Some(message) = events.blocking_recv() {
+        if !filter.matches(&message) {
+            continue;
+        }
+        match queue.try_send(message.to_json().to_string()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("webhook queue full, event dropped");
+                Metrics::add(&chatroom.counters().webhook_drops, 1);
+            }
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+fn post_with_retries(
+    client: &Client,
+    config: &WebhookConfig,
+    body: &str,
+) -> Result<(), ureq::Error> {
+    let mut backoff = config.backoff;
+    let mut attempt = 0;
+    loop {
+        match client.post(body) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < config.retries => {
+                debug!(error = %e, attempt, "webhook request failed, retrying");
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! WebSocket transport: each text frame carries one chat line.
+//!
+//! [`accept`] answers the opening handshake of a client, then bridges its frames to
+//! a byte stream of lines, served by the chat like any TCP connection.
+
+use std::io;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{self as aio, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use tracing::debug;
+
+use crate::{
+    lines::{Line, LineReader},
+    server::Connection,
+};
+
+/// Maximum size of a line of the opening handshake request
+const MAX_HEADER_LINE: usize = 4096;
+/// Maximum number of header lines of the opening handshake request
+const MAX_HEADERS: usize = 64;
+/// Larger messages end the connection
+const MAX_MESSAGE: usize = 64 * 1024;
+/// Size of the buffers between the frames and the lines
+const BRIDGE_BUFFER: usize = 64 * 1024;
+/// Appended to the client key to compute the accept key, from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Status code of the close frames sent on protocol errors
+const PROTOCOL_ERROR: u16 = 1002;
+/// Status code of the close frames sent for too large messages
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Answer the opening handshake of the client on `stream`.
+///
+/// Returns the stream of lines exchanged with the client: the lines written to it are
+/// sent as text frames, and every text frame from the client is read as a line. The
+/// stream ends when the client closes the connection.
+pub(crate) async fn accept<S: Connection>(stream: S) -> io::Result<DuplexStream> {
+    let (read_stream, mut write_stream) = aio::split(stream);
+    let mut lines = LineReader::new(read_stream);
+    let key = match read_key(&mut lines).await? {
+        Some(key) => key,
+        None => {
+            write_stream
+                .write_all(
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            let _ = write_stream.shutdown().await;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a WebSocket upgrade request",
+            ));
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    write_stream.write_all(response.as_bytes()).await?;
+
+    let (chat_stream, bridge_stream) = aio::duplex(BRIDGE_BUFFER);
+    let (from_chat, to_chat) = aio::split(bridge_stream);
+    let (control, controls) = channel(8);
+    tokio::spawn(async move {
+        let incoming = tokio::spawn(read_frames(lines.into_inner(), to_chat, control));
+        write_frames(write_stream, from_chat, controls).await;
+        // the client had the time to answer the closing frame while the chat ended
+        incoming.abort();
+    });
+    Ok(chat_stream)
+}
+
+/// Read the request of the opening handshake, returns its `Sec-WebSocket-Key`
+async fn read_key<S: Connection>(
+    lines: &mut LineReader<ReadHalf<S>>,
+) -> io::Result<Option<String>> {
+    let mut request = Vec::new();
+    loop {
+        match lines.read_line(MAX_HEADER_LINE).await? {
+            Some(Line::Complete(line)) if line.trim_ascii().is_empty() => break,
+            Some(Line::Complete(line)) if request.len() < MAX_HEADERS => {
+                request.push(String::from_utf8_lossy(&line).into_owned())
+            }
+            _ => return Ok(None),
+        }
+    }
+    if !request
+        .first()
+        .is_some_and(|request_line| request_line.starts_with("GET "))
+    {
+        return Ok(None);
+    }
+    let mut upgrade = false;
+    let mut key = None;
+    for header in &request[1..] {
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value.to_string());
+        }
+    }
+    Ok(key.filter(|_| upgrade))
+}
+
+/// Forward the messages of the client frames to the chat, a line per message.
+///
+/// The pings and the closing frame are handed to the writer of the frames, through
+/// `control`.
+async fn read_frames<S: Connection>(
+    mut stream: BufReader<ReadHalf<S>>,
+    mut chat: WriteHalf<DuplexStream>,
+    control: Sender<(u8, Vec<u8>)>,
+) {
+    let mut message = Vec::new();
+    loop {
+        let frame = match read_frame(&mut stream, MAX_MESSAGE - message.len()).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!(error = %e, "WebSocket connection failed");
+                let code = match e.kind() {
+                    io::ErrorKind::InvalidData => PROTOCOL_ERROR,
+                    io::ErrorKind::OutOfMemory => MESSAGE_TOO_BIG,
+                    _ => break,
+                };
+                let _ = control.send((CLOSE, code.to_be_bytes().to_vec())).await;
+                break;
+            }
+        };
+        match frame.opcode {
+            TEXT | BINARY | CONTINUATION => {
+                message.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    continue;
+                }
+                // a message is a single line
+                for byte in &mut message {
+                    if *byte == b'\n' || *byte == b'\r' {
+                        *byte = b' ';
+                    }
+                }
+                message.push(b'\n');
+                if chat.write_all(&message).await.is_err() {
+                    break;
+                }
+                message.clear();
+            }
+            PING => {
+                let _ = control.send((PONG, frame.payload)).await;
+            }
+            PONG => {}
+            CLOSE => {
+                let code = frame.payload.get(..2).unwrap_or_default().to_vec();
+                let _ = control.send((CLOSE, code)).await;
+                break;
+            }
+            _ => {
+                let _ = control
+                    .send((CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec()))
+                    .await;
+                break;
+            }
+        }
+    }
+    // the end of the lines ends the session
+    let _ = chat.shutdown().await;
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Read a frame sent by the client, with a payload of at most `max_len` bytes
+async fn read_frame<S: Connection>(
+    stream: &mut BufReader<ReadHalf<S>>,
+    max_len: usize,
+) -> io::Result<Frame> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7F {
+        126 => u64::from(stream.read_u16().await?),
+        127 => stream.read_u64().await?,
+        len => u64::from(len),
+    };
+    if opcode >= CLOSE && (len > 125 || !fin) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid control frame",
+        ));
+    }
+    if len > max_len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "message too large",
+        ));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        stream.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// Send the lines written by the chat to the client as text frames, along with the
+/// control frames received on `controls`.
+///
+/// Once a closing frame is sent, the remaining lines are discarded.
+async fn write_frames<S: Connection>(
+    mut stream: WriteHalf<S>,
+    from_chat: ReadHalf<DuplexStream>,
+    mut controls: Receiver<(u8, Vec<u8>)>,
+) {
+    let mut lines = LineReader::new(from_chat);
+    let mut closed = false;
+    loop {
+        let frame = tokio::select! {
+            line = lines.read_line(usize::MAX) => match line {
+                Ok(Some(Line::Complete(line) | Line::TooLong(line))) => (TEXT, line),
+                _ => break,
+            },
+            Some(control) = controls.recv(), if !closed => control,
+        };
+        if closed {
+            continue;
+        }
+        closed = frame.0 == CLOSE;
+        if stream
+            .write_all(&encode_frame(frame.0, &frame.1))
+            .await
+            .is_err()
+        {
+            closed = true;
+        }
+    }
+    if !closed {
+        let _ = stream.write_all(&encode_frame(CLOSE, &[])).await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// An unmasked, unfragmented frame, as sent by servers
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` `key`
+fn accept_key(key: &str) -> String {
+    BASE64_STANDARD.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}")))
+}
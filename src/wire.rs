@@ -0,0 +1,298 @@
+//! The serde representation of the messages with the `serde` feature, see
+//! [`Message`] for the objects.
+
+use std::{fmt, time::Duration};
+
+use serde::{
+    de::{self, IgnoredAny, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::chatroom::{Message, RoomInfo};
+
+/// The `type` of the messages
+const TYPES: &[&str] = &[
+    "joined",
+    "left",
+    "users",
+    "message",
+    "emote",
+    "paste",
+    "private",
+    "private_sent",
+    "who",
+    "rooms",
+    "notice",
+    "server_notice",
+    "motd",
+    "kicked",
+    "banned",
+    "renamed",
+    "topic",
+    "history",
+];
+
+/// Serialize the `type` then the fields of a message
+macro_rules! object {
+    ($map:ident, $type:literal $(, $key:literal => $value:expr)*) => {{
+        $map.serialize_entry("type", $type)?;
+        $($map.serialize_entry($key, $value)?;)*
+    }};
+}
+
+/// A `{"type": ...}` object, see [`Message`]
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Message::Joined { nickname, users } => {
+                object!(map, "joined", "nick" => nickname);
+                if let Some(users) = users {
+                    map.serialize_entry("count", users)?;
+                }
+            }
+            Message::Left {
+                nickname,
+                online,
+                reason,
+            } => {
+                object!(map, "left", "nick" => nickname);
+                if let Some(online) = online {
+                    map.serialize_entry("online", &online.as_secs())?;
+                }
+                if let Some(reason) = reason {
+                    map.serialize_entry("reason", reason)?;
+                }
+            }
+            Message::ConnectedUsers(users) => object!(map, "users", "users" => users),
+            Message::Message { from, text } => {
+                object!(map, "message", "from" => &**from, "text" => &**text)
+            }
+            Message::Mentioned { from, text } => {
+                object!(map, "message", "from" => &**from, "text" => &**text, "mentioned" => &true)
+            }
+            Message::Emote { from, action } => {
+                object!(map, "emote", "from" => &**from, "text" => &**action)
+            }
+            Message::Paste { from, lines } => {
+                object!(map, "paste", "from" => &**from, "lines" => &**lines)
+            }
+            Message::Private { from, text } => {
+                object!(map, "private", "from" => from, "text" => text)
+            }
+            Message::PrivateSent { to, text } => {
+                object!(map, "private_sent", "to" => to, "text" => text)
+            }
+            Message::UserList(users) => object!(map, "who", "users" => users),
+            Message::RoomList(rooms) => object!(map, "rooms", "rooms" => rooms),
+            Message::Notice(text) => object!(map, "notice", "text" => text),
+            Message::ServerNotice(text) => object!(map, "server_notice", "text" => text),
+            Message::Motd(text) => object!(map, "motd", "text" => text),
+            Message::Kicked { nickname, by } => {
+                object!(map, "kicked", "nick" => nickname, "by" => by)
+            }
+            Message::Banned { nickname, by } => {
+                object!(map, "banned", "nick" => nickname, "by" => by)
+            }
+            Message::Renamed { old, new } => object!(map, "renamed", "old" => old, "new" => new),
+            Message::Topic { text, set_by } => {
+                object!(map, "topic", "text" => text, "by" => set_by)
+            }
+            Message::History(message) => object!(map, "history", "message" => message),
+        }
+        map.end()
+    }
+}
+
+/// The fields of a message object, any of them may be missing
+#[derive(Default)]
+struct Fields {
+    kind: Option<String>,
+    nick: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    text: Option<String>,
+    by: Option<String>,
+    old: Option<String>,
+    new: Option<String>,
+    reason: Option<String>,
+    online: Option<u64>,
+    count: Option<usize>,
+    mentioned: bool,
+    users: Option<Vec<String>>,
+    lines: Option<Vec<String>>,
+    rooms: Option<Vec<RoomInfo>>,
+    message: Option<Box<Message>>,
+}
+
+/// The value of the required field `name`
+fn required<T, E: de::Error>(value: Option<T>, name: &'static str) -> Result<T, E> {
+    value.ok_or_else(|| E::missing_field(name))
+}
+
+impl Fields {
+    fn into_message<E: de::Error>(self) -> Result<Message, E> {
+        let kind = required(self.kind, "type")?;
+        Ok(match kind.as_str() {
+            "joined" => Message::Joined {
+                nickname: required(self.nick, "nick")?,
+                users: self.count,
+            },
+            "left" => Message::Left {
+                nickname: required(self.nick, "nick")?,
+                online: self.online.map(Duration::from_secs),
+                reason: self.reason,
+            },
+            "users" => Message::ConnectedUsers(required(self.users, "users")?),
+            "message" if self.mentioned => Message::Mentioned {
+                from: required(self.from, "from")?.into(),
+                text: required(self.text, "text")?.into(),
+            },
+            "message" => Message::Message {
+                from: required(self.from, "from")?.into(),
+                text: required(self.text, "text")?.into(),
+            },
+            "emote" => Message::Emote {
+                from: required(self.from, "from")?.into(),
+                action: required(self.text, "text")?.into(),
+            },
+            "paste" => Message::Paste {
+                from: required(self.from, "from")?.into(),
+                lines: required(self.lines, "lines")?.into(),
+            },
+            "private" => Message::Private {
+                from: required(self.from, "from")?,
+                text: required(self.text, "text")?,
+            },
+            "private_sent" => Message::PrivateSent {
+                to: required(self.to, "to")?,
+                text: required(self.text, "text")?,
+            },
+            "who" => Message::UserList(required(self.users, "users")?),
+            "rooms" => Message::RoomList(required(self.rooms, "rooms")?),
+            "notice" => Message::Notice(required(self.text, "text")?),
+            "server_notice" => Message::ServerNotice(required(self.text, "text")?),
+            "motd" => Message::Motd(required(self.text, "text")?),
+            "kicked" => Message::Kicked {
+                nickname: required(self.nick, "nick")?,
+                by: required(self.by, "by")?,
+            },
+            "banned" => Message::Banned {
+                nickname: required(self.nick, "nick")?,
+                by: required(self.by, "by")?,
+            },
+            "renamed" => Message::Renamed {
+                old: required(self.old, "old")?,
+                new: required(self.new, "new")?,
+            },
+            "topic" => Message::Topic {
+                text: required(self.text, "text")?,
+                set_by: required(self.by, "by")?,
+            },
+            "history" => Message::History(required(self.message, "message")?),
+            kind => return Err(E::unknown_variant(kind, TYPES)),
+        })
+    }
+}
+
+struct MessageVisitor;
+
+impl<'de> Visitor<'de> for MessageVisitor {
+    type Value = Message;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a message object with a type")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Message, A::Error> {
+        let mut fields = Fields::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "type" => fields.kind = Some(map.next_value()?),
+                "nick" => fields.nick = Some(map.next_value()?),
+                "from" => fields.from = Some(map.next_value()?),
+                "to" => fields.to = Some(map.next_value()?),
+                "text" => fields.text = Some(map.next_value()?),
+                "by" => fields.by = Some(map.next_value()?),
+                "old" => fields.old = Some(map.next_value()?),
+                "new" => fields.new = Some(map.next_value()?),
+                "reason" => fields.reason = Some(map.next_value()?),
+                "online" => fields.online = Some(map.next_value()?),
+                "count" => fields.count = Some(map.next_value()?),
+                "mentioned" => fields.mentioned = map.next_value()?,
+                "users" => fields.users = Some(map.next_value()?),
+                "lines" => fields.lines = Some(map.next_value()?),
+                "rooms" => fields.rooms = Some(map.next_value()?),
+                "message" => fields.message = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        fields.into_message()
+    }
+}
+
+/// A `{"type": ...}` object, see [`Message`]
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(MessageVisitor)
+    }
+}
+
+/// A `{"name": ..., "users": <count>}` object, with `"invite_only": true` for the
+/// invite-only rooms and the `"limit"` of the limited ones
+impl Serialize for RoomInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = 2 + usize::from(self.invite_only) + usize::from(self.limit.is_some());
+        let mut map = serializer.serialize_map(Some(entries))?;
+        map.serialize_entry("name", &self.name)?;
+        map.serialize_entry("users", &self.users)?;
+        if self.invite_only {
+            map.serialize_entry("invite_only", &true)?;
+        }
+        if let Some(limit) = self.limit {
+            map.serialize_entry("limit", &limit)?;
+        }
+        map.end()
+    }
+}
+
+struct RoomInfoVisitor;
+
+impl<'de> Visitor<'de> for RoomInfoVisitor {
+    type Value = RoomInfo;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a room object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RoomInfo, A::Error> {
+        let (mut name, mut users, mut invite_only, mut limit) = (None, None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "name" => name = Some(map.next_value()?),
+                "users" => users = Some(map.next_value()?),
+                "invite_only" => invite_only = Some(map.next_value()?),
+                "limit" => limit = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(RoomInfo {
+            name: required(name, "name")?,
+            users: required(users, "users")?,
+            invite_only: invite_only.unwrap_or(false),
+            limit,
+        })
+    }
+}
+
+/// A `{"name": ..., "users": <count>}` object
+impl<'de> Deserialize<'de> for RoomInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(RoomInfoVisitor)
+    }
+}
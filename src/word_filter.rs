@@ -0,0 +1,122 @@
+//! Words not allowed in the messages and nicknames, loaded from a file
+
+use std::{collections::HashSet, fs, io, path::Path, str::FromStr};
+
+/// Handling of the messages containing filtered words
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WordFilterMode {
+    /// replace the letters of the filtered words with asterisks
+    Mask,
+    /// refuse the message with [`SendError::FilteredWords`](crate::SendError::FilteredWords)
+    Reject,
+}
+
+impl FromStr for WordFilterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mask" => Ok(WordFilterMode::Mask),
+            "reject" => Ok(WordFilterMode::Reject),
+            _ => Err(format!("expected mask or reject, got {s}")),
+        }
+    }
+}
+
+/// A set of words, matched case-insensitively against the whole words of the texts.
+///
+/// A word is a run of letters and digits: the text is split into its words in one
+/// pass, each looked up in the set, so matching does not depend on the number of
+/// filtered words.
+#[derive(Clone, Debug, Default)]
+pub struct WordFilter {
+    /// lowercase
+    words: HashSet<String>,
+}
+
+impl WordFilter {
+    /// A filter of `words`, those that are not made of letters and digits only can
+    /// never match
+    pub fn new(words: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.as_ref().to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Load the words of `file`, one per line. Blank lines are ignored, a line that is
+    /// not a single word is an error.
+    pub fn load(file: &Path) -> io::Result<Self> {
+        let words = fs::read_to_string(file)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.chars().all(char::is_alphanumeric) {
+                true => Ok(line.to_lowercase()),
+                false => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("not a word: {line}"),
+                )),
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { words })
+    }
+
+    /// Number of filtered words
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Whether `text` contains a filtered word
+    pub fn matches(&self, text: &str) -> bool {
+        self.filtered_words(text).next().is_some()
+    }
+
+    /// The text with its filtered words handled per `mode`, `None` if it has some and
+    /// they are rejected
+    pub(crate) fn apply(&self, mode: WordFilterMode, text: String) -> Option<String> {
+        let filtered: Vec<_> = self.filtered_words(&text).collect();
+        if filtered.is_empty() {
+            return Some(text);
+        }
+        if mode == WordFilterMode::Reject {
+            return None;
+        }
+        let mut masked = String::with_capacity(text.len());
+        let mut end = 0;
+        for (start, word) in filtered {
+            masked.push_str(&text[end..start]);
+            masked.extend(word.chars().map(|_| '*'));
+            end = start + word.len();
+        }
+        masked.push_str(&text[end..]);
+        Some(masked)
+    }
+
+    /// The filtered words of `text`, with their byte offset
+    fn filtered_words<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (usize, &'a str)> {
+        // no lowercase copy of the words without filter
+        let text = if self.words.is_empty() { "" } else { text };
+        words(text).filter(|(_, word)| self.words.contains(&word.to_lowercase()))
+    }
+}
+
+/// The runs of letters and digits of `text`, with their byte offset
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut rest = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        while rest.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let (start, _) = *rest.peek()?;
+        let mut end = start;
+        while let Some((i, c)) = rest.next_if(|(_, c)| c.is_alphanumeric()) {
+            end = i + c.len_utf8();
+        }
+        Some((start, &text[start..end]))
+    })
+}
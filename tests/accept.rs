@@ -0,0 +1,88 @@
+//! Lives in its own test binary: it lowers the file descriptor limit of the whole
+//! process.
+
+use std::{
+    fs, future,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use budget_chat::{
+    server::{serve, ServerConfig},
+    Chatroom,
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+fn open_files() -> u64 {
+    fs::read_dir("/proc/self/fd").unwrap().count() as u64
+}
+
+fn open_files_limit() -> libc::rlimit {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: valid pointer to a rlimit
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) },
+        0
+    );
+    rlimit
+}
+
+fn set_open_files_limit(limit: u64) {
+    let rlimit = libc::rlimit {
+        rlim_cur: limit,
+        ..open_files_limit()
+    };
+    // SAFETY: valid pointer to a rlimit
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) }, 0);
+}
+
+/// Join as `nickname`, returns the stream and the user list line
+fn join(addr: SocketAddr, nickname: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "{nickname}").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    (stream, line)
+}
+
+#[test]
+fn accept_recovers_from_file_descriptors_exhaustion() {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        runtime.block_on(serve(
+            listener,
+            Chatroom::default(),
+            ServerConfig::default(),
+            future::pending(),
+        ))
+    });
+    let (_alice, welcome) = join(addr, "alice");
+    assert!(welcome.starts_with("* Welcome"), "{welcome}");
+
+    // the clients take the last descriptors, the server cannot accept them
+    let limit = open_files_limit().rlim_cur;
+    set_open_files_limit(open_files() + 8);
+    let clients: Vec<_> = (0..16)
+        .map_while(|_| TcpStream::connect(addr).ok())
+        .collect();
+    thread::sleep(Duration::from_millis(500));
+
+    // the waiting clients are accepted once descriptors are available again
+    set_open_files_limit(limit);
+    drop(clients);
+    let (_bob, welcome) = join(addr, "bob");
+    assert_eq!(welcome, "* Welcome, the room contains 1 user: alice\n");
+}
@@ -0,0 +1,3179 @@
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Barrier, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use budget_chat::{
+    hash_password, parse_mute_duration, read_archive, read_chat_log, validate_nickname, Accounts,
+    Archive, ArchivedMessage, BanList, ChatLog, Chatroom, ChatroomConfig, Envelope, HistoryConfig,
+    HookResult, JoinError, KickError, LogRotation, LogSync, Message, MessageTemplates,
+    NicknameError, NicknameRules, OnDuplicate, OperatorList, PasswordParams, RateLimit,
+    ResumeError, RoomError, RuntimeConfig, Sanitize, SearchError, SendError, SlowClientPolicy,
+    TopicError, WordFilter, WordFilterMode,
+};
+use chrono::{DateTime, Local};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Collect every message currently queued on `receiver`, rendered as the clients see them.
+fn drain(receiver: &mut Receiver<impl ToString>) -> Vec<String> {
+    iter::from_fn(|| receiver.try_recv().ok())
+        .map(|m| m.to_string())
+        .collect()
+}
+
+#[test]
+fn join_send_leave() {
+    let chatroom = Chatroom::default();
+
+    let (alice_sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), alice_sender).ok();
+    assert!(alice_session.is_some());
+    assert_eq!(drain(&mut alice), ["* Welcome, the room is empty"]);
+
+    let (bob_sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), bob_sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        ["* Welcome, the room contains 1 user: alice"]
+    );
+    assert_eq!(drain(&mut alice), ["* bob joined the room"]);
+
+    bob_session.send_message("hello".to_string()).unwrap();
+    assert_eq!(drain(&mut alice), ["[bob] hello"]);
+    assert!(drain(&mut bob).is_empty());
+
+    drop(bob_session);
+    assert_eq!(drain(&mut alice), ["* bob left the room (online 0s)"]);
+}
+
+#[test]
+fn pastes_are_one_message() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    let lines = ["let x = 1;", "  \x1b[31mx\x1b[0m"].map(str::to_string);
+    assert_eq!(alice_session.send_paste(lines.to_vec()), Ok(1));
+    assert_eq!(
+        drain(&mut bob),
+        ["[alice] (paste, 2 lines)\n    let x = 1;\n      x"]
+    );
+    assert!(drain(&mut alice).is_empty());
+}
+
+#[test]
+fn rejected_joins() {
+    let chatroom = Chatroom::default();
+
+    let (sender, _receiver) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+
+    let (sender, mut receiver) = channel(16);
+    assert!(matches!(
+        chatroom.join("alice".to_string(), sender),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    assert!(drain(&mut receiver).is_empty());
+
+    let (sender, _receiver) = channel(16);
+    assert!(matches!(
+        chatroom.join("not valid".to_string(), sender),
+        Err(JoinError::InvalidNickname(_))
+    ));
+
+    let (sender, _receiver) = channel(16);
+    assert!(matches!(
+        chatroom.join(String::new(), sender),
+        Err(JoinError::InvalidNickname(_))
+    ));
+}
+
+#[test]
+fn slow_consumers_are_evicted() {
+    let chatroom = Chatroom::default();
+
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(2);
+    let (evicted_sender, evicted) = mpsc::sync_channel(1);
+    let _bob_session = chatroom
+        .join_with_disconnect("bob".to_string(), sender, move || {
+            evicted_sender.send(()).unwrap()
+        })
+        .ok()
+        .unwrap();
+    drain(&mut alice);
+
+    // bob's queue already holds the user list
+    assert_eq!(alice_session.send_message("one".to_string()), Ok(1));
+    assert!(evicted.try_recv().is_err());
+    // the message is not queued for bob
+    assert_eq!(alice_session.send_message("two".to_string()), Ok(0));
+    assert!(evicted.try_recv().is_ok());
+    assert_eq!(drain(&mut alice), ["* bob left the room"]);
+
+    assert_eq!(alice_session.send_message("three".to_string()), Ok(0));
+    assert!(drain(&mut alice).is_empty());
+}
+
+#[test]
+fn slow_consumers_drop_the_newest_messages() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        slow_client_policy: SlowClientPolicy::DropNewest,
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    // bob stalls, its queue already holds the user list
+    let (sender, mut bob) = channel(2);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+
+    assert_eq!(alice.send_message("one".to_string()), Ok(1));
+    assert_eq!(alice.send_message("two".to_string()), Ok(0));
+    assert_eq!(alice.send_message("three".to_string()), Ok(0));
+    assert_eq!(chatroom.connected_users(), ["alice", "bob"]);
+    assert_eq!(
+        drain(&mut bob),
+        ["* Welcome, the room contains 1 user: alice", "[alice] one"]
+    );
+    // the drops are told before the next message
+    assert_eq!(alice.send_message("four".to_string()), Ok(1));
+    assert_eq!(
+        receive_until(&mut bob, "[alice] four"),
+        ["* some messages were dropped (2)", "[alice] four"]
+    );
+
+    let metrics = chatroom.metrics();
+    assert_eq!(metrics.evictions, 0);
+    assert_eq!(metrics.slow_client_drops.newest, 2);
+    assert_eq!(metrics.slow_client_drops.oldest, 0);
+    assert!(metrics
+        .to_prometheus()
+        .contains("budget_chat_slow_client_drops_total{policy=\"drop-newest\"} 2\n"));
+}
+
+#[test]
+fn slow_consumers_drop_the_oldest_messages() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        slow_client_policy: SlowClientPolicy::DropOldest,
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(2);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+
+    // as many messages as its queue holds wait for bob, the oldest dropped
+    for text in ["one", "two", "three", "four"] {
+        assert_eq!(alice.send_message(text.to_string()), Ok(1));
+    }
+    assert_eq!(chatroom.connected_users(), ["alice", "bob"]);
+    assert_eq!(
+        drain(&mut bob),
+        ["* Welcome, the room contains 1 user: alice", "[alice] one"]
+    );
+    // the waiting messages are queued once bob reads again, without new messages
+    assert_eq!(
+        receive_until(&mut bob, "[alice] four"),
+        [
+            "* some messages were dropped (1)",
+            "[alice] three",
+            "[alice] four"
+        ]
+    );
+
+    let metrics = chatroom.metrics();
+    assert_eq!(metrics.evictions, 0);
+    assert_eq!(metrics.slow_client_drops.oldest, 1);
+    assert_eq!(metrics.slow_client_drops.newest, 0);
+}
+
+#[test]
+fn slow_client_policies_are_parsed() {
+    assert_eq!("disconnect".parse(), Ok(SlowClientPolicy::Disconnect));
+    assert_eq!("drop-oldest".parse(), Ok(SlowClientPolicy::DropOldest));
+    assert_eq!("drop-newest".parse(), Ok(SlowClientPolicy::DropNewest));
+    assert!("drop".parse::<SlowClientPolicy>().is_err());
+}
+
+/// The numbers of the messages currently queued on `receiver`
+fn seqs(receiver: &mut Receiver<Envelope>) -> Vec<Option<u64>> {
+    iter::from_fn(|| receiver.try_recv().ok())
+        .map(|envelope| envelope.seq)
+        .collect()
+}
+
+#[test]
+fn messages_are_numbered() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        slow_client_policy: SlowClientPolicy::DropNewest,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(2);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    // the welcome, then the join of bob
+    assert_eq!(seqs(&mut alice), [Some(1), Some(2)]);
+
+    // the dropped messages leave a gap, the notice of the drops has no number
+    for text in ["one", "two", "three"] {
+        alice_session.send_message(text.to_string()).unwrap();
+    }
+    assert_eq!(seqs(&mut bob), [Some(1), Some(2)]);
+    alice_session.send_message("four".to_string()).unwrap();
+    let received: Vec<_> = iter::from_fn(|| bob.try_recv().ok())
+        .map(|envelope| (envelope.seq, envelope.to_string()))
+        .collect();
+    assert_eq!(
+        received,
+        [
+            (None, "* some messages were dropped (2)".to_string()),
+            (Some(5), "[alice] four".to_string())
+        ]
+    );
+
+    // the lag counts the messages queued since the last one acknowledged
+    assert_eq!(bob_session.lag(), None);
+    assert!(!bob_session.ack(6));
+    assert!(bob_session.ack(5));
+    assert_eq!(bob_session.lag(), Some(0));
+    alice_session.send_message("five".to_string()).unwrap();
+    assert_eq!(bob_session.lag(), Some(1));
+    let whois = chatroom.whois(&alice_session, "bob").unwrap();
+    assert_eq!(whois.lag, Some(1));
+    let metrics = chatroom.metrics();
+    assert_eq!((metrics.client_lag, metrics.acking_clients), (1, 1));
+    assert!(metrics
+        .to_prometheus()
+        .contains("budget_chat_client_lag 1\n"));
+
+    // the lags are summed as the clients acknowledge, until they leave
+    assert!(bob_session.ack(3));
+    assert!(alice_session.ack(2));
+    let metrics = chatroom.metrics();
+    assert_eq!((metrics.client_lag, metrics.acking_clients), (3, 2));
+    // alice is told of the leave of bob
+    drop(bob_session);
+    let metrics = chatroom.metrics();
+    assert_eq!((metrics.client_lag, metrics.acking_clients), (1, 1));
+}
+
+#[test]
+fn numbering_goes_on_when_resumed() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        resume_window: Some(Duration::from_secs(10)),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let token = alice_session.resume_token().unwrap();
+    let (sender, _bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    // the welcome, the token and the join of bob
+    assert_eq!(seqs(&mut alice), [Some(1), Some(2), Some(3)]);
+    assert!(alice_session.ack(3));
+
+    alice_session.detach(alice);
+    bob_session
+        .send_message("still there?".to_string())
+        .unwrap();
+    let mut resumed = chatroom.resume(&token, None, || {}).unwrap();
+    assert_eq!(resumed.session.lag(), Some(1));
+    bob_session
+        .send_message("welcome back".to_string())
+        .unwrap();
+    assert_eq!(seqs(&mut resumed.receiver), [Some(4), Some(5)]);
+
+    // a new session numbers its messages from 1
+    drop(resumed.session);
+    let (sender, mut alice) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).unwrap();
+    assert_eq!(seqs(&mut alice)[0], Some(1));
+}
+
+#[test]
+fn private_messages() {
+    let chatroom = Chatroom::default();
+
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    let (sender, mut carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+    drain(&mut carol);
+
+    assert!(chatroom
+        .send_private(&alice_session, "bob", "hello there".to_string())
+        .is_ok());
+    assert_eq!(drain(&mut bob), ["[alice -> you] hello there"]);
+    assert_eq!(drain(&mut alice), ["[you -> bob] hello there"]);
+    assert!(drain(&mut carol).is_empty());
+
+    let error = chatroom
+        .send_private(&alice_session, "dave", "hello".to_string())
+        .err()
+        .unwrap();
+    assert_eq!(error.to_string(), "no such user: dave");
+    assert!(drain(&mut alice).is_empty());
+}
+
+#[test]
+fn connected_users_are_sorted() {
+    let chatroom = Chatroom::default();
+    assert!(chatroom.connected_users().is_empty());
+
+    let sessions = ["carol", "alice", "bob"]
+        .into_iter()
+        .map(|nickname| {
+            let (sender, receiver) = channel(16);
+            (
+                chatroom.join(nickname.to_string(), sender).ok().unwrap(),
+                receiver,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(chatroom.connected_users(), ["alice", "bob", "carol"]);
+
+    drop(sessions);
+    assert!(chatroom.connected_users().is_empty());
+}
+
+#[test]
+fn user_lists_are_sorted() {
+    let chatroom = Chatroom::default();
+    let mut receivers = Vec::new();
+    let mut sessions = Vec::new();
+    for nickname in ["Charlie", "alice", "Bob"] {
+        let (sender, receiver) = channel(16);
+        sessions.push(chatroom.join(nickname.to_string(), sender).ok().unwrap());
+        receivers.push(receiver);
+    }
+    let (sender, mut dave) = channel(16);
+    let _dave = chatroom.join("dave".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut dave),
+        ["* Welcome, the room contains 3 users: alice, Bob, Charlie"]
+    );
+    assert_eq!(
+        chatroom.who(Chatroom::LOBBY),
+        ["alice", "Bob", "@Charlie", "dave"]
+    );
+    assert_eq!(
+        chatroom.connected_users(),
+        ["alice", "Bob", "Charlie", "dave"]
+    );
+
+    // the connection of Bob ended, its session did not leave yet: the joining users
+    // are not told of it, the others are told it left
+    drop(receivers.remove(2));
+    let (sender, mut erin) = channel(16);
+    let _erin = chatroom.join("erin".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut erin),
+        ["* Welcome, the room contains 3 users: alice, Charlie, dave"]
+    );
+    assert_eq!(
+        drain(&mut dave),
+        ["* Bob left the room", "* erin joined the room"]
+    );
+    drop(sessions.remove(2));
+    assert!(drain(&mut erin).is_empty());
+}
+
+#[test]
+fn joins_are_announced_with_counts() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        announce_counts: true,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    assert_eq!(drain(&mut alice), ["* Welcome, the room is empty"]);
+    let (sender, mut bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        ["* Welcome, the room contains 1 user: alice"]
+    );
+    assert_eq!(drain(&mut alice), ["* bob joined the room (2 users)"]);
+    let (sender, mut carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut carol),
+        ["* Welcome, the room contains 2 users: alice, bob"]
+    );
+    assert_eq!(drain(&mut bob), ["* carol joined the room (3 users)"]);
+
+    // the other rooms count their own users
+    carol_session.join_room("rust").unwrap();
+    let (sender, _dave) = channel(16);
+    let dave = chatroom.join("dave".to_string(), sender).ok().unwrap();
+    dave.join_room("rust").unwrap();
+    assert_eq!(drain(&mut carol)[2..], ["* dave joined the room (2 users)"]);
+}
+
+#[test]
+fn limited_rooms() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        default_room_limit: Some(1),
+        ..Default::default()
+    });
+    let join = |nickname: &str| {
+        let (sender, receiver) = channel(16);
+        let session = chatroom.join(nickname.to_string(), sender).ok().unwrap();
+        (session, receiver)
+    };
+    let (alice, _alice) = join("alice");
+    let (bob, _bob) = join("bob");
+    let (carol, _carol) = join("carol");
+
+    alice.join_room("small").unwrap();
+    let full = RoomError::Full {
+        room: "small".to_string(),
+        limit: 1,
+    };
+    assert_eq!(bob.join_room("small"), Err(full.clone()));
+    assert_eq!(
+        full.to_string(),
+        "room #small is full, it holds up to 1 users"
+    );
+    assert_eq!(
+        bob.set_room_limit("small", None),
+        Err(RoomError::NotInRoom("small".to_string()))
+    );
+    alice.set_room_limit("small", Some(2)).unwrap();
+    bob.join_room("small").unwrap();
+    assert!(carol.join_room("small").is_err());
+    assert_eq!(chatroom.rooms()[1].limit, Some(2));
+    assert_eq!(chatroom.rooms()[1].users, 2);
+
+    // a leave frees a place
+    bob.join_room(Chatroom::LOBBY).unwrap();
+    carol.join_room("small").unwrap();
+    alice.set_room_limit("small", None).unwrap();
+    bob.join_room("small").unwrap();
+    assert_eq!(chatroom.room_users("small"), ["alice", "bob", "carol"]);
+    // the lobby is never limited
+    assert_eq!(
+        alice.set_room_limit(Chatroom::LOBBY, Some(1)),
+        Err(RoomError::Lobby)
+    );
+    assert_eq!(chatroom.rooms()[0].limit, None);
+}
+
+#[test]
+fn rooms_are_isolated() {
+    let chatroom = Chatroom::default();
+
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    let (sender, mut carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+    drain(&mut carol);
+
+    assert!(bob_session.join_room("rust").is_ok());
+    assert_eq!(
+        drain(&mut bob),
+        ["* you are now in #rust", "* Welcome, the room is empty"]
+    );
+    assert_eq!(drain(&mut alice), ["* bob left the room"]);
+    assert_eq!(drain(&mut carol), ["* bob left the room"]);
+    assert!(carol_session.join_room("rust").is_ok());
+    assert_eq!(drain(&mut bob), ["* carol joined the room"]);
+    assert_eq!(
+        drain(&mut carol),
+        [
+            "* you are now in #rust",
+            "* Welcome, the room contains 1 user: bob"
+        ]
+    );
+    assert_eq!(drain(&mut alice), ["* carol left the room"]);
+    assert_eq!(bob_session.room().unwrap(), "rust");
+    assert_eq!(chatroom.room_users("rust"), ["bob", "carol"]);
+    assert!(matches!(
+        bob_session.join_room("rust"),
+        Err(RoomError::AlreadyInRoom(_))
+    ));
+
+    // messages only reach the room
+    bob_session.send_message("hi".to_string()).unwrap();
+    assert_eq!(drain(&mut carol), ["[bob] hi"]);
+    assert!(drain(&mut alice).is_empty());
+    alice_session.send_message("hello".to_string()).unwrap();
+    assert!(drain(&mut bob).is_empty());
+
+    // nicknames are unique across rooms
+    let (sender, _receiver) = channel(16);
+    assert!(matches!(
+        chatroom.join("bob".to_string(), sender),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+
+    let rooms = chatroom
+        .rooms()
+        .into_iter()
+        .map(|room| (room.name, room.users))
+        .collect::<Vec<_>>();
+    assert_eq!(rooms, [("lobby".to_string(), 1), ("rust".to_string(), 2)]);
+
+    // empty rooms are dropped
+    drop(bob_session);
+    assert_eq!(drain(&mut carol), ["* bob left the room (online 0s)"]);
+    assert!(drain(&mut alice).is_empty());
+    drop(carol_session);
+    assert_eq!(chatroom.rooms().len(), 1);
+}
+
+#[test]
+fn invite_only_rooms() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    let (sender, _carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+
+    alice_session.join_room("private").unwrap();
+    assert_eq!(
+        bob_session.set_invite_only("private", true),
+        Err(RoomError::NotInRoom("private".to_string()))
+    );
+    assert_eq!(
+        alice_session.set_invite_only(Chatroom::LOBBY, true),
+        Err(RoomError::Lobby)
+    );
+    alice_session.set_invite_only("private", true).unwrap();
+    let rooms = chatroom.rooms();
+    assert!(rooms[1].invite_only && !rooms[0].invite_only, "{rooms:?}");
+
+    // join without invite
+    assert_eq!(
+        bob_session.join_room("private"),
+        Err(RoomError::InviteOnly("private".to_string()))
+    );
+    assert_eq!(
+        RoomError::InviteOnly("private".to_string()).to_string(),
+        "room #private is invite-only"
+    );
+    assert_eq!(bob_session.invite("carol"), Err(RoomError::NotOperator));
+
+    // invite, then join: the invite is used up
+    drain(&mut alice);
+    drain(&mut bob);
+    assert_eq!(alice_session.invite("bob"), Ok("private".to_string()));
+    assert_eq!(drain(&mut bob), ["* alice invited you to #private"]);
+    bob_session.join_room("private").unwrap();
+    assert_eq!(chatroom.room_users("private"), ["alice", "bob"]);
+    bob_session.join_room(Chatroom::LOBBY).unwrap();
+    assert!(bob_session.join_room("private").is_err());
+
+    // the invites expire when the room empties, and so does the mode
+    alice_session.invite("carol").unwrap();
+    alice_session.join_room(Chatroom::LOBBY).unwrap();
+    assert!(chatroom.rooms().iter().all(|room| room.name != "private"));
+    carol_session.join_room("private").unwrap();
+    bob_session.join_room("private").unwrap();
+    assert_eq!(chatroom.room_users("private"), ["bob", "carol"]);
+}
+
+#[test]
+fn nickname_validation() {
+    assert!(validate_nickname("alice42").is_ok());
+    assert!(matches!(
+        validate_nickname(""),
+        Err(NicknameError::TooShort { min: 1 })
+    ));
+    let error = validate_nickname("abcdefghijklmnopq").err().unwrap();
+    assert!(matches!(error, NicknameError::TooLong { max: 16 }));
+    assert_eq!(error.to_string(), "Nickname too long (max 16)");
+    assert!(matches!(
+        validate_nickname("al_ce"),
+        Err(NicknameError::InvalidChar {
+            position: 2,
+            ch: '_'
+        })
+    ));
+    assert!(matches!(
+        validate_nickname("élise"),
+        Err(NicknameError::InvalidChar {
+            position: 0,
+            ch: 'é'
+        })
+    ));
+
+    let rules = NicknameRules {
+        min_len: 3,
+        max_len: 20,
+        extra_chars: "_-".to_string(),
+        unicode: false,
+    };
+    assert!(rules.validate("al_ce-2").is_ok());
+    assert!(rules.validate("abcdefghijklmnopq").is_ok());
+    assert!(matches!(
+        rules.validate("al"),
+        Err(NicknameError::TooShort { min: 3 })
+    ));
+    assert!(matches!(
+        rules.validate("al.ce"),
+        Err(NicknameError::InvalidChar {
+            position: 2,
+            ch: '.'
+        })
+    ));
+
+    let chatroom = Chatroom::new(ChatroomConfig {
+        nicknames: rules,
+        ..Default::default()
+    });
+    let (sender, _receiver) = channel(16);
+    assert!(chatroom.join("al_ce".to_string(), sender).is_ok());
+    let (sender, _receiver) = channel(16);
+    let error = chatroom.join("a".to_string(), sender).err().unwrap();
+    assert_eq!(error.to_string(), "Nickname too short (min 3).");
+}
+
+#[test]
+fn unicode_nicknames() {
+    let rules = NicknameRules {
+        max_len: 5,
+        unicode: true,
+        ..Default::default()
+    };
+    assert!(rules.validate("héllo").is_ok());
+    assert!(rules.validate("日本語").is_ok());
+    assert!(matches!(
+        rules.validate("hé lo"),
+        Err(NicknameError::InvalidChar {
+            position: 2,
+            ch: ' '
+        })
+    ));
+    // the length is counted in characters, not bytes
+    assert!(matches!(
+        rules.validate("héllos"),
+        Err(NicknameError::TooLong { max: 5 })
+    ));
+
+    let chatroom = Chatroom::new(ChatroomConfig {
+        nicknames: rules,
+        ..Default::default()
+    });
+    let join = |nickname: &str| {
+        let (sender, receiver) = channel(16);
+        chatroom
+            .join(nickname.to_string(), sender)
+            .map(|session| (session, receiver))
+    };
+    // a decomposed é is composed, and fits the length
+    let (_hello, _) = join("he\u{301}llo").ok().unwrap();
+    assert_eq!(chatroom.connected_users(), ["héllo"]);
+    assert!(matches!(
+        join("héllo"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    // whatever the order of the marks, and in the other scripts
+    let (_dotted, _) = join("xa\u{302}\u{323}").ok().unwrap();
+    assert!(matches!(
+        join("x\u{1ead}"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    assert!(matches!(
+        join("xa\u{323}\u{302}"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    let (_hangul, _) = join("\u{1100}\u{1161}").ok().unwrap();
+    assert!(matches!(
+        join("\u{ac00}"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    let (_abc, _) = join("ABC").ok().unwrap();
+    assert!(matches!(
+        join("ＡＢＣ"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    assert!(matches!(join("abc"), Err(JoinError::DuplicateNickname(_))));
+    let (admin, _) = join("admin").ok().unwrap();
+    // with a Cyrillic а
+    assert!(matches!(
+        join("\u{430}dmin"),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    let (_bob, _) = join("bob").ok().unwrap();
+    assert!(admin.rename("Admin".to_string()).is_ok());
+    assert!(matches!(
+        admin.rename("b\u{43e}b".to_string()),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+
+    // ASCII only by default
+    let (sender, _receiver) = channel(16);
+    assert!(matches!(
+        Chatroom::default().join("héllo".to_string(), sender),
+        Err(JoinError::InvalidNickname(_))
+    ));
+}
+
+#[test]
+fn motd_is_sent_before_the_user_list() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    assert_eq!(drain(&mut alice), ["* Welcome, the room is empty"]);
+
+    chatroom.set_motd(vec!["Hello!".to_string(), "Be nice.".to_string()]);
+    let (sender, mut bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "* Hello!",
+            "* Be nice.",
+            "* Welcome, the room contains 1 user: alice"
+        ]
+    );
+}
+
+#[test]
+fn metrics_track_the_activity() {
+    let chatroom = Chatroom::default();
+
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(1);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    let (sender, _receiver) = channel(16);
+    assert!(chatroom.join("alice".to_string(), sender).is_err());
+    let (sender, _receiver) = channel(16);
+    assert!(chatroom.join("not valid".to_string(), sender).is_err());
+    assert_eq!(chatroom.metrics().connected_users, 2);
+
+    // bob's queue is full with the user list
+    alice.send_message("hello".to_string()).unwrap();
+    let metrics = chatroom.metrics();
+    assert_eq!(metrics.connected_users, 1);
+    assert_eq!(metrics.messages_broadcast, 1);
+    assert_eq!(metrics.evictions, 1);
+    assert_eq!(metrics.join_rejections.duplicate_nickname, 1);
+    assert_eq!(metrics.join_rejections.invalid_nickname, 1);
+    assert_eq!(metrics.join_rejections.shutting_down, 0);
+
+    let text = metrics.to_prometheus();
+    assert!(text.contains("budget_chat_connected_users 1\n"));
+    assert!(text.contains("budget_chat_join_rejections_total{reason=\"duplicate_nickname\"} 1\n"));
+
+    drop(alice);
+    assert_eq!(chatroom.metrics().connected_users, 0);
+}
+
+#[test]
+fn rate_limit_parsing() {
+    let limit = |messages, millis| RateLimit {
+        messages,
+        per: Duration::from_millis(millis),
+    };
+    assert_eq!("10/5s".parse(), Ok(limit(10, 5000)));
+    assert_eq!("3/s".parse(), Ok(limit(3, 1000)));
+    assert_eq!("1/500ms".parse(), Ok(limit(1, 500)));
+    assert_eq!("60/2m".parse(), Ok(limit(60, 120_000)));
+    for invalid in ["10", "10/5", "0/5s", "10/0s", "x/5s", "10/5h"] {
+        assert!(invalid.parse::<RateLimit>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn locked_chatrooms_are_read_only() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        locked: true,
+        ..Default::default()
+    });
+    assert!(chatroom.stats().locked);
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    // the operator is heard, the others are not
+    alice_session
+        .send_message("announcement".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] announcement"]);
+    assert_eq!(
+        bob_session.send_emote("complains".to_string()),
+        Err(SendError::ReadOnly)
+    );
+    assert_eq!(
+        chatroom.send_private(&bob_session, "alice", "psst".to_string()),
+        Err(SendError::ReadOnly)
+    );
+    assert_eq!(drain(&mut bob), ["* the room is currently read-only"; 2]);
+    assert!(drain(&mut alice).is_empty());
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice", "bob"]);
+
+    assert_eq!(
+        chatroom.set_locked(&bob_session, false),
+        Err(KickError::NotOperator)
+    );
+    assert_eq!(chatroom.set_locked(&alice_session, false), Ok(true));
+    assert_eq!(chatroom.set_locked(&alice_session, false), Ok(false));
+    assert!(!chatroom.is_locked());
+    let notice = "* [server] the room is no longer read-only, by alice";
+    assert_eq!(drain(&mut alice), [notice]);
+    assert_eq!(drain(&mut bob), [notice]);
+    bob_session.send_message("thanks".to_string()).unwrap();
+    assert_eq!(drain(&mut alice), ["[bob] thanks"]);
+}
+
+#[test]
+fn mute_duration_parsing() {
+    let secs = Duration::from_secs;
+    assert_eq!(parse_mute_duration("90s"), Ok(secs(90)));
+    assert_eq!(parse_mute_duration("10m"), Ok(secs(600)));
+    assert_eq!(parse_mute_duration("1h"), Ok(secs(3600)));
+    assert_eq!(parse_mute_duration("2h30m"), Ok(secs(9000)));
+    assert_eq!(parse_mute_duration("1d2h3m4s"), Ok(secs(93_784)));
+    assert_eq!(parse_mute_duration(" 5m "), Ok(secs(300)));
+    for invalid in [
+        "", "10", "m", "0s", "0h0m", "10ms", "30m2h", "1h1h", "1.5h", "-1m", "1 h", "1w", "400d",
+    ] {
+        assert!(parse_mute_duration(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn muted_users_are_not_heard() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("Bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    assert_eq!(
+        chatroom.mute(&bob_session, "alice", Duration::from_secs(60)),
+        Err(KickError::NotOperator)
+    );
+    assert_eq!(
+        chatroom.mute(&alice_session, "carol", Duration::from_secs(60)),
+        Err(KickError::NoSuchUser("carol".to_string()))
+    );
+    chatroom
+        .mute(&alice_session, "Bob", Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(
+        bob_session.send_message("hello".to_string()),
+        Err(SendError::Muted)
+    );
+    assert_eq!(
+        bob_session.send_emote("waves".to_string()),
+        Err(SendError::Muted)
+    );
+    // nor privately
+    assert_eq!(
+        chatroom.send_private(&bob_session, "alice", "psst".to_string()),
+        Err(SendError::Muted)
+    );
+    let notices = drain(&mut bob);
+    assert_eq!(notices.len(), 3);
+    assert!(
+        notices[0].starts_with("* you are muted until "),
+        "{notices:?}"
+    );
+    // bob still hears the others
+    alice_session.send_message("hi bob".to_string()).unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] hi bob"]);
+    assert!(drain(&mut alice).is_empty());
+
+    // joining again, with another case, does not end the mute
+    drop(bob_session);
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        bob_session.send_message("hello".to_string()),
+        Err(SendError::Muted)
+    );
+    drain(&mut alice);
+
+    assert_eq!(chatroom.unmute(&alice_session, "BOB"), Ok(true));
+    assert_eq!(chatroom.unmute(&alice_session, "bob"), Ok(false));
+    bob_session.send_message("hello".to_string()).unwrap();
+    assert_eq!(drain(&mut alice), ["[bob] hello"]);
+
+    // the mutes end by themselves
+    chatroom
+        .mute(&alice_session, "bob", Duration::from_millis(50))
+        .unwrap();
+    assert!(bob_session.send_message("hello".to_string()).is_err());
+    std::thread::sleep(Duration::from_millis(100));
+    bob_session.send_message("hello again".to_string()).unwrap();
+    drain(&mut bob);
+    assert_eq!(drain(&mut alice), ["[bob] hello again"]);
+}
+
+#[test]
+fn fast_senders_are_limited() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        rate_limit: Some("3/10s".parse().unwrap()),
+        max_rate_violations: 4,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(64);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(64);
+    let (disconnected_sender, disconnected) = mpsc::sync_channel(1);
+    let bob_session = chatroom
+        .join_with_disconnect("bob".to_string(), sender, move || {
+            disconnected_sender.send(()).unwrap()
+        })
+        .ok()
+        .unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    // the first messages fill the bucket allowance, the next ones are dropped
+    let sent: Vec<_> = (0..6)
+        .map(|i| bob_session.send_message(format!("spam {i}")))
+        .collect();
+    assert!(sent[..3].iter().all(|sent| *sent == Ok(1)));
+    assert!(sent[3..]
+        .iter()
+        .all(|sent| *sent == Err(SendError::RateLimited)));
+    assert_eq!(
+        drain(&mut alice),
+        ["[bob] spam 0", "[bob] spam 1", "[bob] spam 2"]
+    );
+    assert_eq!(drain(&mut bob), ["* you are sending messages too fast"; 3]);
+    assert!(disconnected.try_recv().is_err());
+
+    // system messages are not limited
+    drop(alice_session);
+    let (sender, mut alice) = channel(64);
+    let _alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "* alice left the room (online 0s)",
+            "* alice joined the room"
+        ]
+    );
+    drain(&mut alice);
+
+    assert_eq!(
+        bob_session.send_message("one more".to_string()),
+        Err(SendError::RateLimited)
+    );
+    assert!(disconnected.try_recv().is_ok());
+    assert_eq!(
+        drain(&mut bob),
+        ["* you were disconnected for sending messages too fast"]
+    );
+    assert_eq!(drain(&mut alice), ["* bob left the room"]);
+    assert_eq!(
+        bob_session.send_message("gone".to_string()),
+        Err(SendError::NotConnected)
+    );
+}
+
+#[test]
+fn idle_users_are_disconnected() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let (disconnected_sender, disconnected) = mpsc::sync_channel(1);
+    let _alice_session = chatroom
+        .join_with_disconnect("alice".to_string(), sender, move || {
+            disconnected_sender.send(()).unwrap()
+        })
+        .ok()
+        .unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    for _ in 0..6 {
+        thread::sleep(Duration::from_millis(100));
+        bob_session.record_activity();
+    }
+    assert!(disconnected.try_recv().is_ok());
+    assert_eq!(drain(&mut alice), ["* disconnected: idle"]);
+    assert_eq!(drain(&mut bob), ["* alice left the room"]);
+    assert_eq!(chatroom.connected_users(), ["bob"]);
+}
+
+#[test]
+fn full_chatroom_rejects_joins() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        max_users: Some(2),
+        ..Default::default()
+    });
+    let mut sessions = ["alice", "bob"]
+        .into_iter()
+        .map(|nickname| {
+            let (sender, receiver) = channel(16);
+            (
+                chatroom.join(nickname.to_string(), sender).ok().unwrap(),
+                receiver,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let (sender, _receiver) = channel(16);
+    let error = chatroom.join("carol".to_string(), sender).err().unwrap();
+    assert!(matches!(error, JoinError::RoomFull));
+    assert_eq!(error.to_string(), "The room is full, try again later.");
+    let metrics = chatroom.metrics();
+    assert_eq!(metrics.connected_users, 2);
+    assert_eq!(metrics.join_rejections.room_full, 1);
+
+    // a leaving user frees a place
+    sessions.pop();
+    let (sender, _receiver) = channel(16);
+    let _carol = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(chatroom.metrics().connected_users, 2);
+}
+
+#[test]
+fn sessions_per_ip_are_limited() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        max_sessions_per_ip: Some(2),
+        ..Default::default()
+    });
+    let join_from = |nickname: &str, addr: &str| {
+        let (sender, receiver) = channel(16);
+        let session =
+            chatroom.join_from(nickname.to_string(), sender, addr.parse().unwrap(), || {})?;
+        Ok::<_, JoinError>((session, receiver))
+    };
+    let _alice = join_from("alice", "127.0.0.1:1000").unwrap();
+    let mut bob = Some(join_from("bob", "127.0.0.1:1001").unwrap());
+
+    let error = join_from("carol", "127.0.0.1:1002").err().unwrap();
+    assert_eq!(error, JoinError::TooManySessions);
+    assert_eq!(
+        error.to_string(),
+        "Too many users are connected from your address."
+    );
+    assert_eq!(chatroom.metrics().join_rejections.too_many_sessions, 1);
+    let _dave = join_from("dave", "127.0.0.2:1000").unwrap();
+
+    // a leaving user frees a slot
+    bob.take();
+    let _carol = join_from("carol", "127.0.0.1:1002").unwrap();
+    assert_eq!(
+        chatroom
+            .users()
+            .into_iter()
+            .find(|user| user.nickname == "carol")
+            .unwrap()
+            .peer_addr,
+        Some("127.0.0.1:1002".parse().unwrap())
+    );
+}
+
+#[test]
+fn nicknames_are_held_after_leaving() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        nick_hold: Some(Duration::from_secs(60)),
+        ..Default::default()
+    });
+    let join_from = |nickname: &str, addr: &str| {
+        let (sender, _receiver) = channel(16);
+        chatroom.join_from(nickname.to_string(), sender, addr.parse().unwrap(), || {})
+    };
+    drop(join_from("alice", "127.0.0.1:1000").unwrap());
+
+    let error = join_from("alice", "127.0.0.2:1000").err().unwrap();
+    assert_eq!(error, JoinError::NicknameReserved(Duration::from_secs(60)));
+    assert_eq!(error.to_string(), "Nickname reserved, try again in 1m00s.");
+    assert_eq!(chatroom.metrics().join_rejections.nickname_reserved, 1);
+    let bob = join_from("bob", "127.0.0.2:1000").unwrap();
+    assert!(matches!(
+        bob.rename("alice".to_string()),
+        Err(JoinError::NicknameReserved(_))
+    ));
+
+    // reclaimed from the same address, then released once it leaves for good
+    let alice = join_from("alice", "127.0.0.1:2000").unwrap();
+    drop(alice);
+    drop(bob);
+    let chatroom = Chatroom::new(ChatroomConfig {
+        nick_hold: Some(Duration::from_millis(50)),
+        ..Default::default()
+    });
+    let (sender, _receiver) = channel(16);
+    drop(chatroom.join("alice".to_string(), sender).unwrap());
+    let (sender, _receiver) = channel(16);
+    let addr = "127.0.0.2:1000".parse().unwrap();
+    assert!(chatroom
+        .join_from("alice".to_string(), sender.clone(), addr, || {})
+        .is_err());
+    thread::sleep(Duration::from_millis(60));
+    assert!(chatroom
+        .join_from("alice".to_string(), sender, addr, || {})
+        .is_ok());
+}
+
+#[test]
+fn detached_sessions_are_resumed() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        resume_window: Some(Duration::from_millis(200)),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let token = alice_session.resume_token().unwrap();
+    assert_eq!(token.len(), 32);
+    assert_eq!(
+        drain(&mut alice),
+        [
+            "* Welcome, the room is empty".to_string(),
+            format!("* resume-token: {token}")
+        ]
+    );
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    assert_ne!(bob_session.resume_token(), Some(token.clone()));
+    drain(&mut alice);
+    drain(&mut bob);
+
+    let error = chatroom.resume(&token, None, || {}).err().unwrap();
+    assert_eq!(error, ResumeError::StillConnected);
+    assert_eq!(error.to_string(), "The session is still connected.");
+    alice_session.detach(alice);
+    bob_session
+        .send_message("still there?".to_string())
+        .unwrap();
+    assert_eq!(chatroom.connected_users(), ["alice", "bob"]);
+    assert_eq!(
+        chatroom.resume("0123", None, || {}).err(),
+        Some(ResumeError::UnknownToken)
+    );
+
+    // the messages of the gap are received first, nobody is told
+    let mut resumed = chatroom
+        .resume(&token, Some("127.0.0.1:1000".parse().unwrap()), || {})
+        .unwrap();
+    assert_eq!(resumed.session.nickname().as_deref(), Some("alice"));
+    assert_eq!(drain(&mut resumed.receiver), ["[bob] still there?"]);
+    resumed.session.send_message("yes".to_string()).unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] yes"]);
+
+    // the users are told once the window passed
+    resumed.session.detach(resumed.receiver);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(drain(&mut bob), ["* alice left the room (online 0s)"]);
+    assert_eq!(chatroom.connected_users(), ["bob"]);
+    assert_eq!(
+        chatroom.resume(&token, None, || {}).err(),
+        Some(ResumeError::UnknownToken)
+    );
+
+    // without window, detached sessions leave
+    let chatroom = Chatroom::default();
+    let (sender, receiver) = channel(16);
+    let session = chatroom.join("alice".to_string(), sender).unwrap();
+    assert_eq!(session.resume_token(), None);
+    session.detach(receiver);
+    assert!(chatroom.connected_users().is_empty());
+}
+
+#[test]
+fn users_list_their_activity() {
+    let chatroom = Chatroom::default();
+    let (sender, _alice) = channel(16);
+    let addr = "192.0.2.1:4000".parse().unwrap();
+    let alice = chatroom
+        .join_from("alice".to_string(), sender, addr, || {})
+        .unwrap();
+    thread::sleep(Duration::from_millis(20));
+    let (sender, _bob) = channel(16);
+    let bob = chatroom.join("Bob".to_string(), sender).unwrap();
+    alice.send_message("hi".to_string()).unwrap();
+    alice.send_emote("waves".to_string()).unwrap();
+
+    // sorted case-insensitively
+    let users = chatroom.users();
+    assert_eq!(
+        users
+            .iter()
+            .map(|user| (user.nickname.as_str(), user.peer_addr, user.messages))
+            .collect::<Vec<_>>(),
+        [("alice", Some(addr), 2), ("Bob", None, 0)]
+    );
+    assert!(users[0].connected_for > users[1].connected_for);
+
+    drop(bob);
+    let users = chatroom.users();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].nickname, "alice");
+    drop(alice);
+    assert!(chatroom.users().is_empty());
+}
+
+#[test]
+fn operators_kick_users() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let (kicked_sender, kicked) = mpsc::sync_channel(1);
+    let bob_session = chatroom
+        .join_with_disconnect("bob".to_string(), sender, move || {
+            kicked_sender.send(()).unwrap()
+        })
+        .ok()
+        .unwrap();
+    let (sender, mut carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+    drain(&mut carol);
+
+    // alice joined first, so is the operator
+    assert!(matches!(
+        chatroom.kick(&bob_session, "carol", None),
+        Err(KickError::NotOperator)
+    ));
+    assert!(matches!(
+        chatroom.kick(&alice_session, "dave", None),
+        Err(KickError::NoSuchUser(_))
+    ));
+    assert!(drain(&mut carol).is_empty());
+
+    assert!(chatroom
+        .kick(&alice_session, "bob", Some("spam".to_string()))
+        .is_ok());
+    assert!(kicked.try_recv().is_ok());
+    assert_eq!(drain(&mut bob), ["* you were kicked: spam"]);
+    assert_eq!(drain(&mut alice), ["* bob was kicked by alice"]);
+    assert_eq!(drain(&mut carol), ["* bob was kicked by alice"]);
+    assert_eq!(chatroom.connected_users(), ["alice", "carol"]);
+}
+
+#[test]
+fn evicted_operators_are_not_operators() {
+    let chatroom = Chatroom::default();
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    chatroom
+        .mute(&alice, "bob", Duration::from_secs(60))
+        .unwrap();
+
+    chatroom.kick_by_nick("alice", None).unwrap();
+    assert_eq!(
+        chatroom.kick(&alice, "bob", None),
+        Err(KickError::NotOperator)
+    );
+    assert_eq!(chatroom.unmute(&alice, "bob"), Err(KickError::NotOperator));
+    assert_eq!(chatroom.op(&alice, "bob"), Err(KickError::NotOperator));
+    assert_eq!(chatroom.connected_users(), ["bob"]);
+}
+
+#[test]
+fn configured_operator() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        operator: Some("bob".to_string()),
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+
+    assert!(chatroom.kick(&alice_session, "bob", None).is_err());
+    assert!(chatroom.kick(&bob_session, "alice", None).is_ok());
+    assert_eq!(chatroom.connected_users(), ["bob"]);
+
+    // the sessions no longer connected are no operators
+    drop(chatroom.kick_by_nick("bob", None));
+    let ip = "10.0.0.1".parse().unwrap();
+    assert_eq!(
+        chatroom.unban(&bob_session, ip),
+        Err(KickError::NotOperator)
+    );
+}
+
+#[test]
+fn bans_are_persisted() {
+    let file = std::env::temp_dir().join(format!("budget-chat-bans-{}", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let ip = "10.0.0.1".parse().unwrap();
+    let other = "::1".parse().unwrap();
+
+    let bans = BanList::load(file.clone()).unwrap();
+    assert!(!bans.is_banned(ip));
+    assert!(bans.ban(ip));
+    assert!(!bans.ban(ip));
+    assert!(bans.ban(other));
+
+    let bans = BanList::load(file.clone()).unwrap();
+    assert_eq!(bans.ips(), [ip, other]);
+    assert!(bans.unban(other));
+    assert!(!bans.unban(other));
+
+    let bans = BanList::load(file.clone()).unwrap();
+    assert_eq!(bans.ips(), [ip]);
+    // rewritten through a temporary file
+    let mut temporary = file.clone().into_os_string();
+    temporary.push(".tmp");
+    assert!(!std::path::Path::new(&temporary).exists());
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn operators_follow_the_nickname_rules() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        nicknames: NicknameRules {
+            extra_chars: ".".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob = chatroom.join("b.o.b".to_string(), sender).ok().unwrap();
+    drain(&mut bob);
+
+    chatroom.op(&alice, "b.o.b").unwrap();
+    assert_eq!(drain(&mut bob), ["* you are now an operator, by alice"]);
+    // offline, with a nickname allowed by the rules
+    chatroom.op(&alice, "c.a.r.o.l").unwrap();
+    assert_eq!(
+        chatroom.op(&alice, "c-a-r-o-l"),
+        Err(KickError::NoSuchUser("c-a-r-o-l".to_string()))
+    );
+}
+
+#[test]
+fn operators_are_persisted() {
+    let file = std::env::temp_dir().join(format!("budget-chat-ops-{}", std::process::id()));
+    std::fs::write(&file, "carol\n\n").unwrap();
+    let operators = Arc::new(OperatorList::load(file.clone()).unwrap());
+    let chatroom = Chatroom::new(ChatroomConfig {
+        operators: operators.clone(),
+        ..Default::default()
+    });
+
+    // with operators listed, the first user to join is not one
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["alice", "@carol"]);
+    assert_eq!(
+        chatroom.op(&alice_session, "bob"),
+        Err(KickError::NotOperator)
+    );
+    drain(&mut alice);
+    drain(&mut carol);
+
+    chatroom.op(&carol_session, "alice").unwrap();
+    chatroom.op(&carol_session, "bob").unwrap();
+    assert_eq!(drain(&mut alice), ["* you are now an operator, by carol"]);
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice", "@carol"]);
+    assert_eq!(
+        std::fs::read_to_string(&file).unwrap(),
+        "alice\nbob\ncarol\n"
+    );
+
+    chatroom.deop(&alice_session, "carol").unwrap();
+    assert_eq!(
+        drain(&mut carol),
+        ["* you are no longer an operator, by alice"]
+    );
+    assert_eq!(
+        chatroom.deop(&carol_session, "alice"),
+        Err(KickError::NotOperator)
+    );
+    assert_eq!(
+        chatroom.op(&alice_session, "not a nickname"),
+        Err(KickError::NoSuchUser("not a nickname".to_string()))
+    );
+
+    // the file holds the operators for the next run, bob is one on joining
+    let operators = OperatorList::load(file.clone()).unwrap();
+    assert_eq!(operators.nicknames(), ["alice", "bob"]);
+    let (sender, _bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice", "@bob", "carol"]);
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn history_is_replayed_to_joining_users() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        history: HistoryConfig {
+            messages: 2,
+            bytes: 1024,
+            notices: false,
+        },
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    for text in ["one", "two", "three"] {
+        alice.send_message(text.to_string()).unwrap();
+    }
+
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "* Welcome, the room contains 1 user: alice",
+            "* [history] [alice] two",
+            "* [history] [alice] three"
+        ]
+    );
+
+    // other rooms have their own history
+    bob_session.join_room("rust").ok().unwrap();
+    bob_session.send_message("in rust".to_string()).unwrap();
+    let (sender, mut carol) = channel(16);
+    let _carol = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut carol),
+        [
+            "* Welcome, the room contains 1 user: alice",
+            "* [history] [alice] three"
+        ]
+    );
+}
+
+#[test]
+fn history_is_bounded_in_bytes() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        history: HistoryConfig {
+            messages: 50,
+            bytes: 40,
+            notices: true,
+        },
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    alice.send_message("x".repeat(100)).unwrap();
+    alice.send_message("short".to_string()).unwrap();
+
+    let (sender, mut bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "* Welcome, the room contains 1 user: alice",
+            "* [history] alice joined the room",
+            "* [history] [alice] short"
+        ]
+    );
+}
+
+#[test]
+fn chat_log_records_the_events() {
+    let file = std::env::temp_dir().join(format!("budget-chat-log-{}", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let chat_log = Arc::new(ChatLog::open(&file, LogSync::Every).unwrap());
+    let chatroom = Chatroom::new(ChatroomConfig {
+        chat_log: Some(chat_log),
+        ..Default::default()
+    });
+
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    alice.send_message("hello \"bob\"".to_string()).unwrap();
+    drop(alice);
+    chatroom.shutdown();
+
+    let events = std::fs::read_to_string(&file)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let brief = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{} {} {}",
+                event["event"].as_str().unwrap(),
+                event["nickname"].as_str().unwrap(),
+                event["text"].as_str().unwrap_or("-")
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        brief,
+        [
+            "join alice -",
+            "join bob -",
+            "message alice hello \"bob\"",
+            "leave alice -",
+            "leave bob -"
+        ]
+    );
+    assert!(events[0]["time"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(events[0]["room"], "lobby");
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn chat_logs_are_read_back() {
+    let file = std::env::temp_dir().join(format!("budget-chat-read-{}", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let chat_log = Arc::new(ChatLog::open(&file, LogSync::Every).unwrap());
+    // a single chat log per file
+    assert!(ChatLog::open(&file, LogSync::Every).is_err());
+    let chatroom = Chatroom::new(ChatroomConfig {
+        chat_log: Some(chat_log.clone()),
+        ..Default::default()
+    });
+
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    for text in ["Rust is fun", "lunch?", "rusty bikes", "TRUST me"] {
+        alice.send_message(text.to_string()).unwrap();
+    }
+    alice.join_room("garage").unwrap();
+    alice.send_emote("fixes the rust".to_string()).unwrap();
+    chatroom.shutdown();
+
+    let events = read_chat_log(&file)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    // the join, the messages, the move to the garage, the emote and the leave
+    assert_eq!(events.len(), 9);
+    assert_eq!(events[0].event, "join");
+    assert!(events[1].to_string().ends_with(" [alice] Rust is fun"));
+    let line = std::fs::read_to_string(&file).unwrap();
+    assert_eq!(
+        events[1].to_json(),
+        serde_json::from_str::<serde_json::Value>(line.lines().nth(1).unwrap()).unwrap()
+    );
+    // the log is released once closed
+    drop(chatroom);
+    drop(chat_log);
+    ChatLog::open(&file, LogSync::Never).unwrap();
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn messages_are_archived_and_searched() {
+    let file = std::env::temp_dir().join(format!("budget-chat-archive-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let archive = Arc::new(Archive::open(&file).unwrap());
+    let chatroom = Chatroom::new(ChatroomConfig {
+        archive: Some(archive.clone()),
+        ..Default::default()
+    });
+
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    for text in [
+        "Rust is fun",
+        "lunch?",
+        "rusty bikes",
+        "TRUST me",
+        "Ærø ROST",
+    ] {
+        alice.send_message(text.to_string()).unwrap();
+    }
+    alice.join_room("garage").unwrap();
+    alice.send_emote("fixes the rust".to_string()).unwrap();
+
+    // the messages still queued for the writer thread are found too
+    let texts = |found: Vec<ArchivedMessage>| {
+        found
+            .into_iter()
+            .map(|message| message.text)
+            .collect::<Vec<_>>()
+    };
+    let found = archive.search(Chatroom::LOBBY, "rust", 2).unwrap();
+    assert_eq!(texts(found), ["rusty bikes", "TRUST me"]);
+    let found = archive.search(Chatroom::LOBBY, "RUST", 10).unwrap();
+    assert_eq!(texts(found), ["Rust is fun", "rusty bikes", "TRUST me"]);
+    let found = archive.search(Chatroom::LOBBY, "ærø", 10).unwrap();
+    assert_eq!(texts(found), ["Ærø ROST"]);
+    let found = archive.search("garage", "rust", 10).unwrap();
+    assert!(found[0].emote);
+    assert!(found[0].to_string().ends_with(" * alice fixes the rust"));
+    assert!(archive.search("garage", "lunch", 10).unwrap().is_empty());
+
+    // once per 10s per nickname, from the room of the user
+    let found = alice.search("rust").unwrap().run(10).unwrap();
+    assert_eq!(texts(found), ["fixes the rust"]);
+    assert_eq!(
+        alice.search("rust").err().unwrap().to_string(),
+        "wait 10s before searching again"
+    );
+    drop(alice);
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("Alice".to_string(), sender).ok().unwrap();
+    assert!(matches!(alice.search("rust"), Err(SearchError::TooSoon(_))));
+    let (sender, _bob) = channel(16);
+    let bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    assert_eq!(bob.search("rust").unwrap().run(1).unwrap().len(), 1);
+    chatroom.shutdown();
+
+    let mut messages = Vec::new();
+    read_archive(&file, None, |message| messages.push(message)).unwrap();
+    assert_eq!(messages.len(), 6);
+    assert_eq!(messages[0].id, 1);
+    assert_eq!(messages[0].room, "lobby");
+    assert_eq!(messages[0].nickname, "alice");
+    assert_eq!(messages[5].to_json()["event"], "emote");
+    let since = |since| {
+        let mut count = 0;
+        read_archive(&file, Some(since), |_| count += 1).unwrap();
+        count
+    };
+    assert_eq!(since(messages[0].time), 6);
+    assert_eq!(
+        since(messages[5].time + chrono::Duration::milliseconds(1)),
+        0
+    );
+    // and opened again by the next server, at the same schema version
+    drop(chatroom);
+    drop(archive);
+    let archive = Archive::open(&file).unwrap();
+    assert_eq!(archive.search("garage", "rust", 10).unwrap().len(), 1);
+    drop(archive);
+    std::fs::remove_file(&file).unwrap();
+    let _ = std::fs::remove_file(file.with_extension("db-wal"));
+    let _ = std::fs::remove_file(file.with_extension("db-shm"));
+}
+
+#[test]
+fn unusable_archives_fail_to_open() {
+    let file = std::env::temp_dir().join(format!("budget-chat-corrupt-{}.db", std::process::id()));
+    std::fs::write(
+        &file,
+        b"not a database, not at all, even if it is long enough",
+    )
+    .unwrap();
+    let e = Archive::open(&file).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(read_archive(&file, None, |_| {}).is_err());
+    std::fs::remove_file(&file).unwrap();
+
+    // locked by another process writing it
+    drop(Archive::open(&file).unwrap());
+    let connection = rusqlite::Connection::open(&file).unwrap();
+    connection.execute_batch("BEGIN EXCLUSIVE").unwrap();
+    let e = Archive::open(&file).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::ResourceBusy);
+    connection.execute_batch("ROLLBACK").unwrap();
+    drop(Archive::open(&file).unwrap());
+
+    // written by a newer version
+    connection.pragma_update(None, "user_version", 99).unwrap();
+    let e = Archive::open(&file).err().unwrap();
+    assert!(e.to_string().contains("newer"), "{e}");
+    drop(connection);
+    std::fs::remove_file(&file).unwrap();
+    let _ = std::fs::remove_file(file.with_extension("db-wal"));
+    let _ = std::fs::remove_file(file.with_extension("db-shm"));
+}
+
+/// Wait for the chat log writer thread to satisfy `condition`
+fn wait_for_log(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "chat log not written");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn chat_logs_are_rotated() {
+    let directory =
+        std::env::temp_dir().join(format!("budget-chat-rotation-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir(&directory).unwrap();
+    let file = directory.join("chat.log");
+    let day = |n: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_564_800 + n * 86_400);
+    let date = |n: u64| DateTime::<Local>::from(day(n)).date_naive().to_string();
+    let today = Arc::new(AtomicU64::new(0));
+    let clock = today.clone();
+    let rotation = LogRotation {
+        daily: true,
+        max_bytes: None,
+        keep: 2,
+    };
+    let chat_log = ChatLog::open_with_clock(&file, LogSync::Every, rotation, move || {
+        day(clock.load(Ordering::Relaxed))
+    })
+    .unwrap();
+    let chatroom = Chatroom::new(ChatroomConfig {
+        chat_log: Some(Arc::new(chat_log)),
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let logged = |path: &std::path::Path| std::fs::read_to_string(path).unwrap_or_default();
+    wait_for_log(|| !logged(&file).is_empty());
+    for n in 1..=3 {
+        today.store(n, Ordering::Relaxed);
+        alice.send_message(format!("day {n}")).unwrap();
+        wait_for_log(|| logged(&file).contains(&format!("\"day {n}\"")));
+    }
+
+    let mut files = std::fs::read_dir(&directory)
+        .unwrap()
+        .map(|file| file.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    // the file of the day 0 was deleted
+    let mut expected = vec![
+        "chat.log".to_string(),
+        format!("chat-{}.log", date(1)),
+        format!("chat-{}.log", date(2)),
+    ];
+    expected.sort();
+    assert_eq!(files, expected);
+    // every event is whole, in the file of its day
+    let day_2 = logged(&directory.join(format!("chat-{}.log", date(2))));
+    assert_eq!(day_2.lines().count(), 1);
+    assert!(day_2.contains("\"day 2\""));
+    assert_eq!(logged(&file).lines().count(), 1);
+    chatroom.shutdown();
+    drop(alice);
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    // and when they are full, numbered if rotated the same day
+    std::fs::create_dir(&directory).unwrap();
+    let rotation = LogRotation {
+        daily: false,
+        max_bytes: Some(1),
+        keep: 5,
+    };
+    let chat_log =
+        ChatLog::open_with_clock(&file, LogSync::Every, rotation, move || day(0)).unwrap();
+    let chatroom = Chatroom::new(ChatroomConfig {
+        chat_log: Some(Arc::new(chat_log)),
+        ..Default::default()
+    });
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    alice.send_message("one".to_string()).unwrap();
+    alice.send_message("two".to_string()).unwrap();
+    wait_for_log(|| logged(&file).contains("\"two\""));
+    assert!(logged(&directory.join(format!("chat-{}.log", date(0)))).contains("join"));
+    assert!(logged(&directory.join(format!("chat-{}.1.log", date(0)))).contains("\"one\""));
+    chatroom.shutdown();
+    drop(alice);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn unwritable_chat_log_fails_at_open() {
+    let path = std::env::temp_dir().join("budget-chat-missing-dir/chat.log");
+    assert!(ChatLog::open(&path, LogSync::Never).is_err());
+}
+
+#[test]
+fn rename() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    assert!(matches!(
+        alice_session.rename("bob".to_string()),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+    assert!(matches!(
+        alice_session.rename("not valid".to_string()),
+        Err(JoinError::InvalidNickname(_))
+    ));
+    assert!(drain(&mut bob).is_empty());
+
+    assert_eq!(alice_session.nickname().as_deref(), Some("alice"));
+    alice_session.rename("alice2".to_string()).ok().unwrap();
+    assert_eq!(alice_session.nickname().as_deref(), Some("alice2"));
+    assert_eq!(drain(&mut alice), ["* alice is now known as alice2"]);
+    assert_eq!(drain(&mut bob), ["* alice is now known as alice2"]);
+    assert_eq!(chatroom.connected_users(), ["alice2", "bob"]);
+
+    // the old nickname is free again, the new one is taken
+    let (sender, _receiver) = channel(16);
+    assert!(chatroom.join("alice".to_string(), sender).is_ok());
+    let (sender, _receiver) = channel(16);
+    assert!(matches!(
+        chatroom.join("alice2".to_string(), sender),
+        Err(JoinError::DuplicateNickname(_))
+    ));
+}
+
+#[test]
+fn duplicate_nicknames_get_a_suffix() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        on_duplicate: OnDuplicate::Suggest,
+        ..Default::default()
+    });
+    let (sender, _receiver) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _receiver) = channel(16);
+    let _alice2 = chatroom.join("alice2".to_string(), sender).ok().unwrap();
+    let (sender, _receiver) = channel(16);
+    let error = chatroom.join("alice".to_string(), sender).err().unwrap();
+    assert_eq!(error.to_string(), "Nickname taken, try: alice3");
+
+    // concurrent joins with the same nickname all get a distinct one
+    let chatroom = Chatroom::new(ChatroomConfig {
+        on_duplicate: OnDuplicate::Auto,
+        ..Default::default()
+    });
+    let joins = (0..8)
+        .map(|_| {
+            let chatroom = chatroom.clone();
+            thread::spawn(move || {
+                let (sender, receiver) = channel(16);
+                let session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+                (session, receiver)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut sessions = joins
+        .into_iter()
+        .map(|join| join.join().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        chatroom.connected_users(),
+        ["bob", "bob2", "bob3", "bob4", "bob5", "bob6", "bob7", "bob8"]
+    );
+    let notices = sessions
+        .iter_mut()
+        .filter_map(|(_, receiver)| {
+            drain(receiver)
+                .into_iter()
+                .find(|line| line.starts_with("* You have joined as"))
+        })
+        .count();
+    assert_eq!(notices, 7);
+}
+
+#[test]
+fn administration() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    alice_session.set_peer_addr("192.0.2.1:4000".parse().unwrap());
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    bob_session.join_room("games").ok().unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    let users = chatroom.users();
+    assert_eq!(
+        users
+            .iter()
+            .map(|user| (user.nickname.as_str(), user.room.as_str(), user.peer_addr))
+            .collect::<Vec<_>>(),
+        [
+            ("alice", "lobby", Some("192.0.2.1:4000".parse().unwrap())),
+            ("bob", "games", None)
+        ]
+    );
+    assert_ne!(users[0].id, users[1].id);
+
+    chatroom.broadcast_notice("maintenance in 5 minutes".to_string());
+    assert_eq!(drain(&mut alice), ["* [server] maintenance in 5 minutes"]);
+    assert_eq!(drain(&mut bob), ["* [server] maintenance in 5 minutes"]);
+
+    let (sender, mut carol) = channel(16);
+    let _carol = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    drain(&mut carol);
+    assert!(matches!(
+        chatroom.kick_by_nick("dave", None),
+        Err(KickError::NoSuchUser(_))
+    ));
+    chatroom.kick_by_nick("carol", None).ok().unwrap();
+    assert_eq!(drain(&mut carol), ["* you were kicked by the server"]);
+    assert_eq!(drain(&mut alice), ["* carol was kicked by the server"]);
+    assert_eq!(chatroom.connected_users(), ["alice", "bob"]);
+}
+
+#[test]
+fn server_notices() {
+    let chatroom = Chatroom::default();
+    // nobody to notify
+    chatroom.broadcast_notice("restarting in 5 minutes");
+    assert_eq!(chatroom.user_count(), 0);
+
+    let mut receivers = ["alice", "bob", "carol"]
+        .into_iter()
+        .map(|nickname| {
+            let (sender, receiver) = channel(16);
+            let session = chatroom.join(nickname.to_string(), sender).ok().unwrap();
+            (session, receiver)
+        })
+        .collect::<Vec<_>>();
+    receivers[2].0.join_room("games").ok().unwrap();
+    assert_eq!(chatroom.user_count(), 3);
+    for (_, receiver) in &mut receivers {
+        drain(receiver);
+    }
+
+    chatroom.broadcast_notice("restarting in 5 minutes");
+    for (_, receiver) in &mut receivers {
+        assert_eq!(drain(receiver), ["* [server] restarting in 5 minutes"]);
+    }
+
+    let (sender, mut dave) = channel(16);
+    let _dave = chatroom.join("dave".to_string(), sender).ok().unwrap();
+    assert_eq!(
+        drain(&mut dave)[1..],
+        ["* [history] [server] restarting in 5 minutes"]
+    );
+}
+
+#[test]
+fn recipient_count() {
+    let chatroom = Chatroom::default();
+
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    assert_eq!(alice.send_message("anybody?".to_string()), Ok(0));
+
+    let (sender, _bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    let (sender, carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(alice.send_message("hello".to_string()), Ok(2));
+    assert_eq!(alice.send_emote("waves".to_string()), Ok(2));
+
+    // carol's connection is gone but her session is still in the room
+    drop(carol);
+    assert_eq!(alice.send_message("bye".to_string()), Ok(1));
+}
+
+#[test]
+fn errors() {
+    let error: Box<dyn std::error::Error> = JoinError::DuplicateNickname("bob".to_string()).into();
+    assert_eq!(error.to_string(), "Nickname already used.");
+    assert!(error.source().is_none());
+
+    let error = JoinError::InvalidNickname(NicknameError::TooLong { max: 16 });
+    let source = std::error::Error::source(&error).unwrap();
+    assert_eq!(source.to_string(), "Nickname too long (max 16)");
+
+    assert_eq!(
+        SendError::NoSuchUser("bob".to_string()).clone(),
+        SendError::NoSuchUser("bob".to_string())
+    );
+}
+
+#[test]
+fn session_identity() {
+    let chatroom = Chatroom::default();
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, _bob) = channel(16);
+    let bob = chatroom.join("bob".to_string(), sender).ok().unwrap();
+
+    assert_ne!(alice.id(), bob.id());
+    let ids: Vec<_> = chatroom.users().iter().map(|user| user.id).collect();
+    assert_eq!(ids, [alice.id(), bob.id()]);
+    assert!(bob.is_connected());
+
+    chatroom.kick_by_nick("bob", None).ok().unwrap();
+    assert!(!bob.is_connected());
+    assert_eq!(bob.nickname(), None);
+    assert_eq!(alice.nickname().as_deref(), Some("alice"));
+}
+
+#[test]
+fn concurrent_joins_get_unique_ids() {
+    let chatroom = Chatroom::default();
+    let joins = (0..8)
+        .map(|thread| {
+            let chatroom = chatroom.clone();
+            thread::spawn(move || {
+                (0..100)
+                    .map(|i| {
+                        let (sender, _receiver) = channel(4);
+                        let nickname = format!("user{thread}x{i}");
+                        // the session leaves right away, its id must not be reused
+                        chatroom.join(nickname, sender).ok().unwrap().id()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut ids = joins
+        .into_iter()
+        .flat_map(|join| join.join().unwrap())
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), 800);
+}
+
+/// Collect the messages received on `receiver` until `last` (included), for at most 10s
+fn receive_until(receiver: &mut Receiver<Envelope>, last: &str) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut lines = Vec::new();
+    while lines.last().is_none_or(|line| line != last) {
+        match receiver.try_recv() {
+            Ok(message) => lines.push(message.to_string()),
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            Err(_) => panic!("{last:?} not received, got {lines:?}"),
+        }
+    }
+    lines
+}
+
+#[test]
+fn fanout_workers_keep_the_order() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        fanout_workers: 3,
+        // the transcripts end with the leave notices, without durations
+        plain_leave: true,
+        ..Default::default()
+    });
+    let watchers = (0..4)
+        .map(|i| {
+            let (sender, receiver) = channel(10_000);
+            let session = chatroom.join(format!("watcher{i}"), sender).ok().unwrap();
+            (session, receiver)
+        })
+        .collect::<Vec<_>>();
+
+    // the chatters join, talk and leave concurrently
+    let chatters = (0..4)
+        .map(|i| {
+            let chatroom = chatroom.clone();
+            thread::spawn(move || {
+                let (sender, _receiver) = channel(10_000);
+                let session = chatroom.join(format!("chatter{i}"), sender).ok().unwrap();
+                for n in 0..200 {
+                    session.send_message(n.to_string()).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for chatter in chatters {
+        chatter.join().unwrap();
+    }
+    let (sender, _receiver) = channel(16);
+    drop(chatroom.join("last".to_string(), sender));
+
+    for (_, mut receiver) in watchers {
+        let lines = receive_until(&mut receiver, "* last left the room");
+        for i in 0..4 {
+            let nickname = format!("chatter{i}");
+            let expected = iter::once(format!("* {nickname} joined the room"))
+                .chain((0..200).map(|n| format!("[{nickname}] {n}")))
+                .chain(iter::once(format!("* {nickname} left the room")))
+                .collect::<Vec<_>>();
+            let received = lines
+                .iter()
+                .filter(|line| line.contains(&nickname))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(received, expected);
+        }
+    }
+}
+
+#[test]
+fn fanout_workers_evict_slow_consumers() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        fanout_workers: 2,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (evicted_sender, evicted) = mpsc::channel();
+    let (sender, _bob) = channel(2);
+    let _bob_session = chatroom
+        .join_with_disconnect("bob".to_string(), sender, move || {
+            evicted_sender.send(()).unwrap()
+        })
+        .ok()
+        .unwrap();
+
+    for text in ["one", "two", "three"] {
+        assert_eq!(alice_session.send_message(text.to_string()), Ok(1));
+    }
+    evicted.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(
+        receive_until(&mut alice, "* bob left the room"),
+        [
+            "* Welcome, the room is empty",
+            "* bob joined the room",
+            "* bob left the room"
+        ]
+    );
+}
+
+/// Every user chats, their transcripts are checked against each other
+fn check_total_order(fanout_workers: usize) {
+    const USERS: usize = 10;
+    const MESSAGES: usize = 100;
+    let chatroom = Chatroom::new(ChatroomConfig {
+        fanout_workers,
+        // the transcripts end with the leave notices, without durations
+        plain_leave: true,
+        ..Default::default()
+    });
+    let users = (0..USERS)
+        .map(|i| {
+            let (sender, receiver) = channel(USERS * MESSAGES + 64);
+            let session = chatroom.join(format!("user{i}"), sender).ok().unwrap();
+            (Arc::new(session), receiver)
+        })
+        .collect::<Vec<_>>();
+
+    let barrier = Arc::new(Barrier::new(USERS));
+    let senders = users
+        .iter()
+        .map(|(session, _)| {
+            let (session, barrier) = (session.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for n in 0..MESSAGES {
+                    session.send_message(n.to_string()).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    let (sender, _receiver) = channel(16);
+    drop(chatroom.join("last".to_string(), sender));
+
+    let transcripts = users
+        .into_iter()
+        .map(|(_, mut receiver)| {
+            receive_until(&mut receiver, "* last left the room")
+                .into_iter()
+                .filter(|line| line.starts_with('['))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let without = |transcript: &[String], user: usize| {
+        let prefix = format!("[user{user}] ");
+        transcript
+            .iter()
+            .filter(|line| !line.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    for (i, transcript) in transcripts.iter().enumerate() {
+        assert_eq!(transcript.len(), (USERS - 1) * MESSAGES);
+        for (j, other) in transcripts.iter().enumerate() {
+            assert_eq!(without(transcript, j), without(other, i));
+        }
+    }
+}
+
+#[test]
+fn total_order() {
+    check_total_order(0);
+}
+
+#[test]
+fn total_order_with_fanout_workers() {
+    check_total_order(4);
+}
+
+#[test]
+fn control_characters_are_stripped() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    for text in [
+        "hi\r[admin] you are banned",
+        "\x1b[2Jclear\x1b[0m screen",
+        "ring\x07 ring",
+        "plain message\twith a tab",
+    ] {
+        alice_session.send_message(text.to_string()).unwrap();
+    }
+    alice_session.send_emote("waves\r".to_string()).unwrap();
+    chatroom
+        .send_private(&alice_session, "bob", "psst\x1b".to_string())
+        .unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "[alice] hi[admin] you are banned",
+            "[alice] clear screen",
+            "[alice] ring ring",
+            "[alice] plain message\twith a tab",
+            "* alice waves",
+            "[alice -> you] psst",
+        ]
+    );
+}
+
+#[test]
+fn control_characters_are_rejected() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        sanitize: Sanitize::Reject,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    for text in ["hi\r[admin] you are banned", "\x1b[2J", "ring\x07"] {
+        assert_eq!(
+            alice_session.send_message(text.to_string()),
+            Err(SendError::ControlCharacters)
+        );
+    }
+    assert_eq!(
+        chatroom.send_private(&alice_session, "bob", "psst\r".to_string()),
+        Err(SendError::ControlCharacters)
+    );
+    assert_eq!(
+        drain(&mut alice),
+        ["* message dropped: control characters are not allowed"; 3]
+    );
+    assert!(drain(&mut bob).is_empty());
+
+    alice_session
+        .send_message("plain message".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] plain message"]);
+    assert_eq!("strip".parse(), Ok(Sanitize::Strip));
+    assert!("never".parse::<Sanitize>().is_err());
+}
+
+#[test]
+fn echo() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        echo: true,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    assert_eq!(alice_session.send_message("hello".to_string()), Ok(2));
+    alice_session.send_emote("waves".to_string()).unwrap();
+    chatroom
+        .send_private(&alice_session, "bob", "psst".to_string())
+        .unwrap();
+    assert_eq!(
+        drain(&mut alice),
+        ["[alice] hello", "* alice waves", "[you -> bob] psst"]
+    );
+    assert_eq!(
+        drain(&mut bob),
+        ["[alice] hello", "* alice waves", "[alice -> you] psst"]
+    );
+
+    alice_session.set_echo(false);
+    assert_eq!(alice_session.send_message("again".to_string()), Ok(1));
+    assert!(drain(&mut alice).is_empty());
+    assert_eq!(drain(&mut bob), ["[alice] again"]);
+}
+
+#[test]
+fn topics() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    // only the operator sets the topic
+    assert_eq!(
+        bob_session.set_topic("mine".to_string()),
+        Err(TopicError::NotOperator)
+    );
+    assert!(bob_session.topic().is_none());
+    alice_session
+        .set_topic("Rust questions only".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice), ["* topic: Rust questions only"]);
+    assert_eq!(drain(&mut bob), ["* topic: Rust questions only"]);
+    let topic = bob_session.topic().unwrap();
+    assert_eq!(topic.text, "Rust questions only");
+    assert_eq!(topic.set_by, "alice");
+
+    // the joining users receive it after the user list
+    let (sender, mut carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).unwrap();
+    let joined = drain(&mut carol);
+    assert!(joined[0].starts_with("* Welcome"), "{joined:?}");
+    assert_eq!(joined[1..], ["* topic: Rust questions only"]);
+    drain(&mut bob);
+
+    // rooms have their own topic, kept while they are empty
+    bob_session.join_room("other").unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        ["* you are now in #other", "* Welcome, the room is empty"]
+    );
+    assert!(bob_session.topic().is_none());
+    bob_session.join_room(Chatroom::LOBBY).unwrap();
+    assert_eq!(
+        drain(&mut bob).last().unwrap(),
+        "* topic: Rust questions only"
+    );
+
+    alice_session.set_topic(String::new()).unwrap();
+    assert_eq!(
+        drain(&mut alice).last().unwrap(),
+        "* alice cleared the topic"
+    );
+    assert!(alice_session.topic().is_none());
+}
+
+#[test]
+fn anyone_sets_the_topic_without_operator() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        operator: Some("root".to_string()),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    drain(&mut alice);
+
+    alice_session
+        .set_topic("free\r for all".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice), ["* topic: free for all"]);
+
+    let (sender, _root) = channel(16);
+    let _root_session = chatroom.join("root".to_string(), sender).unwrap();
+    assert_eq!(
+        alice_session.set_topic("mine".to_string()),
+        Err(TopicError::NotOperator)
+    );
+}
+
+#[test]
+fn ignored_users() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    // ignores apply to the users joining later
+    alice_session.ignore("bob");
+    alice_session.ignore("carol");
+    assert_eq!(alice_session.ignored(), ["bob", "carol"]);
+    let (sender, mut carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+    drain(&mut carol);
+
+    assert_eq!(bob_session.send_message("hello".to_string()), Ok(1));
+    bob_session.send_emote("waves".to_string()).unwrap();
+    chatroom
+        .send_private(&bob_session, "alice", "psst".to_string())
+        .unwrap();
+    carol_session.send_message("hi".to_string()).unwrap();
+    assert!(drain(&mut alice).is_empty());
+    assert_eq!(drain(&mut bob), ["[you -> alice] psst", "[carol] hi"]);
+
+    assert!(alice_session.unignore("bob"));
+    assert!(!alice_session.unignore("bob"));
+    bob_session.send_message("again".to_string()).unwrap();
+    assert_eq!(drain(&mut alice), ["[bob] again"]);
+
+    // the ignores leave with the session
+    drop(alice_session);
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    assert!(alice_session.ignored().is_empty());
+    drain(&mut alice);
+    carol_session
+        .send_message("welcome back".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice), ["[carol] welcome back"]);
+}
+
+#[test]
+fn templates() {
+    let templates = MessageTemplates::parse(
+        r#"
+        # French
+        joined = "* {nick} est arrivé"
+        message = '<{from}> {text}'
+        history = "* [historique] {text} {{archive}}" # braces
+        notice = "→ {text}"
+        "#,
+    )
+    .unwrap();
+    let render = |message: Message| message.render(&templates);
+    assert_eq!(
+        render(Message::Joined {
+            nickname: "alice".into(),
+            users: None
+        }),
+        "* alice est arrivé"
+    );
+    assert_eq!(
+        render(Message::Message {
+            from: "alice".into(),
+            text: "salut".into()
+        }),
+        "<alice> salut"
+    );
+    assert_eq!(
+        render(Message::History(Box::new(Message::Joined {
+            nickname: "bob".into(),
+            users: None
+        }))),
+        "* [historique] bob est arrivé {archive}"
+    );
+    assert_eq!(render(Message::Notice("ping".into())), "→ ping");
+    // the other templates are the built-in ones
+    assert_eq!(
+        render(Message::Left {
+            nickname: "alice".into(),
+            online: None,
+            reason: None,
+        }),
+        "* alice left the room"
+    );
+    assert_eq!(
+        Message::Left {
+            nickname: "alice".into(),
+            online: None,
+            reason: None,
+        }
+        .render(&MessageTemplates::default()),
+        Message::Left {
+            nickname: "alice".into(),
+            online: None,
+            reason: None,
+        }
+        .to_string()
+    );
+}
+
+#[test]
+fn invalid_templates() {
+    let error = |toml| MessageTemplates::parse(toml).err().unwrap().to_string();
+    assert_eq!(
+        error("joined = \"{nick} joined\"\nleft = \"{nickname} left\""),
+        "line 2, left: unknown placeholder {nickname}, expected {nick}"
+    );
+    assert_eq!(
+        error("prompt = \"Hello {nick}\""),
+        "line 1, prompt: unknown placeholder {nick}, expected none"
+    );
+    assert_eq!(
+        error("welcome = \"hi\""),
+        "line 1, welcome: unknown template welcome"
+    );
+    assert_eq!(
+        error("\n\njoined = \"{nick\""),
+        "line 3, joined: unclosed {, write {{ for a brace"
+    );
+    assert_eq!(
+        error("joined = \"a\"\njoined = \"b\""),
+        "line 2: duplicate key"
+    );
+    assert_eq!(
+        error("joined = \"a"),
+        "line 1: invalid basic string, expected `\"`"
+    );
+    assert_eq!(error("[templates]"), "line 1: expected a string");
+    assert_eq!(error("joined = 42"), "line 1: expected a string");
+}
+
+#[test]
+fn reloaded_settings_apply_to_connected_users() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        rate_limit: Some("1/10s".parse().unwrap()),
+        max_users: Some(2),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).ok().unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).ok().unwrap();
+    drain(&mut alice);
+    assert_eq!(bob_session.send_message("first".to_string()), Ok(1));
+    assert_eq!(
+        bob_session.send_message("second".to_string()),
+        Err(SendError::RateLimited)
+    );
+    let (sender, _carol) = channel(16);
+    let error = chatroom.join("carol".to_string(), sender).err().unwrap();
+    assert!(matches!(error, JoinError::RoomFull));
+
+    chatroom.reload(RuntimeConfig {
+        rate_limit: None,
+        max_users: Some(3),
+        ..(*chatroom.runtime_config()).clone()
+    });
+    assert_eq!(chatroom.runtime_config().max_users, Some(3));
+    // the sessions are kept, with the new settings
+    assert_eq!(bob_session.send_message("third".to_string()), Ok(1));
+    assert_eq!(bob_session.send_message("fourth".to_string()), Ok(1));
+    assert_eq!(
+        drain(&mut alice),
+        ["[bob] first", "[bob] third", "[bob] fourth"]
+    );
+    let (sender, _carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).ok().unwrap();
+    assert_eq!(chatroom.user_count(), 3);
+    drop((alice_session, drain(&mut bob)));
+}
+
+#[test]
+fn stats() {
+    let chatroom = Chatroom::default();
+    let (sender, _alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, _bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+
+    alice_session.send_message("hello".to_string()).unwrap();
+    alice_session.send_emote("waves".to_string()).unwrap();
+    bob_session.send_message("hi".to_string()).unwrap();
+    let stats = chatroom.stats();
+    assert_eq!(stats.users, 2);
+    assert_eq!(stats.messages, 3);
+    assert!(stats.uptime >= alice_session.stats().unwrap().connected_for);
+    assert_eq!(alice_session.stats().unwrap().messages, 2);
+    assert_eq!(bob_session.stats().unwrap().messages, 1);
+
+    drop(bob_session);
+    assert_eq!(chatroom.stats().users, 1);
+    assert_eq!(chatroom.stats().messages, 3);
+}
+
+#[test]
+fn leave_durations() {
+    let left = |seconds| {
+        Message::Left {
+            nickname: "bob".into(),
+            online: Some(Duration::from_secs(seconds)),
+            reason: None,
+        }
+        .to_string()
+    };
+    assert_eq!(left(0), "* bob left the room (online 0s)");
+    assert_eq!(left(59), "* bob left the room (online 59s)");
+    assert_eq!(left(60), "* bob left the room (online 1m00s)");
+    assert_eq!(left(2533), "* bob left the room (online 42m13s)");
+    assert_eq!(left(3599), "* bob left the room (online 59m59s)");
+    assert_eq!(left(3600), "* bob left the room (online 1h00m00s)");
+    assert_eq!(left(3661), "* bob left the room (online 1h01m01s)");
+
+    let chatroom = Chatroom::new(ChatroomConfig {
+        plain_leave: true,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let _alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, _bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drop(bob_session);
+    assert_eq!(drain(&mut alice), ["* bob left the room"]);
+}
+
+#[test]
+fn quit_with_a_parting_message() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        plain_leave: true,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let _alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    bob_session.quit(Some("gotta\x1b[31m go".to_string()));
+    assert_eq!(drain(&mut alice), ["* bob left the room: gotta go"]);
+    assert_eq!(chatroom.connected_users(), ["alice"]);
+    assert_eq!(
+        bob_session.send_message("still here?".to_string()),
+        Err(SendError::NotConnected)
+    );
+    // dropping the session does not leave again
+    drop(bob_session);
+    assert!(drain(&mut alice).is_empty());
+
+    let (sender, _carol) = channel(16);
+    let carol_session = chatroom.join("carol".to_string(), sender).unwrap();
+    drain(&mut alice);
+    carol_session.quit(None);
+    assert_eq!(drain(&mut alice), ["* carol left the room"]);
+}
+
+#[test]
+fn away_users() {
+    let chatroom = Chatroom::default();
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    bob_session.set_away(Some("lunch".to_string())).unwrap();
+    assert_eq!(bob_session.away().as_deref(), Some("lunch"));
+    // nobody is told, but those writing to bob
+    assert!(drain(&mut alice).is_empty());
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice", "bob (away)"]);
+    chatroom
+        .send_private(&alice_session, "bob", "hungry?".to_string())
+        .unwrap();
+    assert_eq!(
+        drain(&mut alice),
+        ["[you -> bob] hungry?", "* bob is away: lunch"]
+    );
+    // away users receive everything
+    alice_session.send_message("hi".to_string()).unwrap();
+    assert_eq!(drain(&mut bob), ["[alice -> you] hungry?", "[alice] hi"]);
+
+    bob_session.set_away(Some(String::new())).unwrap();
+    chatroom
+        .send_private(&alice_session, "bob", "still?".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice), ["[you -> bob] still?", "* bob is away"]);
+
+    bob_session.set_away(None).unwrap();
+    assert_eq!(bob_session.away(), None);
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice", "bob"]);
+    chatroom
+        .send_private(&alice_session, "bob", "back?".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice), ["[you -> bob] back?"]);
+}
+
+#[test]
+fn mentions() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        echo: true,
+        history: HistoryConfig {
+            messages: 10,
+            bytes: 1024,
+            notices: false,
+        },
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    let (sender, mut bobby) = channel(16);
+    let _bobby_session = chatroom.join("bobby".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+    drain(&mut bobby);
+
+    for text in ["hey @bob, lunch?", "@BOB!", "@bobby only", "mail alice@bob"] {
+        alice_session.send_message(text.to_string()).unwrap();
+    }
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "(!) [alice] hey @bob, lunch?",
+            "(!) [alice] @BOB!",
+            "[alice] @bobby only",
+            "[alice] mail alice@bob"
+        ]
+    );
+    assert_eq!(
+        drain(&mut bobby),
+        [
+            "[alice] hey @bob, lunch?",
+            "[alice] @BOB!",
+            "(!) [alice] @bobby only",
+            "[alice] mail alice@bob"
+        ]
+    );
+    // the echo of the author is not marked
+    alice_session
+        .send_message("I am @alice".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut alice).last().unwrap(), "[alice] I am @alice");
+
+    // the mentions follow renames and ring the bell on demand
+    bob_session.rename("robert".to_string()).unwrap();
+    bob_session.set_bell(true);
+    drain(&mut bob);
+    alice_session
+        .send_message("@bob @robert".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut bob), ["\u{7}(!) [alice] @bob @robert"]);
+    // the bell is not kept in the history
+    let (sender, mut carol) = channel(16);
+    let _carol_session = chatroom.join("carol".to_string(), sender).unwrap();
+    let history = drain(&mut carol);
+    assert!(history.iter().any(|line| line.ends_with("@bob @robert")));
+    assert!(!history.iter().any(|line| line.contains('\u{7}')));
+}
+
+#[test]
+fn filtered_words_are_masked() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        word_filter: Arc::new(WordFilter::new(["darn", "Heck"])),
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    for text in [
+        "darn it",
+        "DARN, heck!",
+        "darning the socks",
+        "what the héck",
+    ] {
+        alice_session.send_message(text.to_string()).unwrap();
+    }
+    alice_session.send_emote("says heck".to_string()).unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "[alice] **** it",
+            "[alice] ****, ****!",
+            "[alice] darning the socks",
+            "[alice] what the héck",
+            "* alice says ****",
+        ]
+    );
+
+    // the nicknames are checked at join and rename
+    let (sender, _carol) = channel(16);
+    let error = chatroom.join("HECK".to_string(), sender).err();
+    assert_eq!(error, Some(JoinError::DisallowedNickname));
+    assert_eq!(chatroom.metrics().join_rejections.disallowed_nickname, 1);
+    assert_eq!(
+        alice_session.rename("darn".to_string()),
+        Err(JoinError::DisallowedNickname)
+    );
+
+    // the reloaded list applies to the connected users
+    chatroom.reload(RuntimeConfig {
+        word_filter: Arc::new(WordFilter::new(["socks"])),
+        ..(*chatroom.runtime_config()).clone()
+    });
+    alice_session
+        .send_message("darn socks".to_string())
+        .unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] darn *****"]);
+}
+
+#[test]
+fn filtered_words_are_rejected() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        word_filter: Arc::new(WordFilter::new(["darn"])),
+        word_filter_mode: WordFilterMode::Reject,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    assert_eq!(
+        alice_session.send_message("Darn it".to_string()),
+        Err(SendError::FilteredWords)
+    );
+    assert_eq!(
+        drain(&mut alice),
+        ["* message dropped: it contains filtered words"]
+    );
+    assert!(drain(&mut bob).is_empty());
+    alice_session.send_message("darning".to_string()).unwrap();
+    assert_eq!(drain(&mut bob), ["[alice] darning"]);
+    assert_eq!("mask".parse(), Ok(WordFilterMode::Mask));
+    assert!("drop".parse::<WordFilterMode>().is_err());
+}
+
+#[test]
+fn word_filter_files() {
+    let path = std::env::temp_dir().join(format!("budget-chat-words-{}", std::process::id()));
+    std::fs::write(&path, "darn\n\n  Heck \n").unwrap();
+    let filter = WordFilter::load(&path).unwrap();
+    assert_eq!(filter.len(), 2);
+    assert!(filter.matches("oh HECK"));
+    assert!(!filter.matches("heckle"));
+
+    std::fs::write(&path, "darn\ntwo words\n").unwrap();
+    let error = WordFilter::load(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(error.to_string(), "not a word: two words");
+}
+
+#[test]
+fn accounts_files() {
+    let path = std::env::temp_dir().join(format!("budget-chat-accounts-{}", std::process::id()));
+    // an Argon2i hash of "password" made by the reference implementation
+    std::fs::write(
+        &path,
+        "\nalice:$argon2i$v=19$m=65536,t=2,p=4$c29tZXNhbHQ$RdescudvJCsgt3ub+b+dWRWJTmaaJObG\n",
+    )
+    .unwrap();
+    let accounts = Accounts::load(&path).unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert!(accounts.is_registered("alice"));
+    assert!(!accounts.is_registered("bob"));
+    assert!(accounts.verify("alice", "password"));
+    assert!(!accounts.verify("alice", "Password"));
+    assert!(!accounts.verify("bob", "password"));
+
+    // and an Argon2id one, made by OpenSSL
+    std::fs::write(
+        &path,
+        "alice:$argon2id$v=19$m=256,t=2,p=2$c29tZXNhbHQ$bQk8UB/VmZZF4Oo79iDXuL5/0ttZwg2f/5U52iv1cDc\n",
+    )
+    .unwrap();
+    let accounts = Accounts::load(&path).unwrap();
+    assert!(accounts.verify("alice", "password"));
+    assert!(!accounts.verify("alice", "passwore"));
+
+    std::fs::write(
+        &path,
+        "alice:$argon2id$v=19$m=64,t=1,p=1$c2FsdHNhbHQ$AAAAAAAAAAAAAAAAAAAAAA\nbob\n",
+    )
+    .unwrap();
+    let error = Accounts::load(&path).err().unwrap();
+    assert_eq!(error.to_string(), "line 2: expected nickname:hash");
+    std::fs::write(&path, "alice:$md5$abc\n").unwrap();
+    assert!(Accounts::load(&path).is_err());
+    std::fs::write(
+        &path,
+        "alice:$argon2id$v=19$m=64,t=1,p=4294967295$c2FsdHNhbHQ$AAAAAAAAAAAAAAAAAAAAAA\n",
+    )
+    .unwrap();
+    let error = Accounts::load(&path).err().unwrap().to_string();
+    assert!(
+        error.starts_with("line 1: invalid Argon2 parameters"),
+        "{error}"
+    );
+    let lanes = PasswordParams {
+        memory_kib: 64,
+        passes: 1,
+        lanes: u32::MAX,
+    };
+    assert!(hash_password("password", lanes).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn repeated_messages_are_dropped() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    let _guard = runtime.enter();
+    let advance = |millis| runtime.block_on(tokio::time::advance(Duration::from_millis(millis)));
+    let chatroom = Chatroom::new(ChatroomConfig {
+        repeat_limit: Some("3/10s".parse().unwrap()),
+        max_repeat_violations: 3,
+        ..Default::default()
+    });
+    let (sender, mut alice) = channel(64);
+    let _alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(64);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    // spaced out, the same acknowledgement is never more than 3 times in 10s
+    for _ in 0..6 {
+        assert_eq!(bob_session.send_message("ok".to_string()), Ok(1));
+        advance(4000);
+    }
+    assert_eq!(drain(&mut alice), ["[bob] ok"; 6]);
+
+    // the copies are compared trimmed and case-folded
+    for text in ["Spam", " spam ", "SPAM"] {
+        assert_eq!(bob_session.send_message(text.to_string()), Ok(1));
+    }
+    assert_eq!(
+        bob_session.send_message("spam".to_string()),
+        Err(SendError::Repeated)
+    );
+    assert_eq!(drain(&mut bob), ["* stop repeating yourself"]);
+    assert_eq!(bob_session.send_message("hello".to_string()), Ok(1));
+    // the first copy is still within the window a millisecond before its end
+    advance(9999);
+    assert_eq!(
+        bob_session.send_message("spam".to_string()),
+        Err(SendError::Repeated)
+    );
+    advance(1);
+    assert_eq!(bob_session.send_message("spam".to_string()), Ok(1));
+    assert_eq!(
+        drain(&mut alice),
+        [
+            "[bob] Spam",
+            "[bob]  spam ",
+            "[bob] SPAM",
+            "[bob] hello",
+            "[bob] spam"
+        ]
+    );
+
+    // kicked after dropping 3 copies in a row
+    for _ in 0..2 {
+        assert_eq!(bob_session.send_message("spam".to_string()), Ok(1));
+    }
+    for _ in 0..3 {
+        assert_eq!(
+            bob_session.send_message("spam".to_string()),
+            Err(SendError::Repeated)
+        );
+    }
+    assert_eq!(
+        drain(&mut bob),
+        [
+            "* stop repeating yourself",
+            "* stop repeating yourself",
+            "* stop repeating yourself",
+            "* you were kicked for repeating yourself"
+        ]
+    );
+    assert_eq!(
+        drain(&mut alice),
+        ["[bob] spam", "[bob] spam", "* bob was kicked by the server"]
+    );
+    assert!(!bob_session.is_connected());
+}
+
+#[test]
+fn observers_watch_without_joining() {
+    let chatroom = Chatroom::default();
+    let mut observer = chatroom.subscribe_observer();
+    let (sender, mut alice) = channel(512);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, _bob) = channel(512);
+    let bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    alice_session.send_message("hi".to_string()).unwrap();
+    bob_session.join_room("rust").unwrap();
+    assert_eq!(
+        drain(&mut observer),
+        [
+            "* alice joined the room",
+            "* bob joined the room",
+            "[alice] hi",
+            "* bob left the room",
+            "* bob joined the room"
+        ]
+    );
+    // neither listed nor announced
+    assert_eq!(chatroom.user_count(), 2);
+    assert_eq!(chatroom.who(Chatroom::LOBBY), ["@alice"]);
+    assert_eq!(
+        drain(&mut alice),
+        [
+            "* Welcome, the room is empty",
+            "* bob joined the room",
+            "* bob left the room"
+        ]
+    );
+
+    // a closed observer is dropped by the next broadcast, a slow one once full
+    drop(observer);
+    let mut slow = chatroom.subscribe_observer();
+    for i in 0..Chatroom::OBSERVER_QUEUE + 1 {
+        alice_session.send_message(format!("{i}")).unwrap();
+    }
+    assert_eq!(drain(&mut slow).len(), Chatroom::OBSERVER_QUEUE);
+    assert!(slow.try_recv().is_err() && slow.is_closed());
+
+    let mut late = chatroom.subscribe_observer();
+    chatroom.shutdown();
+    assert!(late.try_recv().is_err() && late.is_closed());
+}
+
+#[test]
+fn observer_handles() {
+    let chatroom = Chatroom::default();
+    let (sender, mut logger) = channel(16);
+    let handle = chatroom.observe(sender);
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    assert_eq!(
+        drain(&mut bob),
+        ["* Welcome, the room contains 1 user: alice"]
+    );
+    alice_session.send_message("hi".to_string()).unwrap();
+    chatroom.broadcast_notice("maintenance");
+    // not a user: neither in /who nor in the user list
+    let mut who = chatroom.who(Chatroom::LOBBY);
+    who.sort();
+    assert_eq!(who, ["@alice", "bob"]);
+    assert_eq!(chatroom.users().len(), 2);
+    assert_eq!(
+        drain(&mut logger),
+        [
+            "* alice joined the room",
+            "* bob joined the room",
+            "[alice] hi",
+            "* [server] maintenance",
+        ]
+    );
+    assert_eq!(
+        drain(&mut alice),
+        [
+            "* Welcome, the room is empty",
+            "* bob joined the room",
+            "* [server] maintenance",
+        ]
+    );
+
+    drop(handle);
+    alice_session.send_message("bye".to_string()).unwrap();
+    assert_eq!(drain(&mut logger), Vec::<String>::new());
+    assert!(logger.is_closed());
+}
+
+#[test]
+fn panicking_handlers_are_called_again() {
+    let chatroom = Chatroom::default();
+    let (handled, received) = mpsc::channel();
+    let _bot = chatroom
+        .join_with_handler("bot".to_string(), move |message| {
+            if let Message::Message { text, .. } = &message {
+                assert_ne!(&**text, "boom", "handler panic");
+            }
+            handled.send(message.to_string()).unwrap();
+        })
+        .unwrap();
+    let (sender, _alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    alice_session.send_message("boom".to_string()).unwrap();
+    alice_session.send_message("hi".to_string()).unwrap();
+    let timeout = Duration::from_secs(5);
+    let received: Vec<_> = iter::from_fn(|| received.recv_timeout(timeout).ok())
+        .take(3)
+        .collect();
+    assert_eq!(
+        received,
+        [
+            "* Welcome, the room is empty",
+            "* alice joined the room",
+            "[alice] hi"
+        ]
+    );
+    assert_eq!(chatroom.user_count(), 2);
+}
+
+#[test]
+fn hooks_are_chained() {
+    let chatroom = Chatroom::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    chatroom.add_inbound_hook(|message| match message {
+        Message::Message { from, text } if text.contains("http://") => {
+            HookResult::Replace(Message::Message {
+                from: from.clone(),
+                text: text.replace("http://", "https://").into(),
+            })
+        }
+        _ => HookResult::Pass,
+    });
+    let outbound_seen = seen.clone();
+    let observed = chatroom.clone();
+    chatroom.add_outbound_hook(move |message| {
+        // the users are not locked
+        let users = observed.user_count();
+        outbound_seen
+            .lock()
+            .unwrap()
+            .push(format!("{message} ({users} users)"));
+        match message {
+            Message::Message { text, .. } if text.contains("spam") => {
+                HookResult::Drop(Some("no spam".to_string()))
+            }
+            Message::Emote { .. } => HookResult::Drop(None),
+            _ => HookResult::Pass,
+        }
+    });
+    let (sender, mut alice) = channel(16);
+    let alice_session = chatroom.join("alice".to_string(), sender).unwrap();
+    let (sender, mut bob) = channel(16);
+    let _bob_session = chatroom.join("bob".to_string(), sender).unwrap();
+    drain(&mut alice);
+    drain(&mut bob);
+
+    assert_eq!(
+        alice_session.send_message("see http://example.com".to_string()),
+        Ok(1)
+    );
+    assert_eq!(
+        alice_session.send_message("buy spam".to_string()),
+        Err(SendError::Dropped)
+    );
+    assert_eq!(
+        alice_session.send_emote("waves".to_string()),
+        Err(SendError::Dropped)
+    );
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            "[alice] see https://example.com (2 users)",
+            "[alice] buy spam (2 users)",
+            "* alice waves (2 users)"
+        ]
+    );
+    assert_eq!(drain(&mut bob), ["[alice] see https://example.com"]);
+    assert_eq!(drain(&mut alice), ["* no spam"]);
+}
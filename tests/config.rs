@@ -0,0 +1,99 @@
+//! Runs the budget-chat binary with a configuration file, printing the merged options.
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Write `toml` to a temporary file named after `name`
+fn config_file(name: &str, toml: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "budget-chat-config-{name}-{}.toml",
+        std::process::id()
+    ));
+    fs::write(&path, toml).unwrap();
+    path
+}
+
+fn budget_chat(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_budget-chat"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn command_line_takes_precedence_over_the_file() {
+    let path = config_file(
+        "precedence",
+        "# limits\nmax_users = 10\nhistory = 5\necho = true\nsanitize = 'reject'\nport = 6_000\n\
+         motd_file = \"/etc/motd\\u0020\"\n",
+    );
+    let output = budget_chat(&[
+        "--config",
+        path.to_str().unwrap(),
+        "--max-users",
+        "3",
+        "--bind",
+        "127.0.0.1:7000",
+        "--print-config",
+    ]);
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    let toml = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = toml.lines().collect();
+    assert!(lines.contains(&"max_users = 3"));
+    assert!(lines.contains(&"history = 5"));
+    assert!(lines.contains(&"echo = true"));
+    assert!(lines.contains(&"sanitize = \"reject\""));
+    assert!(lines.contains(&"bind = \"127.0.0.1:7000\""));
+    assert!(lines.contains(&"# port is not set"));
+    assert!(lines.contains(&"motd_file = \"/etc/motd \""));
+    // defaults for the options in neither
+    assert!(lines.contains(&"client_queue = 256"));
+    assert!(lines.contains(&"write_batch = false"));
+}
+
+#[test]
+fn printed_config_can_be_loaded() {
+    let output = budget_chat(&[
+        "--max-users",
+        "3",
+        "--nick-allow-extra",
+        "_\"",
+        "--print-config",
+    ]);
+    assert!(output.status.success());
+    let path = config_file(
+        "printed",
+        &String::from_utf8(output.stdout.clone()).unwrap(),
+    );
+    let reloaded = budget_chat(&["--config", path.to_str().unwrap(), "--print-config"]);
+    fs::remove_file(&path).unwrap();
+    assert!(reloaded.status.success());
+    assert_eq!(reloaded.stdout, output.stdout);
+}
+
+#[test]
+fn invalid_files_are_refused() {
+    let error = |name, toml| {
+        let path = config_file(name, toml);
+        let output = budget_chat(&["--config", path.to_str().unwrap(), "--print-config"]);
+        fs::remove_file(&path).unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        stderr.lines().next().unwrap().to_string()
+    };
+    let unknown = error("unknown", "history = 5\nmax_user = 3\n");
+    assert!(unknown.starts_with("error: invalid configuration file "));
+    assert!(unknown.ends_with(": line 2, max_user: unknown key"));
+    assert!(error("value", "max_users = \"many\"")
+        .ends_with(": line 1, max_users: invalid value \"many\": invalid digit found in string"));
+    assert!(error("flag", "echo = 1").ends_with(": line 1, echo: expected a boolean"));
+    assert!(error("duplicate", "history = 1\nhistory = 2").ends_with(": line 2: duplicate key"));
+    assert!(error("nested", "config = \"other.toml\"").ends_with(": line 1, config: unknown key"));
+    assert!(error("table", "\n[server]\nport = 1").ends_with(": line 2, server: unknown key"));
+    assert!(error("array", "max_users = [3]")
+        .ends_with(": line 1, max_users: expected a string or a number"));
+}
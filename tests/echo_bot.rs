@@ -0,0 +1,49 @@
+//! The echo bot example, over a real connection to the server
+
+use std::{
+    future,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    thread,
+};
+
+use budget_chat::{
+    server::{serve, ServerConfig},
+    Chatroom,
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+#[path = "../examples/echo_bot.rs"]
+mod echo_bot;
+
+#[test]
+fn echo_bot_repeats_its_mentions() {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chatroom = Chatroom::default();
+    echo_bot::start(&chatroom).unwrap();
+    thread::spawn(move || {
+        runtime.block_on(serve(
+            listener,
+            chatroom,
+            ServerConfig::default(),
+            future::pending(),
+        ))
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "alice").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* Welcome, the room contains 1 user: echobot\n");
+
+    writeln!(stream, "hello world").unwrap();
+    writeln!(stream, "hello echobot").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[echobot] hello echobot\n");
+}
@@ -0,0 +1,177 @@
+//! Arbitrary inputs, generated from a fixed seed or by proptest: the nicknames and
+//! the streams of the clients, with lone `\r`, NULs, overlong lines, invalid UTF-8
+//! and half commands.
+
+use std::time::Duration;
+
+use budget_chat::{
+    server::{serve_stream, ServerConfig},
+    validate_nickname, Chatroom,
+};
+use proptest::prelude::*;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+/// How long a session may take to end once its client closed the stream
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// xorshift64*, good enough to spread the inputs and the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[test]
+fn nicknames_are_validated() {
+    let chars = [
+        'a', 'Z', '0', '_', '-', '.', ' ', '\t', '\r', '\n', '\0', '\u{7f}', '\u{85}', '\u{a0}',
+        'é', '日', '\u{200b}', '\u{3000}',
+    ];
+    let mut rng = Rng(0x5eed);
+    for _ in 0..20_000 {
+        let len = rng.below(24);
+        let nickname: String = (0..len).map(|_| *rng.pick(&chars)).collect();
+        if validate_nickname(&nickname).is_ok() {
+            assert!(
+                !nickname
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control()),
+                "{nickname:?} accepted"
+            );
+        }
+    }
+    // and the lossy conversions of arbitrary bytes
+    for _ in 0..20_000 {
+        let bytes: Vec<u8> = (0..rng.below(24)).map(|_| rng.next() as u8).collect();
+        let nickname = String::from_utf8_lossy(&bytes);
+        if validate_nickname(&nickname).is_ok() {
+            assert!(!nickname
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control()));
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn validated_nicknames_hold_no_whitespace(bytes in prop::collection::vec(any::<u8>(), 0..32)) {
+        let nickname = String::from_utf8_lossy(&bytes);
+        if validate_nickname(&nickname).is_ok() {
+            prop_assert!(!nickname.chars().any(|c| c.is_whitespace() || c.is_control()));
+        }
+    }
+
+    #[test]
+    fn validated_unicode_nicknames_hold_no_whitespace(nickname in "\\PC{0,4}[\\s\\p{Cc}]?\\PC{0,4}") {
+        if validate_nickname(&nickname).is_ok() {
+            prop_assert!(!nickname.chars().any(|c| c.is_whitespace() || c.is_control()));
+        }
+    }
+}
+
+/// A stream of a client, mostly made of pieces of lines and commands
+fn client_stream(rng: &mut Rng, index: usize) -> Vec<u8> {
+    let pieces: [&[u8]; 34] = [
+        b"\n",
+        b"\r",
+        b"\r\n",
+        b"\0",
+        b" ",
+        b"hello",
+        b"\xff\xfe",
+        b"\xe2\x82",
+        b"\xf0\x9f\x98\x80",
+        b"/",
+        b"/msg ",
+        b"/me ",
+        b"/paste",
+        b"\n.\n",
+        b"/nick ",
+        b"/join #",
+        b"/leave",
+        b"/kick ",
+        b"/unban ",
+        b"/mute ",
+        b"/lockdown on",
+        b"/invite ",
+        b"/mode #room +i",
+        b"/topic ",
+        b"/ignore ",
+        b"/away ",
+        b"/timestamps on",
+        b"/color on",
+        b"/seq on",
+        b"/ack ",
+        b"/protocol json",
+        b"/stats",
+        b"/help",
+        b"/who",
+    ];
+    let mut stream = Vec::new();
+    // most clients join first, for their lines to be parsed as commands
+    if rng.below(4) != 0 {
+        stream.extend_from_slice(format!("fuzzer{index}\n").as_bytes());
+    }
+    for _ in 0..rng.below(64) {
+        match rng.below(10) {
+            0 => stream.extend((0..rng.below(32)).map(|_| rng.next() as u8)),
+            1 => stream.extend(std::iter::repeat_n(b'a', rng.below(8192))),
+            _ => {
+                let piece = *rng.pick(&pieces);
+                stream.extend_from_slice(piece);
+            }
+        }
+    }
+    stream
+}
+
+#[tokio::test]
+async fn arbitrary_streams_end_their_sessions() {
+    let chatroom = Chatroom::default();
+    let mut rng = Rng(0xc0ffee);
+    for index in 0..300 {
+        let input = client_stream(&mut rng, index);
+        let (client, server) = io::duplex(4096);
+        let session = tokio::spawn(serve_stream(
+            server,
+            chatroom.clone(),
+            ServerConfig::default(),
+        ));
+        let (mut read, mut write) = io::split(client);
+        let reader = tokio::spawn(async move {
+            let mut output = Vec::new();
+            // the server may close the connection first
+            let _ = read.read_to_end(&mut output).await;
+        });
+        // the server may stop reading first, e.g. after too many invalid nicknames
+        let _ = write.write_all(&input).await;
+        let _ = write.shutdown().await;
+        drop(write);
+        timeout(SESSION_TIMEOUT, session)
+            .await
+            .unwrap_or_else(|_| panic!("session {index} did not end, stream {input:?}"))
+            .unwrap();
+        timeout(SESSION_TIMEOUT, reader).await.unwrap().unwrap();
+    }
+    assert_eq!(chatroom.user_count(), 0);
+    let metrics = chatroom.metrics();
+    assert_eq!(metrics.connections, 300);
+    // the lines of the joined clients went through the commands
+    assert!(metrics.messages_broadcast > 0);
+}
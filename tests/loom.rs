@@ -0,0 +1,104 @@
+//! The races of `tests/races.rs` as loom models, which go through all the
+//! interleavings of the locks of the chatroom rather than those a barrier happens to
+//! hit. Built only with `--cfg loom`, without the servers:
+//!
+//! ```sh
+//! RUSTFLAGS="--cfg loom" cargo test --release --no-default-features --test loom
+//! ```
+//!
+//! The invariants checked:
+//!
+//! - of the concurrent joins with the same nickname, exactly one succeeds;
+//! - a user is never told of the leave of a user it was not told of, by the list of
+//!   users sent when it joined or by a join notice;
+//! - a message racing the leave of a receiver is received at most once by it, and
+//!   by every user staying in the room if it was sent.
+#![cfg(loom)]
+
+use std::iter;
+
+use budget_chat::{Chatroom, ChatroomConfig, Envelope, Message};
+use loom::{model, thread};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// The messages queued for a user
+fn drain(receiver: &mut Receiver<Envelope>) -> Vec<Message> {
+    iter::from_fn(|| receiver.try_recv().ok())
+        .map(|envelope| envelope.message)
+        .collect()
+}
+
+#[test]
+fn concurrent_joins_with_the_same_nickname() {
+    model(|| {
+        let chatroom = Chatroom::new(ChatroomConfig::default());
+        let join = |chatroom: Chatroom| {
+            move || {
+                let (sender, receiver) = channel(16);
+                chatroom
+                    .join("alice".to_string(), sender)
+                    .ok()
+                    .map(|session| (session, receiver))
+            }
+        };
+        let other = thread::spawn(join(chatroom.clone()));
+        let joined = [join(chatroom.clone())(), other.join().unwrap()];
+        assert_eq!(joined.iter().flatten().count(), 1);
+        assert_eq!(chatroom.connected_users(), ["alice"]);
+    });
+}
+
+#[test]
+fn joins_racing_leaves() {
+    model(|| {
+        let chatroom = Chatroom::new(ChatroomConfig::default());
+        let (sender, _carol_receiver) = channel(16);
+        let carol = chatroom.join("carol".to_string(), sender).ok().unwrap();
+        let leave = thread::spawn(move || drop(carol));
+
+        let (sender, mut receiver) = channel(16);
+        let _watcher = chatroom.join("watcher".to_string(), sender).ok().unwrap();
+        leave.join().unwrap();
+
+        let mut known = Vec::new();
+        for message in drain(&mut receiver) {
+            match message {
+                Message::ConnectedUsers(users) => known.extend(users),
+                Message::Joined { nickname, .. } => known.push(nickname),
+                Message::Left { nickname, .. } => {
+                    assert!(known.contains(&nickname), "{nickname} left unseen");
+                    known.retain(|known| *known != nickname);
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+#[test]
+fn sends_racing_leaves() {
+    model(|| {
+        let chatroom = Chatroom::new(ChatroomConfig::default());
+        let join = |nickname: &str| {
+            let (sender, receiver) = channel(16);
+            let session = chatroom.join(nickname.to_string(), sender).ok().unwrap();
+            (session, receiver)
+        };
+        let (alice, _alice_receiver) = join("alice");
+        let (bob, mut bob_receiver) = join("bob");
+        let (_watcher, mut watcher_receiver) = join("watcher");
+        let leave = thread::spawn(move || drop(bob));
+        let sent = alice.send_message("hello".to_string()).is_ok();
+        leave.join().unwrap();
+
+        let hellos = |messages: Vec<Message>| {
+            let hello = |message: &&Message| match message {
+                Message::Message { text, .. } => &**text == "hello",
+                _ => false,
+            };
+            messages.iter().filter(hello).count()
+        };
+        assert!(hellos(drain(&mut bob_receiver)) <= 1);
+        assert_eq!(hellos(drain(&mut watcher_receiver)), usize::from(sent));
+    });
+}
@@ -0,0 +1,180 @@
+//! The joins, leaves and sends racing each other. Each race is replayed many times
+//! from a barrier to go through its interleavings, the tests check the invariants:
+//!
+//! - of the concurrent joins with the same nickname, exactly one succeeds;
+//! - a user is never told of the leave of a user it was not told of, by the list of
+//!   users sent when it joined or by a join notice;
+//! - a message racing the leave of its sender or of a receiver is received at most
+//!   once by each user, and by every user staying in the room if it was sent;
+//! - of the concurrent joins of a limited room, as many succeed as its limit.
+
+use std::{
+    iter,
+    sync::{Arc, Barrier},
+    thread,
+};
+
+use budget_chat::{Chatroom, ChatroomConfig, Envelope, Message};
+use tokio::sync::mpsc::{channel, Receiver};
+
+const ITERATIONS: usize = 500;
+
+fn chatroom() -> Chatroom {
+    Chatroom::new(ChatroomConfig::default())
+}
+
+/// The messages queued for a user
+fn drain(receiver: &mut Receiver<Envelope>) -> Vec<Message> {
+    iter::from_fn(|| receiver.try_recv().ok())
+        .map(|envelope| envelope.message)
+        .collect()
+}
+
+/// Run `races` at once from a barrier, returns their results in order
+fn race<T: Send>(races: Vec<Box<dyn FnOnce() -> T + Send + '_>>) -> Vec<T> {
+    let barrier = Barrier::new(races.len());
+    thread::scope(|scope| {
+        let threads = races
+            .into_iter()
+            .map(|race| {
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    barrier.wait();
+                    race()
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    })
+}
+
+#[test]
+fn concurrent_joins_with_the_same_nickname() {
+    for _ in 0..ITERATIONS {
+        let chatroom = chatroom();
+        let join = || {
+            let (sender, receiver) = channel(16);
+            chatroom
+                .join("alice".to_string(), sender)
+                .ok()
+                .map(|session| (session, receiver))
+        };
+        let joined = race(vec![Box::new(join), Box::new(join)]);
+        assert_eq!(joined.iter().flatten().count(), 1);
+        assert_eq!(chatroom.connected_users(), ["alice"]);
+    }
+}
+
+#[test]
+fn joins_racing_leaves() {
+    for _ in 0..ITERATIONS {
+        let chatroom = chatroom();
+        let (sender, _carol_receiver) = channel(16);
+        let carol = chatroom.join("carol".to_string(), sender).ok().unwrap();
+        let watcher = || {
+            let (sender, receiver) = channel(16);
+            let session = chatroom.join("watcher".to_string(), sender).ok().unwrap();
+            (session, receiver)
+        };
+        let leave = || {
+            drop(carol);
+            None
+        };
+        let visitor = || {
+            let (sender, _receiver) = channel(16);
+            drop(chatroom.join("visitor".to_string(), sender));
+            None
+        };
+        let mut outcomes = race(vec![
+            Box::new(|| Some(watcher())),
+            Box::new(leave),
+            Box::new(visitor),
+        ]);
+        let (_watcher, mut receiver) = outcomes.remove(0).unwrap();
+
+        let mut known = Vec::new();
+        for message in drain(&mut receiver) {
+            match message {
+                Message::ConnectedUsers(users) => known.extend(users),
+                Message::Joined { nickname, .. } => known.push(nickname),
+                Message::Left { nickname, .. } => {
+                    assert!(known.contains(&nickname), "{nickname} left unseen");
+                    known.retain(|known| *known != nickname);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn sends_racing_leaves() {
+    for _ in 0..ITERATIONS {
+        let chatroom = chatroom();
+        let join = |nickname: &str| {
+            let (sender, receiver) = channel(16);
+            let session = chatroom.join(nickname.to_string(), sender).ok().unwrap();
+            (session, receiver)
+        };
+        let (alice, _alice_receiver) = join("alice");
+        let (bob, mut bob_receiver) = join("bob");
+        let (_watcher, mut watcher_receiver) = join("watcher");
+        let alice = Arc::new(alice);
+        let sent = {
+            let alice = alice.clone();
+            move || alice.send_message("hello".to_string()).is_ok()
+        };
+        let outcomes = race::<bool>(vec![
+            Box::new(sent),
+            Box::new(|| {
+                drop(bob);
+                true
+            }),
+            Box::new(|| {
+                // the sender leaves too
+                drop(chatroom.kick_by_nick("alice", None));
+                true
+            }),
+        ]);
+
+        let hellos = |messages: Vec<Message>| {
+            let hello = |message: &&Message| match message {
+                Message::Message { text, .. } => &**text == "hello",
+                _ => false,
+            };
+            messages.iter().filter(hello).count()
+        };
+        assert!(hellos(drain(&mut bob_receiver)) <= 1);
+        let expected = usize::from(outcomes[0]);
+        assert_eq!(hellos(drain(&mut watcher_receiver)), expected);
+        drop(alice);
+    }
+}
+
+#[test]
+fn joins_of_a_limited_room() {
+    const LIMIT: usize = 4;
+    for _ in 0..ITERATIONS / 10 {
+        let chatroom = Chatroom::new(ChatroomConfig {
+            default_room_limit: Some(LIMIT),
+            ..Default::default()
+        });
+        let sessions = (0..2 * LIMIT)
+            .map(|i| {
+                let (sender, receiver) = channel(64);
+                let session = chatroom.join(format!("user{i}"), sender).ok().unwrap();
+                (session, receiver)
+            })
+            .collect::<Vec<_>>();
+        let joins = sessions
+            .iter()
+            .map(|(session, _)| {
+                Box::new(move || session.join_room("small").is_ok())
+                    as Box<dyn FnOnce() -> bool + Send>
+            })
+            .collect();
+        let joined = race(joins);
+        assert_eq!(joined.iter().filter(|joined| **joined).count(), LIMIT);
+        assert_eq!(chatroom.room_users("small").len(), LIMIT);
+    }
+}
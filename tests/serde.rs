@@ -0,0 +1,184 @@
+//! The serde representation of the messages, pinned: it is a stable wire schema.
+
+use std::time::Duration;
+
+use budget_chat::{Message, RoomInfo};
+
+/// Every variant, with the JSON it is serialized to
+fn messages() -> Vec<(Message, &'static str)> {
+    vec![
+        (
+            Message::Joined {
+                nickname: "alice".to_string(),
+                users: None,
+            },
+            r#"{"type":"joined","nick":"alice"}"#,
+        ),
+        (
+            Message::Joined {
+                nickname: "alice".to_string(),
+                users: Some(3),
+            },
+            r#"{"type":"joined","nick":"alice","count":3}"#,
+        ),
+        (
+            Message::Left {
+                nickname: "alice".to_string(),
+                online: None,
+                reason: None,
+            },
+            r#"{"type":"left","nick":"alice"}"#,
+        ),
+        (
+            Message::Left {
+                nickname: "alice".to_string(),
+                online: Some(Duration::from_secs(75)),
+                reason: Some("bye".to_string()),
+            },
+            r#"{"type":"left","nick":"alice","online":75,"reason":"bye"}"#,
+        ),
+        (
+            Message::ConnectedUsers(Vec::new()),
+            r#"{"type":"users","users":[]}"#,
+        ),
+        (
+            Message::ConnectedUsers(vec!["alice".to_string(), "bob".to_string()]),
+            r#"{"type":"users","users":["alice","bob"]}"#,
+        ),
+        (
+            Message::Message {
+                from: "bob".into(),
+                text: "hi \"all\"".into(),
+            },
+            r#"{"type":"message","from":"bob","text":"hi \"all\""}"#,
+        ),
+        (
+            Message::Mentioned {
+                from: "bob".into(),
+                text: "@alice".into(),
+            },
+            r#"{"type":"message","from":"bob","text":"@alice","mentioned":true}"#,
+        ),
+        (
+            Message::Emote {
+                from: "bob".into(),
+                action: "waves".into(),
+            },
+            r#"{"type":"emote","from":"bob","text":"waves"}"#,
+        ),
+        (
+            Message::Paste {
+                from: "bob".into(),
+                lines: vec!["fn main() {".to_string(), "}".to_string()].into(),
+            },
+            r#"{"type":"paste","from":"bob","lines":["fn main() {","}"]}"#,
+        ),
+        (
+            Message::Private {
+                from: "bob".to_string(),
+                text: "psst".to_string(),
+            },
+            r#"{"type":"private","from":"bob","text":"psst"}"#,
+        ),
+        (
+            Message::PrivateSent {
+                to: "alice".to_string(),
+                text: "psst".to_string(),
+            },
+            r#"{"type":"private_sent","to":"alice","text":"psst"}"#,
+        ),
+        (
+            Message::UserList(vec!["alice".to_string()]),
+            r#"{"type":"who","users":["alice"]}"#,
+        ),
+        (
+            Message::RoomList(vec![RoomInfo {
+                name: "lobby".to_string(),
+                users: 2,
+                invite_only: false,
+                limit: None,
+            }]),
+            r#"{"type":"rooms","rooms":[{"name":"lobby","users":2}]}"#,
+        ),
+        (
+            Message::Notice("ping".to_string()),
+            r#"{"type":"notice","text":"ping"}"#,
+        ),
+        (
+            Message::ServerNotice("restarting".to_string()),
+            r#"{"type":"server_notice","text":"restarting"}"#,
+        ),
+        (
+            Message::Motd("welcome".to_string()),
+            r#"{"type":"motd","text":"welcome"}"#,
+        ),
+        (
+            Message::Kicked {
+                nickname: "eve".to_string(),
+                by: "alice".to_string(),
+            },
+            r#"{"type":"kicked","nick":"eve","by":"alice"}"#,
+        ),
+        (
+            Message::Banned {
+                nickname: "eve".to_string(),
+                by: "alice".to_string(),
+            },
+            r#"{"type":"banned","nick":"eve","by":"alice"}"#,
+        ),
+        (
+            Message::Renamed {
+                old: "bob".to_string(),
+                new: "robert".to_string(),
+            },
+            r#"{"type":"renamed","old":"bob","new":"robert"}"#,
+        ),
+        (
+            Message::Topic {
+                text: "rust".to_string(),
+                set_by: "alice".to_string(),
+            },
+            r#"{"type":"topic","text":"rust","by":"alice"}"#,
+        ),
+        (
+            Message::History(Box::new(Message::Message {
+                from: "bob".into(),
+                text: "earlier".into(),
+            })),
+            r#"{"type":"history","message":{"type":"message","from":"bob","text":"earlier"}}"#,
+        ),
+    ]
+}
+
+#[test]
+fn messages_round_trip() {
+    for (message, json) in messages() {
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+        assert_eq!(serde_json::from_str::<Message>(json).unwrap(), message);
+    }
+}
+
+#[test]
+fn json_line_protocol_has_the_same_objects() {
+    for (message, _) in messages() {
+        assert_eq!(serde_json::to_value(&message).unwrap(), message.to_json());
+    }
+}
+
+#[test]
+fn invalid_objects_are_refused() {
+    let error = |json| {
+        serde_json::from_str::<Message>(json)
+            .unwrap_err()
+            .to_string()
+    };
+    assert!(error(r#"{"nick":"alice"}"#).starts_with("missing field `type`"));
+    assert!(error(r#"{"type":"joined"}"#).starts_with("missing field `nick`"));
+    assert!(error(r#"{"type":"dance"}"#).starts_with("unknown variant `dance`"));
+    assert!(error(r#"["joined"]"#).starts_with("invalid type: sequence"));
+    // unknown fields are ignored, e.g. the time of the JSON line protocol
+    assert_eq!(
+        serde_json::from_str::<Message>(r#"{"type":"motd","text":"hi","time":"12:00"}"#).unwrap(),
+        Message::Motd("hi".to_string())
+    );
+}
@@ -0,0 +1,2272 @@
+use std::{
+    collections::HashSet,
+    fs,
+    future::{self, Future},
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use budget_chat::{
+    hash_password,
+    loadtest::{parse_duration, run_load_test, LoadTestConfig},
+    nickname_color,
+    server::{
+        bind, bind_unix, serve, serve_events, serve_metrics, serve_unix, serve_websocket,
+        InvalidUtf8, ServerConfig,
+    },
+    Accounts, Archive, BanList, Chatroom, ChatroomConfig, HistoryConfig, MessageTemplates,
+    PasswordParams, RuntimeConfig,
+};
+use socket2::{Domain, Socket, Type};
+use tokio::{net::TcpListener, runtime::Runtime, sync::oneshot};
+
+/// How long the tests wait for a line, a stuck server fails them rather than hanging
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect to `addr`, the reads time out after [`READ_TIMEOUT`]
+fn connect(addr: impl ToSocketAddrs) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    stream
+}
+
+/// Start a server on an ephemeral local port
+fn start_server(config: ServerConfig) -> SocketAddr {
+    start_server_until(config, future::pending()).0
+}
+
+/// Start a server on an ephemeral local port, serving until `shutdown` completes
+fn start_server_until(
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> (SocketAddr, JoinHandle<()>) {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        runtime.block_on(serve(listener, Chatroom::default(), config, shutdown))
+    });
+    (addr, server)
+}
+
+/// Serve `chatroom` on an ephemeral local port
+fn serve_chatroom(chatroom: Chatroom, config: ServerConfig) -> SocketAddr {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || runtime.block_on(serve(listener, chatroom, config, future::pending())));
+    addr
+}
+
+/// Connect to the server and join as `nickname`, the user list is consumed.
+fn join(addr: SocketAddr, nickname: &str) -> (TcpStream, BufReader<TcpStream>) {
+    let mut stream = connect(addr);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "{nickname}").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("* Welcome"), "{line}");
+    (stream, reader)
+}
+
+/// Read lines until one satisfies `predicate`
+fn read_until(reader: &mut BufReader<TcpStream>, predicate: impl Fn(&str) -> bool) {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        assert_ne!(reader.read_line(&mut line).unwrap(), 0, "connection closed");
+        if predicate(line.trim_end()) {
+            return;
+        }
+    }
+}
+
+#[test]
+fn early_disconnects_leave_no_trace() {
+    let addr = start_server(ServerConfig::default());
+
+    for _ in 0..300 {
+        drop(connect(addr));
+    }
+
+    let mut stream = connect(addr);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(
+        line,
+        "Welcome to our chat room, please enter your nickname:\n"
+    );
+    stream.write_all(b"alice\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* Welcome, the room is empty\n");
+}
+
+#[test]
+fn chat_session() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let bob = connect(addr);
+    let mut bob_reader = BufReader::new(bob.try_clone().unwrap());
+    let mut expect_line = move |expected: &str| {
+        let mut line = String::new();
+        bob_reader.read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), expected);
+    };
+    expect_line("Welcome to our chat room, please enter your nickname:");
+    writeln!(&bob, "alice").unwrap();
+    expect_line("Nickname already used.");
+    expect_line("Please enter your nickname:");
+    writeln!(&bob, "b b").unwrap();
+    expect_line("Nickname contains an invalid character ' ' at position 1.");
+    expect_line("Please enter your nickname:");
+    writeln!(&bob, "bob").unwrap();
+    expect_line("* Welcome, the room contains 1 user: alice");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    // not sent back to its author
+    writeln!(alice, "hi bob").unwrap();
+    expect_line("[alice] hi bob");
+    writeln!(&bob, "hi alice").unwrap();
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[bob] hi alice\n");
+
+    drop((bob, expect_line));
+    line.clear();
+    alice_reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("* bob left the room"), "{line}");
+}
+
+#[test]
+fn slow_consumer_is_kicked() {
+    let addr = start_server(ServerConfig {
+        client_queue: 16,
+        ..Default::default()
+    });
+
+    let (_slow, _) = join(addr, "slow");
+    let (mut talker, _) = join(addr, "talker");
+    let (_observer, mut observer) = join(addr, "observer");
+
+    let text = "x".repeat(10_000);
+    let stop = Arc::new(AtomicBool::new(false));
+    let spam = thread::spawn({
+        let stop = stop.clone();
+        move || {
+            // slow enough for the observer to keep up
+            while !stop.load(Ordering::Relaxed) {
+                writeln!(talker, "{text}").unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            talker
+        }
+    });
+    read_until(&mut observer, |line| line == "* slow left the room");
+    stop.store(true, Ordering::Relaxed);
+    let mut talker = spam.join().unwrap();
+
+    writeln!(talker, "still working").unwrap();
+    read_until(&mut observer, |line| line == "[talker] still working");
+}
+
+#[test]
+fn bind_addresses() {
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), 0);
+
+    // the IPv6 wildcard also accepts IPv4 clients
+    let listener = bind("[::]:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert!(addr.is_ipv6());
+    TcpStream::connect(("127.0.0.1", addr.port())).unwrap();
+}
+
+#[test]
+fn private_message_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    let (_carol, mut carol_reader) = join(addr, "carol");
+    read_until(&mut alice_reader, |line| line == "* carol joined the room");
+    read_until(&mut bob_reader, |line| line == "* carol joined the room");
+
+    writeln!(alice, "/msg bob hello there").unwrap();
+    read_until(&mut bob_reader, |line| line == "[alice -> you] hello there");
+    read_until(&mut alice_reader, |line| line == "[you -> bob] hello there");
+
+    writeln!(alice, "/msg dave hello").unwrap();
+    read_until(&mut alice_reader, |line| line == "* no such user: dave");
+    writeln!(alice, "/msg bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /msg <nick> <text>"
+    });
+
+    // none of the commands reached carol, only the message that follows
+    writeln!(alice, "public").unwrap();
+    let mut line = String::new();
+    carol_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] public\n");
+}
+
+#[test]
+fn fast_private_messages_are_limited() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        rate_limit: Some("3/10s".parse().unwrap()),
+        max_rate_violations: 2,
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    let (mut alice, alice_reader) = join(addr, "alice");
+    read_until(&mut bob_reader, |line| line == "* alice joined the room");
+
+    // the third message beyond the allowance disconnects the flooding user
+    for i in 0..5 {
+        writeln!(alice, "/msg bob spam {i}").unwrap();
+    }
+    let lines: Vec<_> = alice_reader.lines().map(Result::unwrap).collect();
+    assert_eq!(
+        lines,
+        [
+            "[you -> bob] spam 0",
+            "[you -> bob] spam 1",
+            "[you -> bob] spam 2",
+            "* you are sending messages too fast",
+            "* you were disconnected for sending messages too fast"
+        ]
+    );
+    let received: Vec<_> = (&mut bob_reader)
+        .lines()
+        .take(4)
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        received[..3],
+        [
+            "[alice -> you] spam 0",
+            "[alice -> you] spam 1",
+            "[alice -> you] spam 2"
+        ]
+    );
+    assert!(received[3].starts_with("* alice left the room"));
+}
+
+#[test]
+fn who_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    read_until(&mut bob_reader, |line| line == "* alice joined the room");
+
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Users in the room: alice, @bob"
+    });
+
+    writeln!(bob, "hello").unwrap();
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[bob] hello\n");
+}
+
+#[test]
+fn room_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/join #rust").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are now in #rust");
+    read_until(&mut alice_reader, |line| line == "* bob left the room");
+    writeln!(alice, "/rooms").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* Rooms: #lobby (1), #rust (1)"
+    });
+
+    writeln!(bob, "/leave").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Users in the room: @alice, bob"
+    });
+}
+
+#[test]
+fn invite_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/join #private").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* you are now in #private"
+    });
+    writeln!(alice, "/mode #private i").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /mode #room +i|-i|limit <count>|-l"
+    });
+    writeln!(alice, "/mode #private +i").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* #private is now invite-only, by alice"
+    });
+    writeln!(bob, "/rooms").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Rooms: #lobby (1), #private (1, invite-only)"
+    });
+    writeln!(bob, "/join #private").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* room #private is invite-only"
+    });
+
+    writeln!(alice, "/invite bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* bob is invited to #private"
+    });
+    read_until(&mut bob_reader, |line| {
+        line == "* alice invited you to #private"
+    });
+    writeln!(bob, "/join #private").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are now in #private");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/mode #private limit 2").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* #private now holds up to 2 users, by alice"
+    });
+    writeln!(bob, "/rooms").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Rooms: #private (2/2, invite-only)"
+    });
+    writeln!(alice, "/mode #private limit none").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /mode #room +i|-i|limit <count>|-l"
+    });
+}
+
+#[test]
+fn graceful_shutdown() {
+    let (shutdown, shutdown_signal) = oneshot::channel();
+    let (addr, server) = start_server_until(
+        ServerConfig {
+            shutdown_grace: Duration::from_secs(2),
+            ..Default::default()
+        },
+        async {
+            let _ = shutdown_signal.await;
+        },
+    );
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    let mut handshaking = connect(addr);
+    let mut line = String::new();
+    BufReader::new(handshaking.try_clone().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+
+    let started = Instant::now();
+    shutdown.send(()).unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* [server] server is shutting down"
+    });
+    let mut rest = String::new();
+    alice_reader.read_to_string(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    line.clear();
+    handshaking.read_to_string(&mut line).unwrap();
+    assert_eq!(line, "server shutting down\n");
+
+    server.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+/// Resident memory of the test process, in kB
+fn resident_memory() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap()
+}
+
+#[test]
+fn overlong_nickname_disconnects() {
+    let addr = start_server(ServerConfig::default());
+
+    let mut stream = connect(addr);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+
+    let before = resident_memory();
+    let chunk = vec![b'a'; 64 * 1024];
+    // the server stops reading early: the writes eventually fail
+    for _ in 0..160 {
+        if stream.write_all(&chunk).is_err() {
+            break;
+        }
+    }
+    // the notice may be lost if the connection is reset with unread data
+    line.clear();
+    if reader.read_line(&mut line).is_ok() && !line.is_empty() {
+        assert_eq!(line, "Nickname too long.\n");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap_or(0), 0);
+    }
+    assert!(resident_memory() < before + 5 * 1024);
+}
+
+#[test]
+fn blank_lines_are_skipped() {
+    for allow_empty in [false, true] {
+        let addr = start_server(ServerConfig {
+            allow_empty,
+            ..Default::default()
+        });
+
+        let (mut alice, mut alice_reader) = join(addr, "alice");
+        let (_bob, mut bob_reader) = join(addr, "bob");
+        read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+        alice.write_all(b"\n\r\n \t\nhello\nbye\n").unwrap();
+        let mut lines = Vec::new();
+        while lines.last().is_none_or(|line| line != "[alice] bye\n") {
+            let mut line = String::new();
+            bob_reader.read_line(&mut line).unwrap();
+            lines.push(line);
+        }
+        if allow_empty {
+            assert_eq!(
+                lines,
+                [
+                    "[alice] \n",
+                    "[alice] \n",
+                    "[alice] \n",
+                    "[alice] hello\n",
+                    "[alice] bye\n"
+                ]
+            );
+        } else {
+            assert_eq!(lines, ["[alice] hello\n", "[alice] bye\n"]);
+        }
+    }
+}
+
+#[test]
+fn pastes_are_sent_as_one_message() {
+    let addr = start_server(ServerConfig {
+        max_line_bytes: 32,
+        paste_max_lines: 3,
+        paste_timeout: Duration::from_secs(1),
+        ..Default::default()
+    });
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    alice
+        .write_all(b"/paste\nfn main() {\n    hello();\n}  \n.\nafter\n")
+        .unwrap();
+    let mut lines = Vec::new();
+    while lines.last().is_none_or(|line| line != "[alice] after\n") {
+        let mut line = String::new();
+        bob_reader.read_line(&mut line).unwrap();
+        lines.push(line);
+    }
+    assert_eq!(
+        lines,
+        [
+            "[alice] (paste, 3 lines)\n",
+            "    fn main() {\n",
+            "        hello();\n",
+            "    }\n",
+            "[alice] after\n"
+        ]
+    );
+
+    // the lines beyond the limit are read up to the . and dropped
+    alice.write_all(b"/paste\n1\n2\n3\n4\n.\n").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* paste dropped: more than 3 lines"
+    });
+    alice.write_all(b"/paste\n.\n").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* paste dropped: no lines"
+    });
+    // a line too long drops the whole paste
+    alice
+        .write_all(format!("/paste\n1\n{}\n3\n.\n", "2".repeat(40)).as_bytes())
+        .unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* paste dropped: more than 32 bytes"
+    });
+
+    // without its . line
+    alice.write_all(b"/paste\nforgotten\n").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* paste aborted: no . line within 1s"
+    });
+    writeln!(alice, "next").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] next\n");
+}
+
+#[test]
+fn pastes_keep_the_user_active() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+
+    writeln!(alice, "/paste").unwrap();
+    for i in 0..5 {
+        thread::sleep(Duration::from_millis(150));
+        writeln!(alice, "line {i}").unwrap();
+    }
+    writeln!(alice, ".\n/who").unwrap();
+    read_until(&mut alice_reader, |line| {
+        assert_ne!(line, "* disconnected: idle");
+        line.starts_with("* Users in the room: ")
+    });
+}
+
+#[test]
+fn long_lines_are_truncated() {
+    let addr = start_server(ServerConfig {
+        max_line_bytes: 8,
+        ..Default::default()
+    });
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "hello world, how are you?").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* your message was truncated to 8 bytes"
+    });
+    writeln!(alice, "short").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] hello wo\n");
+    line.clear();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] short\n");
+}
+
+#[test]
+fn invalid_utf8_handling() {
+    for mode in [
+        InvalidUtf8::Lossy,
+        InvalidUtf8::Drop,
+        InvalidUtf8::Disconnect,
+    ] {
+        let addr = start_server(ServerConfig {
+            invalid_utf8: mode,
+            ..Default::default()
+        });
+
+        let (mut alice, mut alice_reader) = join(addr, "alice");
+        let (_bob, mut bob_reader) = join(addr, "bob");
+        read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+        alice.write_all(b"caf\xe9 \xfe ok\nstill here\n").unwrap();
+        let mut line = String::new();
+        bob_reader.read_line(&mut line).unwrap();
+        match mode {
+            InvalidUtf8::Lossy => {
+                assert_eq!(line, "[alice] caf\u{fffd} \u{fffd} ok\n");
+                line.clear();
+                bob_reader.read_line(&mut line).unwrap();
+                assert_eq!(line, "[alice] still here\n");
+            }
+            InvalidUtf8::Drop => {
+                assert_eq!(line, "[alice] still here\n");
+                read_until(&mut alice_reader, |line| {
+                    line == "* message dropped: invalid encoding"
+                });
+            }
+            InvalidUtf8::Disconnect => {
+                assert!(line.starts_with("* alice left the room (online "))
+            }
+        }
+    }
+}
+
+/// What Windows telnet sends right after connecting: WILL NAWS, WILL TSPEED, WILL
+/// TTYPE, WILL NEW-ENVIRON, DO ECHO, WILL SGA and DO SGA, then the window size
+const TELNET_HANDSHAKE: &[u8] = b"\xff\xfb\x1f\xff\xfb\x20\xff\xfb\x18\xff\xfb\x27\xff\xfd\x01\xff\xfb\x03\xff\xfd\x03\xff\xfa\x1f\x00\x78\x00\x1e\xff\xf0";
+
+#[test]
+fn telnet_clients() {
+    let addr = start_server(ServerConfig::default());
+    let (_bob, mut bob_reader) = join(addr, "bob");
+
+    let mut alice = connect(addr);
+    let mut alice_reader = BufReader::new(alice.try_clone().unwrap());
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    alice.write_all(TELNET_HANDSHAKE).unwrap();
+    alice.write_all(b"alice\r\n").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* Welcome, the room contains 1 user: bob"
+    });
+    read_until(&mut bob_reader, |line| line == "* alice joined the room");
+
+    let mut expect_line = |expected: &str| {
+        let mut line = String::new();
+        bob_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("{expected}\n"));
+    };
+    // a window size of 10x10 in the middle of a line, its line feeds are not data
+    alice
+        .write_all(b"hel\xff\xfa\x1f\x00\x0a\x00\x0a\xff\xf0lo\r\n")
+        .unwrap();
+    expect_line("[alice] hello");
+    // the bare carriage returns, followed by a NUL as telnet sends them or not
+    alice.write_all(b"a\rb\r\0c\r\n").unwrap();
+    expect_line("[alice] abc");
+    // a command split across writes, NOP and DONT ECHO
+    alice.write_all(b"good\xff").unwrap();
+    alice.flush().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    alice.write_all(b"\xf1by\xff\xfe\x01e\n").unwrap();
+    expect_line("[alice] goodbye");
+}
+
+#[test]
+fn metrics_endpoint() {
+    let runtime = Runtime::new().unwrap();
+    let chatroom = Chatroom::default();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let metrics_listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap();
+    runtime.spawn(serve_metrics(metrics_listener, chatroom.clone()));
+    thread::spawn(move || {
+        runtime.block_on(serve(
+            listener,
+            chatroom,
+            ServerConfig::default(),
+            future::pending(),
+        ))
+    });
+
+    let (_alice, _) = join(addr, "alice");
+    // closed without a request, once the others are answered
+    let mut idle = connect(metrics_addr);
+
+    let scrape = |path: &str| {
+        let mut stream = connect(metrics_addr);
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = scrape("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("\nbudget_chat_connected_users 1\n"));
+    assert!(response.contains("\nbudget_chat_connections_total 1\n"));
+    assert!(scrape("/").starts_with("HTTP/1.1 404"));
+    let mut response = Vec::new();
+    idle.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty());
+}
+
+#[test]
+fn joined_clients_are_pinged() {
+    let addr = start_server(ServerConfig {
+        ping_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    });
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    let started = Instant::now();
+    read_until(&mut alice_reader, |line| line == "* ping");
+    read_until(&mut alice_reader, |line| line == "* ping");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn handshaking_connections_are_capped() {
+    let addr = start_server(ServerConfig {
+        max_connections: Some(2),
+        ..Default::default()
+    });
+
+    let read_line = |stream: &TcpStream| {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        line
+    };
+    let mut first = connect(addr);
+    let second = connect(addr);
+    read_line(&first);
+    read_line(&second);
+    let third = connect(addr);
+    assert_eq!(read_line(&third), "server full\n");
+
+    // joined users no longer count
+    writeln!(first, "alice").unwrap();
+    assert!(read_line(&first).starts_with("* Welcome"));
+    let fourth = connect(addr);
+    assert!(read_line(&fourth).starts_with("Welcome to our chat room"));
+}
+
+#[test]
+fn connection_rate_is_limited() {
+    let first_line = |addr| {
+        let mut line = String::new();
+        BufReader::new(connect(addr)).read_line(&mut line).unwrap();
+        line
+    };
+    let addr = start_server(ServerConfig {
+        conn_rate: Some("2/500ms".parse().unwrap()),
+        ..Default::default()
+    });
+    for _ in 0..2 {
+        assert!(first_line(addr).starts_with("Welcome to our chat room"));
+    }
+    assert_eq!(first_line(addr), "too many connections, slow down\n");
+    thread::sleep(Duration::from_millis(500));
+    assert!(first_line(addr).starts_with("Welcome to our chat room"));
+
+    let addr = start_server(ServerConfig {
+        conn_rate: Some("2/500ms".parse().unwrap()),
+        conn_rate_exempt_local: true,
+        ..Default::default()
+    });
+    for _ in 0..5 {
+        assert!(first_line(addr).starts_with("Welcome to our chat room"));
+    }
+}
+
+#[test]
+fn sessions_per_ip_are_limited() {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chatroom = Chatroom::new(ChatroomConfig {
+        max_sessions_per_ip: Some(2),
+        ..Default::default()
+    });
+    thread::spawn(move || {
+        runtime.block_on(serve(
+            listener,
+            chatroom,
+            ServerConfig::default(),
+            future::pending(),
+        ))
+    });
+    let (_alice, _) = join(addr, "alice");
+    let (_bob, _) = join(addr, "bob");
+
+    let carol = connect(addr);
+    writeln!(&carol, "carol").unwrap();
+    let mut lines = BufReader::new(carol).lines().map(Result::unwrap);
+    lines.next();
+    assert_eq!(
+        lines.next().unwrap(),
+        "Too many users are connected from your address."
+    );
+    assert!(lines.next().is_none(), "connection still open");
+
+    // another address still gets in
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 2], 0)).into())
+        .unwrap();
+    socket.connect(&addr.into()).unwrap();
+    let dave = TcpStream::from(socket);
+    writeln!(&dave, "dave").unwrap();
+    let mut lines = BufReader::new(dave).lines().map(Result::unwrap);
+    lines.next();
+    assert!(lines.next().unwrap().starts_with("* Welcome"));
+}
+
+#[test]
+fn silent_connections_time_out() {
+    let addr = start_server(ServerConfig {
+        handshake_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    });
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let silent = connect(addr);
+    let started = Instant::now();
+    let mut lines = BufReader::new(silent).lines().map(Result::unwrap);
+    assert_eq!(
+        lines.next().unwrap(),
+        "Welcome to our chat room, please enter your nickname:"
+    );
+    assert_eq!(lines.next().unwrap(), "timed out waiting for nickname");
+    assert!(lines.next().is_none(), "connection still open");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // the silent client never joined, the joined one outlived the timeout
+    writeln!(alice, "/who").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* Users in the room: @alice"
+    });
+}
+
+#[test]
+fn kick_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/kick alice").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+    writeln!(alice, "/kick").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /kick <nick> [reason]"
+    });
+
+    writeln!(alice, "/kick bob be nice").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* bob was kicked by alice"
+    });
+    read_until(&mut bob_reader, |line| line == "* you were kicked: be nice");
+    let mut rest = String::new();
+    bob_reader.read_to_string(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn op_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/op bob").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+    writeln!(alice, "/op").unwrap();
+    read_until(&mut alice_reader, |line| line == "* usage: /op <nick>");
+
+    writeln!(alice, "/op bob").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob is now an operator");
+    read_until(&mut bob_reader, |line| {
+        line == "* you are now an operator, by alice"
+    });
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Users in the room: @alice, @bob"
+    });
+
+    writeln!(bob, "/deop alice").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* alice is no longer an operator"
+    });
+    read_until(&mut alice_reader, |line| {
+        line == "* you are no longer an operator, by bob"
+    });
+    writeln!(alice, "/kick bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+}
+
+#[test]
+fn mute_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/mute bob 2h30").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /mute <nick> <duration>"
+    });
+    writeln!(alice, "/mute bob 2h30m").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* bob is muted for 2h30m00s"
+    });
+    writeln!(bob, "hello").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line.starts_with("* you are muted until ")
+    });
+
+    writeln!(alice, "/unmute bob").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob is no longer muted");
+    writeln!(bob, "hello").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hello");
+    writeln!(alice, "/unmute bob").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob is not muted");
+}
+
+#[test]
+fn lockdown_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/lockdown on").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+    writeln!(alice, "/lockdown").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* usage: /lockdown on|off"
+    });
+    writeln!(alice, "/lockdown on").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* [server] the room is now read-only, by alice"
+    });
+    writeln!(bob, "hello").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* the room is currently read-only"
+    });
+    writeln!(bob, "/stats").unwrap();
+    read_until(&mut bob_reader, |line| line.contains(", read-only, "));
+
+    writeln!(alice, "/lockdown off").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* [server] the room is no longer read-only, by alice"
+    });
+    writeln!(bob, "hello").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hello");
+}
+
+#[test]
+fn ban_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/ban alice").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+    writeln!(alice, "/ban bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* bob was banned by alice"
+    });
+    read_until(&mut alice_reader, |line| line == "* 127.0.0.1 is banned");
+    read_until(&mut bob_reader, |line| line == "* you are banned");
+
+    let mut line = String::new();
+    let banned = connect(addr);
+    BufReader::new(banned).read_line(&mut line).unwrap();
+    assert_eq!(line, "you are banned\n");
+
+    writeln!(alice, "/unban 127.0.0.1").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* 127.0.0.1 is no longer banned"
+    });
+    join(addr, "bob");
+}
+
+#[test]
+fn nick_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/nick bob").unwrap();
+    read_until(&mut alice_reader, |line| line == "* Nickname already used.");
+    writeln!(alice, "/nick alicia").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* alice is now known as alicia"
+    });
+    writeln!(alice, "hi").unwrap();
+    read_until(&mut bob_reader, |line| line == "[alicia] hi");
+}
+
+#[test]
+fn me_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/me").unwrap();
+    read_until(&mut alice_reader, |line| line == "* usage: /me <action>");
+    writeln!(alice, "/me waves").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* alice waves\n");
+
+    // emotes are part of the history
+    let (_carol, mut carol_reader) = join(addr, "carol");
+    read_until(&mut carol_reader, |line| line == "* [history] alice waves");
+}
+
+#[test]
+fn last_command() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        history: HistoryConfig {
+            messages: 3,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    for message in ["one", "two", "three", "four", "five"] {
+        writeln!(alice, "{message}").unwrap();
+    }
+    writeln!(alice, "/last 2").unwrap();
+    let mut line = String::new();
+    for expected in ["* [history] [alice] four\n", "* [history] [alice] five\n"] {
+        line.clear();
+        alice_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, expected);
+    }
+    writeln!(alice, "/last 20").unwrap();
+    for expected in [
+        "* only the last 3 messages are kept\n",
+        "* [history] [alice] three\n",
+        "* [history] [alice] four\n",
+        "* [history] [alice] five\n",
+    ] {
+        line.clear();
+        alice_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, expected);
+    }
+    writeln!(alice, "/last some").unwrap();
+    read_until(&mut alice_reader, |line| line == "* usage: /last [count]");
+
+    let chatroom = Chatroom::new(ChatroomConfig {
+        history: HistoryConfig {
+            messages: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    writeln!(bob, "/last").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* history is disabled on this server"
+    });
+}
+
+#[test]
+fn search_command() {
+    let file = std::env::temp_dir().join(format!("budget-chat-search-{}.db", std::process::id()));
+    let _ = fs::remove_file(&file);
+    let chatroom = Chatroom::new(ChatroomConfig {
+        archive: Some(Arc::new(Archive::open(&file).unwrap())),
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+
+    let (_carol, mut carol_reader) = join(addr, "carol");
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    writeln!(alice, "/search").unwrap();
+    read_until(&mut alice_reader, |line| line == "* usage: /search <term>");
+    writeln!(alice, "the cake is a lie").unwrap();
+    writeln!(alice, "nothing to see").unwrap();
+    writeln!(alice, "/search CAKE").unwrap();
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("* [archive] "), "{line}");
+    assert!(line.ends_with(" [alice] the cake is a lie\n"), "{line}");
+    writeln!(alice, "/search see").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* wait 10s before searching again"
+    });
+    // the limit is on the user, not on the connection
+    drop((alice, alice_reader));
+    read_until(&mut carol_reader, |line| {
+        line.starts_with("* alice left the room")
+    });
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    writeln!(alice, "/search see").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line.starts_with("* wait ") && line.ends_with(" before searching again")
+    });
+
+    let addr = start_server(ServerConfig::default());
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    writeln!(bob, "/search cake").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* the messages are not archived on this server"
+    });
+    fs::remove_file(&file).unwrap();
+    let _ = fs::remove_file(file.with_extension("db-wal"));
+    let _ = fs::remove_file(file.with_extension("db-shm"));
+}
+
+#[test]
+fn timestamps_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/timestamps on").unwrap();
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line.ends_with("* Users in the room: @alice, bob")
+    });
+    writeln!(alice, "hi").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    let (time, message) = line.split_at(9);
+    assert_eq!(message, "[alice] hi\n");
+    assert!(
+        time.char_indices().all(|(i, c)| match i {
+            2 | 5 => c == ':',
+            8 => c == ' ',
+            _ => c.is_ascii_digit(),
+        }),
+        "{line}"
+    );
+
+    writeln!(bob, "/timestamps off").unwrap();
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Users in the room: @alice, bob"
+    });
+    writeln!(alice, "bye").unwrap();
+    read_until(&mut bob_reader, |line| line == "[alice] bye");
+}
+
+#[test]
+fn nickname_colors() {
+    // the same on every run
+    assert_eq!(nickname_color("alice"), 96);
+    assert_eq!(nickname_color("bob"), 31);
+    assert_eq!(nickname_color("carol"), 95);
+    assert_eq!(nickname_color("dave"), 34);
+    let colors: HashSet<u8> = (0..100)
+        .map(|i| nickname_color(&format!("user{i}")))
+        .collect();
+    assert!(colors.len() > 6, "{colors:?}");
+    assert!(colors
+        .iter()
+        .all(|color| (31..=36).contains(color) || (91..=96).contains(color)));
+}
+
+#[test]
+fn color_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/color on").unwrap();
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "\x1b[2m* Users in the room: @alice, bob\x1b[0m"
+    });
+    // the escape sequences of the users are stripped
+    writeln!(alice, "hi \x1b[31mthere").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "[\x1b[96malice\x1b[39m] hi there"
+    });
+    writeln!(alice, "@bob hello").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "\x1b[1m(!) [\x1b[96malice\x1b[39m] @bob hello\x1b[0m"
+    });
+    // alice did not ask for colors
+    writeln!(bob, "hi alice").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hi alice");
+
+    writeln!(bob, "/color off").unwrap();
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* Users in the room: @alice, bob"
+    });
+    writeln!(alice, "bye").unwrap();
+    read_until(&mut bob_reader, |line| line == "[alice] bye");
+
+    let addr = start_server(ServerConfig {
+        color: true,
+        ..Default::default()
+    });
+    let carol = connect(addr);
+    let mut carol_reader = BufReader::new(carol.try_clone().unwrap());
+    // the prompt is written before the client can choose
+    read_until(&mut carol_reader, |line| {
+        line == "Welcome to our chat room, please enter your nickname:"
+    });
+    writeln!(&carol, "carol").unwrap();
+    read_until(&mut carol_reader, |line| {
+        line == "\x1b[2m* Welcome, the room is empty\x1b[0m"
+    });
+}
+
+#[test]
+fn quit_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/quit gotta go").unwrap();
+    read_until(&mut bob_reader, |line| line == "* goodbye");
+    let mut line = String::new();
+    assert_eq!(bob_reader.read_line(&mut line).unwrap(), 0, "{line}");
+    read_until(&mut alice_reader, |line| {
+        line == "* bob left the room: gotta go"
+    });
+}
+
+#[test]
+fn away_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/away lunch").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are now away: lunch");
+    writeln!(alice, "/who").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* Users in the room: @alice, bob (away)"
+    });
+    writeln!(alice, "/msg bob hungry?").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob is away: lunch");
+
+    writeln!(bob, "/back").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are back");
+    writeln!(bob, "/back").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are not away");
+    writeln!(bob, "/away").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are now away");
+    writeln!(bob, "/away").unwrap();
+    read_until(&mut bob_reader, |line| line == "* you are back");
+}
+
+#[test]
+fn mentions_ring_the_bell() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, _alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    let mut expect_line = |expected: &[u8]| {
+        let mut line = Vec::new();
+        bob_reader.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(line, expected, "{:?}", String::from_utf8_lossy(&line));
+    };
+    writeln!(alice, "hi @bob").unwrap();
+    expect_line(b"(!) [alice] hi @bob\n");
+    writeln!(alice, "/msg bob psst").unwrap();
+    expect_line(b"[alice -> you] psst\n");
+
+    writeln!(bob, "/bell on").unwrap();
+    writeln!(bob, "/who").unwrap();
+    expect_line(b"* Users in the room: @alice, bob\n");
+    writeln!(alice, "hi again @Bob").unwrap();
+    expect_line(b"\x07(!) [alice] hi again @Bob\n");
+    // the nickname as a word, whatever the case
+    writeln!(alice, "is BOB here?").unwrap();
+    expect_line(b"\x07[alice] is BOB here?\n");
+    writeln!(alice, "bobby is not").unwrap();
+    expect_line(b"[alice] bobby is not\n");
+    writeln!(alice, "/me waves at bob").unwrap();
+    expect_line(b"\x07* alice waves at bob\n");
+    writeln!(alice, "/msg bob psst").unwrap();
+    expect_line(b"\x07[alice -> you] psst\n");
+    // the bells of the users are control characters
+    writeln!(alice, "bob \x07").unwrap();
+    expect_line(b"\x07[alice] bob \n");
+
+    writeln!(bob, "/bell off").unwrap();
+    writeln!(bob, "/who").unwrap();
+    expect_line(b"* Users in the room: @alice, bob\n");
+    writeln!(alice, "bye bob").unwrap();
+    expect_line(b"[alice] bye bob\n");
+    writeln!(bob, "/bell maybe").unwrap();
+    expect_line(b"* usage: /bell on|off\n");
+}
+
+#[test]
+fn help_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/shrug").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* unknown command: /shrug — try /help"
+    });
+    writeln!(alice, "/helpme please").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* unknown command: /helpme — try /help"
+    });
+    // a message starting with a slash
+    writeln!(alice, "//shrug").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] /shrug\n");
+
+    writeln!(alice, "/help").unwrap();
+    read_until(&mut alice_reader, |line| line == "* commands:");
+    let mut listed = Vec::new();
+    loop {
+        let mut line = String::new();
+        alice_reader.read_line(&mut line).unwrap();
+        listed.push(line.trim_end().to_string());
+        if line.starts_with("* /echo on|off: ") {
+            break;
+        }
+    }
+    let commands = [
+        "/help",
+        "/msg",
+        "/me",
+        "/paste",
+        "/who",
+        "/whois",
+        "/join",
+        "/leave",
+        "/rooms",
+        "/invite",
+        "/mode",
+        "/kick",
+        "/ban",
+        "/unban",
+        "/mute",
+        "/unmute",
+        "/lockdown",
+        "/op",
+        "/deop",
+        "/nick",
+        "/timestamps",
+        "/color",
+        "/seq",
+        "/ack",
+        "/topic",
+        "/ignore",
+        "/unignore",
+        "/quit",
+        "/away",
+        "/back",
+        "/last",
+        "/search",
+        "/stats",
+        "/bell",
+        "/protocol",
+        "/echo",
+    ];
+    assert_eq!(listed.len(), commands.len(), "{listed:?}");
+    for (line, command) in listed.iter().zip(commands) {
+        let usage = line.strip_prefix("* ").unwrap().split(':').next().unwrap();
+        assert!(
+            usage == command || usage.starts_with(&format!("{command} ")),
+            "{line}"
+        );
+    }
+    assert!(listed.contains(&"* /msg <nick> <text>: send a private message".to_string()));
+}
+
+#[test]
+fn sequence_numbers() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    writeln!(alice, "/seq on\n/who").unwrap();
+    let mut line = String::new();
+    while !line.starts_with('<') {
+        line.clear();
+        alice_reader.read_line(&mut line).unwrap();
+    }
+    let (seq, line) = line[1..].split_once("> ").unwrap();
+    let seq: u64 = seq.parse().unwrap();
+    assert!(line.starts_with("* Users in the room"), "{line}");
+
+    writeln!(bob, "hi").unwrap();
+    let expected = format!("<{}> [bob] hi", seq + 1);
+    read_until(&mut alice_reader, |line| line == expected);
+    writeln!(alice, "/ack {}", seq + 5).unwrap();
+    let expected = format!("<{}> * no line is numbered {} yet", seq + 2, seq + 5);
+    read_until(&mut alice_reader, |line| line == expected);
+    writeln!(alice, "/ack {}\n/seq off\n/who", seq + 2).unwrap();
+    read_until(&mut alice_reader, |line| {
+        line.starts_with("* Users in the room")
+    });
+
+    writeln!(bob, "/whois alice").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* alice is 1 line behind its last /ack"
+    });
+}
+
+#[test]
+fn whois_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    let (mut carol, mut carol_reader) = join(addr, "carol");
+    writeln!(carol, "/away lunch").unwrap();
+    read_until(&mut carol_reader, |line| {
+        line.starts_with("* you are now away")
+    });
+
+    // the lines of a /whois, up to the answer of a /who sent after it
+    let whois = |stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, target: &str| {
+        writeln!(stream, "/whois {target}").unwrap();
+        writeln!(stream, "/who").unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.starts_with("* Users in the room") {
+                return lines;
+            }
+            lines.push(line.trim_end().to_string());
+        }
+    };
+    // an operator sees the address
+    let lines = whois(&mut alice, &mut alice_reader, "carol");
+    let lines = &lines[lines.len() - 3..];
+    assert!(
+        lines[0].starts_with("* carol: connected for ") && lines[0].contains(", idle for "),
+        "{lines:?}"
+    );
+    assert_eq!(
+        lines[1..],
+        [
+            "* carol is away: lunch",
+            "* carol is connected from 127.0.0.1"
+        ]
+    );
+    // the other users do not
+    let lines = whois(&mut bob, &mut bob_reader, "carol");
+    let lines = &lines[lines.len() - 2..];
+    assert!(lines[0].starts_with("* carol: connected for "), "{lines:?}");
+    assert_eq!(lines[1], "* carol is away: lunch");
+    let lines = whois(&mut bob, &mut bob_reader, "alice");
+    assert_eq!(lines.last().unwrap(), "* alice is an operator");
+
+    assert_eq!(whois(&mut bob, &mut bob_reader, "dave"), ["* no such user"]);
+    writeln!(bob, "/whois").unwrap();
+    read_until(&mut bob_reader, |line| line == "* usage: /whois <nick>");
+}
+
+#[test]
+fn stats_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    writeln!(alice, "hello").unwrap();
+    writeln!(alice, "/me waves").unwrap();
+    writeln!(alice, "/stats").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line.starts_with("* users: 1, uptime: ")
+            && line.contains(", messages: 2, your session: ")
+            && line.ends_with(", your messages: 2")
+    });
+}
+
+#[test]
+fn echo_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (_bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/echo on").unwrap();
+    writeln!(alice, "hello").unwrap();
+    writeln!(alice, "/me waves").unwrap();
+    read_until(&mut alice_reader, |line| line == "[alice] hello");
+    read_until(&mut alice_reader, |line| line == "* alice waves");
+
+    writeln!(alice, "/echo off").unwrap();
+    writeln!(alice, "quiet").unwrap();
+    writeln!(alice, "/who").unwrap();
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* Users in the room: @alice, bob\n");
+    read_until(&mut bob_reader, |line| line == "[alice] quiet");
+
+    writeln!(alice, "/echo maybe").unwrap();
+    read_until(&mut alice_reader, |line| line == "* usage: /echo on|off");
+}
+
+#[test]
+fn topic_command() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "/topic").unwrap();
+    read_until(&mut bob_reader, |line| line == "* no topic is set");
+    writeln!(bob, "/topic mine").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* permission denied: you are not an operator"
+    });
+
+    writeln!(alice, "/topic Rust questions only").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* topic: Rust questions only"
+    });
+    writeln!(bob, "/topic").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line == "* topic: Rust questions only (set by alice)"
+    });
+
+    let mut carol = connect(addr);
+    let mut lines = BufReader::new(carol.try_clone().unwrap()).lines();
+    lines.next();
+    writeln!(carol, "carol").unwrap();
+    assert!(lines.next().unwrap().unwrap().starts_with("* Welcome"));
+    assert_eq!(
+        lines.next().unwrap().unwrap(),
+        "* topic: Rust questions only"
+    );
+}
+
+#[test]
+fn ignore_commands() {
+    let addr = start_server(ServerConfig::default());
+
+    let (mut alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(alice, "/ignore").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* you are not ignoring anyone"
+    });
+    writeln!(alice, "/ignore bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* you are now ignoring bob"
+    });
+    writeln!(alice, "/ignore").unwrap();
+    read_until(&mut alice_reader, |line| line == "* ignored users: bob");
+
+    writeln!(bob, "ignored").unwrap();
+    writeln!(bob, "/who").unwrap();
+    read_until(&mut bob_reader, |line| {
+        line.starts_with("* Users in the room")
+    });
+    writeln!(alice, "/unignore bob").unwrap();
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* you are no longer ignoring bob\n");
+    writeln!(bob, "heard").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] heard");
+
+    writeln!(alice, "/unignore bob").unwrap();
+    read_until(&mut alice_reader, |line| {
+        line == "* you are not ignoring bob"
+    });
+}
+
+#[test]
+fn custom_templates() {
+    let templates = MessageTemplates::parse(
+        "prompt = \"Bienvenue, votre pseudo :\"\njoined = \"* {nick} est arrivé\"",
+    )
+    .unwrap();
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chatroom = Chatroom::new(ChatroomConfig {
+        templates: Arc::new(templates),
+        ..Default::default()
+    });
+    {
+        let chatroom = chatroom.clone();
+        thread::spawn(move || {
+            runtime.block_on(serve(
+                listener,
+                chatroom,
+                ServerConfig::default(),
+                future::pending(),
+            ))
+        });
+    }
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    let bob = connect(addr);
+    let mut bob_reader = BufReader::new(bob.try_clone().unwrap());
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "Bienvenue, votre pseudo :\n");
+    writeln!(&bob, "bob").unwrap();
+    read_until(&mut alice_reader, |line| line == "* bob est arrivé");
+
+    // reloaded for the connected clients too
+    let templates = MessageTemplates::parse("message = \"<{from}> {text}\"").unwrap();
+    chatroom.reload(RuntimeConfig {
+        templates: Arc::new(templates),
+        ..(*chatroom.runtime_config()).clone()
+    });
+    writeln!(&bob, "salut").unwrap();
+    read_until(&mut alice_reader, |line| line == "<bob> salut");
+}
+
+#[test]
+fn registered_nicknames() {
+    // cheap hashes, the defaults take seconds in debug builds
+    let params = PasswordParams {
+        memory_kib: 64,
+        passes: 1,
+        lanes: 1,
+    };
+    let path = std::env::temp_dir().join(format!("budget-chat-accounts-{}", std::process::id()));
+    let hash = hash_password("hunter2", params).unwrap();
+    assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"), "{hash}");
+    fs::write(&path, format!("alice:{hash}\n")).unwrap();
+    let accounts = Accounts::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let chatroom = Chatroom::new(ChatroomConfig {
+        accounts: Arc::new(accounts),
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom.clone(), ServerConfig::default());
+
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    writeln!(bob, "/nick alice").unwrap();
+    read_until(&mut bob_reader, |line| line == "* Authentication failed.");
+
+    let alice = connect(addr);
+    let mut alice_reader = BufReader::new(alice.try_clone().unwrap());
+    read_until(&mut alice_reader, |line| line.ends_with("nickname:"));
+    writeln!(&alice, "alice").unwrap();
+    read_until(&mut alice_reader, |line| line == "password:");
+    writeln!(&alice, "hunter3").unwrap();
+    read_until(&mut alice_reader, |line| line == "Wrong password.");
+    read_until(&mut alice_reader, |line| line == "password:");
+    writeln!(&alice, "hunter2\r").unwrap();
+    read_until(&mut alice_reader, |line| line.starts_with("* Welcome"));
+    read_until(&mut bob_reader, |line| line == "* alice joined the room");
+
+    let intruder = connect(addr);
+    let mut intruder_reader = BufReader::new(intruder.try_clone().unwrap());
+    read_until(&mut intruder_reader, |line| line.ends_with("nickname:"));
+    writeln!(&intruder, "alice").unwrap();
+    for _ in 0..3 {
+        read_until(&mut intruder_reader, |line| line == "password:");
+        writeln!(&intruder, "hunter2?").unwrap();
+    }
+    read_until(&mut intruder_reader, |line| {
+        line == "Authentication failed."
+    });
+    let mut line = String::new();
+    assert_eq!(intruder_reader.read_line(&mut line).unwrap(), 0, "{line}");
+    assert_eq!(chatroom.metrics().join_rejections.authentication_failed, 1);
+}
+
+#[test]
+fn connections_are_numbered() {
+    let chatroom = Chatroom::default();
+    let addr = serve_chatroom(chatroom.clone(), ServerConfig::default());
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    // numbered though it never joins
+    let mut line = String::new();
+    let early = connect(addr);
+    BufReader::new(&early).read_line(&mut line).unwrap();
+    drop(early);
+    let (bob, _) = join(addr, "bob");
+    // registered before the messages of bob are read
+    writeln!(&bob, "hi").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hi");
+
+    let users = chatroom.users();
+    let (alice, bob) = (users[0].connection.unwrap(), users[1].connection.unwrap());
+    assert!(bob >= alice + 2, "{alice} {bob}");
+}
+
+#[test]
+fn resumed_sessions() {
+    let chatroom = Chatroom::new(ChatroomConfig {
+        resume_window: Some(Duration::from_secs(60)),
+        ..Default::default()
+    });
+    let addr = serve_chatroom(chatroom, ServerConfig::default());
+
+    let (alice, mut alice_reader) = join(addr, "alice");
+    let mut line = String::new();
+    alice_reader.read_line(&mut line).unwrap();
+    let token = line
+        .trim_end()
+        .strip_prefix("* resume-token: ")
+        .unwrap()
+        .to_string();
+    let (mut bob, mut bob_reader) = join(addr, "bob");
+    read_until(&mut bob_reader, |line| line.starts_with("* resume-token: "));
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    let resume = |token: &str| {
+        let stream = connect(addr);
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        writeln!(&stream, "/resume {token}").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        (stream, reader, line)
+    };
+    let (_, _, line) = resume(&token);
+    assert_eq!(line, "The session is still connected.\n");
+    let (_, _, line) = resume("00000000000000000000000000000000");
+    assert_eq!(line, "Unknown or expired resume token.\n");
+
+    drop(alice);
+    drop(alice_reader);
+    thread::sleep(Duration::from_millis(100));
+    writeln!(bob, "are you there?").unwrap();
+    let (alice, mut alice_reader, line) = resume(&token);
+    assert_eq!(line, "Resumed as alice.\n");
+    read_until(&mut alice_reader, |line| line == "[bob] are you there?");
+    writeln!(&alice, "yes").unwrap();
+    // without leave nor join in between
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "[alice] yes\n");
+}
+
+/// Connect to the server and give it `password`, returns the line answering it
+fn give_password(addr: SocketAddr, password: &str) -> (TcpStream, BufReader<TcpStream>, String) {
+    let stream = connect(addr);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "password:\n");
+    writeln!(&stream, "{password}").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    (stream, reader, line)
+}
+
+#[test]
+fn server_password() {
+    let addr = start_server(ServerConfig {
+        password: Some("swordfish".to_string()),
+        ..Default::default()
+    });
+
+    let (bob, mut bob_reader, line) = give_password(addr, "swordfish\r");
+    assert_eq!(
+        line,
+        "Welcome to our chat room, please enter your nickname:\n"
+    );
+    writeln!(&bob, "bob").unwrap();
+    read_until(&mut bob_reader, |line| line.starts_with("* Welcome"));
+
+    let (alice, mut alice_reader, line) = give_password(addr, "sword");
+    assert_eq!(line, "Wrong password.\n");
+    read_until(&mut alice_reader, |line| line == "password:");
+    writeln!(&alice, "swordfish").unwrap();
+    read_until(&mut alice_reader, |line| line.ends_with("nickname:"));
+    writeln!(&alice, "alice").unwrap();
+    read_until(&mut bob_reader, |line| line == "* alice joined the room");
+
+    let (intruder, mut intruder_reader, line) = give_password(addr, "swordfish!");
+    assert_eq!(line, "Wrong password.\n");
+    writeln!(&intruder, "swordfish?").unwrap();
+    read_until(&mut intruder_reader, |line| line == "Wrong password.");
+    let mut line = String::new();
+    assert_eq!(intruder_reader.read_line(&mut line).unwrap(), 0, "{line}");
+
+    // nothing is told about the clients leaving at the prompt
+    let quitter = connect(addr);
+    BufReader::new(&quitter).read_line(&mut line).unwrap();
+    drop(quitter);
+    let (carol, _, _) = give_password(addr, "swordfish");
+    writeln!(&carol, "carol").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* carol joined the room\n");
+}
+
+#[test]
+fn batched_writes() {
+    let addr = start_server(ServerConfig {
+        write_batch: true,
+        ..Default::default()
+    });
+
+    let (_alice, mut alice_reader) = join(addr, "alice");
+    let (mut bob, bob_reader) = join(addr, "bob");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    let lines: String = (0..50).map(|i| format!("line {i}\n")).collect();
+    bob.write_all(lines.as_bytes()).unwrap();
+    drop((bob, bob_reader));
+    for i in 0..50 {
+        let mut line = String::new();
+        alice_reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("[bob] line {i}\n"));
+    }
+    read_until(&mut alice_reader, |line| {
+        line.starts_with("* bob left the room (online ")
+    });
+}
+
+#[test]
+fn socket_options() {
+    for (nodelay, tcp_keepalive) in [(true, Some(Duration::from_secs(60))), (false, None)] {
+        let addr = start_server(ServerConfig {
+            nodelay,
+            tcp_keepalive,
+            ..Default::default()
+        });
+        let (mut alice, _alice_reader) = join(addr, "alice");
+        let (_bob, mut bob_reader) = join(addr, "bob");
+        writeln!(alice, "hi").unwrap();
+        read_until(&mut bob_reader, |line| line == "[alice] hi");
+    }
+}
+
+/// Send a masked frame, as the WebSocket clients do
+fn send_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    stream.write_all(&frame).unwrap();
+}
+
+/// Read a frame sent by the WebSocket server, returns its opcode and payload
+fn read_frame(reader: &mut impl Read) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).unwrap();
+    let len = match head[1] {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0F, payload)
+}
+
+#[test]
+fn websocket_clients() {
+    let runtime = Runtime::new().unwrap();
+    let chatroom = Chatroom::default();
+    let tcp = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let ws = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let ws_addr = ws.local_addr().unwrap();
+    let config = ServerConfig::default();
+    runtime.spawn(serve(
+        tcp,
+        chatroom.clone(),
+        config.clone(),
+        future::pending(),
+    ));
+    runtime.spawn(serve_websocket(ws, chatroom, config, future::pending()));
+
+    let (_alice, mut alice_reader) = join(tcp_addr, "alice");
+
+    let mut bob = connect(ws_addr);
+    // the example handshake of RFC 6455
+    write!(
+        bob,
+        "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    let mut bob_reader = BufReader::new(bob.try_clone().unwrap());
+    let mut response = String::new();
+    while !response.ends_with("\r\n\r\n") {
+        bob_reader.read_line(&mut response).unwrap();
+    }
+    assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    let welcome = read_frame(&mut bob_reader);
+    assert_eq!(
+        welcome,
+        (
+            1,
+            b"Welcome to our chat room, please enter your nickname:".to_vec()
+        )
+    );
+    send_frame(&mut bob, 1, b"bob");
+    let users = read_frame(&mut bob_reader);
+    assert_eq!(
+        users,
+        (1, b"* Welcome, the room contains 1 user: alice".to_vec())
+    );
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    send_frame(&mut bob, 9, b"still there?");
+    assert_eq!(read_frame(&mut bob_reader), (0xA, b"still there?".to_vec()));
+    send_frame(&mut bob, 1, b"hi");
+    read_until(&mut alice_reader, |line| line == "[bob] hi");
+
+    send_frame(&mut bob, 8, &1000u16.to_be_bytes());
+    assert_eq!(read_frame(&mut bob_reader).0, 8);
+    read_until(&mut alice_reader, |line| {
+        line.starts_with("* bob left the room (online ")
+    });
+}
+
+#[test]
+fn unix_socket_clients() {
+    let path = std::env::temp_dir().join(format!("budget-chat-{}.sock", std::process::id()));
+    // a stale socket file is replaced
+    fs::write(&path, "").unwrap();
+
+    let runtime = Runtime::new().unwrap();
+    let chatroom = Chatroom::default();
+    let tcp = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let unix = runtime.block_on(async { bind_unix(&path) }).unwrap();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o660
+    );
+    let (stop, stopped) = oneshot::channel::<()>();
+    let config = ServerConfig::default();
+    runtime.spawn(serve(
+        tcp,
+        chatroom.clone(),
+        config.clone(),
+        future::pending(),
+    ));
+    let unix_server = runtime.spawn(serve_unix(unix, chatroom, config, async {
+        let _ = stopped.await;
+    }));
+
+    let (_alice, mut alice_reader) = join(tcp_addr, "alice");
+    let mut bob = UnixStream::connect(&path).unwrap();
+    let mut bob_reader = BufReader::new(bob.try_clone().unwrap());
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    writeln!(bob, "bob").unwrap();
+    line.clear();
+    bob_reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* Welcome, the room contains 1 user: alice\n");
+    read_until(&mut alice_reader, |line| line == "* bob joined the room");
+
+    writeln!(bob, "hi").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hi");
+
+    let _ = stop.send(());
+    runtime.block_on(unix_server).unwrap();
+    assert!(!path.exists());
+}
+
+/// Connect to the server through a fake proxy sending `header`, returns the first
+/// line of the server, empty if it closed the connection
+fn proxied(addr: SocketAddr, header: &[u8]) -> String {
+    let mut stream = connect(addr);
+    stream.write_all(header).unwrap();
+    let mut line = String::new();
+    // closing with unread bytes resets the connection
+    let _ = BufReader::new(stream).read_line(&mut line);
+    line
+}
+
+#[test]
+fn proxy_protocol_headers() {
+    let runtime = Runtime::new().unwrap();
+    let bans = Arc::new(BanList::default());
+    bans.ban("192.0.2.1".parse().unwrap());
+    bans.ban("2001:db8::1".parse().unwrap());
+    let chatroom = Chatroom::new(ChatroomConfig {
+        bans,
+        ..Default::default()
+    });
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig {
+        proxy_protocol: true,
+        ..Default::default()
+    };
+    runtime.spawn(serve(listener, chatroom, config, future::pending()));
+
+    let welcome = "Welcome to our chat room, please enter your nickname:\n";
+    let banned = "you are banned\n";
+    let v1 = |header: &str| proxied(addr, header.as_bytes());
+    assert_eq!(
+        v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 5555\r\n"),
+        banned
+    );
+    assert_eq!(
+        v1("PROXY TCP4 192.0.2.2 198.51.100.1 56324 5555\r\n"),
+        welcome
+    );
+    assert_eq!(
+        v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 5555\r\n"),
+        banned
+    );
+    assert_eq!(
+        v1("PROXY TCP6 2001:db8::3 2001:db8::2 56324 5555\r\n"),
+        welcome
+    );
+    // health checks of the proxy
+    assert_eq!(v1("PROXY UNKNOWN\r\n"), welcome);
+    // malformed headers close the connection
+    assert_eq!(v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"), "");
+    assert_eq!(v1("PROXY TCP6 192.0.2.1 198.51.100.1 56324 5555\r\n"), "");
+    assert_eq!(v1("alice\nhello everyone\n"), "");
+
+    let v2 = |command: u8, family: u8, addresses: &[u8]| {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend([command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        proxied(addr, &header)
+    };
+    let tcp4 = |source: [u8; 4]| {
+        let mut addresses = source.to_vec();
+        addresses.extend([198, 51, 100, 1]);
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(5555u16.to_be_bytes());
+        addresses
+    };
+    assert_eq!(v2(0x21, 0x11, &tcp4([192, 0, 2, 1])), banned);
+    assert_eq!(v2(0x21, 0x11, &tcp4([192, 0, 2, 2])), welcome);
+    let mut tcp6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+    tcp6.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+    tcp6.extend(56324u16.to_be_bytes());
+    tcp6.extend(5555u16.to_be_bytes());
+    assert_eq!(v2(0x21, 0x21, &tcp6), banned);
+    // LOCAL connections of the proxy itself
+    assert_eq!(v2(0x20, 0x00, &[]), welcome);
+    assert_eq!(v2(0x21, 0x11, &[192, 0, 2, 1]), "");
+    assert_eq!(v2(0x31, 0x11, &tcp4([192, 0, 2, 2])), "");
+}
+
+/// Read the next line of a JSON client, without the `seq` of its events
+fn read_json(reader: &mut BufReader<TcpStream>) -> serde_json::Value {
+    let mut line = String::new();
+    assert_ne!(reader.read_line(&mut line).unwrap(), 0, "connection closed");
+    let mut json: serde_json::Value = serde_json::from_str(&line).unwrap();
+    if let Some(object) = json.as_object_mut() {
+        object.remove("seq");
+    }
+    json
+}
+
+#[test]
+fn json_protocol() {
+    let runtime = Runtime::new().unwrap();
+    let chatroom = Chatroom::default();
+    let plain = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let json = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let plain_addr = plain.local_addr().unwrap();
+    let json_addr = json.local_addr().unwrap();
+    let config = ServerConfig::default();
+    runtime.spawn(serve(
+        plain,
+        chatroom.clone(),
+        config.clone(),
+        future::pending(),
+    ));
+    let config = ServerConfig {
+        json: true,
+        ..config
+    };
+    runtime.spawn(serve(json, chatroom, config, future::pending()));
+
+    let (_alice, mut alice_reader) = join(plain_addr, "alice");
+    let mut bob = connect(json_addr);
+    let mut bob_reader = BufReader::new(bob.try_clone().unwrap());
+    let prompt = read_json(&mut bob_reader);
+    assert_eq!(prompt["type"], "prompt");
+    writeln!(bob, "bob").unwrap();
+    assert_eq!(
+        read_json(&mut bob_reader),
+        serde_json::json!({"type": "users", "users": ["alice"]})
+    );
+
+    // a client of the plain server switches with its first line
+    let (mut carol, mut carol_reader) = join(plain_addr, "carol");
+    writeln!(carol, "/protocol json\n/who").unwrap();
+    assert_eq!(read_json(&mut carol_reader)["type"], "who");
+    assert_eq!(
+        read_json(&mut bob_reader),
+        serde_json::json!({"type": "joined", "nick": "carol"})
+    );
+    read_until(&mut alice_reader, |line| line == "* carol joined the room");
+
+    // the JSON clients write JSON objects or plain lines, in a mixed room
+    writeln!(bob, r#"{{"type": "message", "text": "hi"}}"#).unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hi");
+    assert_eq!(
+        read_json(&mut carol_reader),
+        serde_json::json!({"type": "message", "from": "bob", "text": "hi"})
+    );
+    writeln!(bob, "hello @carol").unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob] hello @carol");
+    assert_eq!(
+        read_json(&mut carol_reader),
+        serde_json::json!({"type": "message", "from": "bob", "text": "hello @carol", "mentioned": true})
+    );
+    writeln!(
+        bob,
+        r#"{{"type": "private", "to": "alice", "text": "psst"}}"#
+    )
+    .unwrap();
+    read_until(&mut alice_reader, |line| line == "[bob -> you] psst");
+    assert_eq!(
+        read_json(&mut bob_reader),
+        serde_json::json!({"type": "private_sent", "to": "alice", "text": "psst"})
+    );
+
+    writeln!(bob, r#"{{"type": "dance"}}"#).unwrap();
+    let notice = read_json(&mut bob_reader);
+    assert_eq!(notice["type"], "notice");
+    assert!(notice["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("invalid command: unknown type dance"));
+    writeln!(bob, "{{not json").unwrap();
+    let notice = read_json(&mut bob_reader);
+    assert!(notice["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("invalid JSON: "));
+    writeln!(carol, "/protocol plain").unwrap();
+    assert_eq!(
+        read_json(&mut carol_reader),
+        serde_json::json!({
+            "type": "notice",
+            "text": "the protocol can only be chosen by the first line"
+        })
+    );
+
+    // the events are numbered from the list of users
+    writeln!(bob, "/who").unwrap();
+    let mut line = String::new();
+    bob_reader.read_line(&mut line).unwrap();
+    let who: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(who["type"], "who");
+    assert_eq!(who["seq"], 6);
+}
+
+/// Read a Server-Sent Event: its `data:` line then a blank line
+fn read_event(reader: &mut BufReader<TcpStream>) -> serde_json::Value {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let data = line
+        .strip_prefix("data: ")
+        .unwrap_or_else(|| panic!("{line:?}"));
+    let event = serde_json::from_str(data).unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "\n");
+    event
+}
+
+#[test]
+fn server_sent_events() {
+    let runtime = Runtime::new().unwrap();
+    let chatroom = Chatroom::default();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let events = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let events_addr = events.local_addr().unwrap();
+    runtime.spawn(serve_events(events, chatroom.clone()));
+    runtime.spawn(serve(
+        listener,
+        chatroom.clone(),
+        ServerConfig::default(),
+        future::pending(),
+    ));
+
+    let mut stream = connect(events_addr);
+    write!(stream, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut response = String::new();
+    while !response.ends_with("\r\n\r\n") {
+        reader.read_line(&mut response).unwrap();
+    }
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Content-Type: text/event-stream\r\n"));
+    // subscribed once the headers are written
+    let (mut alice, _) = join(addr, "alice");
+    writeln!(alice, "hi").unwrap();
+    assert_eq!(
+        read_event(&mut reader),
+        serde_json::json!({"type": "joined", "nick": "alice"})
+    );
+    assert_eq!(
+        read_event(&mut reader),
+        serde_json::json!({"type": "message", "from": "alice", "text": "hi"})
+    );
+    assert_eq!(chatroom.user_count(), 1);
+
+    let mut other = connect(events_addr);
+    write!(other, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    other.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 404"));
+
+    // the streams end on shutdown
+    chatroom.shutdown();
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+}
+
+#[test]
+fn load_test() {
+    let addr = start_server(ServerConfig::default());
+    let config = LoadTestConfig {
+        target: addr,
+        clients: 5,
+        msgs_per_sec: 4.0,
+        duration: parse_duration("500ms").unwrap(),
+    };
+    let report = Runtime::new().unwrap().block_on(run_load_test(config));
+    assert_eq!(report.connect_failures, 0, "{report}");
+    assert_eq!(report.disconnects, 0, "{report}");
+    assert!(report.sent >= 5, "{report}");
+    // every message is received by the 4 other clients
+    assert_eq!(report.received, report.sent * 4, "{report}");
+    assert!(report.latency(0.5).unwrap() <= report.latency(0.99).unwrap());
+    assert!(parse_duration("30").is_err());
+}
@@ -0,0 +1,72 @@
+//! Runs the budget-chat binary as systemd would, with an inherited listening socket.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    os::{
+        fd::AsRawFd,
+        unix::{net::UnixDatagram, process::CommandExt},
+    },
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+#[test]
+fn socket_activation() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let notify_path =
+        std::env::temp_dir().join(format!("budget-chat-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&notify_path);
+    let notifications = UnixDatagram::bind(&notify_path).unwrap();
+    notifications
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_budget-chat"));
+    command
+        .arg("--systemd-socket")
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    // SAFETY: dup2 and fcntl are async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 does nothing when the listener already is the fd 3, kept on exec
+            let result = match fd {
+                3 => libc::fcntl(3, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, 3),
+            };
+            match result {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    let mut server = command.spawn().unwrap();
+    drop(listener);
+
+    let mut notification = [0; 64];
+    let len = notifications.recv(&mut notification).unwrap();
+    assert_eq!(&notification[..len], b"READY=1");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(
+        line,
+        "Welcome to our chat room, please enter your nickname:\n"
+    );
+    writeln!(stream, "alice").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "* Welcome, the room is empty\n");
+
+    unsafe { libc::kill(server.id() as i32, libc::SIGTERM) };
+    let len = notifications.recv(&mut notification).unwrap();
+    assert_eq!(&notification[..len], b"STOPPING=1");
+    assert!(server.wait().unwrap().success());
+    let _ = std::fs::remove_file(&notify_path);
+}
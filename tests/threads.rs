@@ -0,0 +1,67 @@
+//! Lives in its own test binary: it counts the threads of the whole process.
+
+use std::{
+    fs, future,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use budget_chat::{
+    server::{serve, ServerConfig},
+    Chatroom,
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+fn thread_count() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+/// Join as `nickname`, returns the stream and the user list line
+fn join(addr: SocketAddr, nickname: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "{nickname}").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    (stream, line)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn connections_do_not_use_threads() {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(serve(
+        listener,
+        Chatroom::default(),
+        ServerConfig::default(),
+        future::pending(),
+    ));
+    let baseline = thread_count();
+
+    let clients = (0..1000)
+        .map(|i| join(addr, &format!("user{i}")).0)
+        .collect::<Vec<_>>();
+    assert!(thread_count() < baseline + 10);
+    drop(clients);
+
+    // every session ends once its client is gone
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while join(addr, "last").1 != "* Welcome, the room is empty\n" {
+        assert!(Instant::now() < deadline, "sessions are still alive");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(thread_count() < baseline + 10);
+}
@@ -0,0 +1,103 @@
+//! The chat served over TLS, to a client trusting the test certificate of
+//! `tests/certs`.
+
+use std::{future, path::Path, sync::Arc, time::Duration};
+
+use budget_chat::{
+    server::{serve_tls, ServerConfig},
+    Chatroom, TlsConfig,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        RootCertStore,
+    },
+    TlsConnector,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn certs() -> &'static Path {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs"))
+}
+
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(certs().join("cert.pem")).unwrap())
+        .unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn start_server() -> std::net::SocketAddr {
+    let tls = TlsConfig::load(&certs().join("cert.pem"), &certs().join("key.pem")).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_tls(
+        listener,
+        Chatroom::default(),
+        ServerConfig::default(),
+        tls,
+        future::pending(),
+    ));
+    addr
+}
+
+#[tokio::test]
+async fn chat_over_tls() {
+    let addr = start_server().await;
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let domain = ServerName::try_from("localhost").unwrap();
+    let stream = connector().connect(domain, stream).await.unwrap();
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    let mut next_line = async || {
+        timeout(READ_TIMEOUT, lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(
+        next_line().await,
+        "Welcome to our chat room, please enter your nickname:"
+    );
+    write.write_all(b"alice\n").await.unwrap();
+    assert_eq!(next_line().await, "* Welcome, the room is empty");
+}
+
+#[tokio::test]
+async fn failed_handshakes_keep_the_listener() {
+    let addr = start_server().await;
+    // a plaintext client
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"alice\n").await.unwrap();
+    let mut response = Vec::new();
+    let _ = timeout(READ_TIMEOUT, stream.read_to_end(&mut response))
+        .await
+        .unwrap();
+    assert!(!response.starts_with(b"Welcome"), "{response:?}");
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let domain = ServerName::try_from("localhost").unwrap();
+    let stream = connector().connect(domain, stream).await.unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    let line = timeout(READ_TIMEOUT, lines.next_line()).await.unwrap();
+    assert_eq!(
+        line.unwrap().unwrap(),
+        "Welcome to our chat room, please enter your nickname:"
+    );
+}
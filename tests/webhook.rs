@@ -0,0 +1,223 @@
+//! The events POSTed to a mock HTTP endpoint
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use budget_chat::{start_webhook, Chatroom, WebhookConfig, WebhookEvents};
+use tokio::sync::mpsc::channel;
+
+/// A request received by the mock endpoint
+struct Request {
+    /// the request line and the headers, their names lowercased
+    head: String,
+    body: serde_json::Value,
+}
+
+/// Serve requests on an ephemeral local port, answering with the `statuses` first
+/// then with 200
+fn mock_endpoint(statuses: &'static [u16]) -> (SocketAddr, Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut statuses = statuses.iter();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.split_once(':') {
+                    Some((name, value)) => {
+                        head.push_str(&format!("{}:{value}", name.to_ascii_lowercase()))
+                    }
+                    None => head.push_str(&line),
+                }
+            }
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let status = statuses.next().copied().unwrap_or(200);
+            write!(
+                stream,
+                "HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+            let body = serde_json::from_slice(&body).unwrap();
+            if status == 200 {
+                let _ = sender.send(Request { head, body });
+            }
+        }
+    });
+    (addr, requests)
+}
+
+fn receive(requests: &Receiver<Request>) -> Request {
+    requests.recv_timeout(Duration::from_secs(5)).unwrap()
+}
+
+#[test]
+fn events_are_posted() {
+    let (addr, requests) = mock_endpoint(&[]);
+    let chatroom = Chatroom::default();
+    start_webhook(
+        &chatroom,
+        WebhookConfig {
+            url: format!("http://{addr}/hooks/chat"),
+            auth_header: Some("Authorization: Bearer s3cret".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).unwrap();
+    alice.send_message("hi".to_string()).unwrap();
+    alice.send_emote("waves".to_string()).unwrap();
+    drop(alice);
+
+    let joined = receive(&requests);
+    assert!(joined.head.starts_with("POST /hooks/chat HTTP/1.1\r\n"));
+    assert!(joined.head.contains(&format!("host: {addr}\r\n")));
+    assert!(joined.head.contains("content-type: application/json\r\n"));
+    assert!(joined.head.contains("authorization: Bearer s3cret\r\n"));
+    assert_eq!(
+        joined.body,
+        serde_json::json!({"type": "joined", "nick": "alice"})
+    );
+    assert_eq!(
+        receive(&requests).body,
+        serde_json::json!({"type": "message", "from": "alice", "text": "hi"})
+    );
+    assert_eq!(
+        receive(&requests).body,
+        serde_json::json!({"type": "emote", "from": "alice", "text": "waves"})
+    );
+    let left = receive(&requests).body;
+    assert_eq!(
+        (&left["type"], &left["nick"]),
+        (&"left".into(), &"alice".into())
+    );
+}
+
+#[test]
+fn messages_are_posted_only_if_enabled() {
+    let (addr, requests) = mock_endpoint(&[]);
+    let chatroom = Chatroom::default();
+    start_webhook(
+        &chatroom,
+        WebhookConfig {
+            url: format!("http://{addr}"),
+            events: "join,leave".parse().unwrap(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let (sender, _alice) = channel(16);
+    let alice = chatroom.join("alice".to_string(), sender).unwrap();
+    alice
+        .send_message("not for the webhook".to_string())
+        .unwrap();
+    drop(alice);
+
+    let joined = receive(&requests);
+    assert!(joined.head.starts_with("POST / HTTP/1.1\r\n"));
+    assert_eq!(joined.body["type"], "joined");
+    assert_eq!(receive(&requests).body["type"], "left");
+    assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn failed_requests_are_retried_then_dropped() {
+    let (addr, requests) = mock_endpoint(&[500, 503]);
+    let chatroom = Chatroom::default();
+    let config = WebhookConfig {
+        url: format!("http://{addr}"),
+        events: WebhookEvents {
+            join: true,
+            leave: false,
+            message: false,
+        },
+        retries: 2,
+        backoff: Duration::from_millis(1),
+        ..Default::default()
+    };
+    start_webhook(&chatroom, config.clone()).unwrap();
+    let (sender, _alice) = channel(16);
+    let _alice = chatroom.join("alice".to_string(), sender).unwrap();
+    // answered by the third attempt
+    assert_eq!(receive(&requests).body["nick"], "alice");
+
+    // nothing listens on the port of a closed listener
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let chatroom = Chatroom::default();
+    start_webhook(
+        &chatroom,
+        WebhookConfig {
+            url: format!("http://{closed}"),
+            ..config
+        },
+    )
+    .unwrap();
+    let (sender, _bob) = channel(16);
+    let _bob = chatroom.join("bob".to_string(), sender).unwrap();
+    let started = Instant::now();
+    while chatroom.metrics().webhook_drops == 0 {
+        assert!(started.elapsed() < Duration::from_secs(5), "not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(chatroom.metrics().webhook_drops, 1);
+}
+
+#[test]
+fn invalid_webhooks_are_refused() {
+    let chatroom = Chatroom::default();
+    let error = |url: &str, auth_header: Option<&str>| {
+        start_webhook(
+            &chatroom,
+            WebhookConfig {
+                url: url.to_string(),
+                auth_header: auth_header.map(str::to_string),
+                ..Default::default()
+            },
+        )
+        .unwrap_err()
+        .to_string()
+    };
+    assert_eq!(
+        error("ftp://example.com", None),
+        "expected an http:// or https:// URL, got ftp://example.com"
+    );
+    assert!(error("http:///path", None).starts_with("invalid URL http:///path: "));
+    assert_eq!(
+        error("http://example.com", Some("Bearer s3cret")),
+        "expected a \"Name: value\" header"
+    );
+    // the https:// webhooks, e.g. of Slack or Discord, are served over TLS
+    start_webhook(
+        &chatroom,
+        WebhookConfig {
+            url: "https://hooks.example.com/services/T0/B0/x".to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        "join,typing".parse::<WebhookEvents>(),
+        Err("expected join, leave or message, got typing".to_string())
+    );
+}